@@ -0,0 +1,360 @@
+// Text buffer: the rows of the open file, stored as a rope of line chunks.
+//
+// A plain Vec<EditorRow> has to shift every following row on each insert or
+// delete. Here rows live in chunks of at most MAX_CHUNK lines, and a prefix
+// table of chunk start lines lets us find any line with a binary search.
+// Editing a line only touches its own chunk plus the (small) prefix table.
+
+use crate::EditorRow;
+use std::ops::{Index, IndexMut};
+
+const MAX_CHUNK: usize = 1024; // Split a chunk once it grows past this many rows
+const MIN_CHUNK: usize = MAX_CHUNK / 4; // Merge a chunk into its neighbour below this
+
+#[derive(Debug, Default)]
+pub struct TextBuffer {
+    chunks: Vec<Vec<EditorRow>>,
+    starts: Vec<usize>, // starts[i] = index of the first line held by chunks[i]
+    len: usize,
+}
+
+impl TextBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Build a buffer in one pass from file contents (no per-row inserts).
+    pub fn from_text(text: &str) -> Self {
+        let mut buffer = Self::new();
+        let mut chunk = Vec::with_capacity(MAX_CHUNK);
+
+        for line in text.lines() {
+            chunk.push(EditorRow::new(line.trim_end_matches('\r')));
+            if chunk.len() == MAX_CHUNK {
+                buffer.chunks.push(chunk);
+                chunk = Vec::with_capacity(MAX_CHUNK);
+            }
+        }
+        if !chunk.is_empty() {
+            buffer.chunks.push(chunk);
+        }
+
+        buffer.rebuild_starts();
+        buffer
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, at: usize) -> Option<&EditorRow> {
+        if at >= self.len {
+            return None;
+        }
+        let (c, off) = self.locate(at);
+        Some(&self.chunks[c][off])
+    }
+
+    pub fn get_mut(&mut self, at: usize) -> Option<&mut EditorRow> {
+        if at >= self.len {
+            return None;
+        }
+        let (c, off) = self.locate(at);
+        Some(&mut self.chunks[c][off])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &EditorRow> {
+        self.chunks.iter().flatten()
+    }
+
+    pub fn insert_row(&mut self, at: usize, row: EditorRow) {
+        if at > self.len {
+            return;
+        }
+
+        if self.chunks.is_empty() {
+            self.chunks.push(Vec::new());
+            self.starts.push(0);
+        }
+
+        // Appending goes to the last chunk, everything else to the owner chunk
+        let (c, off) = if at == self.len {
+            let last = self.chunks.len() - 1;
+            (last, self.chunks[last].len())
+        } else {
+            self.locate(at)
+        };
+
+        self.chunks[c].insert(off, row);
+        self.len += 1;
+
+        if self.chunks[c].len() > MAX_CHUNK {
+            let tail = self.chunks[c].split_off(MAX_CHUNK / 2);
+            self.chunks.insert(c + 1, tail);
+            self.rebuild_starts();
+        } else {
+            for start in &mut self.starts[c + 1..] {
+                *start += 1;
+            }
+        }
+    }
+
+    pub fn remove_row(&mut self, at: usize) -> Option<EditorRow> {
+        if at >= self.len {
+            return None;
+        }
+
+        let (c, off) = self.locate(at);
+        let row = self.chunks[c].remove(off);
+        self.len -= 1;
+
+        let chunk_len = self.chunks[c].len();
+        if chunk_len == 0 {
+            self.chunks.remove(c);
+            self.rebuild_starts();
+        } else if chunk_len < MIN_CHUNK && c + 1 < self.chunks.len() {
+            // Fold small chunks into the next one so lookups stay shallow
+            let next = self.chunks.remove(c + 1);
+            self.chunks[c].extend(next);
+            // Too big for one chunk: two halves again
+            let merged = self.chunks[c].len();
+            if merged > MAX_CHUNK {
+                let tail = self.chunks[c].split_off(merged / 2);
+                self.chunks.insert(c + 1, tail);
+            }
+            self.rebuild_starts();
+        } else {
+            for start in &mut self.starts[c + 1..] {
+                *start -= 1;
+            }
+        }
+
+        Some(row)
+    }
+
+    pub fn insert_char(&mut self, y: usize, x: usize, c: char) {
+        if let Some(row) = self.get_mut(y) {
+            row.insert_char(x, c);
+        }
+    }
+
+    pub fn delete_char(&mut self, y: usize, x: usize) {
+        if let Some(row) = self.get_mut(y) {
+            row.delete_char(x);
+        }
+    }
+
+    pub fn insert_str(&mut self, y: usize, x: usize, s: &str) {
+        if let Some(row) = self.get_mut(y) {
+            row.insert_str(x, s);
+        }
+    }
+
+    // Remove bytes x..end of line `y`, returning what was removed.
+    pub fn delete_str(&mut self, y: usize, x: usize, end: usize) -> String {
+        match self.get_mut(y) {
+            Some(row) => row.delete_range(x, end),
+            None => String::new(),
+        }
+    }
+
+    // Split line `y` at byte `x`, moving the right half to a new line y + 1.
+    pub fn split_line(&mut self, y: usize, x: usize) {
+        let right = match self.get_mut(y) {
+            Some(row) => {
+                let x = x.min(row.chars.len());
+                if !row.chars.is_char_boundary(x) {
+                    return;
+                }
+                let right = row.chars.split_off(x);
+                row.size = row.chars.len();
+                row.update_row();
+                right
+            }
+            None => return,
+        };
+        self.insert_row(y + 1, EditorRow::new(&right));
+    }
+
+    // Append line y + 1 onto line y. Returns the old length of line y.
+    pub fn join_lines(&mut self, y: usize) -> Option<usize> {
+        if y + 1 >= self.len {
+            return None;
+        }
+        let next = self.remove_row(y + 1)?;
+        let row = self.get_mut(y)?;
+        let at = row.size;
+        row.append_string(&next.chars);
+        Some(at)
+    }
+
+    // Whole buffer as file contents, one '\n' after each line.
+    pub fn to_text(&self) -> String {
+        let total_len = self.iter().map(|row| row.chars.len() + 1).sum();
+        let mut text = String::with_capacity(total_len);
+        for row in self.iter() {
+            text.push_str(&row.chars);
+            text.push('\n');
+        }
+        text
+    }
+
+    fn locate(&self, at: usize) -> (usize, usize) {
+        let c = match self.starts.binary_search(&at) {
+            Ok(c) => c,
+            Err(c) => c - 1,
+        };
+        (c, at - self.starts[c])
+    }
+
+    fn rebuild_starts(&mut self) {
+        self.starts.clear();
+        let mut line = 0;
+        for chunk in &self.chunks {
+            self.starts.push(line);
+            line += chunk.len();
+        }
+        self.len = line;
+    }
+}
+
+impl Index<usize> for TextBuffer {
+    type Output = EditorRow;
+
+    fn index(&self, at: usize) -> &EditorRow {
+        self.get(at).expect("row index out of bounds")
+    }
+}
+
+impl IndexMut<usize> for TextBuffer {
+    fn index_mut(&mut self, at: usize) -> &mut EditorRow {
+        self.get_mut(at).expect("row index out of bounds")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Chunks hold between one and MAX_CHUNK rows and the table of where
+    // they start is right.
+    fn check_chunks(buffer: &TextBuffer) {
+        let mut line = 0;
+        for (chunk, &start) in buffer.chunks.iter().zip(&buffer.starts) {
+            assert!(!chunk.is_empty() && chunk.len() <= MAX_CHUNK, "chunk of {} rows", chunk.len());
+            assert_eq!(start, line);
+            line += chunk.len();
+        }
+        assert_eq!(buffer.starts.len(), buffer.chunks.len());
+        assert_eq!(buffer.len(), line);
+    }
+
+    fn same_rows(buffer: &TextBuffer, rows: &[String]) {
+        check_chunks(buffer);
+        assert_eq!(buffer.len(), rows.len());
+        assert!(buffer.iter().map(|row| &row.chars).eq(rows));
+        for (y, row) in rows.iter().enumerate() {
+            assert_eq!(&buffer[y].chars, row);
+        }
+        assert!(buffer.get(rows.len()).is_none());
+    }
+
+    fn numbered(n: usize) -> Vec<String> {
+        (0..n).map(|y| y.to_string()).collect()
+    }
+
+    #[test]
+    fn rows_at_chunk_boundaries() {
+        let mut rows = numbered(2 * MAX_CHUNK);
+        let mut buffer = TextBuffer::from_text(&rows.join("\n"));
+        assert_eq!(buffer.chunks.len(), 2);
+        same_rows(&buffer, &rows);
+
+        // The last row of the full first chunk, then the first of the full
+        // second one, now at MAX_CHUNK + 1: each insert splits its chunk.
+        // Appending goes to the end of the last chunk.
+        for at in [MAX_CHUNK - 1, MAX_CHUNK + 1, rows.len() + 2] {
+            buffer.insert_row(at, EditorRow::new("new"));
+            rows.insert(at, "new".to_string());
+            same_rows(&buffer, &rows);
+        }
+        assert_eq!(buffer.chunks.len(), 4);
+
+        // The first row, either side of where a chunk starts, and the last
+        for pick in 0..4 {
+            let at = match pick {
+                0 => 0,
+                1 => buffer.starts[1],
+                2 => buffer.starts[1] - 1,
+                _ => rows.len() - 1,
+            };
+            assert_eq!(buffer.remove_row(at).map(|row| row.chars), Some(rows.remove(at)));
+            same_rows(&buffer, &rows);
+        }
+        assert!(buffer.remove_row(rows.len()).is_none());
+        buffer.insert_row(rows.len() + 1, EditorRow::new("past the end"));
+        same_rows(&buffer, &rows);
+    }
+
+    #[test]
+    fn small_chunks_merge_without_growing_past_max_chunk() {
+        let mut rows = numbered(2 * MAX_CHUNK);
+        let mut buffer = TextBuffer::from_text(&rows.join("\n"));
+        for _ in 0..MAX_CHUNK - MIN_CHUNK + 1 {
+            buffer.remove_row(0);
+            rows.remove(0);
+        }
+        // The first chunk was folded into the full one after it and the
+        // two split evenly
+        same_rows(&buffer, &rows);
+        assert_eq!(buffer.chunks.len(), 2);
+        assert!(buffer.chunks.iter().all(|chunk| chunk.len() >= MIN_CHUNK));
+    }
+
+    #[test]
+    fn the_last_row_can_go_and_come_back() {
+        let mut buffer = TextBuffer::from_text("one\ntwo\n");
+        assert_eq!(buffer.remove_row(1).map(|row| row.chars), Some("two".to_string()));
+        assert_eq!(buffer[0].chars, "one");
+        assert!(buffer.get(1).is_none());
+        buffer.remove_row(0);
+        assert!(buffer.is_empty() && buffer.chunks.is_empty());
+        buffer.insert_row(0, EditorRow::new("again"));
+        same_rows(&buffer, &["again".to_string()]);
+        assert_eq!(buffer.to_text(), "again\n");
+    }
+
+    #[test]
+    fn edits_match_a_vec_of_rows() {
+        let mut rows = numbered(3 * MAX_CHUNK / 2);
+        let mut buffer = TextBuffer::from_text(&rows.join("\n"));
+
+        // The same pseudo-random edits each run, biased to grow first and
+        // shrink after, so chunks split and merge
+        let mut seed: u64 = 1;
+        let mut random = |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % n
+        };
+        for step in 0..20_000 {
+            let grow = if step < 10_000 { 3 } else { 1 };
+            if rows.is_empty() || random(4) < grow {
+                let at = random(rows.len() + 1);
+                buffer.insert_row(at, EditorRow::new(&step.to_string()));
+                rows.insert(at, step.to_string());
+            } else {
+                let at = random(rows.len());
+                assert_eq!(buffer.remove_row(at).map(|row| row.chars), Some(rows.remove(at)));
+            }
+            check_chunks(&buffer);
+            if let Some(y) = (!rows.is_empty()).then(|| random(rows.len())) {
+                assert_eq!(buffer[y].chars, rows[y]);
+            }
+        }
+        same_rows(&buffer, &rows);
+    }
+}
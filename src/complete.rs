@@ -0,0 +1,350 @@
+// Insert mode completion.
+//
+// Tab after part of a word opens a menu of words that could finish it,
+// drawn over the text under the cursor. They come from the buffer, the
+// keywords and types of its syntax, and the language server when there is
+// one. The typed characters only have to appear in a candidate in order,
+// so `vnw` finds `vec_new`; candidates that start with them, or match at
+// word boundaries or in a row, rank first.
+//
+//   Tab / Shift-Tab   next / previous candidate, wrapping through what
+//                     was typed
+//   Enter             keep the candidate and close the menu
+//   Escape            go back to what was typed
+//
+// Any other key keeps the candidate and is handled as usual. The selected
+// candidate is put in the buffer as it is selected, so the text is its own
+// preview; a box next to the menu shows where the word comes from.
+
+use crate::grapheme;
+use crate::mode::EditorMode;
+use crate::screen::Grid;
+use crate::theme::Ui;
+use crate::{EditorConfig, EditorKey};
+
+const MAX_ITEMS: usize = 50;
+const MENU_HEIGHT: usize = 10;
+const MENU_WIDTH: usize = 40;
+const PREVIEW_WIDTH: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Source {
+    Lsp,
+    Keyword,
+    Buffer,
+}
+
+impl Source {
+    fn tag(self) -> &'static str {
+        match self {
+            Source::Lsp => "lsp",
+            Source::Keyword => "kw",
+            Source::Buffer => "buf",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    word: String,
+    source: Source,
+    preview: String,
+}
+
+#[derive(Debug)]
+pub struct Completion {
+    y: usize,
+    start: usize,  // byte column where the word being completed starts
+    typed: String, // what was there when the menu opened
+    items: Vec<Candidate>,
+    selected: Option<usize>, // None while the typed text is shown
+}
+
+// Characters words are made of; separators end them.
+fn is_word_char(c: char) -> bool {
+    !EditorConfig::is_separator(c) && (c.is_alphanumeric() || c == '_')
+}
+
+// How well `candidate` matches `typed`, or None when the typed characters
+// don't all appear in it in order. Higher is better.
+fn fuzzy_score(candidate: &str, typed: &str) -> Option<i32> {
+    let chars: Vec<char> = candidate.chars().collect();
+    let mut score = 0;
+    let mut at = 0;
+    let mut last: Option<usize> = None;
+    for t in typed.chars() {
+        while !chars.get(at)?.eq_ignore_ascii_case(&t) {
+            at += 1;
+        }
+        score += if chars[at] == t { 2 } else { 1 };
+        match last {
+            Some(last) if last + 1 == at => score += 5,
+            Some(last) => score -= (at - last - 1).min(5) as i32,
+            None => {}
+        }
+        if at == 0 {
+            score += 10;
+        } else {
+            let prev = chars[at - 1];
+            if prev == '_' || (prev.is_ascii_lowercase() && chars[at].is_ascii_uppercase()) {
+                score += 8;
+            }
+        }
+        last = Some(at);
+        at += 1;
+    }
+    if candidate.starts_with(typed) {
+        score += 20;
+    }
+    Some(score - (chars.len() / 8) as i32)
+}
+
+impl EditorConfig {
+    // The start of the word that ends at the cursor.
+    fn completion_start(&self) -> Option<usize> {
+        let row = self.erow.get(self.cy)?;
+        let before = &row.chars[..self.cx.min(row.chars.len())];
+        let start = before.trim_end_matches(is_word_char).len();
+        (start < before.len()).then_some(start)
+    }
+
+    fn completion_candidates(&mut self, typed: &str) -> Vec<Candidate> {
+        let mut candidates: Vec<Candidate> = Vec::new();
+
+        for (word, preview) in self.lsp_completions() {
+            let preview = if preview.is_empty() { "language server".to_string() } else { preview };
+            candidates.push(Candidate { word, source: Source::Lsp, preview });
+        }
+        if let Some(syntax) = &self.syntax {
+            for keyword in &syntax.keywords {
+                let word = keyword.trim_end_matches('|').to_string();
+                candidates.push(Candidate { word, source: Source::Keyword, preview: "keyword".to_string() });
+            }
+            for word in &syntax.types {
+                let word = word.clone();
+                candidates.push(Candidate { word, source: Source::Keyword, preview: "type".to_string() });
+            }
+        }
+        for y in 0..self.erow.len() {
+            let line = &self.erow[y].chars;
+            for word in line.split(|c| !is_word_char(c)) {
+                // The word being completed doesn't count
+                if word.len() < 2 || word.starts_with(|c: char| c.is_ascii_digit()) || word == typed {
+                    continue;
+                }
+                let preview = format!("line {}: {}", y + 1, line.trim());
+                candidates.push(Candidate { word: word.to_string(), source: Source::Buffer, preview });
+            }
+        }
+
+        let mut ranked: Vec<(i32, Candidate)> = candidates
+            .into_iter()
+            .filter(|candidate| candidate.word != typed)
+            .filter_map(|candidate| Some((fuzzy_score(&candidate.word, typed)?, candidate)))
+            .collect();
+        // Best first; of equal words the one from the best source stays
+        ranked.sort_by(|(a, x), (b, y)| {
+            (b, x.source, x.word.len(), &x.word).cmp(&(a, y.source, y.word.len(), &y.word))
+        });
+        let mut items: Vec<Candidate> = Vec::new();
+        for (_, candidate) in ranked {
+            if !items.iter().any(|item| item.word == candidate.word) {
+                items.push(candidate);
+            }
+            if items.len() == MAX_ITEMS {
+                break;
+            }
+        }
+        items
+    }
+
+    // Tab in Insert mode. Returns false when there's no word before the
+    // cursor, so the Tab is typed as usual.
+    pub(crate) fn editor_complete(&mut self, backward: bool) -> bool {
+        let Some(start) = self.completion_start() else {
+            return false;
+        };
+        let typed = self.erow[self.cy].chars[start..self.cx].to_string();
+        let items = self.completion_candidates(&typed);
+        if items.is_empty() {
+            self.editor_set_status_msg("No completions");
+            return true;
+        }
+        self.completion = Some(Completion { y: self.cy, start, typed, items, selected: None });
+        self.completion_step(backward);
+        true
+    }
+
+    // Select the next or previous candidate and put it in the buffer.
+    fn completion_step(&mut self, backward: bool) {
+        let Some(completion) = &mut self.completion else {
+            return;
+        };
+        let len = completion.items.len();
+        completion.selected = match (completion.selected, backward) {
+            (None, false) => Some(0),
+            (None, true) => Some(len - 1),
+            (Some(i), false) => (i + 1 < len).then_some(i + 1),
+            (Some(i), true) => i.checked_sub(1),
+        };
+        let text = match completion.selected {
+            Some(i) => completion.items[i].word.clone(),
+            None => completion.typed.clone(),
+        };
+        let start = completion.start;
+        self.completion_replace(start, &text);
+    }
+
+    // Replace the word from `start` to the cursor as if it was typed, so
+    // that `.` repeats it.
+    fn completion_replace(&mut self, start: usize, text: &str) {
+        while self.cx > start {
+            self.editor_del_char();
+        }
+        for c in text.chars() {
+            self.editor_insert_char(c);
+        }
+    }
+
+    // A key pressed while the menu is open. Returns true if the menu used it.
+    pub(crate) fn completion_key(&mut self, key: EditorKey) -> bool {
+        let Some(completion) = &self.completion else {
+            return false;
+        };
+        if self.mode != EditorMode::Insert || completion.y != self.cy {
+            self.completion = None;
+            return false;
+        }
+        match key {
+            EditorKey::Other(b'\t') => self.completion_step(false),
+            EditorKey::ShiftTab => self.completion_step(true),
+            EditorKey::EnterKey => self.completion = None,
+            EditorKey::Escape => {
+                let (start, typed) = (completion.start, completion.typed.clone());
+                self.completion_replace(start, &typed);
+                self.completion = None;
+            }
+            _ => {
+                self.completion = None;
+                return false;
+            }
+        }
+        true
+    }
+
+    // Draw the menu under the word (or over it, near the bottom of the
+    // screen) with the preview box to its right.
+    pub(crate) fn draw_completion(&self, grid: &mut Grid) {
+        let Some(completion) = &self.completion else {
+            return;
+        };
+        let Some(row) = self.erow.get(completion.y) else {
+            return;
+        };
+        let visible = (self.row_off..self.row_off + self.screen_rows).contains(&completion.y);
+        if self.mode != EditorMode::Insert || !visible {
+            return;
+        }
+
+        let height = completion.items.len().min(MENU_HEIGHT);
+        let cursor_row = completion.y - self.row_off;
+        let below = self.screen_rows - cursor_row - 1;
+        let (top, height) = if below >= height || below >= cursor_row {
+            (cursor_row + 1, height.min(below))
+        } else {
+            (cursor_row.saturating_sub(height), height.min(cursor_row))
+        };
+        if height == 0 {
+            return;
+        }
+
+        // Keep the selected item in view
+        let selected = completion.selected.unwrap_or(0);
+        let first = (selected + 1).saturating_sub(height);
+        let shown = &completion.items[first..first + height];
+
+        let word_width = shown.iter().map(|item| grapheme::str_width(&item.word)).max().unwrap_or(0);
+        let width = (word_width + 5).min(MENU_WIDTH).min(self.screen_cols);
+        let rx = self.editor_row_cx_to_rx(row, completion.start).saturating_sub(self.col_off);
+        let left = (self.gutter_width() + rx).min(self.screen_cols - width);
+        let menu = self.theme.ui(Ui::Menu);
+
+        for (i, item) in shown.iter().enumerate() {
+            let is_selected = completion.selected == Some(first + i);
+            let pad = width.saturating_sub(4).saturating_sub(grapheme::str_width(&item.word));
+            let text = format!("{}{:pad$} {:>3}", item.word, "", item.source.tag());
+            let (text, _) = grapheme::split_at_width(&text, width);
+            let style = if is_selected { self.theme.ui(Ui::MenuSelected) } else { menu };
+            grid.print(top + i, left, text, style);
+        }
+
+        // The preview of the selected candidate, if there is room for it
+        let Some(item) = completion.selected.map(|i| &completion.items[i]) else {
+            return;
+        };
+        let preview_left = left + width + 1;
+        let preview_width = self.screen_cols.saturating_sub(preview_left).min(PREVIEW_WIDTH);
+        if preview_width < 10 {
+            return;
+        }
+        let mut rest = item.preview.as_str();
+        for i in 0..height {
+            let (line, after) = grapheme::split_at_width(rest, preview_width);
+            if line.is_empty() {
+                break;
+            }
+            let pad = preview_width - grapheme::str_width(line);
+            grid.print(top + i, preview_left, &format!("{line}{:pad$}", ""), menu);
+            rest = after;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_characters_must_appear_in_order() {
+        assert!(fuzzy_score("vec_new", "vnw").is_some());
+        assert!(fuzzy_score("Vec_New", "vnw").is_some());
+        assert_eq!(fuzzy_score("new_vec", "vnw"), None);
+        assert_eq!(fuzzy_score("vn", "vnw"), None);
+    }
+
+    #[test]
+    fn prefixes_word_starts_and_runs_rank_first() {
+        let score = |candidate| fuzzy_score(candidate, "cou").unwrap();
+        assert!(score("count") > score("account"));
+        assert!(score("counter") > score("c_o_u"));
+        assert!(fuzzy_score("vec_new", "vn") > fuzzy_score("vecanew", "vn"));
+        assert!(fuzzy_score("newVec", "nv") > fuzzy_score("newvec", "nv"));
+        // Matching case beats ignoring it
+        assert!(fuzzy_score("foo", "foo") > fuzzy_score("Foo", "foo"));
+    }
+
+    #[test]
+    fn tab_and_shift_tab_cycle_through_the_menu_and_what_was_typed() {
+        // The two score the same, so the shorter comes first
+        let mut editor = EditorConfig::with_lines(&["counter cost", ""]);
+        editor.type_keys("jico\t");
+        assert_eq!(editor.lines()[1], "cost");
+        editor.type_keys("\t");
+        assert_eq!(editor.lines()[1], "counter");
+        editor.type_keys("\t");
+        assert_eq!(editor.lines()[1], "co");
+        editor.type_keys("\u{e00a}\u{e00a}");
+        assert_eq!(editor.lines()[1], "cost");
+
+        // Escape goes back to what was typed and stays in Insert mode
+        editor.type_keys("\x1b");
+        assert_eq!(editor.lines()[1], "co");
+        assert!(editor.completion.is_none());
+        assert_eq!(editor.mode, EditorMode::Insert);
+
+        // Any other key keeps the candidate and is typed after it
+        editor.type_keys("\t!");
+        assert_eq!(editor.lines()[1], "cost!");
+        assert!(editor.completion.is_none());
+    }
+}
@@ -0,0 +1,141 @@
+// Range-level editing on top of the row primitives.
+//
+// Positions are (x, y) pairs in the same order as (cx, cy). Every change
+// goes through editor_apply so it lands in the undo history; callers that
+// make several changes should wrap them in history.begin_group/end_group.
+
+use crate::undo::EditOp;
+use crate::EditorConfig;
+
+impl EditorConfig {
+    // Text between two positions (end exclusive), lines joined with '\n'.
+    pub(crate) fn editor_text_range(&self, start: (usize, usize), end: (usize, usize)) -> String {
+        let (start, end) = self.order_range(start, end);
+        if !self.on_char_boundary(start) || !self.on_char_boundary(end) {
+            return String::new();
+        }
+        let mut text = String::new();
+
+        for y in start.1..=end.1 {
+            let row = match self.erow.get(y) {
+                Some(row) => &row.chars,
+                None => break,
+            };
+            let from = if y == start.1 { start.0.min(row.len()) } else { 0 };
+            let to = if y == end.1 { end.0.min(row.len()) } else { row.len() };
+            if y > start.1 {
+                text.push('\n');
+            }
+            if from < to {
+                text.push_str(&row[from..to]);
+            }
+        }
+        text
+    }
+
+    // Delete between two positions (end exclusive) and return the removed text.
+    pub(crate) fn editor_delete_range(&mut self, start: (usize, usize), end: (usize, usize)) -> String {
+        let (start, end) = self.order_range(start, end);
+        if start == end || start.1 >= self.erow.len() {
+            return String::new();
+        }
+        if !self.on_char_boundary(start) || !self.on_char_boundary(end) {
+            return String::new();
+        }
+        let removed = self.editor_text_range(start, end);
+        let (sx, sy) = start;
+
+        if sy == end.1 {
+            let row = &self.erow[sy].chars;
+            let sx = sx.min(row.len());
+            let text = row[sx..end.0.clamp(sx, row.len())].to_string();
+            self.editor_apply(EditOp::DeleteText { y: sy, x: sx, text });
+        } else {
+            // Cut the tail of the first line, drop whole lines in between,
+            // cut the head of the last line, then glue the two together.
+            let sx = sx.min(self.erow[sy].size);
+            let tail = self.erow[sy].chars[sx..].to_string();
+            if !tail.is_empty() {
+                self.editor_apply(EditOp::DeleteText { y: sy, x: sx, text: tail });
+            }
+            for _ in sy + 1..end.1 {
+                let text = self.erow[sy + 1].chars.clone();
+                self.editor_apply(EditOp::RemoveRow { y: sy + 1, text });
+            }
+            if sy + 1 < self.erow.len() {
+                let row = &self.erow[sy + 1].chars;
+                let head = row[..end.0.min(row.len())].to_string();
+                if !head.is_empty() {
+                    self.editor_apply(EditOp::DeleteText { y: sy + 1, x: 0, text: head });
+                }
+                self.editor_apply(EditOp::JoinLines { y: sy, x: sx });
+            }
+        }
+
+        removed
+    }
+
+    // Insert text (which may contain '\n') at a position. Returns the
+    // position just after the inserted text.
+    pub(crate) fn editor_insert_text(&mut self, at: (usize, usize), text: &str) -> (usize, usize) {
+        let (mut x, mut y) = at;
+        if y >= self.erow.len() {
+            y = self.erow.len();
+            x = 0;
+            self.editor_apply(EditOp::InsertRow { y, text: String::new() });
+        }
+
+        for (i, piece) in text.split('\n').enumerate() {
+            if i > 0 {
+                self.editor_apply(EditOp::SplitLine { y, x });
+                y += 1;
+                x = 0;
+            }
+            if !piece.is_empty() {
+                self.editor_apply(EditOp::InsertText { y, x, text: piece.to_string() });
+                x += piece.len();
+            }
+        }
+        (x, y)
+    }
+
+    // Remove `count` whole lines starting at `y`, returning their text.
+    pub(crate) fn editor_delete_rows(&mut self, y: usize, count: usize) -> Vec<String> {
+        let mut removed = Vec::new();
+        for _ in 0..count {
+            if y >= self.erow.len() {
+                break;
+            }
+            let text = self.erow[y].chars.clone();
+            removed.push(text.clone());
+            self.editor_apply(EditOp::RemoveRow { y, text });
+        }
+        removed
+    }
+
+    // Insert whole lines so that the first one ends up at row `y`.
+    pub(crate) fn editor_insert_rows(&mut self, y: usize, lines: &[String]) {
+        let y = y.min(self.erow.len());
+        for (i, line) in lines.iter().enumerate() {
+            self.editor_insert_row(y + i, line);
+        }
+    }
+
+    // Offsets past the end of a row are clamped by the callers, so only
+    // one that lands inside a character is refused.
+    fn on_char_boundary(&self, (x, y): (usize, usize)) -> bool {
+        self.erow.get(y).is_none_or(|row| row.chars.is_char_boundary(x.min(row.chars.len())))
+    }
+
+    fn order_range(
+        &self,
+        a: (usize, usize),
+        b: (usize, usize),
+    ) -> ((usize, usize), (usize, usize)) {
+        if (a.1, a.0) <= (b.1, b.0) {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+}
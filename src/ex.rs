@@ -0,0 +1,963 @@
+// The `:` command line.
+//
+// A command is an optional line range followed by a command name, an
+// optional `!` and arguments, as in `:'a,'bs/foo/bar/g` or `:%sort u`.
+// Line numbers are 1-based here as in Vim; 0 is only meaningful as the
+// target of :m and :t ("above the first line"). Errors come back as
+// strings for the message bar.
+
+use crate::mode::EditorMode;
+use crate::motion;
+use crate::prompt::HistoryKind;
+use crate::register::{Register, RegisterWrite, Registers};
+use crate::search::{self, SearchPattern};
+use crate::undo::EditOp;
+use crate::{EditorConfig, EditorKey};
+use std::fs;
+use std::path::Path;
+
+// Full name and the shortest accepted abbreviation
+const EX_COMMANDS: &[(&str, usize)] = &[
+    ("cnext", 2),
+    ("copen", 4),
+    ("copy", 2),
+    ("cprevious", 2),
+    ("delete", 1),
+    ("edit", 1),
+    ("global", 1),
+    ("grep", 2),
+    ("make", 3),
+    ("move", 1),
+    ("nohlsearch", 3),
+    ("quit", 1),
+    ("rename", 3),
+    ("set", 2),
+    ("sort", 3),
+    ("substitute", 1),
+    ("t", 1),
+    ("vglobal", 1),
+    ("wq", 2),
+    ("write", 1),
+    ("xit", 1),
+];
+
+// Commands whose argument is a file name, for tab completion
+const FILE_COMMANDS: &[&str] = &["edit", "write", "wq", "xit"];
+
+#[derive(Debug, Default)]
+pub struct ExState {
+    last_replacement: Option<String>,
+    in_global: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Base {
+    Line(usize),
+    Current,
+    Last,
+    Mark(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Address {
+    base: Base,
+    offset: isize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeSpec {
+    Default,
+    Whole,
+    One(Address),
+    Two(Address, Address),
+}
+
+fn parse_number(s: &str) -> (Option<usize>, &str) {
+    let digits = s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    (s[..digits].parse().ok(), &s[digits..])
+}
+
+fn parse_address(s: &str) -> Result<(Option<Address>, &str), String> {
+    let (base, mut rest) = match s.chars().next() {
+        Some('.') => (Some(Base::Current), &s[1..]),
+        Some('$') => (Some(Base::Last), &s[1..]),
+        Some('\'') => {
+            let mark = s[1..].chars().next().ok_or("Missing mark name")?;
+            (Some(Base::Mark(mark)), &s[1 + mark.len_utf8()..])
+        }
+        Some(c) if c.is_ascii_digit() => {
+            let (n, rest) = parse_number(s);
+            (Some(Base::Line(n.ok_or("Invalid line number")?)), rest)
+        }
+        _ => (None, s),
+    };
+
+    // Offsets like .+3 or $-1; a bare offset is relative to the current line
+    let mut offset = 0isize;
+    while let Some(sign) = rest.chars().next().filter(|c| *c == '+' || *c == '-') {
+        let (n, after) = parse_number(&rest[1..]);
+        // A sign alone counts one line; a number too big for a line is an error
+        let n = match n {
+            None if after.len() == rest.len() - 1 => Some(1),
+            n => n.and_then(|n| isize::try_from(n).ok()),
+        };
+        offset = n
+            .and_then(|n| if sign == '+' { offset.checked_add(n) } else { offset.checked_sub(n) })
+            .ok_or("Invalid range")?;
+        rest = after;
+    }
+
+    match base {
+        Some(base) => Ok((Some(Address { base, offset }), rest)),
+        None if offset != 0 => Ok((Some(Address { base: Base::Current, offset }), rest)),
+        None => Ok((None, rest)),
+    }
+}
+
+fn parse_range(s: &str) -> Result<(RangeSpec, &str), String> {
+    if let Some(rest) = s.strip_prefix('%') {
+        return Ok((RangeSpec::Whole, rest));
+    }
+    let (first, rest) = parse_address(s)?;
+    let rest_sep = rest.strip_prefix(',').or_else(|| rest.strip_prefix(';'));
+    match (first, rest_sep) {
+        (first, Some(rest)) => {
+            let first = first.unwrap_or(Address { base: Base::Current, offset: 0 });
+            let (second, rest) = parse_address(rest)?;
+            let second = second.unwrap_or(first);
+            Ok((RangeSpec::Two(first, second), rest))
+        }
+        (Some(first), None) => Ok((RangeSpec::One(first), rest)),
+        (None, None) => Ok((RangeSpec::Default, rest)),
+    }
+}
+
+// Split the command name off the rest of the line.
+fn parse_name(s: &str) -> (&str, bool, &str) {
+    let s = s.trim_start();
+    let len = s.len() - s.trim_start_matches(|c: char| c.is_ascii_alphabetic()).len();
+    let (name, rest) = s.split_at(len);
+    match rest.strip_prefix('!') {
+        Some(rest) => (name, true, rest.trim_start()),
+        None => (name, false, rest.trim_start()),
+    }
+}
+
+fn command_name(abbrev: &str) -> Option<&'static str> {
+    EX_COMMANDS
+        .iter()
+        .find(|(full, min)| abbrev.len() >= *min && full.starts_with(abbrev))
+        .map(|(full, _)| *full)
+}
+
+// Split :set arguments at blanks, except ones escaped with a backslash.
+fn split_set_args(s: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek().is_some_and(|&next| next == '\\' || next.is_whitespace()) => {
+                arg.extend(chars.next());
+            }
+            c if c.is_whitespace() => {
+                if !arg.is_empty() {
+                    args.push(std::mem::take(&mut arg));
+                }
+            }
+            c => arg.push(c),
+        }
+    }
+    if !arg.is_empty() {
+        args.push(arg);
+    }
+    args
+}
+
+// Read up to the next unescaped `delim`, dropping the escapes in front of it.
+// Returns the text and what follows the delimiter, if there was one.
+fn split_delimited(s: &str, delim: char) -> (String, Option<&str>) {
+    let mut out = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == delim {
+            return (out, Some(&s[i + c.len_utf8()..]));
+        }
+        if c == '\\' {
+            match chars.next() {
+                Some((_, next)) if next == delim => out.push(next),
+                Some((_, next)) => {
+                    out.push('\\');
+                    out.push(next);
+                }
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    (out, None)
+}
+
+impl EditorConfig {
+    // Read a command line, with `initial` already typed, and run it.
+    pub(crate) fn editor_ex_prompt(&mut self, initial: &str) {
+        let mut completions: Option<(String, Vec<String>, usize)> = None;
+        let complete = move |editor: &mut Self, buf: &mut String, key: EditorKey| {
+            if key != EditorKey::Other(b'\t') {
+                completions = None;
+                return;
+            }
+            // Further tabs cycle through the candidates
+            if let Some((prefix, matches, index)) = &mut completions {
+                *index = (*index + 1) % matches.len();
+                *buf = format!("{}{}", prefix, matches[*index]);
+                return;
+            }
+            let (prefix, matches) = editor.ex_completions(buf);
+            let common = matches.iter().skip(1).fold(matches.first().cloned(), |acc, m| {
+                acc.map(|a| a.chars().zip(m.chars()).take_while(|(x, y)| x == y).map(|(x, _)| x).collect())
+            });
+            match common {
+                Some(common) if matches.len() == 1 || format!("{}{}", prefix, common).len() > buf.len() => {
+                    *buf = format!("{}{}", prefix, common);
+                }
+                Some(_) => {
+                    *buf = format!("{}{}", prefix, matches[0]);
+                    completions = Some((prefix, matches, 0));
+                }
+                None => {}
+            }
+        };
+
+        if let Some(line) = self.editor_prompt(":", initial, Some(HistoryKind::Command), Some(complete)) {
+            self.editor_ex_command(&line);
+        }
+    }
+
+    // Candidates for completing the word at the end of `line`, and the text
+    // that goes in front of each.
+    fn ex_completions(&self, line: &str) -> (String, Vec<String>) {
+        let after_range = match parse_range(line) {
+            Ok((_, rest)) => rest,
+            Err(_) => return (String::new(), Vec::new()),
+        };
+        let head = &line[..line.len() - after_range.len()];
+        let (name, bang, args) = parse_name(after_range);
+
+        if !after_range.contains(' ') && !bang {
+            let mut names: Vec<String> = EX_COMMANDS
+                .iter()
+                .filter(|(full, _)| full.starts_with(name))
+                .map(|(full, _)| full.to_string())
+                .collect();
+            names.sort();
+            return (head.to_string(), names);
+        }
+
+        match command_name(name) {
+            Some(full) if FILE_COMMANDS.contains(&full) => {}
+            _ => return (String::new(), Vec::new()),
+        }
+        let (dir, file) = match args.rfind('/') {
+            Some(i) => (&args[..=i], &args[i + 1..]),
+            None => ("", args),
+        };
+        let entries = match fs::read_dir(if dir.is_empty() { "." } else { dir }) {
+            Ok(entries) => entries,
+            Err(_) => return (String::new(), Vec::new()),
+        };
+        let mut matches: Vec<String> = entries
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let name = entry.file_name().into_string().ok()?;
+                if !name.starts_with(file) || (name.starts_with('.') && !file.starts_with('.')) {
+                    return None;
+                }
+                let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+                Some(if is_dir { format!("{}/", name) } else { name })
+            })
+            .collect();
+        matches.sort();
+        (line[..line.len() - file.len()].to_string(), matches)
+    }
+
+    // Run one command line, reporting any error in the message bar.
+    pub(crate) fn editor_ex_command(&mut self, line: &str) {
+        if let Err(msg) = self.run_ex(line) {
+            self.editor_set_status_msg(msg);
+        }
+    }
+
+    fn run_ex(&mut self, line: &str) -> Result<(), String> {
+        let line = line.trim_start_matches([':', ' ']);
+        let (range, rest) = parse_range(line)?;
+        let (name, bang, args) = parse_name(rest);
+
+        if name.is_empty() {
+            if !bang && args.is_empty() {
+                // :<n> jumps to a line, the last one for any line past it
+                let last = match range {
+                    RangeSpec::Default => return Ok(()),
+                    RangeSpec::One(address) => self.address_line(address)?.min(self.erow.len()),
+                    range => self.resolve_range(range, (self.cy + 1, self.cy + 1))?.1,
+                };
+                self.ex_goto(last);
+                return Ok(());
+            }
+            return Err(format!("Not an editor command: {}", line));
+        }
+        let command = command_name(name).ok_or_else(|| format!("Not an editor command: {}", line))?;
+
+        let current = (self.cy + 1, self.cy + 1);
+        let whole = (1, self.erow.len().max(1));
+
+        match command {
+            "write" => {
+                self.ex_write(args)?;
+            }
+            "wq" | "xit" => {
+                if command == "wq" || self.dirty > 0 || !args.is_empty() {
+                    self.ex_write(args)?;
+                }
+                if self.dirty == 0 {
+                    self.quit_requested = true;
+                }
+            }
+            "quit" => {
+                if self.dirty > 0 && !bang {
+                    return Err("No write since last change (add ! to override)".to_string());
+                }
+                self.quit_requested = true;
+            }
+            "edit" => self.ex_edit(args, bang)?,
+            "set" => self.ex_set(args)?,
+            "nohlsearch" => self.highlight_search(None),
+            "grep" => self.ex_grep(args)?,
+            "copen" => self.editor_show_quickfix()?,
+            "make" => self.ex_make(args)?,
+            "cnext" => self.ex_quickfix_step(true)?,
+            "cprevious" => self.ex_quickfix_step(false)?,
+            "rename" => self.ex_rename(args)?,
+            "substitute" => {
+                let lines = self.resolve_range(range, current)?;
+                self.ex_substitute(lines, args)?;
+            }
+            "global" | "vglobal" => {
+                let lines = self.resolve_range(range, whole)?;
+                self.ex_global(lines, args, bang || command == "vglobal")?;
+            }
+            "delete" => {
+                let (first, last) = self.resolve_range(range, current)?;
+                let register = match args.chars().next() {
+                    Some(c) if Registers::is_valid(c) => Some(c),
+                    Some(_) => return Err(format!("Invalid register: {}", args)),
+                    None => None,
+                };
+                self.history.begin_group((self.cx, self.cy));
+                let text = self.editor_delete_rows(first - 1, last - first + 1).join("\n");
+                self.registers.store(register, RegisterWrite::Delete, Register { text, linewise: true });
+                self.ex_goto(first.min(self.erow.len()));
+                self.history.end_group((self.cx, self.cy));
+            }
+            "move" | "copy" | "t" => {
+                let (first, last) = self.resolve_range(range, current)?;
+                let target = self.ex_target(args)?;
+                if command == "move" {
+                    self.ex_move(first, last, target)?;
+                } else {
+                    self.ex_copy(first, last, target);
+                }
+            }
+            "sort" => {
+                let lines = self.resolve_range(range, whole)?;
+                self.ex_sort(lines, args, bang)?;
+            }
+            _ => return Err(format!("Not an editor command: {}", line)),
+        }
+        Ok(())
+    }
+
+    fn resolve_address(&self, address: Address) -> Result<usize, String> {
+        match self.address_line(address)? {
+            line if line > self.erow.len().max(1) => Err("Invalid range".to_string()),
+            line => Ok(line),
+        }
+    }
+
+    // The line an address names, which may be past the end of the buffer.
+    fn address_line(&self, address: Address) -> Result<usize, String> {
+        let base = match address.base {
+            Base::Line(n) => n,
+            Base::Current => self.cy + 1,
+            Base::Last => self.erow.len(),
+            Base::Mark(name) => match self.marks.get(&name) {
+                Some(&(_, y)) => y.min(self.erow.len().saturating_sub(1)) + 1,
+                None => return Err(format!("Mark not set: {}", name)),
+            },
+        };
+        match isize::try_from(base).ok().and_then(|base| base.checked_add(address.offset)) {
+            Some(line) if line >= 0 => Ok(line as usize),
+            _ => Err("Invalid range".to_string()),
+        }
+    }
+
+    // First and last line (1-based, inclusive) of a range.
+    fn resolve_range(&self, range: RangeSpec, default: (usize, usize)) -> Result<(usize, usize), String> {
+        let (first, last) = match range {
+            RangeSpec::Default => default,
+            RangeSpec::Whole => (1, self.erow.len().max(1)),
+            RangeSpec::One(a) => {
+                let line = self.resolve_address(a)?;
+                (line, line)
+            }
+            RangeSpec::Two(a, b) => (self.resolve_address(a)?, self.resolve_address(b)?),
+        };
+        let (first, last) = (first.min(last).max(1), first.max(last).max(1));
+        Ok((first, last))
+    }
+
+    // Destination line of :m and :t; 0 means above the first line.
+    fn ex_target(&self, args: &str) -> Result<usize, String> {
+        match parse_address(args)? {
+            (Some(address), rest) if rest.trim().is_empty() => self.resolve_address(address),
+            _ => Err(format!("Invalid address: {}", args)),
+        }
+    }
+
+    fn ex_goto(&mut self, line: usize) {
+        self.cy = line.saturating_sub(1).min(self.erow.len().saturating_sub(1));
+        self.cx = self.erow.get(self.cy).map_or(0, |row| motion::first_non_blank(&row.chars));
+    }
+
+    fn ex_write(&mut self, args: &str) -> Result<(), String> {
+        if !args.is_empty() {
+            let renamed = self.filename.as_deref() != Some(args);
+            if renamed && self.filename.is_some() {
+                // Write a copy; the buffer keeps its own name
+                let text = self.erow.to_text();
+                fs::write(args, &text).map_err(|e| format!("Can't write {}: {}", args, e))?;
+                self.editor_set_status_msg(format!("\"{}\" {} bytes written", args, text.len()));
+                return Ok(());
+            }
+            self.filename = Some(args.to_string());
+            self.editor_select_syntax_highlight();
+        }
+        if self.filename.is_none() {
+            return Err("No file name".to_string());
+        }
+        self.editor_save();
+        if self.dirty > 0 {
+            return Err(self.status_msg.clone());
+        }
+        Ok(())
+    }
+
+    fn ex_edit(&mut self, args: &str, bang: bool) -> Result<(), String> {
+        if self.dirty > 0 && !bang {
+            return Err("No write since last change (add ! to override)".to_string());
+        }
+        let filename = match (args, &self.filename) {
+            ("", Some(name)) => name.clone(),
+            ("", None) => return Err("No file name".to_string()),
+            (name, _) => name.to_string(),
+        };
+
+        if Path::new(&filename).exists() {
+            self.editor_open(&filename).map_err(|e| format!("Can't open {}: {}", filename, e))?;
+            self.editor_set_status_msg(format!("\"{}\" {} lines", filename, self.erow.len()));
+        } else {
+            self.editor_open_empty(&filename);
+            self.editor_set_status_msg(format!("\"{}\" [New File]", filename));
+        }
+        (self.cx, self.cy, self.row_off, self.col_off) = (0, 0, 0, 0);
+        self.mode = EditorMode::Normal;
+        self.marks.clear();
+        Ok(())
+    }
+
+    fn ex_set(&mut self, args: &str) -> Result<(), String> {
+        if args.is_empty() || args == "all" {
+            self.editor_set_status_msg(self.options.show_all());
+            return Ok(());
+        }
+        let hlsearch = self.options.hlsearch;
+        let theme = self.options.theme.clone();
+        let mut shown = Vec::new();
+        for arg in split_set_args(args) {
+            if let Some(text) = self.options.set(&arg)? {
+                shown.push(text);
+            }
+        }
+        if self.options.theme != theme {
+            if let Err(e) = self.editor_select_theme() {
+                self.options.theme = theme;
+                return Err(e);
+            }
+        }
+        if self.options.hlsearch != hlsearch {
+            let pattern = self.last_search().ok();
+            self.highlight_search(pattern);
+        }
+        if !shown.is_empty() {
+            self.editor_set_status_msg(shown.join("  "));
+        }
+        Ok(())
+    }
+
+    fn ex_substitute(&mut self, (first, last): (usize, usize), args: &str) -> Result<(), String> {
+        // :s/pat/rep/flags, or :s alone to repeat the last one
+        let (pattern, replacement, flags) = match args.chars().next() {
+            Some(delim) if !delim.is_alphanumeric() && !"\\\"| ".contains(delim) => {
+                let (pattern, rest) = split_delimited(&args[delim.len_utf8()..], delim);
+                let (replacement, flags) = match rest {
+                    Some(rest) => {
+                        let (replacement, flags) = split_delimited(rest, delim);
+                        (replacement, flags.unwrap_or(""))
+                    }
+                    None => (String::new(), ""),
+                };
+                (pattern, replacement, flags)
+            }
+            _ => {
+                let replacement = self.ex.last_replacement.clone().ok_or("No previous substitute")?;
+                (String::new(), replacement, args)
+            }
+        };
+
+        // An empty pattern means the last one used
+        let pattern = if pattern.is_empty() {
+            self.last_pattern.clone().ok_or("No previous pattern")?
+        } else {
+            pattern
+        };
+        self.last_pattern = Some(pattern.clone());
+        self.ex.last_replacement = Some(replacement.clone());
+
+        let mut all = false;
+        let mut ignore_case = None;
+        for flag in flags.trim().chars() {
+            match flag {
+                'g' => all = true,
+                'i' => ignore_case = Some(true),
+                'I' => ignore_case = Some(false),
+                _ => return Err(format!("Trailing characters: {}", flags)),
+            }
+        }
+        let regex = SearchPattern::new(&pattern, &self.options, ignore_case)?;
+        let template = search::replacement_template(&replacement);
+
+        let mut substitutions = 0;
+        let mut changed_lines = 0;
+        let mut last_changed = None;
+        self.history.begin_group((self.cx, self.cy));
+        for y in first - 1..last.min(self.erow.len()) {
+            let old = self.erow[y].chars.clone();
+            let mut matches = regex.replacements(&old, &template);
+            if !all {
+                matches.truncate(1);
+            }
+            if matches.is_empty() {
+                continue;
+            }
+            let mut new = String::with_capacity(old.len());
+            let mut at = 0;
+            for (start, end, text) in &matches {
+                new.push_str(&old[at..*start]);
+                new.push_str(text);
+                at = *end;
+            }
+            new.push_str(&old[at..]);
+
+            self.editor_apply(EditOp::DeleteText { y, x: 0, text: old });
+            self.editor_apply(EditOp::InsertText { y, x: 0, text: new });
+            substitutions += matches.len();
+            changed_lines += 1;
+            last_changed = Some(y);
+        }
+        if let Some(y) = last_changed {
+            self.ex_goto(y + 1);
+        }
+        self.history.end_group((self.cx, self.cy));
+
+        if substitutions == 0 {
+            // Inside :g a line without a match is not an error
+            return if self.ex.in_global {
+                Ok(())
+            } else {
+                Err(format!("Pattern not found: {}", pattern))
+            };
+        }
+        if !self.ex.in_global {
+            self.editor_set_status_msg(format!(
+                "{} substitution{} on {} line{}",
+                substitutions,
+                if substitutions == 1 { "" } else { "s" },
+                changed_lines,
+                if changed_lines == 1 { "" } else { "s" }
+            ));
+        }
+        Ok(())
+    }
+
+    fn ex_global(&mut self, (first, last): (usize, usize), args: &str, invert: bool) -> Result<(), String> {
+        if self.ex.in_global {
+            return Err("Cannot do :global recursively".to_string());
+        }
+        let delim = match args.chars().next() {
+            Some(delim) if !delim.is_alphanumeric() && !"\\\"| ".contains(delim) => delim,
+            _ => return Err("Regular expression missing from :global".to_string()),
+        };
+        let (pattern, command) = split_delimited(&args[delim.len_utf8()..], delim);
+        let pattern = if pattern.is_empty() {
+            self.last_pattern.clone().ok_or("No previous pattern")?
+        } else {
+            pattern
+        };
+        self.last_pattern = Some(pattern.clone());
+        let regex = SearchPattern::new(&pattern, &self.options, None)?;
+        let command = command.unwrap_or("").to_string();
+
+        // Mark the lines first, then run the command on each marked line
+        // that is still there. The mark travels with the line as others
+        // are deleted or moved around it.
+        let mut marked = 0;
+        for y in first - 1..last.min(self.erow.len()) {
+            let found = regex.is_match(&self.erow[y].chars);
+            self.erow[y].global_mark = found != invert;
+            marked += (found != invert) as usize;
+        }
+        if marked == 0 {
+            return Err(format!("Pattern not found: {}", pattern));
+        }
+
+        self.ex.in_global = true;
+        self.history.begin_group((self.cx, self.cy));
+        let mut next = 0;
+        let mut result = Ok(());
+        while let Some(y) = self.next_global_mark(next) {
+            self.erow[y].global_mark = false;
+            self.cy = y;
+            self.cx = 0;
+            if let Err(msg) = self.run_ex(&command) {
+                result = Err(msg);
+                break;
+            }
+            next = self.cy;
+        }
+        for y in 0..self.erow.len() {
+            self.erow[y].global_mark = false;
+        }
+        self.clamp_normal_cursor();
+        self.history.end_group((self.cx, self.cy));
+        self.ex.in_global = false;
+        result
+    }
+
+    // First marked line at or after `from`, wrapping around once.
+    fn next_global_mark(&self, from: usize) -> Option<usize> {
+        let len = self.erow.len();
+        let from = from.min(len);
+        (from..len).chain(0..from).find(|&y| self.erow[y].global_mark)
+    }
+
+    fn ex_move(&mut self, first: usize, last: usize, target: usize) -> Result<(), String> {
+        if target >= first && target < last {
+            return Err("Cannot move a range of lines into itself".to_string());
+        }
+        if target == last || target + 1 == first {
+            self.ex_goto(last.min(target.max(first)));
+            return Ok(());
+        }
+        let count = last - first + 1;
+        let marks = self.global_marks(first - 1, count);
+        self.history.begin_group((self.cx, self.cy));
+        let lines = self.editor_delete_rows(first - 1, count);
+        let at = if target > last { target - count } else { target };
+        self.editor_insert_rows(at, &lines);
+        self.set_global_marks(at, &marks);
+        self.ex_goto(at + count);
+        self.history.end_group((self.cx, self.cy));
+        Ok(())
+    }
+
+    // The :g marks of `count` lines from row `y`, to put back with
+    // set_global_marks once the lines have been moved.
+    fn global_marks(&self, y: usize, count: usize) -> Vec<bool> {
+        (y..(y + count).min(self.erow.len())).map(|y| self.erow[y].global_mark).collect()
+    }
+
+    fn set_global_marks(&mut self, y: usize, marks: &[bool]) {
+        for (i, &mark) in marks.iter().enumerate() {
+            if let Some(row) = self.erow.get_mut(y + i) {
+                row.global_mark = mark;
+            }
+        }
+    }
+
+    fn ex_copy(&mut self, first: usize, last: usize, target: usize) {
+        let lines: Vec<String> = (first - 1..last.min(self.erow.len()))
+            .map(|y| self.erow[y].chars.clone())
+            .collect();
+        self.history.begin_group((self.cx, self.cy));
+        self.editor_insert_rows(target, &lines);
+        self.ex_goto(target + lines.len());
+        self.history.end_group((self.cx, self.cy));
+    }
+
+    // :sort [i] [n] [u], reversed with !
+    fn ex_sort(&mut self, (first, last): (usize, usize), args: &str, reverse: bool) -> Result<(), String> {
+        let (mut ignore_case, mut numeric, mut unique) = (false, false, false);
+        for flag in args.chars().filter(|c| !c.is_whitespace()) {
+            match flag {
+                'i' => ignore_case = true,
+                'n' => numeric = true,
+                'u' => unique = true,
+                _ => return Err(format!("Invalid argument: {}", args)),
+            }
+        }
+        if self.erow.is_empty() {
+            return Ok(());
+        }
+
+        let last = last.min(self.erow.len());
+        let old: Vec<String> = (first - 1..last).map(|y| self.erow[y].chars.clone()).collect();
+        let marks = self.global_marks(first - 1, old.len());
+        let key = |line: &str| if ignore_case { line.to_lowercase() } else { line.to_string() };
+        // Lines without a number sort before all others, as in Vim
+        let number = |line: &str| -> Option<i64> {
+            let start = line.find(|c: char| c.is_ascii_digit())?;
+            let negative = line[..start].ends_with('-');
+            let digits = &line[start..];
+            let end = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len());
+            let n: i64 = digits[..end].parse().ok()?;
+            Some(if negative { -n } else { n })
+        };
+
+        // Each line keeps its :g mark wherever it ends up
+        let mut sorted: Vec<(String, bool)> = old.iter().cloned().zip(marks).collect();
+        if numeric {
+            sorted.sort_by_key(|(line, _)| number(line));
+        } else {
+            sorted.sort_by_key(|(line, _)| key(line));
+        }
+        if reverse {
+            sorted.reverse();
+        }
+        if unique {
+            sorted.dedup_by(|(a, _), (b, _)| {
+                if numeric {
+                    number(a) == number(b)
+                } else {
+                    key(a) == key(b)
+                }
+            });
+        }
+        let (lines, marks): (Vec<String>, Vec<bool>) = sorted.into_iter().unzip();
+
+        if lines != old {
+            self.history.begin_group((self.cx, self.cy));
+            self.editor_delete_rows(first - 1, old.len());
+            self.editor_insert_rows(first - 1, &lines);
+            self.set_global_marks(first - 1, &marks);
+            self.ex_goto(first);
+            self.history.end_group((self.cx, self.cy));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offset_of(s: &str) -> Result<isize, String> {
+        parse_address(s).map(|(address, _)| address.map_or(0, |address| address.offset))
+    }
+
+    #[test]
+    fn offsets_add_up_and_a_bare_sign_is_one() {
+        assert_eq!(offset_of(".+3-1"), Ok(2));
+        assert_eq!(offset_of("$--"), Ok(-2));
+        assert_eq!(parse_address("+,"), Ok((Some(Address { base: Base::Current, offset: 1 }), ",")));
+    }
+
+    #[test]
+    fn offsets_too_big_for_a_line_are_invalid() {
+        let invalid = "Invalid range".to_string();
+        assert_eq!(offset_of(".+18446744073709551614"), Err(invalid.clone()));
+        assert_eq!(offset_of(".+99999999999999999999999"), Err(invalid.clone()));
+        assert_eq!(offset_of("$+9223372036854775807+1"), Err(invalid.clone()));
+        assert_eq!(offset_of(".-9223372036854775807-2"), Err(invalid.clone()));
+
+        let mut editor = EditorConfig::with_size(80, 24);
+        editor.editor_insert_row(0, "only");
+        let (Some(address), _) = parse_address(".+9223372036854775807").unwrap() else {
+            panic!("no address");
+        };
+        assert_eq!(editor.address_line(address), Err(invalid.clone()));
+        assert_eq!(editor.run_ex(".,.+18446744073709551614d"), Err(invalid));
+        assert_eq!(editor.erow.len(), 1);
+    }
+
+    #[test]
+    fn a_failed_edit_keeps_the_buffer_and_its_name() {
+        let mut editor = EditorConfig::with_size(80, 24);
+        editor.editor_open_empty("notes.txt");
+        editor.editor_insert_row(0, "keep me");
+        let dir = std::env::temp_dir().join(format!("kibi-ex-edit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let result = editor.run_ex(&format!("e! {}", dir.display()));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(editor.filename.as_deref(), Some("notes.txt"));
+        assert_eq!(editor.editor_row_to_string(), "keep me\n");
+    }
+
+    #[test]
+    fn tab_cycles_on_through_an_idle_pause() {
+        let mut editor = EditorConfig::with_size(80, 24);
+        editor.editor_insert_row(0, "b");
+        editor.editor_insert_row(1, "a");
+
+        // "s" completes to set, then sort, with a read timeout in between
+        let tab = EditorKey::Other(b'\t');
+        editor.key_queue.extend([EditorKey::Other(b's'), tab, EditorKey::Other(0), tab, EditorKey::EnterKey]);
+        editor.editor_ex_prompt("");
+        assert_eq!((editor.erow[0].chars.as_str(), editor.erow[1].chars.as_str()), ("a", "b"));
+    }
+
+    #[test]
+    fn substitute_takes_a_range_flags_and_capture_groups() {
+        let mut editor = EditorConfig::with_lines(&["a-b a-b", "a-b", "x"]);
+        editor.run_ex("s/a/A/").unwrap();
+        assert_eq!(editor.lines(), ["A-b a-b", "a-b", "x"]);
+        editor.run_ex(r"%s/(\w)-(\w)/$2+\1/g").unwrap();
+        assert_eq!(editor.lines(), ["b+A b+a", "b+a", "x"]);
+        assert_eq!(editor.run_ex("3s/a/b/"), Err("Pattern not found: a".to_string()));
+    }
+
+    #[test]
+    fn global_runs_the_command_on_each_matching_line() {
+        let mut editor = EditorConfig::with_lines(&["a1", "b", "a2", "c", "a3"]);
+        editor.run_ex("g/a/d").unwrap();
+        assert_eq!(editor.lines(), ["b", "c"]);
+
+        let mut editor = EditorConfig::with_lines(&["a1", "b", "a2", "c", "a3"]);
+        editor.run_ex("v/a/s/$/!/").unwrap();
+        assert_eq!(editor.lines(), ["a1", "b!", "a2", "c!", "a3"]);
+
+        // All of it is one undo step
+        editor.type_keys("u");
+        assert_eq!(editor.lines(), ["a1", "b", "a2", "c", "a3"]);
+    }
+
+    #[test]
+    fn global_marks_move_with_their_lines() {
+        let mut editor = EditorConfig::with_lines(&["1", "2", "3", "4"]);
+        editor.run_ex("g/^/m0").unwrap();
+        assert_eq!(editor.lines(), ["4", "3", "2", "1"]);
+
+        // Moving a marked line along with the one being visited
+        let mut editor = EditorConfig::with_lines(&["x", "a1", "a2", "y"]);
+        editor.run_ex("g/a/.,+1m0").unwrap();
+        assert_eq!(editor.lines(), ["a2", "x", "a1", "y"]);
+
+        let mut editor = EditorConfig::with_lines(&["b2", "a", "b1"]);
+        editor.run_ex("g/b/sort").unwrap();
+        editor.run_ex("g/b/s/$/!/").unwrap();
+        assert_eq!(editor.lines(), ["a", "b1!", "b2!"]);
+    }
+
+    #[test]
+    fn move_and_copy_put_lines_below_the_target() {
+        let mut editor = EditorConfig::with_lines(&["1", "2", "3", "4"]);
+        editor.run_ex("1,2m$").unwrap();
+        assert_eq!(editor.lines(), ["3", "4", "1", "2"]);
+        assert_eq!(editor.cy, 3);
+        editor.run_ex("4m0").unwrap();
+        assert_eq!(editor.lines(), ["2", "3", "4", "1"]);
+        assert!(editor.run_ex("1,3m2").is_err());
+
+        editor.run_ex("1t.").unwrap();
+        assert_eq!(editor.lines(), ["2", "2", "3", "4", "1"]);
+        editor.run_ex("3,4t0").unwrap();
+        assert_eq!(editor.lines(), ["3", "4", "2", "2", "3", "4", "1"]);
+        assert_eq!(editor.cy, 1);
+    }
+
+    #[test]
+    fn sort_orders_lines_by_text_or_number() {
+        let mut editor = EditorConfig::with_lines(&["b", "B", "a10", "a9", "b"]);
+        editor.run_ex("sort").unwrap();
+        assert_eq!(editor.lines(), ["B", "a10", "a9", "b", "b"]);
+        editor.run_ex("sort iu").unwrap();
+        assert_eq!(editor.lines(), ["a10", "a9", "B"]);
+        editor.run_ex("sort! n").unwrap();
+        assert_eq!(editor.lines(), ["a10", "a9", "B"]);
+        editor.run_ex("sort n").unwrap();
+        assert_eq!(editor.lines(), ["B", "a9", "a10"]);
+        assert!(editor.run_ex("sort x").is_err());
+    }
+
+    #[test]
+    fn set_splits_at_blanks_but_not_escaped_ones() {
+        assert_eq!(split_set_args(r"makeprg=cargo\ build  nu sw=2"), ["makeprg=cargo build", "nu", "sw=2"]);
+        assert_eq!(split_set_args(r"mp=a\\b\x"), [r"mp=a\b\x"]);
+
+        let mut editor = EditorConfig::with_size(80, 24);
+        assert_eq!(editor.run_ex(r"set mp=make\ -j4 nu sw?"), Ok(()));
+        assert_eq!(editor.options.make_program, "make -j4");
+        assert!(editor.options.number);
+        assert_eq!(editor.status_msg, "shiftwidth=4");
+        // Arguments before a bad one are kept
+        assert_eq!(editor.run_ex("set nonu sw=0 et!"), Err("shiftwidth must be a positive number".to_string()));
+        assert!(!editor.options.number);
+        assert!(editor.options.expand_tab);
+    }
+
+    #[test]
+    fn write_names_a_new_buffer_or_writes_a_copy_and_wq_quits() {
+        let dir = std::env::temp_dir().join(format!("kibi-ex-write-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file.txt").to_string_lossy().into_owned();
+        let copy = dir.join("copy.txt").to_string_lossy().into_owned();
+        let mut editor = EditorConfig::with_lines(&["a", "b"]);
+
+        assert_eq!(editor.run_ex("w"), Err("No file name".to_string()));
+        assert_eq!(editor.run_ex(&format!("w {}", file)), Ok(()));
+        assert_eq!(editor.filename.as_deref(), Some(file.as_str()));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "a\nb\n");
+        assert_eq!(editor.dirty, 0);
+
+        // Another name gets a copy, and the buffer keeps its own
+        editor.type_keys("x");
+        assert_eq!(editor.run_ex(&format!("w {}", copy)), Ok(()));
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "\nb\n");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "a\nb\n");
+        assert_eq!(editor.filename.as_deref(), Some(file.as_str()));
+        assert!(editor.dirty > 0);
+        assert_eq!(editor.run_ex("q"), Err("No write since last change (add ! to override)".to_string()));
+        assert!(!editor.quit_requested);
+
+        assert_eq!(editor.run_ex("wq"), Ok(()));
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(editor.quit_requested);
+        assert_eq!(editor.dirty, 0);
+    }
+
+    #[test]
+    fn a_line_number_past_the_end_goes_to_the_last_line() {
+        let mut editor = EditorConfig::with_lines(&["one", "  two", "three"]);
+        assert_eq!(editor.run_ex("2"), Ok(()));
+        assert_eq!((editor.cx, editor.cy), (2, 1));
+        assert_eq!(editor.run_ex("99"), Ok(()));
+        assert_eq!((editor.cx, editor.cy), (0, 2));
+        assert_eq!(editor.run_ex("0"), Ok(()));
+        assert_eq!((editor.cx, editor.cy), (0, 0));
+        assert_eq!(editor.run_ex("$-1"), Ok(()));
+        assert_eq!(editor.cy, 1);
+    }
+}
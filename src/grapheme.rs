@@ -0,0 +1,109 @@
+// Grapheme clusters and how wide they are on screen.
+//
+// The cursor moves over grapheme clusters, what a reader takes for one
+// character: an `é` written as `e` and a combining accent, a flag made of
+// two regional indicators, or an emoji joined from several. Positions in a
+// line stay byte offsets, at the start of a cluster.
+//
+// On screen a cluster takes as many columns as the terminal gives it: two
+// for East Asian wide characters and most emoji, one for the rest. Control
+// characters are shown as ^A and so on in one column, and nothing takes
+// less than one, so the cursor always has somewhere to sit.
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// The start of the cluster after the one at `x`, or the end of `s`.
+pub fn next_boundary(s: &str, x: usize) -> usize {
+    match s.get(x..).and_then(|rest| rest.graphemes(true).next()) {
+        Some(g) => x + g.len(),
+        None => s.len(),
+    }
+}
+
+// The start of the cluster before `x`, or 0.
+pub fn prev_boundary(s: &str, x: usize) -> usize {
+    let x = x.min(s.len());
+    match s.get(..x).and_then(|head| head.graphemes(true).next_back()) {
+        Some(g) => x - g.len(),
+        None => 0,
+    }
+}
+
+// Byte offset of the last cluster on a line (0 for an empty line).
+pub fn last_start(s: &str) -> usize {
+    prev_boundary(s, s.len())
+}
+
+// The start of the cluster byte `x` is in, or the end of `s` for anything
+// past it. For positions kept from before the text changed, which may
+// have been left inside a character.
+pub fn cluster_start(s: &str, x: usize) -> usize {
+    if x >= s.len() {
+        return s.len();
+    }
+    s.grapheme_indices(true).map(|(i, _)| i).take_while(|&i| i <= x).last().unwrap_or(0)
+}
+
+// Columns a cluster takes on screen. Tabs depend on where they are, so
+// callers deal with them.
+pub fn width(g: &str) -> usize {
+    if g.starts_with(char::is_control) {
+        return 1;
+    }
+    g.width().clamp(1, 2)
+}
+
+// Columns a string without tabs takes on screen.
+pub fn str_width(s: &str) -> usize {
+    s.graphemes(true).map(width).sum()
+}
+
+// Split `s` after as many whole clusters as fit in `width` columns.
+pub fn split_at_width(s: &str, width: usize) -> (&str, &str) {
+    let mut used = 0;
+    for (i, g) in s.grapheme_indices(true) {
+        used += self::width(g);
+        if used > width {
+            return s.split_at(i);
+        }
+    }
+    (s, "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boundaries_step_over_whole_clusters() {
+        let combining = "ae\u{301}b"; // é as e and a combining acute
+        assert_eq!(next_boundary(combining, 1), 4);
+        assert_eq!(prev_boundary(combining, 4), 1);
+        assert_eq!(prev_boundary(combining, 2), 1); // from inside the cluster
+        assert_eq!(last_start(combining), 4);
+
+        let precomposed = "a\u{e9}b";
+        assert_eq!(next_boundary(precomposed, 1), 3);
+        assert_eq!(prev_boundary(precomposed, 3), 1);
+        assert_eq!((next_boundary("", 0), prev_boundary("", 0), last_start("")), (0, 0, 0));
+    }
+
+    #[test]
+    fn a_stale_offset_goes_to_the_start_of_its_cluster() {
+        let line = "ae\u{301}\u{e9}b";
+        let starts: Vec<usize> = (0..=line.len() + 1).map(|x| cluster_start(line, x)).collect();
+        assert_eq!(starts, [0, 1, 1, 1, 4, 4, 6, 7, 7]);
+        assert_eq!(cluster_start("", 3), 0);
+    }
+
+    #[test]
+    fn wide_characters_take_two_columns_and_nothing_takes_none() {
+        assert_eq!(str_width("a字é"), 4);
+        assert_eq!(str_width("e\u{301}"), 1);
+        assert_eq!(width("\u{1}"), 1);
+        assert_eq!(width("\u{200b}"), 1); // zero width space
+        assert_eq!(split_at_width("字字a", 3), ("字", "字a"));
+        assert_eq!(split_at_width("a字", 1), ("a", "字"));
+    }
+}
@@ -0,0 +1,135 @@
+// :grep — search every file under the current directory.
+//
+// The walk skips what .gitignore (and .ignore) files exclude, hidden files,
+// and files that are too big, binary or not UTF-8. Each matching line
+// becomes an entry in the quickfix list, shown once the search is done.
+
+use crate::quickfix::Location;
+use crate::search::SearchPattern;
+use crate::EditorConfig;
+use ignore::WalkBuilder;
+use std::fs;
+use std::path::Path;
+
+const MAX_FILE_SIZE: u64 = 1 << 20; // bytes; bigger files are skipped
+const BINARY_CHECK: usize = 8192; // a NUL in this many leading bytes means binary
+const MAX_RESULTS: usize = 10_000;
+const PREVIEW_CHARS: usize = 200;
+
+// The text of a file worth searching, or None for ones that are not.
+fn searchable_text(path: &Path) -> Option<String> {
+    if fs::metadata(path).ok()?.len() > MAX_FILE_SIZE {
+        return None;
+    }
+    let bytes = fs::read(path).ok()?;
+    if bytes[..bytes.len().min(BINARY_CHECK)].contains(&0) {
+        return None;
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// The first match on each line of the files under `root`, in file name
+// order. The second value is the number of files that matched.
+pub fn grep(root: &Path, pattern: &SearchPattern) -> (Vec<Location>, usize) {
+    let mut results = Vec::new();
+    let mut files = 0;
+
+    let walker = WalkBuilder::new(root).require_git(false).sort_by_file_name(|a, b| a.cmp(b)).build();
+    for entry in walker.flatten() {
+        if !entry.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let path = entry.path();
+        let Some(text) = searchable_text(path) else {
+            continue;
+        };
+        let file = path.strip_prefix(root).unwrap_or(path).to_string_lossy().into_owned();
+
+        let before = results.len();
+        for (i, line) in text.lines().enumerate() {
+            let Some((start, _)) = pattern.find(line) else {
+                continue;
+            };
+            results.push(Location {
+                file: file.clone(),
+                line: i + 1,
+                col: line[..start].chars().count() + 1,
+                text: line.trim().chars().take(PREVIEW_CHARS).collect(),
+            });
+            if results.len() >= MAX_RESULTS {
+                return (results, files + 1);
+            }
+        }
+        if results.len() > before {
+            files += 1;
+        }
+    }
+    (results, files)
+}
+
+impl EditorConfig {
+    // :grep {pattern}, or the last search pattern when none is given.
+    pub(crate) fn ex_grep(&mut self, args: &str) -> Result<(), String> {
+        let pattern = match args {
+            "" => self.last_pattern.clone().ok_or("No previous pattern")?,
+            pattern => pattern.to_string(),
+        };
+        let regex = SearchPattern::new(&pattern, &self.options, None)?;
+        if self.dirty > 0 && !self.quickfix.showing() {
+            return Err("No write since last change".to_string());
+        }
+
+        let (results, files) = grep(Path::new("."), &regex);
+        if results.is_empty() {
+            return Err(format!("Pattern not found: {}", pattern));
+        }
+        let count = results.len();
+        self.last_pattern = Some(pattern.clone());
+        self.search_backward = false;
+        self.set_quickfix(&format!("[grep {}]", pattern), results);
+        self.editor_show_quickfix()?;
+        self.editor_set_status_msg(format!(
+            "{} match{} in {} file{}{}",
+            count,
+            if count == 1 { "" } else { "es" },
+            files,
+            if files == 1 { "" } else { "s" },
+            if count >= MAX_RESULTS { " (stopped early)" } else { "" }
+        ));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::EditorOptions;
+
+    #[test]
+    fn grep_skips_ignored_binary_and_big_files() {
+        let root = std::env::temp_dir().join(format!("kibi-grep-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join(".gitignore"), "ignored.txt\n").unwrap();
+        fs::write(root.join("ignored.txt"), "needle\n").unwrap();
+        fs::write(root.join("binary.bin"), "needle\0\n").unwrap();
+        let big = format!("needle\n{}", "a".repeat(MAX_FILE_SIZE as usize));
+        fs::write(root.join("big.txt"), big).unwrap();
+        fs::write(root.join("a.txt"), "héllo wörld needle\nno\n  needle\n").unwrap();
+        fs::write(root.join("b.txt"), "字needle\n").unwrap();
+
+        let pattern = SearchPattern::new("needle", &EditorOptions::default(), None).unwrap();
+        let (results, files) = grep(&root, &pattern);
+        let found: Vec<(&str, usize, usize, &str)> =
+            results.iter().map(|loc| (loc.file.as_str(), loc.line, loc.col, loc.text.as_str())).collect();
+        // Columns count characters, not bytes
+        assert_eq!(
+            found,
+            [
+                ("a.txt", 1, 13, "héllo wörld needle"),
+                ("a.txt", 3, 3, "needle"),
+                ("b.txt", 1, 2, "字needle"),
+            ]
+        );
+        assert_eq!(files, 2);
+    }
+}
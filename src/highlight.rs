@@ -0,0 +1,662 @@
+// Highlighters for languages the keyword rules of a syntax file can't
+// describe. Each one takes a row's rendered text and what was left open at
+// the end of the row before it, and returns the row's highlights and what
+// is open at its end.
+//
+// TOML: `[table]` and `[[array]]` headers, keys (bare, quoted and dotted,
+// also inside `{ inline = "tables" }`), strings including multi-line `"""`
+// and `'''` ones, numbers, booleans and dates. Lines of a multi-line array
+// hold values only, so the row state counts the brackets still open.
+//
+// Rust: block comments nest, strings and raw strings (`r#"..."#`, also
+// byte and C strings) may go on for several rows, `'a'` is a character and
+// `'a` a lifetime. Escapes in strings, `println!`-style macros and
+// `#[attributes]` have colours of their own; words are looked up in the
+// keywords and types of the syntax.
+
+use crate::syntax::EditorSyntax;
+use crate::EditorHighlight;
+
+// What is still open at the end of a row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HlState {
+    #[default]
+    Normal,
+    Comment(u8),    // a block comment, nested this deep
+    String(u8, u8), // a multi-line string opened with this quote, inside this many arrays
+    RawString(u8),  // a Rust raw string closed by a quote and this many #s
+    Array(u8),      // a multi-line array, this many levels deep
+}
+
+struct Lexer<'a> {
+    bytes: &'a [u8],
+    hl: Vec<u8>,
+    i: usize,
+}
+
+impl Lexer<'_> {
+    fn mark(&mut self, start: usize, kind: EditorHighlight) {
+        self.hl[start..self.i].fill(kind as u8);
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.i).copied()
+    }
+
+    fn at(&self, s: &[u8]) -> bool {
+        self.bytes[self.i..].starts_with(s)
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek().is_some_and(|b| b == b' ' || b == b'\t') {
+            self.i += 1;
+        }
+    }
+
+    // The rest of a string after its opening quote(s). Returns false if it
+    // is multi-line and goes on past this row.
+    fn string_rest(&mut self, quote: u8, multiline: bool) -> bool {
+        while let Some(b) = self.peek() {
+            if b == b'\\' && quote == b'"' {
+                self.i = (self.i + 2).min(self.bytes.len());
+            } else if b == quote && (!multiline || self.at(&[quote; 3])) {
+                self.i += if multiline { 3 } else { 1 };
+                // """a"""" ends with a quote in the string
+                for _ in 0..2 {
+                    if multiline && self.peek() == Some(quote) {
+                        self.i += 1;
+                    }
+                }
+                return true;
+            } else {
+                self.i += 1;
+            }
+        }
+        !multiline
+    }
+
+    // A string starting at the cursor. Returns false if it is multi-line
+    // and goes on past this row.
+    fn string(&mut self, kind: EditorHighlight) -> bool {
+        let start = self.i;
+        let quote = self.bytes[self.i];
+        let multiline = self.at(&[quote; 3]);
+        self.i += if multiline { 3 } else { 1 };
+        let closed = self.string_rest(quote, multiline);
+        self.mark(start, kind);
+        closed
+    }
+
+    fn comment(&mut self) {
+        let start = self.i;
+        self.i = self.bytes.len();
+        self.mark(start, EditorHighlight::HlComment);
+    }
+
+    // A dotted key, up to the `=` (or whatever else stops it).
+    fn key(&mut self) {
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                Some(b'"' | b'\'') => {
+                    self.string(EditorHighlight::Key);
+                }
+                Some(b) if b.is_ascii_alphanumeric() || b == b'_' || b == b'-' => {
+                    let start = self.i;
+                    while self.peek().is_some_and(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-') {
+                        self.i += 1;
+                    }
+                    self.mark(start, EditorHighlight::Key);
+                }
+                _ => return,
+            }
+            self.skip_spaces();
+            if self.peek() != Some(b'.') {
+                return;
+            }
+            self.i += 1;
+        }
+    }
+
+    // `[table]` or `[[array.of.tables]]`, quoted parts and all.
+    fn header(&mut self) {
+        let start = self.i;
+        while let Some(b) = self.peek() {
+            match b {
+                b'"' | b'\'' => {
+                    self.string(EditorHighlight::Section);
+                }
+                b'#' => break,
+                b']' => {
+                    self.i += if self.at(b"]]") { 2 } else { 1 };
+                    break;
+                }
+                _ => self.i += 1,
+            }
+        }
+        self.mark(start, EditorHighlight::Section);
+    }
+
+    // A number, boolean, date or time.
+    fn scalar(&mut self) {
+        let start = self.i;
+        while self.peek().is_some_and(|b| b.is_ascii_alphanumeric() || b"+-_.:".contains(&b)) {
+            self.i += 1;
+        }
+        // 1979-05-27 07:32:00 has a space in it
+        let date = |s: &[u8]| {
+            s.len() >= 10 && s[4] == b'-' && s[7] == b'-' && s[..4].iter().all(u8::is_ascii_digit)
+        };
+        if date(&self.bytes[start..self.i])
+            && self.peek() == Some(b' ')
+            && self.bytes.get(self.i + 3) == Some(&b':')
+            && self.bytes.get(self.i + 1).is_some_and(u8::is_ascii_digit)
+        {
+            self.i += 1;
+            while self.peek().is_some_and(|b| b.is_ascii_alphanumeric() || b"+-_.:".contains(&b)) {
+                self.i += 1;
+            }
+        }
+
+        let token = &self.bytes[start..self.i];
+        let time = token.len() >= 5 && token[2] == b':' && token[..2].iter().all(u8::is_ascii_digit);
+        let unsigned = token.strip_prefix(b"+").or(token.strip_prefix(b"-")).unwrap_or(token);
+        let kind = if date(token) || time || token == b"true" || token == b"false" {
+            EditorHighlight::Value
+        } else if unsigned.first().is_some_and(u8::is_ascii_digit)
+            || unsigned == b"inf"
+            || unsigned == b"nan"
+        {
+            EditorHighlight::Number
+        } else {
+            EditorHighlight::Normal
+        };
+        self.mark(start, kind);
+    }
+}
+
+// Highlight one row of a TOML file.
+pub fn highlight_toml(line: &str, state: HlState) -> (Vec<u8>, HlState) {
+    let bytes = line.as_bytes();
+    let mut lex = Lexer { bytes, hl: vec![EditorHighlight::Normal as u8; bytes.len()], i: 0 };
+
+    // Open brackets, innermost last: b'[' for arrays and b'{' for inline tables
+    let mut open: Vec<u8> = Vec::new();
+    match state {
+        HlState::String(quote, depth) => {
+            open.resize(depth as usize, b'[');
+            let closed = lex.string_rest(quote, true);
+            lex.mark(0, EditorHighlight::HlString);
+            if !closed {
+                return (lex.hl, state);
+            }
+        }
+        HlState::Array(depth) => open.resize(depth as usize, b'['),
+        _ => {
+            lex.skip_spaces();
+            match lex.peek() {
+                Some(b'[') => lex.header(),
+                Some(b'#') | None => {}
+                Some(_) => lex.key(),
+            }
+        }
+    }
+
+    // The value, or the rest of a multi-line array
+    let mut expect_key = false;
+    loop {
+        lex.skip_spaces();
+        let Some(b) = lex.peek() else {
+            break;
+        };
+        match b {
+            b'#' => lex.comment(),
+            b'"' | b'\'' if expect_key => lex.key(),
+            b'"' | b'\'' => {
+                if !lex.string(EditorHighlight::HlString) {
+                    return (lex.hl, HlState::String(b, open.len() as u8));
+                }
+            }
+            b'[' | b'{' => {
+                open.push(b);
+                expect_key = b == b'{';
+                lex.i += 1;
+            }
+            b']' | b'}' => {
+                open.pop();
+                lex.i += 1;
+            }
+            b',' => {
+                expect_key = open.last() == Some(&b'{');
+                lex.i += 1;
+            }
+            b'=' => {
+                expect_key = false;
+                lex.i += 1;
+            }
+            _ if expect_key => {
+                let start = lex.i;
+                lex.key();
+                if lex.i == start {
+                    lex.i += 1;
+                }
+            }
+            _ => {
+                let start = lex.i;
+                lex.scalar();
+                if lex.i == start {
+                    lex.i += 1;
+                }
+            }
+        }
+    }
+
+    // Inline tables end with their line; arrays may go on
+    let depth = open.iter().filter(|&&b| b == b'[').count();
+    let state = if depth > 0 { HlState::Array(depth as u8) } else { HlState::Normal };
+    (lex.hl, state)
+}
+
+fn is_ident_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80
+}
+
+impl Lexer<'_> {
+    // The rest of a block comment, `depth` levels deep. Returns the depth
+    // still open at the end of the row.
+    fn rust_comment_rest(&mut self, mut depth: u8) -> u8 {
+        let start = self.i;
+        while depth > 0 && self.i < self.bytes.len() {
+            if self.at(b"/*") {
+                depth = depth.saturating_add(1);
+                self.i += 2;
+            } else if self.at(b"*/") {
+                depth -= 1;
+                self.i += 2;
+            } else {
+                self.i += 1;
+            }
+        }
+        self.mark(start, EditorHighlight::HlMComment);
+        depth
+    }
+
+    // The rest of a string, its escapes marked. Returns false if it goes on
+    // past this row.
+    fn rust_string_rest(&mut self, quote: u8) -> bool {
+        while let Some(b) = self.peek() {
+            if b == b'\\' {
+                let start = self.i;
+                self.i += 1;
+                match self.peek() {
+                    Some(b'x') => self.i = (self.i + 3).min(self.bytes.len()),
+                    Some(b'u') if self.bytes.get(self.i + 1) == Some(&b'{') => {
+                        while self.peek().is_some_and(|b| b != b'}' && b != quote) {
+                            self.i += 1;
+                        }
+                        if self.peek() == Some(b'}') {
+                            self.i += 1;
+                        }
+                    }
+                    Some(_) => self.i += 1,
+                    None => {} // the string goes on without the line break
+                }
+                self.mark(start, EditorHighlight::Escape);
+                continue;
+            }
+            self.hl[self.i] = EditorHighlight::HlString as u8;
+            self.i += 1;
+            if b == quote {
+                return true;
+            }
+        }
+        false
+    }
+
+    // The rest of a raw string closed by `"` and `hashes` #s. Returns false
+    // if it goes on past this row.
+    fn rust_raw_string_rest(&mut self, hashes: u8) -> bool {
+        let start = self.i;
+        let mut closed = false;
+        while self.i < self.bytes.len() {
+            let end = self.i + 1 + hashes as usize;
+            let closes = self.bytes.get(self.i + 1..end).is_some_and(|h| h.iter().all(|&b| b == b'#'));
+            if self.bytes[self.i] == b'"' && closes {
+                self.i = end;
+                closed = true;
+                break;
+            }
+            self.i += 1;
+        }
+        self.mark(start, EditorHighlight::HlString);
+        closed
+    }
+
+    // A raw string prefix (r, br or cr), its #s and the opening quote at the
+    // cursor, if there is one there: the number of #s.
+    fn rust_raw_string_start(&self) -> Option<(usize, u8)> {
+        let rest = &self.bytes[self.i..];
+        let prefix = match rest {
+            [b'r', ..] => 1,
+            [b'b' | b'c', b'r', ..] => 2,
+            _ => return None,
+        };
+        let hashes = rest[prefix..].iter().take_while(|&&b| b == b'#').count();
+        (rest.get(prefix + hashes) == Some(&b'"')).then_some((prefix + hashes + 1, hashes.min(255) as u8))
+    }
+
+    // After a `'`: the length of the character literal starting there, or
+    // None for a lifetime or label.
+    fn rust_char_len(&self, line: &str) -> Option<usize> {
+        let rest = &self.bytes[self.i + 1..];
+        if rest.first() == Some(&b'\\') {
+            // Past the escaped character, so '\'' ends at the second quote
+            let close = rest.get(2..)?.iter().position(|&b| b == b'\'')?;
+            return Some(close + 4);
+        }
+        let c = line[self.i + 1..].chars().next()?;
+        (rest.get(c.len_utf8()) == Some(&b'\'')).then_some(c.len_utf8() + 2)
+    }
+
+    // A number: 0x1F, 1_000, 2.5e-3f64. A dot only counts with a digit
+    // after it, so 1..2 and 1.max(2) stay apart.
+    fn rust_number(&mut self) {
+        let start = self.i;
+        let radix = self.at(b"0x") || self.at(b"0o") || self.at(b"0b");
+        if radix {
+            self.i += 2;
+        } else {
+            while self.peek().is_some_and(|b| b.is_ascii_digit() || b == b'_') {
+                self.i += 1;
+            }
+            if self.peek() == Some(b'.') && self.bytes.get(self.i + 1).is_some_and(u8::is_ascii_digit) {
+                self.i += 1;
+                while self.peek().is_some_and(|b| b.is_ascii_digit() || b == b'_') {
+                    self.i += 1;
+                }
+            }
+            let sign = matches!(self.bytes.get(self.i + 1), Some(b'+' | b'-')) as usize;
+            if matches!(self.peek(), Some(b'e' | b'E'))
+                && self.bytes.get(self.i + 1 + sign).is_some_and(u8::is_ascii_digit)
+            {
+                self.i += 1 + sign;
+            }
+        }
+        // Digits, hex digits and a suffix like u8 or f64
+        while self.peek().is_some_and(|b| b.is_ascii_alphanumeric() || b == b'_') {
+            self.i += 1;
+        }
+        self.mark(start, EditorHighlight::Number);
+    }
+
+    // `#[...]` or `#![...]`, up to the bracket that closes it on this row.
+    fn rust_attribute(&mut self) {
+        let start = self.i;
+        let mut depth = 0;
+        while let Some(b) = self.peek() {
+            match b {
+                b'"' => {
+                    self.i += 1;
+                    while self.peek().is_some_and(|b| b != b'"') {
+                        self.i += if self.peek() == Some(b'\\') { 2 } else { 1 };
+                    }
+                }
+                b'[' => depth += 1,
+                b']' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.i += 1;
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            self.i = (self.i + 1).min(self.bytes.len());
+        }
+        self.mark(start, EditorHighlight::Attribute);
+    }
+
+    // A word: a macro name, keyword, type or anything else.
+    fn rust_word(&mut self, syntax: &EditorSyntax) {
+        let start = self.i;
+        while self.peek().is_some_and(is_ident_byte) {
+            self.i += 1;
+        }
+        let word = &self.bytes[start..self.i];
+        if self.peek() == Some(b'!') && self.bytes.get(self.i + 1) != Some(&b'=') {
+            self.i += 1;
+            self.mark(start, EditorHighlight::Macro);
+            return;
+        }
+        for keyword in &syntax.keywords {
+            let (name, kind) = match keyword.strip_suffix('|') {
+                Some(name) => (name, EditorHighlight::HlKeyword2),
+                None => (keyword.as_str(), EditorHighlight::HlKeyword1),
+            };
+            if name.as_bytes() == word {
+                self.mark(start, kind);
+                return;
+            }
+        }
+        if syntax.types.iter().any(|name| name.as_bytes() == word) {
+            self.mark(start, EditorHighlight::HlKeyword2);
+        }
+    }
+}
+
+// Highlight one row of a Rust file.
+pub fn highlight_rust(line: &str, state: HlState, syntax: &EditorSyntax) -> (Vec<u8>, HlState) {
+    let bytes = line.as_bytes();
+    let mut lex = Lexer { bytes, hl: vec![EditorHighlight::Normal as u8; bytes.len()], i: 0 };
+
+    // Finish what the row above left open
+    match state {
+        HlState::Comment(depth) => {
+            let depth = lex.rust_comment_rest(depth);
+            if depth > 0 {
+                return (lex.hl, HlState::Comment(depth));
+            }
+        }
+        HlState::String(quote, _) if !lex.rust_string_rest(quote) => return (lex.hl, state),
+        HlState::RawString(hashes) if !lex.rust_raw_string_rest(hashes) => return (lex.hl, state),
+        _ => {}
+    }
+
+    while let Some(b) = lex.peek() {
+        let prev_ident = lex.i > 0 && is_ident_byte(bytes[lex.i - 1]);
+        if lex.at(b"//") {
+            lex.comment();
+        } else if lex.at(b"/*") {
+            let start = lex.i;
+            lex.i += 2;
+            let depth = lex.rust_comment_rest(1);
+            lex.mark(start, EditorHighlight::HlMComment);
+            if depth > 0 {
+                return (lex.hl, HlState::Comment(depth));
+            }
+        } else if lex.at(b"#[") || lex.at(b"#![") {
+            lex.rust_attribute();
+        } else if let (false, Some((len, hashes))) = (prev_ident, lex.rust_raw_string_start()) {
+            let start = lex.i;
+            lex.i += len;
+            lex.mark(start, EditorHighlight::HlString);
+            if !lex.rust_raw_string_rest(hashes) {
+                return (lex.hl, HlState::RawString(hashes));
+            }
+        } else if !prev_ident && (lex.at(b"b\"") || lex.at(b"c\"") || b == b'"') {
+            let start = lex.i;
+            lex.i += if b == b'"' { 1 } else { 2 };
+            lex.mark(start, EditorHighlight::HlString);
+            if !lex.rust_string_rest(b'"') {
+                return (lex.hl, HlState::String(b'"', 0));
+            }
+        } else if !prev_ident && (b == b'\'' || lex.at(b"b'")) {
+            let prefix = (b == b'b') as usize;
+            lex.i += prefix;
+            match lex.rust_char_len(line) {
+                Some(len) => {
+                    let (start, end) = (lex.i - prefix, lex.i + len);
+                    lex.hl[start..end].fill(EditorHighlight::HlString as u8);
+                    if bytes[lex.i + 1] == b'\\' {
+                        lex.hl[lex.i + 1..end - 1].fill(EditorHighlight::Escape as u8);
+                    }
+                    lex.i = end;
+                }
+                None => {
+                    // 'a or 'outer: a lifetime or label
+                    let start = lex.i;
+                    lex.i += 1;
+                    while lex.peek().is_some_and(is_ident_byte) {
+                        lex.i += 1;
+                    }
+                    if lex.i > start + 1 {
+                        lex.mark(start, EditorHighlight::Lifetime);
+                    }
+                }
+            }
+        } else if b.is_ascii_digit() && !prev_ident {
+            lex.rust_number();
+        } else if is_ident_byte(b) && !prev_ident {
+            if lex.at(b"r#") && lex.bytes.get(lex.i + 2).is_some_and(|&b| is_ident_byte(b)) {
+                lex.i += 2; // r#match is a plain name
+                while lex.peek().is_some_and(is_ident_byte) {
+                    lex.i += 1;
+                }
+            } else {
+                lex.rust_word(syntax);
+            }
+        } else {
+            lex.i += 1;
+        }
+    }
+    (lex.hl, HlState::Normal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The class of each byte as a letter, in EditorHighlight order: plain,
+    // number, match, string, comment, block comment, keyword, keyword2,
+    // current match, header, key, value, lifetime, macro, attribute, escape.
+    fn classes(hl: &[u8]) -> String {
+        hl.iter().map(|&class| b".n?scCkt?hyvlMae"[class as usize] as char).collect()
+    }
+
+    // Highlight rows one after the other, each starting in the state the
+    // one before ended in.
+    fn toml(lines: &[&str]) -> Vec<(String, HlState)> {
+        let mut state = HlState::Normal;
+        lines
+            .iter()
+            .map(|line| {
+                let (hl, end) = highlight_toml(line, state);
+                state = end;
+                (classes(&hl), end)
+            })
+            .collect()
+    }
+
+    fn rust(lines: &[&str]) -> Vec<(String, HlState)> {
+        let syntax = crate::syntax::rust_syntax();
+        let mut state = HlState::Normal;
+        lines
+            .iter()
+            .map(|line| {
+                let (hl, end) = highlight_rust(line, state, &syntax);
+                state = end;
+                (classes(&hl), end)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn toml_comments_take_the_rest_of_the_line() {
+        assert_eq!(toml(&["# comment"]), [("ccccccccc".to_string(), HlState::Normal)]);
+        assert_eq!(toml(&["  # indented"])[0].0, "..cccccccccc");
+        assert_eq!(toml(&[r#"name = "x" # note"#])[0].0, "yyyy...sss.cccccc");
+        assert_eq!(toml(&["n = 42 # answer"])[0].0, "y...nn.cccccccc");
+        assert_eq!(toml(&["[table] # header"])[0].0, "hhhhhhh.cccccccc");
+    }
+
+    #[test]
+    fn toml_hash_in_a_string_is_not_a_comment() {
+        assert_eq!(toml(&[r#"url = "http://a#b""#])[0].0, "yyy...ssssssssssss");
+        assert_eq!(toml(&["re = '#[0-9]'"])[0].0, "yy...ssssssss");
+        assert_eq!(toml(&[r##""#key" = 1"##])[0].0, "yyyyyy...n");
+    }
+
+    #[test]
+    fn toml_headers_and_keys() {
+        assert_eq!(toml(&["[table]"])[0].0, "hhhhhhh");
+        assert_eq!(toml(&["[[bin]]"])[0].0, "hhhhhhh");
+        assert_eq!(toml(&[r#"[a."b c"]"#])[0].0, "hhhhhhhhh");
+        assert_eq!(toml(&[r#"a.b."c d" = true"#])[0].0, "y.y.yyyyy...vvvv");
+        assert_eq!(toml(&["p = { x = 1, y = 2 }"])[0].0, "y.....y...n..y...n..");
+    }
+
+    #[test]
+    fn toml_multiline_strings_and_arrays_go_on_to_the_next_row() {
+        let rows = toml(&[r#"s = """first"#, "# not a comment", r#"last""" # comment"#, "k = 1"]);
+        let quote = HlState::String(b'"', 0);
+        assert_eq!(rows[0], ("y...ssssssss".to_string(), quote));
+        assert_eq!(rows[1], ("sssssssssssssss".to_string(), quote));
+        assert_eq!(rows[2], ("sssssss.ccccccccc".to_string(), HlState::Normal));
+        assert_eq!(rows[3], ("y...n".to_string(), HlState::Normal));
+
+        let rows = toml(&["a = [", "  1, # one", "  '''x", "y''',", "]"]);
+        let states: Vec<HlState> = rows.iter().map(|(_, state)| *state).collect();
+        let (array, string) = (HlState::Array(1), HlState::String(b'\'', 1));
+        assert_eq!(states, [array, array, string, array, HlState::Normal]);
+        assert_eq!(rows[1].0, "..n..ccccc");
+        assert_eq!(rows[3].0, "ssss.");
+    }
+
+    #[test]
+    fn rust_raw_strings_end_at_as_many_hashes() {
+        assert_eq!(rust(&[r##"r#"a "b" c"#;"##])[0].0, "ssssssssssss.");
+        assert_eq!(rust(&[r#"br"\\n" x"#])[0].0, "sssssss..");
+
+        let rows = rust(&[r###"x = r##"one "# still"###, r###"two"##.len()"###]);
+        assert_eq!(rows[0], ("....ssssssssssssssss".to_string(), HlState::RawString(2)));
+        assert_eq!(rows[1], ("ssssss......".to_string(), HlState::Normal));
+    }
+
+    #[test]
+    fn rust_characters_and_lifetimes() {
+        assert_eq!(rust(&["('a', &'a str)"])[0].0, ".sss...ll.ttt.");
+        assert_eq!(rust(&["'outer: loop"])[0].0, "llllll..kkkk");
+        assert_eq!(rust(&["b'x' 'é'"])[0].0, "ssss.ssss");
+        // A quote right after a name starts nothing
+        assert_eq!(rust(&["x' 'y'"])[0].0, "...sss");
+    }
+
+    #[test]
+    fn rust_escapes_in_characters_and_strings() {
+        assert_eq!(rust(&[r"'\''"])[0].0, "sees");
+        assert_eq!(rust(&[r"'\u{1F600}' x"])[0].0, "seeeeeeeees..");
+        assert_eq!(rust(&[r#""a\n\u{41}b\"""#])[0].0, "sseeeeeeeesees");
+
+        // A string goes on to the next row, escapes and all
+        let rows = rust(&[r#"s = "one\"#, r#"two\t" x"#]);
+        assert_eq!(rows[0], ("....sssse".to_string(), HlState::String(b'"', 0)));
+        assert_eq!(rows[1], ("sssees..".to_string(), HlState::Normal));
+    }
+
+    #[test]
+    fn rust_block_comments_nest() {
+        assert_eq!(rust(&["/* a /* b */ c */ x"])[0].0, "CCCCCCCCCCCCCCCCC..");
+
+        let rows = rust(&["/* one /* two */", "/* three", "*/ */ */ x"]);
+        let states: Vec<HlState> = rows.iter().map(|(_, state)| *state).collect();
+        assert_eq!(states, [HlState::Comment(1), HlState::Comment(2), HlState::Normal]);
+        assert_eq!(rows[2].0, "CCCCC.....");
+    }
+
+    #[test]
+    fn rust_attributes_and_macros() {
+        assert_eq!(rust(&["#[derive(Debug)] struct"])[0].0, "aaaaaaaaaaaaaaaa.tttttt");
+        assert_eq!(rust(&[r#"#![doc = "]"] x"#])[0].0, "aaaaaaaaaaaaa..");
+        assert_eq!(rust(&["println!(x != y)"])[0].0, "MMMMMMMM........");
+    }
+}
@@ -0,0 +1,1363 @@
+// Language server support, for Rust files with rust-analyzer by default.
+//
+// The server named by 'lspserver' is started for the first Rust file that
+// is opened, rooted at the nearest directory with a Cargo.toml, and spoken
+// to in JSON-RPC over its stdin and stdout. It gets the buffer on didOpen,
+// again in full after edits (didChange, sent once the editor is idle or
+// before a request) and a didSave on every write. What it is used for:
+//
+//   K          hover: the type and docs of the item under the cursor
+//   gd         go to the definition
+//   gr         list the references in the quickfix list
+//   :rename x  rename the item under the cursor, in every file
+//   Tab        in Insert mode, its completions are offered with the others
+//
+// Diagnostics are underlined, counted in the status bar, and the one under
+// the cursor is shown in the message bar.
+
+use crate::grapheme;
+use crate::quickfix::Location;
+use crate::EditorConfig;
+use serde_json::{json, Value};
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const INIT_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(500);
+const INIT_ID: u64 = 1; // later requests count on from here
+
+const EXITED: &str = "the language server exited";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+
+impl Severity {
+    fn from_lsp(value: &Value) -> Severity {
+        match value.as_u64() {
+            Some(2) => Severity::Warning,
+            Some(3) => Severity::Information,
+            Some(4) => Severity::Hint,
+            _ => Severity::Error,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Information => "info",
+            Severity::Hint => "hint",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    start: (usize, usize), // (x, y) with x a byte offset, as for the cursor
+    end: (usize, usize),
+    severity: Severity,
+    message: String,
+}
+
+// Column of byte offset `x` in `line` the way the server counts: bytes
+// when it agreed to UTF-8, UTF-16 code units otherwise.
+pub fn to_lsp_character(line: &str, x: usize, utf8: bool) -> usize {
+    if utf8 {
+        return x.min(line.len());
+    }
+    line.char_indices().take_while(|&(i, _)| i < x).map(|(_, c)| c.len_utf16()).sum()
+}
+
+// Byte offset in `line` of a column counted the server's way.
+pub fn from_lsp_character(line: &str, character: usize, utf8: bool) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= character {
+            return i;
+        }
+        units += if utf8 { c.len_utf8() } else { c.len_utf16() };
+    }
+    line.len()
+}
+
+pub fn path_to_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for &b in path.as_os_str().as_bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+    uri
+}
+
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        let hex = encoded.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match (encoded[i], hex.and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(b)) => {
+                bytes.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                bytes.push(b);
+                i += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+// Where a file's project starts: the nearest directory up from it with a
+// Cargo.toml, or just the directory it is in.
+fn project_root(file: &Path) -> PathBuf {
+    let dir = file.parent().unwrap_or(Path::new("/"));
+    dir.ancestors().find(|dir| dir.join("Cargo.toml").is_file()).unwrap_or(dir).to_path_buf()
+}
+
+// A path as shown to the user: relative to the current directory if it is
+// under it.
+fn display_path(path: &Path) -> String {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    path.strip_prefix(&cwd).unwrap_or(path).to_string_lossy().into_owned()
+}
+
+// Byte offset of a server position in `text`, clamped to the text.
+fn text_offset(text: &str, pos: &Value, utf8: bool) -> usize {
+    let line = pos["line"].as_u64().unwrap_or(0) as usize;
+    let character = pos["character"].as_u64().unwrap_or(0) as usize;
+    let mut offset = 0;
+    for _ in 0..line {
+        match text[offset..].find('\n') {
+            Some(i) => offset += i + 1,
+            None => return text.len(),
+        }
+    }
+    let rest = text[offset..].split('\n').next().unwrap_or("");
+    offset + from_lsp_character(rest, character, utf8)
+}
+
+// Apply TextEdits to the contents of a file that isn't in the buffer.
+fn apply_text_edits(text: &str, edits: &[Value], utf8: bool) -> String {
+    let mut ranges: Vec<(usize, usize, &str)> = edits
+        .iter()
+        .map(|edit| {
+            let start = text_offset(text, &edit["range"]["start"], utf8);
+            let end = text_offset(text, &edit["range"]["end"], utf8).max(start);
+            (start, end, edit["newText"].as_str().unwrap_or(""))
+        })
+        .collect();
+    // Back to front so the offsets stay good; inserts at the same place
+    // end up in the order they were given
+    ranges.reverse();
+    ranges.sort_by_key(|&(start, _, _)| Reverse(start));
+    let mut text = text.to_string();
+    for (start, end, new_text) in ranges {
+        text.replace_range(start..end, new_text);
+    }
+    text
+}
+
+// Hover contents on one line for the message bar, without the markdown
+// code fences and rules.
+fn hover_text(contents: &Value) -> String {
+    let text = match contents {
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items.iter().map(hover_text).collect::<Vec<_>>().join("\n"),
+        Value::Object(map) => map.get("value").and_then(Value::as_str).unwrap_or("").to_string(),
+        _ => String::new(),
+    };
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("```") && *line != "---")
+        .collect::<Vec<_>>()
+        .join("  ")
+}
+
+// Read one message: headers, a blank line, then Content-Length bytes of JSON.
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Write one message, framed as read_message expects.
+fn write_message(out: &mut impl Write, msg: &Value) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+// The server asks things of its own now and then. None of them matter
+// here, but it waits for an answer.
+fn reply_to_server(msg: &Value) -> Value {
+    let result = match msg["method"].as_str() {
+        Some("workspace/configuration") => {
+            let items = msg["params"]["items"].as_array().map_or(0, Vec::len);
+            Value::Array(vec![Value::Null; items])
+        }
+        _ => Value::Null,
+    };
+    json!({ "jsonrpc": "2.0", "id": msg["id"], "result": result })
+}
+
+// Send initialize and wait for the answer, then say initialized. This runs
+// on the reader thread, so a server that is slow to start doesn't hold up
+// the editor. Notifications that come first are passed on. Returns whether
+// the server agreed to count positions in bytes.
+fn handshake(
+    stdin: &mut ChildStdin,
+    reader: &mut impl BufRead,
+    params: Value,
+    sender: &Sender<Value>,
+) -> Result<bool, String> {
+    let initialize = json!({ "jsonrpc": "2.0", "id": INIT_ID, "method": "initialize", "params": params });
+    write_message(stdin, &initialize).map_err(|_| EXITED.to_string())?;
+    loop {
+        let msg = match read_message(reader) {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return Err(EXITED.to_string()),
+            Err(e) => return Err(e.to_string()),
+        };
+        if msg.get("method").is_some() {
+            if msg.get("id").is_some() {
+                let _ = write_message(stdin, &reply_to_server(&msg));
+            } else {
+                let _ = sender.send(msg);
+            }
+            continue;
+        }
+        if msg["id"] != INIT_ID {
+            continue;
+        }
+        if let Some(error) = msg.get("error") {
+            return Err(error["message"].as_str().unwrap_or("initialize failed").to_string());
+        }
+        let utf8 = msg["result"]["capabilities"]["positionEncoding"] == "utf-8";
+        let initialized = json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} });
+        write_message(stdin, &initialized).map_err(|_| EXITED.to_string())?;
+        return Ok(utf8);
+    }
+}
+
+type Handshake = Result<(ChildStdin, bool), String>;
+
+// A running server. Its output is read on a thread of its own and handed
+// over through a channel, so the editor never blocks on it except while
+// waiting for the answer to a request. The thread starts with the
+// initialize exchange and hands over the server's stdin when it is done;
+// until then the client isn't ready.
+pub struct LspClient {
+    command: String,
+    root: PathBuf,
+    child: Child,
+    stdin: Option<ChildStdin>,                        // None until ready
+    starting: Option<(Receiver<Handshake>, Instant)>, // the handshake, while it goes on
+    incoming: Receiver<Value>,
+    backlog: VecDeque<Value>, // notifications that came in while waiting for a response
+    next_id: u64,
+    utf8: bool, // positions count bytes rather than UTF-16 code units
+}
+
+impl LspClient {
+    fn start(command: &str, root: &Path) -> Result<LspClient, String> {
+        let mut words = command.split_whitespace();
+        let program = words.next().ok_or("No language server")?;
+        let mut child = Command::new(program)
+            .args(words)
+            .current_dir(root)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Can't start {}: {}", command, e))?;
+        let (Some(mut stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            let _ = child.kill();
+            return Err(format!("Can't talk to {}", command));
+        };
+
+        let name = root.file_name().map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        let params = json!({
+            "processId": std::process::id(),
+            "rootUri": path_to_uri(root),
+            "workspaceFolders": [{ "uri": path_to_uri(root), "name": name }],
+            "capabilities": {
+                "general": { "positionEncodings": ["utf-8", "utf-16"] },
+                "textDocument": {
+                    "synchronization": { "didSave": true },
+                    "hover": { "contentFormat": ["plaintext", "markdown"] },
+                    "completion": { "completionItem": { "snippetSupport": false } },
+                    "definition": { "linkSupport": true },
+                    "references": {},
+                    "rename": {},
+                    "publishDiagnostics": {},
+                },
+                "workspace": { "workspaceEdit": { "documentChanges": true } },
+            },
+        });
+
+        let (sender, incoming) = mpsc::channel();
+        let (ready, handshake_done) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            let result = handshake(&mut stdin, &mut reader, params, &sender);
+            let failed = result.is_err();
+            if ready.send(result.map(|utf8| (stdin, utf8))).is_err() || failed {
+                return;
+            }
+            while let Ok(Some(msg)) = read_message(&mut reader) {
+                if sender.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(LspClient {
+            command: command.to_string(),
+            root: root.to_path_buf(),
+            child,
+            stdin: None,
+            starting: Some((handshake_done, Instant::now())),
+            incoming,
+            backlog: VecDeque::new(),
+            next_id: INIT_ID,
+            utf8: false,
+        })
+    }
+
+    // Whether the server has answered initialize, or an error if it
+    // failed to or is taking too long.
+    fn ready(&mut self) -> Result<bool, String> {
+        let Some((handshake, started)) = &self.starting else {
+            return Ok(true);
+        };
+        let waited = started.elapsed();
+        match handshake.try_recv() {
+            Ok(Ok((stdin, utf8))) => {
+                (self.stdin, self.utf8, self.starting) = (Some(stdin), utf8, None);
+                Ok(true)
+            }
+            Ok(Err(msg)) => Err(msg),
+            Err(TryRecvError::Empty) if waited < INIT_TIMEOUT => Ok(false),
+            Err(TryRecvError::Empty) => Err("initialize timed out".to_string()),
+            Err(TryRecvError::Disconnected) => Err(EXITED.to_string()),
+        }
+    }
+
+    fn send(&mut self, msg: Value) -> io::Result<()> {
+        match &mut self.stdin {
+            Some(stdin) => write_message(stdin, &msg),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "the language server is starting")),
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: Value) -> io::Result<()> {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }))
+    }
+
+    // Send a request and wait for its response.
+    fn request(&mut self, method: &str, params: Value, timeout: Duration) -> Result<Value, String> {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))
+            .map_err(|e| e.to_string())?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let msg = match self.incoming.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => return Err(format!("{} timed out", method)),
+                Err(RecvTimeoutError::Disconnected) => return Err(EXITED.to_string()),
+            };
+            if msg.get("method").is_some() {
+                if msg.get("id").is_some() {
+                    let _ = self.send(reply_to_server(&msg));
+                } else {
+                    self.backlog.push_back(msg);
+                }
+                continue;
+            }
+            if msg["id"] != id {
+                continue; // the answer to a request that timed out
+            }
+            if let Some(error) = msg.get("error") {
+                return Err(error["message"].as_str().unwrap_or("request failed").to_string());
+            }
+            return Ok(msg.get("result").cloned().unwrap_or(Value::Null));
+        }
+    }
+
+    // The notifications that arrived so far, or None once the server is gone.
+    fn notifications(&mut self) -> Option<Vec<Value>> {
+        let mut messages: Vec<Value> = self.backlog.drain(..).collect();
+        loop {
+            match self.incoming.try_recv() {
+                Ok(msg) if msg.get("method").is_none() => {} // a late response
+                Ok(msg) if msg.get("id").is_some() => {
+                    let _ = self.send(reply_to_server(&msg));
+                }
+                Ok(msg) => messages.push(msg),
+                Err(TryRecvError::Empty) => return Some(messages),
+                Err(TryRecvError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for LspClient {
+    fn drop(&mut self) {
+        // Ask it to stop, then make sure it has
+        if self.request("shutdown", Value::Null, SHUTDOWN_TIMEOUT).is_ok() {
+            let _ = self.notify("exit", Value::Null);
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// The buffer as the server knows it.
+struct Document {
+    path: PathBuf,
+    uri: String,
+    version: i64,
+}
+
+#[derive(Default)]
+pub struct Lsp {
+    client: Option<LspClient>,
+    failed: Option<String>,     // a server that would not start, not tried again for this buffer
+    document: Option<Document>,
+    replaced: bool,             // the buffer was reloaded or renamed since didOpen
+    changed: bool,              // there are edits the server hasn't seen
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Lsp {
+    pub fn buffer_replaced(&mut self) {
+        self.replaced = true;
+    }
+
+    pub fn buffer_changed(&mut self) {
+        self.changed = true;
+    }
+
+    fn utf8(&self) -> bool {
+        self.client.as_ref().is_some_and(|client| client.utf8)
+    }
+}
+
+impl EditorConfig {
+    // The file the server should have open: the buffer's, if it's Rust.
+    fn lsp_wanted(&self) -> Option<PathBuf> {
+        let rust = self.syntax.as_ref().is_some_and(|syntax| syntax.filetype == "Rust");
+        if !rust || self.options.lsp_server.trim().is_empty() {
+            return None;
+        }
+        std::path::absolute(self.filename.as_deref()?).ok()
+    }
+
+    // Bring the server up to date with the buffer, starting it if needed.
+    // Returns whether there is a server with the buffer open.
+    fn lsp_sync(&mut self) -> bool {
+        let wanted = self.lsp_wanted();
+        let same = self.lsp.document.as_ref().map(|doc| &doc.path) == wanted.as_ref();
+        if self.lsp.replaced || !same {
+            if self.lsp.replaced {
+                self.lsp.failed = None; // worth another try with a new file
+            }
+            self.lsp_close_document();
+            (self.lsp.replaced, self.lsp.changed) = (false, false);
+            if let Some(path) = wanted {
+                self.lsp_open_document(path);
+            }
+        } else if self.lsp.changed {
+            self.lsp.changed = false;
+            let text = self.erow.to_text();
+            if let (Some(doc), Some(client)) = (&mut self.lsp.document, &mut self.lsp.client) {
+                doc.version += 1;
+                let params = json!({
+                    "textDocument": { "uri": doc.uri, "version": doc.version },
+                    "contentChanges": [{ "text": text }],
+                });
+                if client.notify("textDocument/didChange", params).is_err() {
+                    self.lsp_lost();
+                }
+            }
+        }
+        self.lsp.document.is_some()
+    }
+
+    fn lsp_open_document(&mut self, path: PathBuf) {
+        let root = project_root(&path);
+        let command = self.options.lsp_server.trim().to_string();
+        let running = self.lsp.client.as_ref().is_some_and(|c| c.command == command && c.root == root);
+        if !running {
+            self.lsp.client = None;
+            if self.lsp.failed.as_deref() == Some(command.as_str()) {
+                return;
+            }
+            match LspClient::start(&command, &root) {
+                Ok(client) => self.lsp.client = Some(client),
+                Err(msg) => {
+                    self.lsp.failed = Some(command);
+                    self.editor_set_status_msg(msg);
+                    return;
+                }
+            }
+        }
+        let Some(client) = &mut self.lsp.client else {
+            return;
+        };
+        match client.ready() {
+            Ok(true) => {}
+            // lsp_poll comes back here until the server has answered
+            Ok(false) => return,
+            Err(msg) => {
+                self.lsp.client = None;
+                self.editor_set_status_msg(format!("{}: {}", command, msg));
+                self.lsp.failed = Some(command);
+                return;
+            }
+        }
+
+        let uri = path_to_uri(&path);
+        let params = json!({
+            "textDocument": { "uri": uri, "languageId": "rust", "version": 1, "text": self.erow.to_text() },
+        });
+        let Some(client) = &mut self.lsp.client else {
+            return;
+        };
+        if client.notify("textDocument/didOpen", params).is_err() {
+            self.lsp_lost();
+            return;
+        }
+        self.lsp.document = Some(Document { path, uri, version: 1 });
+    }
+
+    fn lsp_close_document(&mut self) {
+        self.lsp.diagnostics.clear();
+        if let (Some(doc), Some(client)) = (self.lsp.document.take(), &mut self.lsp.client) {
+            let _ = client.notify("textDocument/didClose", json!({ "textDocument": { "uri": doc.uri } }));
+        }
+    }
+
+    // The server died or stopped listening. It is started again for the
+    // next file opened.
+    fn lsp_lost(&mut self) {
+        if let Some(client) = self.lsp.client.take() {
+            self.lsp.failed = Some(client.command.clone());
+        }
+        self.lsp.document = None;
+        self.lsp.diagnostics.clear();
+        self.editor_set_status_msg("The language server exited");
+    }
+
+    // Called while the editor is idle: send pending edits and take in
+    // what the server has published.
+    pub(crate) fn lsp_poll(&mut self) {
+        if !self.lsp_sync() {
+            return;
+        }
+        let Some(client) = &mut self.lsp.client else {
+            return;
+        };
+        match client.notifications() {
+            Some(messages) => {
+                for msg in messages {
+                    self.lsp_handle_notification(&msg);
+                }
+            }
+            None => self.lsp_lost(),
+        }
+    }
+
+    pub(crate) fn lsp_did_save(&mut self) {
+        if !self.lsp_sync() {
+            return;
+        }
+        if let (Some(doc), Some(client)) = (&self.lsp.document, &mut self.lsp.client) {
+            let params = json!({ "textDocument": { "uri": doc.uri } });
+            if client.notify("textDocument/didSave", params).is_err() {
+                self.lsp_lost();
+            }
+        }
+    }
+
+    fn lsp_handle_notification(&mut self, msg: &Value) {
+        if msg["method"] != "textDocument/publishDiagnostics" {
+            return;
+        }
+        let params = &msg["params"];
+        if !self.lsp.document.as_ref().is_some_and(|doc| params["uri"] == doc.uri.as_str()) {
+            return;
+        }
+        let utf8 = self.lsp.utf8();
+        let mut diagnostics: Vec<Diagnostic> = params["diagnostics"]
+            .as_array()
+            .map(|list| {
+                list.iter()
+                    .filter_map(|d| {
+                        Some(Diagnostic {
+                            start: self.lsp_position(&d["range"]["start"], utf8)?,
+                            end: self.lsp_position(&d["range"]["end"], utf8)?,
+                            severity: Severity::from_lsp(&d["severity"]),
+                            message: d["message"].as_str().unwrap_or("").to_string(),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        diagnostics.sort_by_key(|d| (d.start.1, d.start.0));
+        self.lsp.diagnostics = diagnostics;
+    }
+
+    // A server position in the buffer, clamped to the text.
+    fn lsp_position(&self, pos: &Value, utf8: bool) -> Option<(usize, usize)> {
+        let y = (pos["line"].as_u64()? as usize).min(self.erow.len().checked_sub(1)?);
+        let character = pos["character"].as_u64()? as usize;
+        Some((from_lsp_character(&self.erow[y].chars, character, utf8), y))
+    }
+
+    // Send a request about the cursor position. `extra` holds any other
+    // parameters it takes.
+    fn lsp_request(&mut self, method: &str, extra: Value) -> Result<Value, String> {
+        if !self.lsp_sync() {
+            let msg = if self.lsp.client.is_some() {
+                "The language server is still starting"
+            } else {
+                "No language server for this file"
+            };
+            return Err(msg.to_string());
+        }
+        let line = self.erow.get(self.cy).map_or("", |row| row.chars.as_str());
+        let character = to_lsp_character(line, self.cx, self.lsp.utf8());
+        let (Some(doc), Some(client)) = (&self.lsp.document, &mut self.lsp.client) else {
+            return Err("No language server for this file".to_string());
+        };
+        let mut params = json!({
+            "textDocument": { "uri": doc.uri },
+            "position": { "line": self.cy, "character": character },
+        });
+        if let (Some(params), Value::Object(extra)) = (params.as_object_mut(), extra) {
+            params.extend(extra);
+        }
+
+        let result = client.request(method, params, REQUEST_TIMEOUT);
+        if matches!(client.child.try_wait(), Ok(Some(_))) {
+            self.lsp_lost();
+        }
+        result
+    }
+
+    // Turn Location, Location[] or LocationLink[] into quickfix entries.
+    fn lsp_locations(&self, result: &Value) -> Vec<Location> {
+        let items: Vec<&Value> = match result {
+            Value::Array(items) => items.iter().collect(),
+            Value::Null => Vec::new(),
+            item => vec![item],
+        };
+        let utf8 = self.lsp.utf8();
+        let current = self.lsp.document.as_ref().map(|doc| doc.path.clone());
+        let mut files: HashMap<PathBuf, Vec<String>> = HashMap::new();
+
+        let mut locations = Vec::new();
+        for item in items {
+            let (uri, range) = match item.get("targetUri") {
+                Some(uri) => (uri, &item["targetSelectionRange"]),
+                None => (&item["uri"], &item["range"]),
+            };
+            let Some(path) = uri.as_str().and_then(uri_to_path) else {
+                continue;
+            };
+            let y = range["start"]["line"].as_u64().unwrap_or(0) as usize;
+            let character = range["start"]["character"].as_u64().unwrap_or(0) as usize;
+
+            let line = if current.as_ref() == Some(&path) {
+                self.erow.get(y).map_or(String::new(), |row| row.chars.clone())
+            } else {
+                let lines = files.entry(path.clone()).or_insert_with(|| {
+                    let text = fs::read_to_string(&path).unwrap_or_default();
+                    text.lines().map(String::from).collect()
+                });
+                lines.get(y).cloned().unwrap_or_default()
+            };
+            let x = from_lsp_character(&line, character, utf8);
+            locations.push(Location {
+                file: display_path(&path),
+                line: y + 1,
+                col: line[..x].chars().count() + 1,
+                text: line.trim().to_string(),
+            });
+        }
+        locations
+    }
+
+    // The server's completions at the cursor as (text, description) pairs,
+    // or none when there is no server.
+    pub(crate) fn lsp_completions(&mut self) -> Vec<(String, String)> {
+        if self.lsp_wanted().is_none() {
+            return Vec::new();
+        }
+        let items = match self.lsp_request("textDocument/completion", json!({})) {
+            Ok(Value::Array(items)) => items,
+            Ok(result) => result["items"].as_array().cloned().unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+        items
+            .iter()
+            .filter_map(|item| {
+                // Snippets have placeholders in them; the label is plain
+                let snippet = item["insertTextFormat"] == 2;
+                let text = match (&item["textEdit"]["newText"], &item["insertText"]) {
+                    _ if snippet => item.get("filterText").unwrap_or(&item["label"]),
+                    (Value::String(_), _) => &item["textEdit"]["newText"],
+                    (_, Value::String(_)) => &item["insertText"],
+                    _ => &item["label"],
+                };
+                let text = text.as_str()?.trim();
+                if text.is_empty() || text.contains(char::is_whitespace) {
+                    return None;
+                }
+                let documentation = match &item["documentation"] {
+                    Value::Null => String::new(),
+                    docs => hover_text(docs),
+                };
+                let detail = item["detail"].as_str().unwrap_or("");
+                let description: Vec<&str> =
+                    [detail, &documentation].into_iter().filter(|s| !s.is_empty()).collect();
+                Some((text.to_string(), description.join("  ")))
+            })
+            .collect()
+    }
+
+    // K
+    pub(crate) fn editor_lsp_hover(&mut self) {
+        let msg = match self.lsp_request("textDocument/hover", json!({})) {
+            Ok(result) => Some(hover_text(&result["contents"])).filter(|text| !text.is_empty()),
+            Err(msg) => Some(msg),
+        };
+        self.editor_set_status_msg(msg.unwrap_or_else(|| "No information".to_string()));
+    }
+
+    // gd
+    pub(crate) fn editor_lsp_definition(&mut self) {
+        let result = self.lsp_request("textDocument/definition", json!({}));
+        let locations = match result {
+            Ok(result) => self.lsp_locations(&result),
+            Err(msg) => {
+                self.editor_set_status_msg(msg);
+                return;
+            }
+        };
+        let result = match locations.len() {
+            0 => Err("No definition found".to_string()),
+            1 => self.editor_open_location(&locations[0]).map(|()| {
+                let loc = &locations[0];
+                self.editor_set_status_msg(format!("\"{}\" line {}", loc.file, loc.line));
+            }),
+            _ => {
+                self.set_quickfix("[definitions]", locations);
+                self.editor_quickfix_go(0)
+            }
+        };
+        if let Err(msg) = result {
+            self.editor_set_status_msg(msg);
+        }
+    }
+
+    // gr
+    pub(crate) fn editor_lsp_references(&mut self) {
+        let context = json!({ "context": { "includeDeclaration": true } });
+        let result = self.lsp_request("textDocument/references", context);
+        let locations = match result {
+            Ok(result) => self.lsp_locations(&result),
+            Err(msg) => {
+                self.editor_set_status_msg(msg);
+                return;
+            }
+        };
+        if locations.is_empty() {
+            self.editor_set_status_msg("No references found");
+            return;
+        }
+        let count = locations.len();
+        self.set_quickfix("[references]", locations);
+        match self.editor_show_quickfix() {
+            Ok(()) => {
+                let plural = if count == 1 { "" } else { "s" };
+                self.editor_set_status_msg(format!("{} reference{}", count, plural));
+            }
+            Err(msg) => self.editor_set_status_msg(msg),
+        }
+    }
+
+    // :rename {new name}
+    pub(crate) fn ex_rename(&mut self, args: &str) -> Result<(), String> {
+        let new_name = args.trim();
+        if new_name.is_empty() {
+            return Err("Argument required".to_string());
+        }
+        let edit = self.lsp_request("textDocument/rename", json!({ "newName": new_name }))?;
+        if edit.is_null() {
+            return Err("Nothing to rename here".to_string());
+        }
+        let (changes, files) = self.lsp_apply_workspace_edit(&edit)?;
+        self.editor_set_status_msg(format!(
+            "{} change{} in {} file{}",
+            changes,
+            if changes == 1 { "" } else { "s" },
+            files,
+            if files == 1 { "" } else { "s" }
+        ));
+        Ok(())
+    }
+
+    // Edits to the buffer go through the undo history as one step; other
+    // files are changed on disk.
+    fn lsp_apply_workspace_edit(&mut self, edit: &Value) -> Result<(usize, usize), String> {
+        let mut by_uri: Vec<(String, Vec<Value>)> = Vec::new();
+        if let Some(changes) = edit["documentChanges"].as_array() {
+            for change in changes {
+                let Some(edits) = change["edits"].as_array() else {
+                    return Err("Can't create, rename or delete files".to_string());
+                };
+                let uri = change["textDocument"]["uri"].as_str().unwrap_or("");
+                by_uri.push((uri.to_string(), edits.clone()));
+            }
+        } else if let Some(changes) = edit["changes"].as_object() {
+            for (uri, edits) in changes {
+                by_uri.push((uri.clone(), edits.as_array().cloned().unwrap_or_default()));
+            }
+        }
+
+        let utf8 = self.lsp.utf8();
+        let current = self.lsp.document.as_ref().map(|doc| doc.uri.clone());
+        let mut changes = 0;
+        for (uri, edits) in &by_uri {
+            if current.as_deref() == Some(uri.as_str()) {
+                self.lsp_apply_buffer_edits(edits, utf8);
+            } else {
+                let path = uri_to_path(uri).ok_or_else(|| format!("Can't edit {}", uri))?;
+                let name = display_path(&path);
+                let text = fs::read_to_string(&path).map_err(|e| format!("Can't read {}: {}", name, e))?;
+                fs::write(&path, apply_text_edits(&text, edits, utf8))
+                    .map_err(|e| format!("Can't write {}: {}", name, e))?;
+            }
+            changes += edits.len();
+        }
+        Ok((changes, by_uri.len()))
+    }
+
+    fn lsp_apply_buffer_edits(&mut self, edits: &[Value], utf8: bool) {
+        let mut ranges: Vec<_> = edits
+            .iter()
+            .filter_map(|edit| {
+                let start = self.lsp_position(&edit["range"]["start"], utf8)?;
+                let end = self.lsp_position(&edit["range"]["end"], utf8)?;
+                Some((start, end, edit["newText"].as_str().unwrap_or("").to_string()))
+            })
+            .collect();
+        ranges.reverse();
+        ranges.sort_by_key(|&((x, y), _, _)| Reverse((y, x)));
+
+        let cursor = (self.cx, self.cy);
+        self.history.begin_group(cursor);
+        for (start, end, text) in ranges {
+            self.editor_delete_range(start, end);
+            if !text.is_empty() {
+                self.editor_insert_text(start, &text);
+            }
+        }
+        self.cy = cursor.1.min(self.erow.len().saturating_sub(1));
+        // The text before it may have changed, leaving it inside a character
+        self.cx = self.erow.get(self.cy).map_or(0, |row| grapheme::cluster_start(&row.chars, cursor.0));
+        self.history.end_group((self.cx, self.cy));
+    }
+
+    // Diagnostic ranges on row y in render columns, for underlining.
+    pub(crate) fn diagnostic_columns(&self, y: usize) -> Vec<(usize, usize)> {
+        let Some(row) = self.erow.get(y) else {
+            return Vec::new();
+        };
+        self.lsp
+            .diagnostics
+            .iter()
+            .filter(|d| d.severity != Severity::Hint && d.start.1 <= y && y <= d.end.1)
+            .map(|d| {
+                let start = if d.start.1 == y { d.start.0 } else { 0 };
+                let mut end = if d.end.1 == y { d.end.0 } else { row.chars.len() };
+                if end <= start {
+                    // An empty range still gets a cell
+                    end = grapheme::next_boundary(&row.chars, start);
+                }
+                (self.editor_row_cx_to_rx(row, start), self.editor_row_cx_to_rx(row, end))
+            })
+            .collect()
+    }
+
+    // Error and warning counts for the status bar, like "E2 W1".
+    pub(crate) fn diagnostic_summary(&self) -> Option<String> {
+        let count = |severity| self.lsp.diagnostics.iter().filter(|d| d.severity == severity).count();
+        let summary: Vec<String> = [("E", count(Severity::Error)), ("W", count(Severity::Warning))]
+            .into_iter()
+            .filter(|&(_, n)| n > 0)
+            .map(|(label, n)| format!("{}{}", label, n))
+            .collect();
+        (!summary.is_empty()).then(|| summary.join(" "))
+    }
+
+    // The diagnostic under the cursor, or else the worst one on its line.
+    pub(crate) fn diagnostic_message(&self) -> Option<String> {
+        let at = (self.cy, self.cx);
+        self.lsp
+            .diagnostics
+            .iter()
+            .filter(|d| d.start.1 <= self.cy && self.cy <= d.end.1)
+            .min_by_key(|d| {
+                let under = (d.start.1, d.start.0) <= at && at <= (d.end.1, d.end.0);
+                (!under, d.severity)
+            })
+            .map(|d| format!("{}: {}", d.severity.label(), d.message.lines().next().unwrap_or("")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    // A language server in sh: it logs every message it gets to the file
+    // named by its argument and answers from a script. In the C locale the
+    // shell's string length counts bytes, so it is the Content-Length.
+    //
+    // Whatever file it is asked about is taken to hold
+    //     fn old() {}
+    //     fn main() { old(); }
+    // with other.rs next to it holding `use crate::old;`.
+    const MOCK_SERVER: &str = r#"
+export LC_ALL=C
+log=$1
+send() { printf 'Content-Length: %d\r\n\r\n%s' "${#1}" "$1"; }
+field() { printf '%s' "$body" | sed -n 's/.*"'$1'":"*\([^",}]*\).*/\1/p'; }
+loc() { printf '{"uri":"%s","range":{"start":{"line":%d,"character":%d},"end":{"line":%d,"character":%d}}}' "$1" $2 $3 $2 $4; }
+edit() { printf '{"range":{"start":{"line":%d,"character":%d},"end":{"line":%d,"character":%d}},"newText":"%s"}' $1 $2 $1 $3 "$4"; }
+while IFS= read -r line; do
+    case $line in
+    Content-Length:*) len=${line#Content-Length: }; len=${len%?} ;;
+    ?) ;;
+    *) continue ;;
+    esac
+    [ "$line" = "$(printf '\r')" ] || continue
+    body=$(dd bs=1 count="$len" 2>/dev/null)
+    printf '%s\n' "$body" >>"$log"
+    id=$(field id)
+    method=$(field method)
+    uri=$(field uri)
+    other=${uri%/*}/other.rs
+    case $method in
+    initialize)
+        send '{"jsonrpc":"2.0","id":99,"method":"workspace/configuration","params":{"items":[{},{}]}}'
+        send '{"jsonrpc":"2.0","method":"window/logMessage","params":{"type":3,"message":"hello"}}'
+        send '{"jsonrpc":"2.0","id":0,"result":"an answer nobody waits for"}'
+        send '{"jsonrpc":"2.0","id":'$id',"result":{"capabilities":{"positionEncoding":"utf-8"}}}' ;;
+    textDocument/hover)
+        send '{"jsonrpc":"2.0","id":'$id',"result":{"contents":"fn main()"}}' ;;
+    textDocument/definition)
+        send '{"jsonrpc":"2.0","id":'$id',"result":'"$(loc "$uri" 0 3 6)"'}' ;;
+    textDocument/references)
+        send '{"jsonrpc":"2.0","id":'$id',"result":['"$(loc "$uri" 1 12 15),$(loc "$other" 0 11 14)"']}' ;;
+    textDocument/rename)
+        name=$(field newName)
+        here='{"textDocument":{"uri":"'$uri'","version":null},"edits":['"$(edit 0 3 6 "$name"),$(edit 1 12 15 "$name")"']}'
+        there='{"textDocument":{"uri":"'$other'","version":null},"edits":['"$(edit 0 11 14 "$name")"']}'
+        send '{"jsonrpc":"2.0","id":'$id',"result":{"documentChanges":['"$here,$there"']}}' ;;
+    shutdown)
+        send '{"jsonrpc":"2.0","id":'$id',"result":null}' ;;
+    exit)
+        exit 0 ;;
+    *)
+        [ -n "$method" ] && [ -n "$id" ] && send '{"jsonrpc":"2.0","id":'$id',"error":{"code":-32601,"message":"not supported"}}' ;;
+    esac
+done
+"#;
+
+    // Hands out its bytes a few at a time, like a pipe that is slow to fill.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    fn frame(body: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    #[test]
+    fn messages_are_read_one_after_another() {
+        let input = frame(r#"{"id":1,"result":null}"#)
+            + "Content-Length: 16\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\r\n"
+            + r#"{"method":"a/b"}"#;
+        let mut reader = input.as_bytes();
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({ "id": 1, "result": null })));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!({ "method": "a/b" })));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn a_message_may_arrive_in_pieces() {
+        let body = r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{"message":"ünïcode"}}"#;
+        let input = frame(body) + &frame("[1,2]");
+        let mut reader = BufReader::with_capacity(4, Trickle(input.as_bytes()));
+        let first = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(first["params"]["message"], "ünïcode");
+        assert_eq!(read_message(&mut reader).unwrap(), Some(json!([1, 2])));
+    }
+
+    #[test]
+    fn broken_messages_are_errors() {
+        let mut no_length = "Content-Type: x\r\n\r\n{}".as_bytes();
+        assert_eq!(read_message(&mut no_length).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut cut_short = "Content-Length: 10\r\n\r\n{}".as_bytes();
+        assert_eq!(read_message(&mut cut_short).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        let not_json = frame("{nope");
+        assert_eq!(read_message(&mut not_json.as_bytes()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn the_client_answers_the_server_and_keeps_its_notifications() {
+        let dir = std::env::temp_dir().join(format!("kibi-lsp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (script, log) = (dir.join("server.sh"), dir.join("log"));
+        fs::write(&script, MOCK_SERVER).unwrap();
+
+        let command = format!("sh {} {}", script.display(), log.display());
+        let mut client = LspClient::start(&command, &dir).unwrap();
+        while !client.ready().unwrap() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(client.utf8);
+
+        let hover = client.request("textDocument/hover", json!({}), REQUEST_TIMEOUT);
+        assert_eq!(hover, Ok(json!({ "contents": "fn main()" })));
+        let implementation = client.request("textDocument/implementation", json!({}), REQUEST_TIMEOUT);
+        assert_eq!(implementation, Err("not supported".to_string()));
+
+        // The notification that came in while waiting for initialize is kept
+        let notes = client.notifications().unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0]["method"], "window/logMessage");
+        assert_eq!(client.notifications().unwrap().len(), 0);
+
+        // The server may be killed before it reads exit, so that isn't checked
+        drop(client);
+        let log = fs::read_to_string(&log).unwrap();
+        let sent: Vec<Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let methods: Vec<&str> = sent.iter().map(|msg| msg["method"].as_str().unwrap_or("-")).collect();
+        assert_eq!(
+            methods[..6],
+            ["initialize", "-", "initialized", "textDocument/hover", "textDocument/implementation", "shutdown"]
+        );
+        assert_eq!(sent[1], json!({ "jsonrpc": "2.0", "id": 99, "result": [null, null] }));
+        assert_eq!(sent[3]["id"], 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_server_that_does_not_answer_does_not_hold_up_the_editor() {
+        let dir = std::env::temp_dir();
+        let started = Instant::now();
+        let mut client = LspClient::start("sleep 30", &dir).unwrap();
+        assert_eq!(client.ready(), Ok(false));
+        assert!(client.request("textDocument/hover", json!({}), REQUEST_TIMEOUT).is_err());
+        drop(client);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let mut client = LspClient::start("true", &dir).unwrap();
+        while client.ready() == Ok(false) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(client.ready(), Err(EXITED.to_string()));
+    }
+
+    #[test]
+    fn diagnostics_are_taken_for_the_open_document_only() {
+        let mut editor = EditorConfig::with_size(80, 24);
+        for (y, line) in ["fn main() {", "    let é = 1;", "}"].iter().enumerate() {
+            editor.editor_insert_row(y, line);
+        }
+        let uri = "file:///src/main.rs".to_string();
+        editor.lsp.document = Some(Document { path: PathBuf::from("/src/main.rs"), uri, version: 1 });
+
+        let diagnostic = |line, start, end, severity, message| {
+            json!({
+                "range": {
+                    "start": { "line": line, "character": start },
+                    "end": { "line": line, "character": end },
+                },
+                "severity": severity,
+                "message": message,
+            })
+        };
+        editor.lsp_handle_notification(&json!({
+            "method": "textDocument/publishDiagnostics",
+            "params": {
+                "uri": "file:///src/main.rs",
+                "diagnostics": [
+                    diagnostic(1, 8, 9, 2, "unused variable: `é`"),
+                    diagnostic(0, 3, 7, 1, "main is wrong\nsecond line"),
+                    diagnostic(1, 4, 7, 4, "a hint"),
+                ],
+            },
+        }));
+        editor.lsp_handle_notification(&json!({
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": "file:///src/other.rs", "diagnostics": [diagnostic(0, 0, 1, 1, "elsewhere")] },
+        }));
+
+        assert_eq!(editor.diagnostic_summary().as_deref(), Some("E1 W1"));
+        // Render columns, from UTF-16 positions as no server agreed to UTF-8
+        assert_eq!(editor.diagnostic_columns(1), [(8, 9)]);
+        assert_eq!(editor.diagnostic_message().as_deref(), Some("error: main is wrong"));
+        editor.cy = 1;
+        assert_eq!(editor.diagnostic_message().as_deref(), Some("warning: unused variable: `é`"));
+    }
+
+    #[test]
+    fn server_positions_are_found_in_utf16_or_bytes() {
+        let text = "a😀b\nxy";
+        let at = |line: usize, character: usize, utf8| {
+            text_offset(text, &json!({ "line": line, "character": character }), utf8)
+        };
+        assert_eq!(at(0, 3, false), 5); // the emoji is two UTF-16 units
+        assert_eq!(at(0, 5, true), 5);
+        assert_eq!(at(1, 1, false), 8);
+        // Past the end of a line or of the text
+        assert_eq!(at(1, 9, false), text.len());
+        assert_eq!(at(0, 9, false), 6);
+        assert_eq!(at(5, 0, false), text.len());
+    }
+
+    #[test]
+    fn text_edits_apply_back_to_front_and_inserts_keep_their_order() {
+        let edit = |line: usize, start: usize, end: usize, new_text: &str| {
+            json!({
+                "range": {
+                    "start": { "line": line, "character": start },
+                    "end": { "line": line, "character": end },
+                },
+                "newText": new_text,
+            })
+        };
+        let text = "let a = 1;\nlet b = a;\n";
+        let edits = [
+            edit(0, 4, 5, "x"),
+            edit(1, 8, 9, "x"),
+            edit(0, 0, 0, "// one\n"),
+            edit(0, 0, 0, "// two\n"),
+        ];
+        assert_eq!(apply_text_edits(text, &edits, false), "// one\n// two\nlet x = 1;\nlet b = x;\n");
+
+        assert_eq!(apply_text_edits("é😀 = 1", &[edit(0, 1, 3, "x")], false), "éx = 1");
+        assert_eq!(apply_text_edits("é😀 = 1", &[edit(0, 2, 6, "x")], true), "éx = 1");
+    }
+
+    // A scratch directory for one test, with the mock server in it.
+    fn scratch(test: &str) -> (PathBuf, String) {
+        let dir = std::env::temp_dir().join(format!("kibi-lsp-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("server.sh");
+        fs::write(&script, MOCK_SERVER).unwrap();
+        let command = format!("sh {} {}", script.display(), dir.join("log").display());
+        (dir, command)
+    }
+
+    // An editor with main.rs from `dir` open and the mock server for it.
+    fn open_with_server(dir: &Path, command: &str) -> EditorConfig {
+        fs::write(dir.join("main.rs"), "fn old() {}\nfn main() { old(); }\n").unwrap();
+        fs::write(dir.join("other.rs"), "use crate::old;\n").unwrap();
+        let mut editor = EditorConfig::with_size(80, 24);
+        editor.options.lsp_server = command.to_string();
+        editor.editor_open(&dir.join("main.rs").to_string_lossy()).unwrap();
+
+        // It takes a few idle ticks for the server to start and get the file
+        let started = Instant::now();
+        while editor.lsp.document.is_none() {
+            assert!(started.elapsed() < INIT_TIMEOUT, "the server never got the file");
+            editor.lsp_poll();
+            thread::sleep(Duration::from_millis(10));
+        }
+        editor
+    }
+
+    // What the editor sent the server, one message per line of its log.
+    fn sent(dir: &Path) -> Vec<Value> {
+        let log = fs::read_to_string(dir.join("log")).unwrap();
+        log.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn the_server_hears_of_edits_and_saves_and_finds_definitions_and_references() {
+        let (dir, command) = scratch("sync");
+        let mut editor = open_with_server(&dir, &command);
+
+        // An edit goes out in full once the editor is idle
+        editor.type_keys("Go// end\x1b");
+        editor.lsp_poll();
+        editor.type_keys(":w\n");
+
+        // gd on the call goes to the definition
+        (editor.cx, editor.cy) = (13, 1);
+        editor.type_keys("gd");
+        assert_eq!((editor.cx, editor.cy), (3, 0));
+
+        // gr lists both references, the one in the other file read from disk
+        editor.type_keys("gr");
+        assert_eq!(editor.status_msg, "2 references");
+        let main = display_path(&dir.join("main.rs"));
+        let other = display_path(&dir.join("other.rs"));
+        let list = [
+            format!("{}:2:13: fn main() {{ old(); }}", main),
+            format!("{}:1:12: use crate::old;", other),
+        ];
+        assert_eq!(editor.lines(), list);
+
+        drop(editor);
+        let sent = sent(&dir);
+        let methods: Vec<&str> = sent.iter().filter_map(|msg| msg["method"].as_str()).collect();
+        assert_eq!(
+            methods[2..7],
+            [
+                "textDocument/didOpen",
+                "textDocument/didChange",
+                "textDocument/didSave",
+                "textDocument/definition",
+                "textDocument/references",
+            ]
+        );
+        let uri = path_to_uri(&dir.join("main.rs"));
+        let text = "fn old() {}\nfn main() { old(); }\n";
+        let open = json!({ "uri": uri, "languageId": "rust", "version": 1, "text": text });
+        assert_eq!(sent[3]["params"]["textDocument"], open);
+        let change = &sent[4]["params"];
+        assert_eq!(change["textDocument"], json!({ "uri": uri, "version": 2 }));
+        assert_eq!(change["contentChanges"][0]["text"], "fn old() {}\nfn main() { old(); }\n// end\n");
+        assert_eq!(sent[5]["params"]["textDocument"]["uri"], uri);
+        assert_eq!(sent[6]["params"]["position"], json!({ "line": 1, "character": 13 }));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_rename_edits_the_buffer_in_one_step_and_other_files_on_disk() {
+        let (dir, command) = scratch("rename");
+        let mut editor = open_with_server(&dir, &command);
+
+        // After the rename the cursor's byte is inside the ï, so it moves back
+        (editor.cx, editor.cy) = (15, 1);
+        editor.type_keys(":rename naïve\n");
+        assert_eq!(editor.status_msg, "3 changes in 2 files");
+        assert_eq!(editor.lines(), ["fn naïve() {}", "fn main() { naïve(); }"]);
+        assert_eq!((editor.cx, editor.cy), (14, 1));
+        assert_eq!(fs::read_to_string(dir.join("other.rs")).unwrap(), "use crate::naïve;\n");
+        // The file in the buffer is left for the user to write
+        assert_eq!(fs::read_to_string(dir.join("main.rs")).unwrap(), "fn old() {}\nfn main() { old(); }\n");
+
+        editor.type_keys("u");
+        assert_eq!(editor.lines(), ["fn old() {}", "fn main() { old(); }"]);
+        assert_eq!((editor.cx, editor.cy), (15, 1));
+        assert_eq!(editor.ex_rename(" "), Err("Argument required".to_string()));
+
+        drop(editor);
+        let rename = sent(&dir).into_iter().find(|msg| msg["method"] == "textDocument/rename").unwrap();
+        assert_eq!(rename["params"]["newName"], "naïve");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_workspace_edit_in_either_form_changes_the_buffer_and_files() {
+        let dir = std::env::temp_dir().join(format!("kibi-lsp-edit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (main, other) = (dir.join("main.rs"), dir.join("other.rs"));
+        fs::write(&other, "use crate::a😀;\n").unwrap();
+        let mut editor = EditorConfig::with_lines(&["fn a😀() {}", "fn main() { a😀(); }"]);
+        let uri = path_to_uri(&main);
+        editor.lsp.document = Some(Document { path: main, uri: uri.clone(), version: 1 });
+
+        // No server agreed to UTF-8, so columns count UTF-16 units
+        let edit = |line: usize, start: usize, end: usize| {
+            json!({
+                "range": {
+                    "start": { "line": line, "character": start },
+                    "end": { "line": line, "character": end },
+                },
+                "newText": "b",
+            })
+        };
+        let changes = json!({
+            "changes": {
+                uri.clone(): [edit(0, 3, 6), edit(1, 12, 15)],
+                path_to_uri(&other): [edit(0, 11, 14)],
+            },
+        });
+        (editor.cx, editor.cy) = (17, 1);
+        assert_eq!(editor.lsp_apply_workspace_edit(&changes), Ok((3, 2)));
+        assert_eq!(editor.lines(), ["fn b() {}", "fn main() { b(); }"]);
+        assert_eq!(fs::read_to_string(&other).unwrap(), "use crate::b;\n");
+        assert_eq!((editor.cx, editor.cy), (17, 1));
+        editor.editor_undo();
+        assert_eq!(editor.lines(), ["fn a😀() {}", "fn main() { a😀(); }"]);
+
+        let document_changes = json!({
+            "documentChanges": [{ "textDocument": { "uri": uri, "version": 2 }, "edits": [edit(0, 0, 2)] }],
+        });
+        assert_eq!(editor.lsp_apply_workspace_edit(&document_changes), Ok((1, 1)));
+        assert_eq!(editor.lines()[0], "b a😀() {}");
+
+        let create = json!({ "documentChanges": [{ "kind": "create", "uri": path_to_uri(&dir.join("new.rs")) }] });
+        let refused = Err("Can't create, rename or delete files".to_string());
+        assert_eq!(editor.lsp_apply_workspace_edit(&create), refused);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
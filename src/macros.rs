@@ -0,0 +1,251 @@
+// Keyboard macros: q{reg} records, @{reg} plays back, @@ replays the last one.
+//
+// Keys are recorded as decoded EditorKeys as they come out of read_key, and
+// kept as text in the register of the same name, so "ap pastes a macro and
+// "ay after editing it stores it back. Playing one queues its keys ahead of
+// the terminal. process_keypress and editor_prompt read from that queue
+// first, so a macro goes through exactly the same path as typing, and
+// nothing is drawn until the queue is empty.
+
+use crate::{EditorConfig, EditorKey};
+use std::io;
+
+// A macro that calls itself would otherwise play forever
+const MAX_MACRO_DEPTH: usize = 1000;
+// And a big count would fill memory with keys
+const MAX_QUEUED_KEYS: usize = 1_000_000;
+
+// How keys that aren't characters are written in a register: control keys
+// as their control characters, the rest as characters from the Private Use
+// Area, so the text reads back as exactly the keys that were typed.
+const KEY_CHARS: [(EditorKey, char); 22] = [
+    (EditorKey::EnterKey, '\n'),
+    (EditorKey::Backspace, '\x7f'),
+    (EditorKey::Escape, '\x1b'),
+    (EditorKey::CtrlQ, '\x11'),
+    (EditorKey::CtrlF, '\x06'),
+    (EditorKey::CtrlBackslash, '\x1c'),
+    (EditorKey::CtrlL, '\x0c'),
+    (EditorKey::CtrlR, '\x12'),
+    (EditorKey::CtrlS, '\x13'),
+    (EditorKey::CtrlY, '\x19'),
+    (EditorKey::CtrlZ, '\x1a'),
+    (EditorKey::CtrlH, '\u{e000}'), // ^H itself reads as Backspace
+    (EditorKey::ArrowUp, '\u{e001}'),
+    (EditorKey::ArrowDown, '\u{e002}'),
+    (EditorKey::ArrowRight, '\u{e003}'),
+    (EditorKey::ArrowLeft, '\u{e004}'),
+    (EditorKey::HomeKey, '\u{e005}'),
+    (EditorKey::EndKey, '\u{e006}'),
+    (EditorKey::Delete, '\u{e007}'),
+    (EditorKey::PageUp, '\u{e008}'),
+    (EditorKey::PageDown, '\u{e009}'),
+    (EditorKey::ShiftTab, '\u{e00a}'),
+];
+
+fn keys_to_text(keys: &[EditorKey]) -> String {
+    keys.iter()
+        .map(|&key| match key {
+            EditorKey::Char(c) => c,
+            EditorKey::Other(byte) => byte as char,
+            key => KEY_CHARS.iter().find(|(k, _)| *k == key).map_or('\0', |&(_, c)| c),
+        })
+        .collect()
+}
+
+// The keys of register text, read as the terminal would have sent them.
+fn text_to_keys(text: &str) -> Vec<EditorKey> {
+    text.chars()
+        .map(|c| match c {
+            '\r' => EditorKey::EnterKey,
+            '\x08' => EditorKey::Backspace,
+            c => match KEY_CHARS.iter().find(|&&(_, k)| k == c) {
+                Some(&(key, _)) => key,
+                None if c.is_ascii() => EditorKey::Other(c as u8),
+                None => EditorKey::Char(c),
+            },
+        })
+        .collect()
+}
+
+pub struct Recording {
+    register: char,
+    keys: Vec<EditorKey>,
+}
+
+#[derive(Default)]
+pub struct Macros {
+    last_played: Option<char>,
+    recording: Option<Recording>,
+    depth: usize, // macros played since the last key from the terminal
+}
+
+impl Macros {
+    // Registers a macro can live in; uppercase appends to the lowercase one.
+    pub fn is_valid(name: char) -> bool {
+        name.is_ascii_alphanumeric()
+    }
+
+    pub fn recording(&self) -> Option<char> {
+        self.recording.as_ref().map(|rec| rec.register)
+    }
+}
+
+impl EditorConfig {
+    // Next key: queued macro keys first, then the terminal.
+    pub(crate) fn read_key(&mut self) -> io::Result<EditorKey> {
+        if let Some(key) = self.key_queue.pop_front() {
+            return Ok(key);
+        }
+        let key = self.read_terminal_key()?;
+        if key != EditorKey::Other(0) {
+            self.macros.depth = 0;
+        }
+        self.record_key(key);
+        Ok(key)
+    }
+
+    pub(crate) fn record_key(&mut self, key: EditorKey) {
+        if key == EditorKey::Other(0) {
+            return; // read timeout, not a key
+        }
+        if let Some(rec) = &mut self.macros.recording {
+            rec.keys.push(key);
+        }
+    }
+
+    pub(crate) fn editor_start_recording(&mut self, register: char) {
+        self.macros.recording = Some(Recording { register, keys: Vec::new() });
+    }
+
+    pub(crate) fn editor_stop_recording(&mut self) {
+        let Some(mut rec) = self.macros.recording.take() else {
+            return;
+        };
+        rec.keys.pop(); // the q that stopped recording
+
+        // Uppercase appends, as for any other write to a register
+        self.registers.store_macro(rec.register, keys_to_text(&rec.keys));
+    }
+
+    // Queue the keys of macro `register` ('@' for the last one) `count` times.
+    pub(crate) fn editor_play_macro(&mut self, register: char, count: usize) {
+        let name = if register == '@' {
+            match self.macros.last_played {
+                Some(name) => name,
+                None => {
+                    self.editor_set_status_msg("No previous macro");
+                    return;
+                }
+            }
+        } else {
+            register.to_ascii_lowercase()
+        };
+
+        let keys = match self.registers.get(Some(name)) {
+            Some(register) if !register.text.is_empty() => {
+                let mut keys = text_to_keys(&register.text);
+                if register.linewise {
+                    keys.push(EditorKey::EnterKey); // a yanked line runs with its Enter
+                }
+                keys
+            }
+            _ => {
+                self.editor_set_status_msg(format!("Register {} is empty", name));
+                return;
+            }
+        };
+        self.macros.last_played = Some(name);
+
+        self.macros.depth += 1;
+        if self.macros.depth > MAX_MACRO_DEPTH {
+            self.key_queue.clear();
+            self.editor_set_status_msg("Macro recursion too deep, playback stopped");
+            return;
+        }
+
+        let room = MAX_QUEUED_KEYS.saturating_sub(self.key_queue.len()) / keys.len();
+        if count > room {
+            self.editor_set_status_msg(format!("Macro too long, played {} of {} times", room, count));
+        }
+
+        // In front of whatever is left of an outer macro
+        for _ in 0..count.min(room) {
+            for &key in keys.iter().rev() {
+                self.key_queue.push_front(key);
+            }
+        }
+    }
+}
+
+// Handle keys written as register text as if they were typed. Prompts
+// take the keys they wait for from the queue, so a sequence has to be
+// complete.
+#[cfg(test)]
+impl EditorConfig {
+    pub(crate) fn type_keys(&mut self, keys: &str) {
+        self.key_queue.extend(text_to_keys(keys));
+        while let Some(key) = self.key_queue.pop_front() {
+            self.editor_handle_key(key).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::register::{Register, RegisterWrite};
+
+    #[test]
+    fn every_key_reads_back_from_its_text() {
+        let mut keys: Vec<EditorKey> = KEY_CHARS.iter().map(|&(key, _)| key).collect();
+        keys.extend([EditorKey::Other(b'i'), EditorKey::Other(b'[')]);
+        keys.extend([EditorKey::Char('é'), EditorKey::Char('字')]);
+        assert_eq!(text_to_keys(&keys_to_text(&keys)), keys);
+    }
+
+    #[test]
+    fn a_recording_is_text_in_its_register() {
+        let mut editor = EditorConfig::with_size(80, 24);
+        editor.editor_start_recording('a');
+        for key in [b'i', b'x', 0x1b, b'O', b'q'] {
+            editor.record_key(if key == 0x1b { EditorKey::Escape } else { EditorKey::Other(key) });
+        }
+        editor.editor_stop_recording();
+        assert_eq!(editor.registers.get(Some('a')).unwrap().text, "ix\x1bO");
+        assert_eq!(editor.registers.get(None), None);
+
+        // qA appends, and what is yanked into the register is what plays
+        editor.editor_start_recording('A');
+        editor.record_key(EditorKey::ArrowUp);
+        editor.record_key(EditorKey::Other(b'q'));
+        editor.editor_stop_recording();
+        editor.editor_play_macro('a', 1);
+        let keys: Vec<EditorKey> = editor.key_queue.drain(..).collect();
+        assert_eq!(keys, text_to_keys("ix\x1bO\u{e001}"));
+        assert_eq!(keys[2..], [EditorKey::Escape, EditorKey::Other(b'O'), EditorKey::ArrowUp]);
+
+        let text = "dd".to_string();
+        editor.registers.store(Some('b'), RegisterWrite::Yank, Register { text, linewise: true });
+        editor.editor_play_macro('b', 2);
+        let enter = EditorKey::EnterKey;
+        assert_eq!(editor.key_queue, [EditorKey::Other(b'd'), EditorKey::Other(b'd'), enter].repeat(2));
+    }
+
+    #[test]
+    fn a_big_count_plays_as_many_times_as_fit() {
+        let mut editor = EditorConfig::with_size(80, 24);
+        let text = "ab".to_string();
+        editor.registers.store(Some('a'), RegisterWrite::Yank, Register { text, linewise: false });
+
+        editor.editor_play_macro('a', 5000);
+        assert_eq!(editor.key_queue.len(), 10_000);
+        assert_eq!(editor.status_msg, "");
+
+        editor.key_queue.clear();
+        editor.editor_play_macro('a', usize::MAX);
+        assert_eq!(editor.key_queue.len(), MAX_QUEUED_KEYS);
+        let status = format!("Macro too long, played {} of {} times", MAX_QUEUED_KEYS / 2, usize::MAX);
+        assert_eq!(editor.status_msg, status);
+    }
+}
@@ -1,15 +1,58 @@
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::{self, BufRead, BufReader};
-use std::io::{Read, Seek, SeekFrom, Write};
+mod buffer;
+mod complete;
+mod edit;
+mod ex;
+mod grapheme;
+mod grep;
+mod highlight;
+mod lsp;
+mod macros;
+mod make;
+mod mode;
+mod motion;
+mod operator;
+mod options;
+mod prompt;
+mod quickfix;
+mod register;
+mod repeat;
+mod replace;
+mod screen;
+mod search;
+mod syntax;
+mod theme;
+mod undo;
+mod undo_file;
+
+use buffer::TextBuffer;
+use complete::Completion;
+use ex::ExState;
+use highlight::HlState;
+use lsp::Lsp;
+use macros::Macros;
+use mode::EditorMode;
+use options::EditorOptions;
+use prompt::{HistoryKind, PromptHistory};
+use quickfix::Quickfix;
+use register::Registers;
+use repeat::{Change, InsertAction};
+use screen::{Grid, Screen};
+use search::MatchOverlay;
+use syntax::{EditorSyntax, Highlighter, HL_HIGHLIGHT_NUMBERS, HL_HIGHLIGHT_STRINGS};
+use theme::{ColorDepth, Theme, Ui};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
 use std::os::unix::io::AsRawFd;
-use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::time::{Duration, SystemTime};
 use termion::terminal_size;
+use undo::{EditHistory, EditOp};
 use termios::{
-    tcgetattr, tcsetattr, Termios, BRKINT, CS8, ECHO, ICANON, ICRNL, IEXTEN, INPCK, ISIG, ISTRIP,
+    tcsetattr, Termios, BRKINT, CS8, ECHO, ICANON, ICRNL, IEXTEN, INPCK, ISIG, ISTRIP,
     IXON, OPOST, TCSAFLUSH, VMIN, VTIME,
 };
+use unicode_segmentation::UnicodeSegmentation;
 
 const VERSION: &str = "0.0.1";
 const TAB_STOP: usize = 8; // Number of spaces for a tab stop
@@ -37,8 +80,14 @@ enum EditorKey {
     Backspace,
     CtrlF,
     CtrlH,
+    CtrlBackslash,
     CtrlL,
+    CtrlR,
     CtrlS,
+    CtrlY,
+    CtrlZ,
+    ShiftTab,
+    Char(char), // a character outside ASCII, decoded from its UTF-8 bytes
     Other(u8),
 }
 
@@ -53,6 +102,14 @@ pub enum EditorHighlight {
     HlMComment,
     HlKeyword1,
     HlKeyword2,
+    CurrentMatch,
+    Section, // [table] headers
+    Key,
+    Value, // true, false, dates and times
+    Lifetime,
+    Macro,
+    Attribute,
+    Escape, // \n and the like in strings
 }
 
 // Now no casting needed — Rust auto-converts via `as u8` safely & clearly.
@@ -97,20 +154,6 @@ impl AppendBuffer {
     }
 }
 
-const HL_HIGHLIGHT_NUMBERS: usize = 1 << 0;
-const HL_HIGHLIGHT_STRINGS: usize = 1 << 1;
-
-pub struct EditorSyntax {
-    filetype: &'static str,
-    filematch: &'static [&'static str],
-    keywords: &'static [&'static str],
-    types: &'static [&'static str],
-    single_line_comment_start: &'static str,
-    multiline_comment_start: &'static str,
-    multiline_comment_end: &'static str,
-    flags: usize,
-}
-
 #[derive(Debug)]
 pub struct EditorRow {
     pub size: usize,
@@ -118,16 +161,32 @@ pub struct EditorRow {
     pub render: String,
     pub r_size: usize,
     pub hl: Option<Vec<u8>>,
-    idx: usize,
-    hl_open_comment: bool
+    hl_start: HlState, // what the row above left open when `hl` was worked out
+    hl_state: HlState, // what is left open at the end of the row
+    pub global_mark: bool, // set on lines :g still has to visit
 }
 impl EditorRow {
+    pub fn new(s: &str) -> Self {
+        let mut row = EditorRow {
+            size: s.len(),
+            chars: s.to_string(),
+            render: String::new(),
+            r_size: 0,
+            hl: None,
+            hl_start: HlState::Normal,
+            hl_state: HlState::Normal,
+            global_mark: false,
+        };
+        row.update_row();
+        row
+    }
+
     pub fn update_row(&mut self) {
         let mut render = String::new();
-        let mut idx = 0;
+        let mut idx = 0; // screen column
 
-        for ch in self.chars.chars() {
-            if ch == '\t' {
+        for g in self.chars.graphemes(true) {
+            if g == "\t" {
                 render.push(' ');
                 idx += 1;
                 while idx % TAB_STOP != 0 {
@@ -135,8 +194,8 @@ impl EditorRow {
                     idx += 1;
                 }
             } else {
-                render.push(ch);
-                idx += 1;
+                render.push_str(g);
+                idx += grapheme::width(g);
             }
         }
 
@@ -146,86 +205,54 @@ impl EditorRow {
 
     pub fn insert_char(&mut self, at: usize, c: char) {
         let at = at.min(self.chars.len());
+        if !self.chars.is_char_boundary(at) {
+            return;
+        }
         self.chars.insert(at, c);
-        self.size += 1;
+        self.size = self.chars.len();
         self.update_row();
         
     }
 
     pub fn delete_char(&mut self, at: usize) {
-        if at >= self.chars.len() {
+        if at >= self.chars.len() || !self.chars.is_char_boundary(at) {
             return;
         }
         self.chars.remove(at);
+        self.size = self.chars.len();
         self.update_row();
        
     }
 
-    pub fn append_string(&mut self, s: &str) {
-        self.chars.push_str(s);
+    pub fn insert_str(&mut self, at: usize, s: &str) {
+        let at = at.min(self.chars.len());
+        if !self.chars.is_char_boundary(at) {
+            return;
+        }
+        self.chars.insert_str(at, s);
         self.size = self.chars.len();
         self.update_row();
-        
     }
 
-    pub fn highlight_match(&mut self, match_index: usize, query: &str) {
-        if let Some(ref mut hl) = self.hl {
-            let start = match_index;
-            let end = start + query.len();
-
-            if end <= hl.len() {
-                for i in start..end {
-                    hl[i] = EditorHighlight::Match as u8;
-                }
-            }
+    pub fn delete_range(&mut self, at: usize, end: usize) -> String {
+        let end = end.min(self.chars.len());
+        if at >= end || !self.chars.is_char_boundary(at) || !self.chars.is_char_boundary(end) {
+            return String::new();
         }
+        let removed: String = self.chars.drain(at..end).collect();
+        self.size = self.chars.len();
+        self.update_row();
+        removed
     }
 
-    pub fn editor_syntax_to_color(hl: EditorHighlight) -> u8 {
-        match hl {
-            EditorHighlight::Number => 31,
-            EditorHighlight::Match => 34,
-            EditorHighlight::HlString => 35,
-            EditorHighlight::HlComment | EditorHighlight::HlMComment => 36,
-            EditorHighlight::HlKeyword1 => 33,
-            EditorHighlight::HlKeyword2 => 32,
-            _ => 37,
-        }
+    pub fn append_string(&mut self, s: &str) {
+        self.chars.push_str(s);
+        self.size = self.chars.len();
+        self.update_row();
+        
     }
 }
 
-const RUST_EXTENSION: &[&str] = &[".rs", ".toml"];
-
-const RUST_HL_KEYWORDS: &[&str] = &[
-    // Control flow keywords (HL_KEYWORD1 - Yellow)
-    "if", "else", "while", "for", "loop", "break", "continue", "return",
-    "match", 
-    
-    // Declaration keywords (HL_KEYWORD2 - Green, marked with |)
-    "struct|", "enum|", "impl|", "trait|", "fn|", "let|", "mut|",
-    "const|", "static|", "pub|", "mod|", "use|", "crate|", "super|", "self|",
-];
-    
-const RUST_TYPES: &[&str] = &[ 
-	 "i8", "i16", "i32", "i64", "i128", "isize",
-    "u8", "u16", "u32", "u64", "u128", "usize",
-    "f32", "f64", "bool", "char", "str", "String",
-    "Vec", "Option", "Result",
-];
-
-const HLDB: &[EditorSyntax] = &[EditorSyntax {
-    filetype: "Rust",
-    filematch: RUST_EXTENSION,
-    types: RUST_TYPES,
-    keywords: RUST_HL_KEYWORDS,
-    single_line_comment_start: "//",
-    multiline_comment_start: "/*",
-    multiline_comment_end: "*/",
-    flags: HL_HIGHLIGHT_NUMBERS | HL_HIGHLIGHT_STRINGS,
-}];
-
-const HLDB_ENTRIES: usize = HLDB.len();
-
 // Main editor state structure
 struct EditorConfig {
     original_termios: Option<Termios>,
@@ -234,45 +261,130 @@ struct EditorConfig {
     rx: usize,
     row_off: usize,
     col_off: usize,
-    screen_rows: usize,
+    screen_rows: usize, // rows of text, at least one
     screen_cols: usize,
+    term_size: (usize, usize), // columns and rows of the terminal, bars included
     dirty: usize,
-    number_of_rows: usize,
     quit_times: u8,
-    erow: Vec<EditorRow>,
+    erow: TextBuffer,
     filename: Option<String>,
     status_msg: String,
     status_msg_time: SystemTime,
-    saved_hl: Option<Vec<u8>>,
-    saved_hl_line: Option<usize>,
-    syntax: Option<&'static EditorSyntax>,
+    syntax: Option<Rc<EditorSyntax>>,
+    syntaxes: Vec<Rc<EditorSyntax>>, // built-in and user definitions, in the order they are tried
+    hl_valid: usize,                 // rows above this one are highlighted and up to date
+    theme: Theme,                    // the one in use, with the colours the terminal shows
+    themes: Vec<Theme>,              // built-in and user themes
+    color_depth: ColorDepth,         // how many colours the terminal shows
+    history: EditHistory,
+    undo_dir: Option<PathBuf>, // where undo histories outlive the session, None for nowhere
+    mode: EditorMode,
+    pending_keys: Vec<u8>,
+    visual_anchor: (usize, usize),
+    registers: Registers,
+    last_change: Option<Change>,
+    pending_change: Option<Change>,
+    macros: Macros,
+    key_queue: VecDeque<EditorKey>, // keys to handle before reading the terminal
+    marks: HashMap<char, (usize, usize)>,
+    options: EditorOptions,
+    ex: ExState,
+    last_pattern: Option<String>, // last search or :s pattern
+    search_backward: bool,        // the last search went up, for n and N
+    search_hl: MatchOverlay,      // search matches to highlight
+    prompt_history: PromptHistory,
+    prompt_cursor: Option<usize>, // cursor column in the message bar while prompting
+    quickfix: Quickfix,           // the last :grep or :make results
+    lsp: Lsp,
+    completion: Option<Completion>, // the Insert mode completion menu
+    screen: Screen,                 // what the terminal shows
+    quit_requested: bool,
 }
 
 impl EditorConfig {
     fn new() -> Result<Self, io::Error> {
         //Try to get terminal size but fallback to 80x24
         let (cols, rows) = Self::get_window_size()?;
+        let mut editor = Self::with_size(cols, rows);
+        editor.editor_load_config();
+        Ok(editor)
+    }
+
+    // An editor for a terminal of `cols` by `rows`, before raw mode. It has
+    // only what is built in: nothing is read from or written to the user's
+    // files or taken from the environment, so tests start from the same
+    // place everywhere.
+    fn with_size(cols: usize, rows: usize) -> Self {
+        let themes = theme::builtin_themes();
+        let color_depth = ColorDepth::TrueColor;
 
-        Ok(EditorConfig {
+        EditorConfig {
             original_termios: None,
-            screen_rows: rows.saturating_sub(2), // Leave space for status bar
-            screen_cols: cols,
+            screen_rows: rows.saturating_sub(2).max(1), // Leave space for status bar
+            screen_cols: cols.max(1),
+            term_size: (cols, rows),
             cx: 0,
             cy: 0,
             rx: 0,
             row_off: 0,
             col_off: 0,
             dirty: 0,
-            number_of_rows: 0,
             quit_times: QUIT_TIMES,
-            erow: Vec::new(),
+            erow: TextBuffer::new(),
             filename: None,
             status_msg: String::new(),
             status_msg_time: SystemTime::now(),
-            saved_hl: None,
-            saved_hl_line: None,
             syntax: None,
-        })
+            syntaxes: syntax::builtin_syntaxes().into_iter().map(Rc::new).collect(),
+            hl_valid: 0,
+            theme: themes[0].for_depth(color_depth),
+            themes,
+            color_depth,
+            history: EditHistory::new(),
+            undo_dir: None,
+            mode: EditorMode::Normal,
+            pending_keys: Vec::new(),
+            visual_anchor: (0, 0),
+            registers: Registers::default(),
+            last_change: None,
+            pending_change: None,
+            macros: Macros::default(),
+            key_queue: VecDeque::new(),
+            marks: HashMap::new(),
+            options: EditorOptions::default(),
+            ex: ExState::default(),
+            last_pattern: None,
+            search_backward: false,
+            search_hl: MatchOverlay::default(),
+            prompt_history: PromptHistory::default(),
+            prompt_cursor: None,
+            quickfix: Quickfix::default(),
+            lsp: Lsp::default(),
+            completion: None,
+            screen: Screen::default(),
+            quit_requested: false,
+        }
+    }
+
+    // Read the user's syntax files, themes and prompt history, keep undo
+    // histories next to it, and see how many colours the terminal shows.
+    fn editor_load_config(&mut self) {
+        let (syntaxes, syntax_errors) = syntax::load_syntaxes();
+        let (themes, theme_errors) = theme::load_themes();
+        self.syntaxes = syntaxes;
+        self.themes = themes;
+        self.color_depth = theme::color_depth();
+        self.undo_dir = undo_file::undo_dir();
+        self.prompt_history = prompt::history_file().map_or_else(PromptHistory::default, PromptHistory::load);
+        // A theme file may replace the default one
+        let _ = self.editor_select_theme();
+
+        // A definition that didn't load is worth a word before anything else
+        if let Some(error) = syntax_errors.first() {
+            self.editor_set_status_msg(format!("Syntax file {}", error));
+        } else if let Some(error) = theme_errors.first() {
+            self.editor_set_status_msg(format!("Theme file {}", error));
+        }
     }
 
     //get window size
@@ -281,51 +393,20 @@ impl EditorConfig {
         Ok((width as usize, height as usize))
     }
 
-    fn highlight_to_color(&self, hl: u8) -> u8 {
-        match hl {
-        x if x == EditorHighlight::Number as u8 => 31,     // Red
-        x if x == EditorHighlight::Match as u8 => 34,      // Blue  
-        x if x == EditorHighlight::HlString as u8 => 35,   // Magenta
-        x if x == EditorHighlight::HlComment as u8 => 36,  // Cyan
-        x if x == EditorHighlight::HlMComment as u8 => 36, // Cyan
-        x if x == EditorHighlight::HlKeyword1 as u8 => 33, // Yellow (control flow)
-        x if x == EditorHighlight::HlKeyword2 as u8 => 32, // Green (declarations)
-        _ => 37, // White (normal)
-    }
-    }
-
-    fn restore_highlight(&mut self) {
-        // If we have saved highlights, restore them
-        if let (Some(saved_hl), Some(saved_line)) = (&self.saved_hl, self.saved_hl_line) {
-            // Ensure the saved line is still valid
-            if saved_line < self.erow.len() {
-                // Restore the original highlight
-                if let Some(ref mut current_hl) = self.erow[saved_line].hl {
-                    // Only restore if the sizes match (safety check)
-                    if current_hl.len() == saved_hl.len() {
-                        current_hl.copy_from_slice(saved_hl);
-                    }
-                }
-            }
-
-            // Clear the saved state
-            self.saved_hl = None;
-            self.saved_hl_line = None;
-        }
-    }
-
-    fn save_highlight(&mut self, line_index: usize) {
-        // First restore any existing saved highlights
-        self.restore_highlight();
-
-        // Save the current line's highlights
-        if line_index < self.erow.len() {
-            if let Some(ref hl) = self.erow[line_index].hl {
-                // Clone the current highlight vector
-                self.saved_hl = Some(hl.clone());
-                self.saved_hl_line = Some(line_index);
-            }
+    // Take the new size of a resized terminal. However small it gets there
+    // is a row and a column of text; the message bar and then the status bar
+    // are what go when there's no room.
+    fn editor_resize(&mut self, cols: usize, rows: usize) {
+        if (cols, rows) == self.term_size {
+            return;
         }
+        self.term_size = (cols, rows);
+        self.screen_rows = rows.saturating_sub(2).max(1);
+        self.screen_cols = cols.max(1);
+        self.col_off = self.col_off.min(self.rx);
+        self.row_off = self.row_off.min(self.cy);
+        self.editor_scroll();
+        self.screen.invalidate();
     }
 
     fn is_separator(c: char) -> bool {
@@ -335,38 +416,38 @@ impl EditorConfig {
     fn editor_row_cx_to_rx(&self, row: &EditorRow, cx: usize) -> usize {
         //initialise rx
         let mut rx = 0;
-        //loop through the chars
-        for (j, ch) in row.chars.chars().enumerate() {
+        //loop through the clusters
+        for (j, g) in row.chars.grapheme_indices(true) {
             if j >= cx {
                 break;
             }
-            if ch == '\t' {
+            if g == "\t" {
                 // calculate padding to the next tab stop
                 rx += (TAB_STOP - 1) - (rx % TAB_STOP);
                 //move to the next position
                 rx += 1;
             } else {
-                rx += 1;
+                rx += grapheme::width(g);
             }
         }
         rx
     }
 
+    // The cluster on screen column `rx`, or the end of the row when it is
+    // shorter. A column in the middle of a tab or a wide character gives
+    // where that starts.
     fn editor_row_rx_to_cx(&self, row: &EditorRow, rx: usize) -> usize {
-        //variable to keep track of rendered index
         let mut cur_rx = 0;
-
-        for (cx, ch) in row.chars.chars().enumerate() {
-            if ch == '\t' {
-                cur_rx += (TAB_STOP - 1) - (cur_rx % TAB_STOP);
+        for (j, g) in row.chars.grapheme_indices(true) {
+            if g == "\t" {
+                cur_rx += TAB_STOP - cur_rx % TAB_STOP;
+            } else {
+                cur_rx += grapheme::width(g);
             }
-            cur_rx += 1;
-
-            if cur_rx >= rx {
-                return cx;
+            if cur_rx > rx {
+                return j;
             }
         }
-
         row.chars.len()
     }
 
@@ -375,328 +456,279 @@ impl EditorConfig {
             return;
         }
 
-        for j in (at + 1)..self.number_of_rows {
-            self.erow[j].idx += 1;
-        }
-
-
-        let mut row = EditorRow {
-            size: s.len(),
-            chars: s.to_string(),
-            render: String::new(),
-            r_size: 0,
-            hl: None,
-            idx: at,
-            hl_open_comment: false,
-        };
-
-        row.update_row();
-        self.erow.insert(at, row);
-        self.number_of_rows = self.erow.len();
+        self.erow.insert_row(at, EditorRow::new(s));
 
         // Update syntax highlighting for the new row
         self.editor_update_syntax(at);
         self.dirty += 1;
+
+        let op = EditOp::InsertRow { y: at, text: s.to_string() };
+        self.record_edit(op, (self.cx, self.cy));
     }
 
-    fn editor_free_row(&mut self, at: usize) {
-        // check index is within bounds
-        if at >= self.number_of_rows {
-            return;
+    // Row `row_index` changed (or is new): it gets highlighted again, and
+    // the rows after it checked, before it is next drawn.
+    pub fn editor_update_syntax(&mut self, row_index: usize) {
+        if let Some(row) = self.erow.get_mut(row_index) {
+            row.hl = None;
         }
+        self.hl_valid = self.hl_valid.min(row_index);
+    }
 
-        for j in at..self.number_of_rows {
-            self.erow[j].idx -= 1;
+    // Bring the highlighting of the rows above `end` up to date. Past the
+    // rows known to be right, a row is highlighted again if it changed or
+    // starts inside something different than it did (a comment opened
+    // above it, say); once the rows start the same as before they are
+    // left alone.
+    fn editor_update_highlight(&mut self, end: usize) {
+        let Some(syntax) = self.syntax.clone() else {
+            return;
+        };
+        let end = end.min(self.erow.len());
+        self.hl_valid = self.hl_valid.min(self.erow.len());
+        let mut state = match self.hl_valid {
+            0 => HlState::Normal,
+            y => self.erow[y - 1].hl_state,
+        };
+        for y in self.hl_valid..end {
+            let row = &self.erow[y];
+            if row.hl.is_none() || row.hl_start != state {
+                self.editor_highlight_row(y, state, &syntax);
+            }
+            state = self.erow[y].hl_state;
         }
-
-        // remove the row from the vector
-        self.erow.remove(at);
-        self.number_of_rows -= 1; // Update the number of rows
-        self.dirty += 1; // mark the editor as modified
+        self.hl_valid = self.hl_valid.max(end);
     }
 
-   pub fn editor_update_syntax(&mut self, row_index: usize) {
-    if row_index >= self.erow.len() {
-        return;
+    fn editor_highlight_row(&mut self, row_index: usize, prev_state: HlState, syntax: &EditorSyntax) {
+        let render = &self.erow[row_index].render;
+        let (hl, state) = match syntax.highlighter {
+            Highlighter::Keywords => Self::highlight_keywords(render, prev_state, syntax),
+            Highlighter::Toml => highlight::highlight_toml(render, prev_state),
+            Highlighter::Rust => highlight::highlight_rust(render, prev_state, syntax),
+        };
+        self.editor_set_row_highlight(row_index, hl, prev_state, state);
     }
 
-    // Early return if no syntax is set
-    if self.syntax.is_none() {
-        return;
-    }
+    // The highlighting of one line for a syntax described by its keywords
+    // and delimiters, and the state the line ends in.
+   fn highlight_keywords(render: &str, prev_state: HlState, syntax: &EditorSyntax) -> (Vec<u8>, HlState) {
+    // Compared as bytes: a multi-byte character never matches a delimiter
+    let bytes = render.as_bytes();
+    let mut hl = vec![EditorHighlight::Normal as u8; render.len()];
 
-    let render_len = self.erow[row_index].render.len();
-    let mut hl = vec![EditorHighlight::Normal as u8; render_len];
+    // Get comment start string and its length
+    let scs = syntax.single_line_comment_start.as_bytes();
+    let mcs = syntax.multiline_comment_start.as_bytes();
+    let mce = syntax.multiline_comment_end.as_bytes();
 
-    if let Some(syntax) = self.syntax {
-        // Get comment start string and its length
-        let scs = syntax.single_line_comment_start;
-        let mcs = syntax.multiline_comment_start;
-        let mce = syntax.multiline_comment_end;
-        
-        let scs_len = scs.len();
-        let mcs_len = mcs.len();
-        let mce_len = mce.len();
+    let mut i = 0;
+    let mut prev_sep = true;
+    let mut in_string: Option<char> = None;
 
-        let mut i = 0;
-        let mut prev_sep = true;
-        let in_string: Option<char> = None;
-        
-        // Initialize in_comment based on previous row's state (like C code)
-        let mut in_comment = if row_index > 0 {
-            self.erow[row_index - 1].hl_open_comment
+    // Initialize in_comment based on previous row's state (like C code)
+    let mut in_comment = matches!(prev_state, HlState::Comment(_));
+
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+
+        let prev_hl = if i > 0 {
+            hl[i - 1]
         } else {
-            false
+            EditorHighlight::Normal as u8
         };
 
-        while i < self.erow[row_index].render.len() {
-            let c = self.erow[row_index].render.as_bytes()[i] as char;
-
-            let prev_hl = if i > 0 {
-                hl[i - 1]
-            } else {
-                EditorHighlight::Normal as u8
-            };
+        // Comment highlighting - check BEFORE string highlighting
+        if !scs.is_empty() && in_string.is_none() && !in_comment && bytes[i..].starts_with(scs) {
+            // Highlight the rest of the line as a comment
+            hl[i..].fill(EditorHighlight::HlComment as u8);
+            break; // Done with this row
+        }
 
-            // Comment highlighting - check BEFORE string highlighting
-            if scs_len > 0 && in_string.is_none() && !in_comment {
-                // Check if we have enough characters left and if it matches the comment start
-                if i + scs_len <= self.erow[row_index].render.len() {
-                    let slice = &self.erow[row_index].render[i..i + scs_len];
-                    if slice == scs {
-                        // Highlight the rest of the line as a comment
-                        for j in i..hl.len() {
-                            hl[j] = EditorHighlight::HlComment as u8;
-                        }
-                        break; // Done with this row
-                    }
+        // Multi-line comment highlighting
+        if !mcs.is_empty() && !mce.is_empty() && in_string.is_none() {
+            if in_comment {
+                hl[i] = EditorHighlight::HlMComment as u8;
+
+                // Check if multi-line comment ends here
+                if bytes[i..].starts_with(mce) {
+                    // Highlight the end marker
+                    hl[i..i + mce.len()].fill(EditorHighlight::HlMComment as u8);
+                    i += mce.len();
+                    in_comment = false;
+                    prev_sep = true;
+                } else {
+                    i += 1;
                 }
+                continue;
+            } else if bytes[i..].starts_with(mcs) {
+                // Highlight the start marker
+                hl[i..i + mcs.len()].fill(EditorHighlight::HlMComment as u8);
+                i += mcs.len();
+                in_comment = true;
+                continue;
             }
-            
-            // Multi-line comment highlighting
-            if mcs_len > 0 && mce_len > 0 && in_string.is_none() {
-                if in_comment {
-                    hl[i] = EditorHighlight::HlMComment as u8;
-
-                    // Check if multi-line comment ends here
-                    if i + mce_len <= self.erow[row_index].render.len() && 
-                       &self.erow[row_index].render[i..i + mce_len] == mce {
-                        // Highlight the end marker
-                        for j in i..(i + mce_len) {
-                            hl[j] = EditorHighlight::HlMComment as u8;
-                        }
-                        i += mce_len;
-                        in_comment = false;
-                        prev_sep = true;
-                        continue;
-                    } else {
-                        i += 1;
-                        continue;
-                    }
-                } else if i + mcs_len <= self.erow[row_index].render.len() && 
-                          &self.erow[row_index].render[i..i + mcs_len] == mcs {
-                    // Highlight the start marker
-                    for j in i..(i + mcs_len) {
-                        hl[j] = EditorHighlight::HlMComment as u8;
-                    }
-                    i += mcs_len;
-                    in_comment = true;
+        }
+
+        // Strings end at the closing delimiter or the end of the line
+        if syntax.flags & HL_HIGHLIGHT_STRINGS != 0 {
+            if let Some(quote) = in_string {
+                hl[i] = EditorHighlight::HlString as u8;
+                if c == '\\' && i + 1 < bytes.len() {
+                    hl[i + 1] = EditorHighlight::HlString as u8;
+                    i += 2;
                     continue;
                 }
+                if c == quote {
+                    in_string = None;
+                }
+                i += 1;
+                prev_sep = true;
+                continue;
+            } else if syntax.string_delimiters.contains(&c) {
+                in_string = Some(c);
+                hl[i] = EditorHighlight::HlString as u8;
+                i += 1;
+                continue;
             }
+        }
 
-            // Number highlighting logic
-            if syntax.flags & HL_HIGHLIGHT_NUMBERS != 0 {
-                if (c.is_ascii_digit()
-                    && (prev_sep || prev_hl == EditorHighlight::Number as u8))
-                    || (c == '.' && prev_hl == EditorHighlight::Number as u8)
-                {
-                    hl[i] = EditorHighlight::Number as u8;
-                    i += 1;
-                    prev_sep = false;
-                    continue;
-                }
+        // Number highlighting logic
+        if syntax.flags & HL_HIGHLIGHT_NUMBERS != 0 {
+            // 0x1F and the like: the prefix and the letters and digits after it
+            let prefix = syntax.number_prefixes.iter().map(|p| p.as_bytes()).find(|p| {
+                bytes[i..].starts_with(p) && bytes.get(i + p.len()).is_some_and(u8::is_ascii_alphanumeric)
+            });
+            if let (true, Some(prefix)) = (prev_sep, prefix) {
+                let digits = bytes[i + prefix.len()..]
+                    .iter()
+                    .take_while(|&&b| b.is_ascii_alphanumeric() || Some(b as char) == syntax.number_separator)
+                    .count();
+                hl[i..i + prefix.len() + digits].fill(EditorHighlight::Number as u8);
+                i += prefix.len() + digits;
+                prev_sep = false;
+                continue;
             }
 
-            // Keyword highlighting logic
-            if prev_sep {
-                let mut keyword_found = false;
-                
-                // Check regular keywords
-                for &keyword in syntax.keywords.iter() {
-                    let klen = keyword.len();
-                    let kw2 = keyword.ends_with('|');
-                    let actual_klen = if kw2 { klen - 1 } else { klen };
-                    
-                    // Check if we have enough characters remaining
-                    if i + actual_klen <= self.erow[row_index].render.len() {
-                        let keyword_to_match = if kw2 { &keyword[..klen-1] } else { keyword };
-                        let slice = &self.erow[row_index].render[i..i + actual_klen];
-                        
-                        // Check if the keyword matches and is followed by a separator
-                        if slice == keyword_to_match {
-                            let next_char_pos = i + actual_klen;
-                            let is_end_of_line = next_char_pos >= self.erow[row_index].render.len();
-                            let next_is_separator = if is_end_of_line {
-                                true
-                            } else {
-                                let next_char = self.erow[row_index].render.as_bytes()[next_char_pos] as char;
-                                Self::is_separator(next_char)
-                            };
-                            
-                            if is_end_of_line || next_is_separator {
-                                // Highlight the keyword
-                                let highlight_type = if kw2 {
-                                    EditorHighlight::HlKeyword2 as u8
-                                } else {
-                                    EditorHighlight::HlKeyword1 as u8
-                                };
-                                
-                                for j in i..i + actual_klen {
-                                    hl[j] = highlight_type;
-                                }
-                                
-                                i += actual_klen;
-                                keyword_found = true;
-                                break;
-                            }
-                        }
-                    }
-                }
-                
-                // Check type keywords
-                if !keyword_found {
-                    for &type_keyword in syntax.types.iter() {
-                        let klen = type_keyword.len();
-                        
-                        // Check if we have enough characters remaining
-                        if i + klen <= self.erow[row_index].render.len() {
-                            let slice = &self.erow[row_index].render[i..i + klen];
-                            
-                            // Check if the type keyword matches and is followed by a separator
-                            if slice == type_keyword {
-                                let next_char_pos = i + klen;
-                                let is_end_of_line = next_char_pos >= self.erow[row_index].render.len();
-                                let next_is_separator = if is_end_of_line {
-                                    true
-                                } else {
-                                    let next_char = self.erow[row_index].render.as_bytes()[next_char_pos] as char;
-                                    Self::is_separator(next_char)
-                                };
-                                
-                                if is_end_of_line || next_is_separator {
-                                    // Highlight the type keyword
-                                    for j in i..i + klen {
-                                        hl[j] = EditorHighlight::HlKeyword2 as u8;
-                                    }
-                                    
-                                    i += klen;
-                                    keyword_found = true;
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-                
-                if keyword_found {
-                    prev_sep = false;
-                    continue;
+            let in_number = prev_hl == EditorHighlight::Number as u8;
+            if (c.is_ascii_digit() && (prev_sep || in_number))
+                || ((c == '.' || Some(c) == syntax.number_separator) && in_number)
+            {
+                hl[i] = EditorHighlight::Number as u8;
+                i += 1;
+                prev_sep = false;
+                continue;
+            }
+        }
+
+        // Keyword highlighting logic: keywords ending in | and types get
+        // the second colour
+        if prev_sep {
+            let keywords = syntax.keywords.iter().map(|keyword| match keyword.strip_suffix('|') {
+                Some(keyword) => (keyword, EditorHighlight::HlKeyword2),
+                None => (keyword.as_str(), EditorHighlight::HlKeyword1),
+            });
+            let types = syntax.types.iter().map(|name| (name.as_str(), EditorHighlight::HlKeyword2));
+
+            let mut keyword_found = false;
+            for (keyword, highlight_type) in keywords.chain(types) {
+                let end = i + keyword.len();
+                // The keyword must be followed by a separator or the end of the line
+                if !keyword.is_empty()
+                    && bytes[i..].starts_with(keyword.as_bytes())
+                    && bytes.get(end).is_none_or(|&next| Self::is_separator(next as char))
+                {
+                    hl[i..end].fill(highlight_type as u8);
+                    i = end;
+                    keyword_found = true;
+                    break;
                 }
             }
 
-            prev_sep = Self::is_separator(c);
-            i += 1;
-        }
-        
-        // Track if the comment state changed (like C code)
-        let changed = self.erow[row_index].hl_open_comment != in_comment;
-        self.erow[row_index].hl_open_comment = in_comment;
-        
-        // If state changed, update the next row recursively
-        if changed && row_index + 1 < self.erow.len() {
-            self.editor_update_syntax(row_index + 1);
+            if keyword_found {
+                prev_sep = false;
+                continue;
+            }
         }
+
+        prev_sep = Self::is_separator(c);
+        i += 1;
     }
 
-    self.erow[row_index].hl = Some(hl);
+    let state = if in_comment { HlState::Comment(1) } else { HlState::Normal };
+    (hl, state)
 }
 
+    // Store a row's highlighting with the states it starts and ends in.
+    fn editor_set_row_highlight(&mut self, row_index: usize, hl: Vec<u8>, start: HlState, end: HlState) {
+        let row = &mut self.erow[row_index];
+        row.hl = Some(hl);
+        row.hl_start = start;
+        row.hl_state = end;
+    }
+
     fn editor_select_syntax_highlight(&mut self) {
         //reset syntax to Null
         self.syntax = None;
+        // Rows are highlighted again as they come into view
+        for y in 0..self.erow.len() {
+            self.erow[y].hl = None;
+        }
+        self.hl_valid = 0;
+        // A new file name or new contents: the language server reopens it
+        self.lsp.buffer_replaced();
 
         //if filename is not set exit early
-        let filename = match &self.filename {
-            Some(name) => name, // this is now a &String
-            None => return,
+        let Some(filename) = &self.filename else {
+            return;
         };
 
-        // Extract the file extension
-        let ext = filename.rfind('.').map(|pos| &filename[pos..]);
-
-        //iterate over the syntax database
-        for syntax in HLDB.iter() {
-            for &pattern in syntax.filematch.iter() {
-                let is_ext = pattern.starts_with('.');
-                if (is_ext && ext.is_some() && ext.unwrap() == pattern)
-                    || (!is_ext && filename.contains(pattern))
-                {
-                    self.syntax = Some(syntax);
-                    //Re-highlight the entire file
-                    for i in 0..self.erow.len() {
-                        self.editor_update_syntax(i);
-                    }
-                    return;
-                }
-            }
-        }
-    }
-
-    fn update_all_syntax(&mut self) {
-        for i in 0..self.erow.len() {
-            self.editor_update_syntax(i);
-        }
+        self.syntax = syntax::find_syntax(&self.syntaxes, filename);
     }
 
     fn editor_insert_new_line(&mut self) {
-        if self.cx == 0 {
+        self.record_insert(InsertAction::NewLine);
+        let before = (self.cx, self.cy);
+
+        let op = if self.cx == 0 {
             // Case: Cursor at beginning of line → insert empty line before
-            self.editor_insert_row(self.cy, "");
+            self.erow.insert_row(self.cy, EditorRow::new(""));
+            self.editor_update_syntax(self.cy);
+            EditOp::InsertRow { y: self.cy, text: String::new() }
         } else {
-            // Case: Split line at self.cx
-            let current_row = &mut self.erow[self.cy];
-            let right = current_row.chars[self.cx..].to_string(); // right half
-
-            // Truncate current row to the left half
-            current_row.chars.truncate(self.cx);
-            current_row.size = self.cx;
-            current_row.update_row();
-
-            // Insert new row after current with right half
-            self.editor_insert_row(self.cy + 1, &right);
-        }
+            // Case: Split line at self.cx, right half goes to a new row
+            self.erow.split_line(self.cy, self.cx);
+            self.editor_update_syntax(self.cy);
+            self.editor_update_syntax(self.cy + 1);
+            EditOp::SplitLine { y: self.cy, x: self.cx }
+        };
 
+        self.dirty += 1;
         self.cy += 1;
         self.cx = 0;
+        self.record_edit(op, before);
     }
 
     fn editor_insert_char(&mut self, c: char) {
-        if self.cy == self.number_of_rows {
-            self.editor_insert_row(self.number_of_rows, "");
+        self.record_insert(InsertAction::Char(c));
+        if self.cy == self.erow.len() {
+            self.editor_insert_row(self.erow.len(), "");
         }
 
-        self.erow[self.cy].insert_char(self.cx, c);
+        let before = (self.cx, self.cy);
+        self.erow.insert_char(self.cy, self.cx, c);
 
         // Update syntax highlighting for the modified row
         self.editor_update_syntax(self.cy);
 
-        self.cx += 1;
+        self.cx += c.len_utf8();
         self.dirty += 1;
+        self.record_edit(EditOp::InsertChar { y: self.cy, x: before.0, c }, before);
     }
 
     fn editor_del_char(&mut self) {
-        if self.cy >= self.number_of_rows {
+        self.record_insert(InsertAction::Backspace);
+        if self.cy >= self.erow.len() {
             return;
         }
 
@@ -704,63 +736,82 @@ impl EditorConfig {
             return;
         }
 
+        let before = (self.cx, self.cy);
+
         if self.cx > 0 {
-            self.cx -= 1;
-            self.erow[self.cy].delete_char(self.cx);
-            self.editor_update_syntax(self.cy);
-            self.dirty += 1;
+            self.cx = grapheme::prev_boundary(&self.erow[self.cy].chars, self.cx);
+            self.editor_delete_cluster(before);
         } else {
-            let current_row = self.erow.remove(self.cy);
+            // Join the current line onto the end of the previous one
             self.cy -= 1;
-            let prev_row = &mut self.erow[self.cy];
-            let prev_row_len = prev_row.size;
+            if let Some(prev_row_len) = self.erow.join_lines(self.cy) {
+                self.cx = prev_row_len;
+                self.editor_update_syntax(self.cy);
+                self.dirty += 1;
+                self.record_edit(EditOp::JoinLines { y: self.cy, x: self.cx }, before);
+            }
+        }
+    }
 
-            prev_row.append_string(&current_row.chars);
-            self.editor_update_syntax(self.cy);
+    // Delete the character under the cursor, joining with the next line at EOL
+    fn editor_del_forward_char(&mut self) {
+        self.record_insert(InsertAction::DeleteForward);
+        if self.cy >= self.erow.len() {
+            return; // Nothing to delete
+        }
+
+        let before = (self.cx, self.cy);
 
-            self.cx = prev_row_len;
-            self.number_of_rows -= 1;
+        if self.cx < self.erow[self.cy].size {
+            self.editor_delete_cluster(before);
+        } else if let Some(x) = self.erow.join_lines(self.cy) {
+            self.editor_update_syntax(self.cy);
             self.dirty += 1;
+            self.record_edit(EditOp::JoinLines { y: self.cy, x }, before);
         }
     }
 
-    fn editor_row_to_string(&self) -> String {
-        let mut total_len = 0;
-
-        // compute the total length of all rows
-        for row in &self.erow {
-            total_len += row.chars.len() + 1;
-        }
+    // Delete the character at the cursor, with any accents and the like
+    // that go with it.
+    fn editor_delete_cluster(&mut self, before: (usize, usize)) {
+        let (x, y) = (self.cx, self.cy);
+        let end = grapheme::next_boundary(&self.erow[y].chars, x);
+        let text = self.erow[y].chars[x..end].to_string();
+        let mut chars = text.chars();
+        let op = match (chars.next(), chars.next()) {
+            (Some(c), None) => {
+                self.erow.delete_char(y, x);
+                EditOp::DeleteChar { y, x, c }
+            }
+            (Some(_), Some(_)) => {
+                self.erow.delete_str(y, x, end);
+                EditOp::DeleteText { y, x, text }
+            }
+            _ => return,
+        };
+        self.editor_update_syntax(y);
+        self.dirty += 1;
+        self.record_edit(op, before);
+    }
 
-        //pre-allocate string with the total length
-        let mut buffer = String::with_capacity(total_len);
-        //push each line and new line
-        for row in &self.erow {
-            buffer.push_str(&row.chars);
-            buffer.push('\n');
-        }
-        //return the string
-        buffer
+    fn editor_row_to_string(&self) -> String {
+        self.erow.to_text()
     }
 
     // Open the editor and initialize the first row
     fn editor_open(&mut self, filename: &str) -> io::Result<()> {
-        // Open the file and read its contents
+        // Read the whole file first so a failed open leaves the buffer alone
+        let contents = std::fs::read_to_string(filename)?;
         self.filename = Some(filename.to_string());
+        self.quickfix.hide();
+        self.erow = TextBuffer::from_text(&contents);
 
-        let file = File::open(filename)?;
-        let mut reader = BufReader::new(file);
-
-        //read the first line
-        let mut line = String::new();
-
-        while reader.read_line(&mut line)? > 0 {
-            // Trim trailing newline characters
-            let trimmed_line = line.trim_end_matches(|c| c == '\n' || c == '\r');
-            // Create a new row and add it to the editor
-            self.editor_insert_row(self.number_of_rows, trimmed_line);
-            line.clear(); // Clear the line for the next read
-        }
+        // Pick up the undo history from the last session, if still valid
+        self.history = self
+            .undo_dir
+            .as_ref()
+            .and_then(|dir| undo_file::load_history(dir, filename, &self.erow.to_text()))
+            .unwrap_or_default();
 
         self.dirty = 0; // Reset dirty flag
                         //set syntax highlighting based on filename
@@ -769,14 +820,26 @@ impl EditorConfig {
         Ok(())
     }
 
+    // Start an empty buffer for a file that doesn't exist yet
+    fn editor_open_empty(&mut self, filename: &str) {
+        self.filename = Some(filename.to_string());
+        self.quickfix.hide();
+        self.erow = TextBuffer::new();
+        self.history = EditHistory::default();
+        self.dirty = 0;
+        self.editor_select_syntax_highlight();
+    }
+
     fn editor_save(&mut self) {
     let filename = match &self.filename {
         Some(name) => name.clone(),
         None => {
             // Pass None for callback since we don't need incremental behavior for filename input
             if let Some(name) = self.editor_prompt(
-                "Save as: (ESC to cancel)",
-                None::<fn(&mut Self, &str, EditorKey)>,
+                "Save as: (ESC to cancel) ",
+                "",
+                Some(HistoryKind::File),
+                None::<fn(&mut Self, &mut String, EditorKey)>,
             ) {
                 self.filename = Some(name.clone());
                 //update syntax highlight for new filename
@@ -797,99 +860,25 @@ impl EditorConfig {
         Ok(()) => {
             // Reset dirty flag and show success message
             self.dirty = 0;
-            self.editor_set_status_msg(&format!("{} bytes written to disk", len));
+            self.history.mark_saved();
+            // Undo history is best effort; a failed write must not fail the save
+            if let Some(dir) = &self.undo_dir {
+                let _ = undo_file::save_history(dir, &filename, &buffer, &self.history);
+            }
+            self.editor_set_status_msg(format!("{} bytes written to disk", len));
+            self.lsp_did_save();
         }
         Err(e) => {
-            self.editor_set_status_msg(&format!("Can't save! I/O error: {}", e));
+            self.editor_set_status_msg(format!("Can't save! I/O error: {}", e));
         }
     }
 }
 
-    pub fn editor_find(&mut self) {
-        let saved_cy = self.cy;
-        let saved_cx = self.cx;
-        let saved_row_off = self.row_off;
-        let saved_col_off = self.col_off;
-
-        // Search state (shared across callback invocations)
-        let mut last_match: Option<usize> = None;
-        let mut direction: i32 = 1;
-
-        let search_callback = move |editor: &mut Self, query: &str, key: EditorKey| {
-            // Restore highlights when search is cancelled or completed
-            match key {
-                EditorKey::EnterKey | EditorKey::Escape => {
-                    editor.restore_highlight(); // Restore highlights when done
-                    last_match = None;
-                    direction = 1;
-                    return;
-                }
-                EditorKey::ArrowRight | EditorKey::ArrowDown => direction = 1,
-                EditorKey::ArrowLeft | EditorKey::ArrowUp => direction = -1,
-                _ => {
-                    last_match = None;
-                    direction = 1;
-                }
-            }
-
-            if query.is_empty() || editor.erow.is_empty() {
-                return;
-            }
-
-            if last_match.is_none() {
-                direction = 1;
-            }
-
-            let row_count = editor.erow.len();
-            let mut current = last_match.unwrap_or(0);
-
-            // Wraparound search loop
-            for _ in 0..row_count {
-                current = if direction == 1 {
-                    (current + 1) % row_count
-                } else {
-                    if current == 0 {
-                        row_count - 1
-                    } else {
-                        current - 1
-                    }
-                };
-
-                let row = &editor.erow[current];
-                if let Some(match_index) = row.render.find(query) {
-                    last_match = Some(current);
-                    editor.cy = current;
-                    editor.cx = editor.editor_row_rx_to_cx(row, match_index);
-                    editor.row_off = editor.number_of_rows;
-
-                    // Save current highlights before applying match highlighting
-                    editor.save_highlight(current);
-
-                    // Apply match highlighting
-                    editor.erow[current].highlight_match(match_index, query);
-                    break;
-                }
-            }
-        };
-
-        // ✅ Prompt message gives the user clear search instructions
-        if self
-            .editor_prompt("Search: (Use ESC/Arrows/Enter)", Some(search_callback))
-            .is_none()
-        {
-            // Restore original cursor position if search was cancelled
-            self.cy = saved_cy;
-            self.cx = saved_cx;
-            self.row_off = saved_row_off;
-            self.col_off = saved_col_off;
-        }
-    }
-
     // Enable raw mode for terminal input
     fn enable_raw_mode(&mut self, fd: i32) -> io::Result<()> {
         // Store original termios first
         let original_termios = Termios::from_fd(fd)?;
-        self.original_termios = Some(original_termios.clone());
+        self.original_termios = Some(original_termios);
 
         // Modify a copy for raw mode
         let mut raw = original_termios;
@@ -912,10 +901,31 @@ impl EditorConfig {
         Ok(())
     }
 
+    // Columns taken by line numbers on the left, 0 without 'number'.
+    fn gutter_width(&self) -> usize {
+        if self.options.number {
+            self.erow.len().to_string().len().max(3) + 1
+        } else {
+            0
+        }
+    }
+
+    // Columns left for the text.
+    fn text_cols(&self) -> usize {
+        self.screen_cols.saturating_sub(self.gutter_width()).max(1)
+    }
+
     fn editor_scroll(&mut self) {
-        if self.cy < self.number_of_rows {
+        // Columns the character under the cursor takes, so that all of a
+        // wide one is scrolled into view
+        let mut width = 1;
+        if self.cy < self.erow.len() {
             let row = &self.erow[self.cy];
             self.rx = self.editor_row_cx_to_rx(row, self.cx);
+            let next = grapheme::next_boundary(&row.chars, self.cx);
+            if let Some(g) = row.chars.get(self.cx..next).filter(|g| *g != "\t" && !g.is_empty()) {
+                width = grapheme::width(g);
+            }
         }
 
         if self.cy < self.row_off {
@@ -930,212 +940,231 @@ impl EditorConfig {
             self.col_off = self.rx;
         }
 
-        if self.rx >= self.col_off + self.screen_cols {
-            self.col_off = self.rx - self.screen_cols + 1;
+        let text_cols = self.text_cols();
+        if self.rx + width > self.col_off + text_cols {
+            self.col_off = (self.rx + width).saturating_sub(text_cols).min(self.rx);
         }
     }
 
-    // Draw the tildes for empty lines
-    fn draw_rows(&self, ab: &mut AppendBuffer) -> io::Result<()> {
+    // Draw the rows of text, and tildes below the end of the file
+    fn draw_rows(&self, grid: &mut Grid) {
+    let theme = &self.theme;
+    let normal = theme.highlight(EditorHighlight::Normal as u8);
+    let gutter = self.gutter_width();
+    let text_cols = self.text_cols();
     for y in 0..self.screen_rows {
         let file_row = y + self.row_off;
-        if file_row >= self.number_of_rows {
+        grid.clear(y, 0, normal);
+        if file_row >= self.erow.len() {
+            let tilde = theme.ui(Ui::Tilde).over(normal);
             // Welcome message logic (unchanged)
-            if self.number_of_rows == 0 && y == self.screen_rows / 3 {
+            if self.erow.is_empty() && y == self.screen_rows / 3 {
                 let welcome = format!("Kibi Editor -- version {}", VERSION);
-                let mut welcomelen = welcome.len();
-
-                if welcomelen > self.screen_cols {
-                    welcomelen = self.screen_cols;
-                }
-
+                let welcomelen = welcome.len().min(self.screen_cols);
                 let padding = (self.screen_cols - welcomelen) / 2;
                 if padding > 0 {
-                    ab.append(b"~");
-                    for _ in 1..padding {
-                        ab.append(b" ");
-                    }
+                    grid.put(y, 0, "~", tilde);
                 }
-                ab.append(&welcome.as_bytes()[..welcomelen]);
+                grid.print(y, padding, &welcome, normal);
             } else {
-                ab.append(b"~");
+                grid.put(y, 0, "~", tilde);
             }
         } else {
+            if gutter > 0 {
+                let number = format!("{:>w$} ", file_row + 1, w = gutter - 1);
+                grid.print(y, 0, &number, theme.ui(Ui::LineNumber).over(normal));
+            }
+
             // Draw the row with proper highlighting
             let row = &self.erow[file_row];
             
-            // Handle horizontal scrolling
-            let start = self.col_off.min(row.render.len());
-            let mut len = row.render.len().saturating_sub(self.col_off);
-            if len > self.screen_cols {
-                len = self.screen_cols;
-            }
-            
-            let end = start + len;
-            let visible = &row.render[start..end];
-            
-            if let Some(ref hl) = row.hl {
-                let mut current_color: Option<u8> = None;
-                
-                for (j, ch) in visible.chars().enumerate() {
-                    let hl_index = start + j;
-                    let highlight_type = hl.get(hl_index)
-                        .copied()
-                        .unwrap_or(EditorHighlight::Normal as u8);
-                    
-                    if ch.is_ascii_control() {
-                        let sym = if (ch as u8) <= 26 {
-                            (b'@' + ch as u8) as char
-                        } else {
-                            '?'
-                        };
-                        
-                        ab.append(b"\x1b[7m"); // Inverted colors
-                        ab.append_char(sym);
-                        ab.append(b"\x1b[m"); // Reset
-                        
-                        // Restore color if we had one
-                        if let Some(color) = current_color {
-                            let color_sequence = format!("\x1b[{}m", color);
-                            ab.append(color_sequence.as_bytes());
-                        }
-                    } else if highlight_type == EditorHighlight::Normal as u8 {
-                        if current_color.is_some() {
-                            ab.append(b"\x1b[39m"); // Reset to default color
-                            current_color = None;
-                        }
-                        ab.append_char(ch);
+            // Handle horizontal scrolling. Overlays are in screen columns,
+            // the highlighting in bytes of the rendered row.
+            let selection = self.visual_cols(file_row);
+            let matches = self.match_columns(file_row);
+            let diagnostics = self.diagnostic_columns(file_row);
+            let hl = row.hl.as_deref().unwrap_or(&[]);
+            let end = self.col_off + text_cols;
+            let mut col = 0;
+
+            for (i, g) in row.render.grapheme_indices(true) {
+                let width = grapheme::width(g);
+                let at = col;
+                col += width;
+                if col <= self.col_off {
+                    continue;
+                }
+                if at >= end {
+                    break;
+                }
+
+                let highlight_type = hl.get(i)
+                    .copied()
+                    .unwrap_or(EditorHighlight::Normal as u8);
+                let mut style = theme.highlight(highlight_type).over(normal);
+
+                // Language server diagnostics go over the syntax colours,
+                // then the Visual mode selection, then search matches
+                if diagnostics.iter().any(|&(a, b)| a <= at && at < b) {
+                    style = theme.ui(Ui::Diagnostic).over(style);
+                }
+                if selection.is_some_and(|(a, b)| a <= at && at < b) {
+                    style = theme.ui(Ui::Selection).over(style);
+                }
+                if let Some(&(_, _, is_current)) =
+                    matches.iter().find(|&&(a, b, _)| a <= at && at < b)
+                {
+                    let kind = if is_current {
+                        EditorHighlight::CurrentMatch
                     } else {
-                        let color = self.highlight_to_color(highlight_type);
-                        if current_color != Some(color) {
-                            let ansi_code = format!("\x1b[{}m", color);
-                            ab.append(ansi_code.as_bytes());
-                            current_color = Some(color);
-                        }
-                        ab.append_char(ch);
-                    }
+                        EditorHighlight::Match
+                    };
+                    style = theme.highlight(kind as u8).over(style);
                 }
-                
-                // Reset color at end of line
-                if current_color.is_some() {
-                    ab.append(b"\x1b[39m");
+
+                // Control characters are shown inverted, as ^A and so on,
+                // and a wide character cut by either edge as blanks
+                let mut buf = [0; 4];
+                let ch = g.chars().next().unwrap_or(' ');
+                let shown = if ch.is_ascii_control() {
+                    style.reverse = !style.reverse;
+                    let ch = if (ch as u8) <= 26 { (b'@' + ch as u8) as char } else { '?' };
+                    &*ch.encode_utf8(&mut buf)
+                } else if at < self.col_off || col > end {
+                    " "
+                } else {
+                    g
+                };
+                for x in at.max(self.col_off)..col.min(end) {
+                    if x == at || shown == " " {
+                        grid.put(y, gutter + x - self.col_off, shown, style);
+                    }
                 }
-            } else {
-                // No highlighting available, just append the visible text
-                ab.append(visible.as_bytes());
             }
-        }
 
-        // Clear the rest of the line and add newline
-        ab.append(b"\x1b[K");
-        if y < self.screen_rows - 1 {
-            ab.append(b"\r\n");
+            // Show a selected line break as one selected cell
+            if let Some((_, b)) = selection {
+                if b > col && col >= self.col_off && col < end {
+                    grid.put(y, gutter + col - self.col_off, " ", theme.ui(Ui::Selection).over(normal));
+                }
+            }
         }
     }
-
-    Ok(())
 }
 
-    fn editor_draw_status_bar(&self, ab: &mut AppendBuffer) {
-        // display inverted colors
-        ab.append(b"\x1b[7m");
+    fn editor_draw_status_bar(&self, grid: &mut Grid) {
+        let row = self.screen_rows;
+        let style = self.theme.ui(Ui::StatusBar);
+        grid.clear(row, 0, style);
 
         let filename_display = self
-            .filename
-            .as_ref()
-            .map(|f| f.as_str())
+            .quickfix
+            .title()
+            .or(self.filename.as_deref())
             .unwrap_or("No File");
 
         let modified = if self.dirty > 0 { "(Modified)" } else { "" };
 
-        let filetype_display = match self.syntax {
-            Some(syntax) => syntax.filetype,
+        let filetype_display = match &self.syntax {
+            Some(syntax) => syntax.filetype.as_str(),
             None => "no ft",
         };
 
-        let r_status = format!(
-            "{} | {}/{}",
+        let diagnostics = match self.diagnostic_summary() {
+            Some(summary) => format!("{} | ", summary),
+            None => String::new(),
+        };
+        let rstatus = format!(
+            "{}{} | {}/{}",
+            diagnostics,
             filetype_display,
             self.cy + 1,
-            self.number_of_rows
+            self.erow.len()
         );
 
-        //format the status string filename
-        let mut status = format!(
-            "{:.20} - {} lines {}",
-            filename_display, self.number_of_rows, modified
-        );
-
-        //trim the string if it exceeds the screen widths
-        if status.len() > self.screen_cols {
-            status.truncate(self.screen_cols);
-        }
+        let recording = match self.macros.recording() {
+            Some(register) => format!(" recording @{}", register),
+            None => String::new(),
+        };
 
-        //right align the status string
-        let rstatus = format!("{}/{}", self.cy + 1, self.number_of_rows);
-        let rlen = rstatus.len();
+        //format the status string: mode, filename
+        let status = format!(
+            " {}{} | {:.20} - {} lines {}",
+            self.mode.label(),
+            recording,
+            filename_display,
+            self.erow.len(),
+            modified
+        );
 
-        let mut len = status.len();
-        while len < self.screen_cols {
-            if self.screen_cols - len == rlen {
-                ab.append(rstatus.as_bytes());
-                break;
-            } else {
-                ab.append(b" ");
-                len += 1;
-            }
+        // The status string is cut at the edge of the screen, and the right
+        // part is only shown if it fits after it
+        let len = grid.print(row, 0, &status, style);
+        let rlen = grapheme::str_width(&rstatus);
+        if len + rlen <= self.screen_cols {
+            grid.print(row, self.screen_cols - rlen, &rstatus, style);
         }
-
-        // append the status string to the buffer
-        ab.append(status.as_bytes());
-
-        // reset text format
-        ab.append(b"\x1b[m"); // reset text format
-        ab.append(b"\r\n"); // Move to the next line
     }
 
-    fn editor_draw_message_bar(&self, ab: &mut AppendBuffer) {
-        // Clear the current line
-        ab.append(b"\x1b[K");
+    fn editor_draw_message_bar(&self, grid: &mut Grid) {
+        // Clear the current line in the colours of the bar
+        let row = self.screen_rows + 1;
+        let style = self.theme.ui(Ui::MessageBar);
+        grid.clear(row, 0, style);
 
         let elapsed = self.status_msg_time.elapsed().unwrap_or_default();
         if !self.status_msg.is_empty() && elapsed < Duration::from_secs(5) {
-            // Truncate message if it's wider than the screen
-            let msg = if self.status_msg.len() > self.screen_cols {
-                &self.status_msg[..self.screen_cols]
-            } else {
-                &self.status_msg
-            };
-            ab.append(msg.as_bytes());
+            grid.print(row, 0, &self.status_msg, style);
+        } else if let Some(msg) = self.diagnostic_message() {
+            grid.print(row, 0, &msg, style);
         }
     }
 
     // Refresh the screen
     fn refresh_screen(&mut self) -> io::Result<()> {
-        self.editor_scroll();
-        let mut ab = AppendBuffer::new();
-
-        // Clear screen and position cursor
-        ab.append(b"\x1b[?25l\x1b[H");
-
-        // Draw the tildes
-        self.draw_rows(&mut ab)?;
-        self.editor_draw_status_bar(&mut ab);
-        self.editor_draw_message_bar(&mut ab);
-        //allow user position cursor
-        let cursor_position = format!(
-            "\x1b[{};{}H",
-            self.cy - self.row_off + 1,
-            self.rx - self.col_off + 1
-        );
-        ab.append(cursor_position.as_bytes());
-
-        // show the cursor again
-        ab.append(b"\x1b[?25h");
+        // Don't draw halfway through a macro, the screen catches up after
+        if !self.key_queue.is_empty() {
+            return Ok(());
+        }
+        // Nor before the terminal is in raw mode, which is never in tests
+        if self.original_termios.is_none() {
+            return Ok(());
+        }
+        // The screen is drawn at least every tenth of a second, when reading
+        // a key times out, so this is how a resized terminal is noticed
+        if let Ok((cols, rows)) = Self::get_window_size() {
+            self.editor_resize(cols, rows);
+        }
         // Output everything in one go
-        ab.write_all()?;
+        self.editor_draw_frame().write_all()
+    }
 
-        Ok(())
+    // Draw the screen and return what has to be sent to the terminal to
+    // show it: only what changed since the last frame.
+    fn editor_draw_frame(&mut self) -> AppendBuffer {
+        self.editor_scroll();
+        self.editor_update_highlight(self.row_off + self.screen_rows);
+
+        // Status and message bars go below the text, if the terminal has
+        // room for them
+        let height = (self.screen_rows + 2).min(self.term_size.1);
+        let mut grid = Grid::new(self.screen_cols, height);
+        self.draw_rows(&mut grid);
+        self.editor_draw_status_bar(&mut grid);
+        self.editor_draw_message_bar(&mut grid);
+        self.draw_completion(&mut grid);
+
+        let cursor = match self.prompt_cursor {
+            // The message bar is below the status bar
+            Some(column) => (self.screen_rows + 1, column),
+            None => (self.cy - self.row_off, self.rx - self.col_off + self.gutter_width()),
+        };
+        // Bar cursor while inserting or typing at a prompt, block otherwise
+        let bar_cursor = self.mode == EditorMode::Insert || self.prompt_cursor.is_some();
+
+        let mut ab = AppendBuffer::new();
+        self.screen.update(grid, cursor, bar_cursor, &mut ab);
+        ab
     }
 
     fn editor_set_status_msg(&mut self, msg: impl std::fmt::Display) {
@@ -1144,7 +1173,7 @@ impl EditorConfig {
     }
 
     // Read a key from stdin
-    fn read_key(&self) -> io::Result<EditorKey> {
+    fn read_terminal_key(&self) -> io::Result<EditorKey> {
     let stdin = io::stdin();
     let mut handle = stdin.lock();
 
@@ -1170,7 +1199,6 @@ impl EditorConfig {
         return Ok(EditorKey::CtrlQ);
     }
     if c[0] == ctrl_key(b's') {
-        eprintln!("DEBUG: Ctrl+S detected"); // Add this line
         return Ok(EditorKey::CtrlS);
     }
     if c[0] == ctrl_key(b'f') {
@@ -1179,9 +1207,21 @@ impl EditorConfig {
     if c[0] == ctrl_key(b'h') {
         return Ok(EditorKey::CtrlH);
     }
+    if c[0] == ctrl_key(b'\\') {
+        return Ok(EditorKey::CtrlBackslash);
+    }
     if c[0] == ctrl_key(b'l') {
         return Ok(EditorKey::CtrlL);
     }
+    if c[0] == ctrl_key(b'r') {
+        return Ok(EditorKey::CtrlR);
+    }
+    if c[0] == ctrl_key(b'y') {
+        return Ok(EditorKey::CtrlY);
+    }
+    if c[0] == ctrl_key(b'z') {
+        return Ok(EditorKey::CtrlZ);
+    }
 
     // Handle escape sequences
     if c[0] == b'\x1b' {
@@ -1223,6 +1263,7 @@ impl EditorConfig {
                     b'D' => Ok(EditorKey::ArrowLeft),
                     b'H' => Ok(EditorKey::HomeKey),
                     b'F' => Ok(EditorKey::EndKey),
+                    b'Z' => Ok(EditorKey::ShiftTab),
                     _ => Ok(EditorKey::Escape),
                 };
             }
@@ -1237,74 +1278,30 @@ impl EditorConfig {
         return Ok(EditorKey::Escape);
     }
 
-    // Only treat WASD as movement if they're not part of normal text input
-    // You might want to add a mode for this or remove WASD movement entirely
-    // For now, I'm commenting this out to prevent conflicts:
-    /*
-    match c[0] {
-        b'w' => Ok(EditorKey::ArrowUp),
-        b's' => Ok(EditorKey::ArrowDown),
-        b'a' => Ok(EditorKey::ArrowLeft),
-        b'd' => Ok(EditorKey::ArrowRight),
-        _ => Ok(EditorKey::Other(c[0])),
-    }
-    */
-    
-    // Just return the character as-is
-    Ok(EditorKey::Other(c[0]))
-}
-
-    fn editor_prompt<F>(&mut self, prompt: &str, mut callback: Option<F>) -> Option<String>
-    where
-        F: FnMut(&mut Self, &str, EditorKey),
-    {
-        let mut buf = String::new();
-
-        loop {
-            self.editor_set_status_msg(&format!("{}{}", prompt, buf));
-            if let Err(_) = self.refresh_screen() {
-                return None;
-            }
-
-            let c = match self.read_key() {
-                Ok(key) => key,
-                Err(_) => return None,
-            };
-
-            match c {
-                EditorKey::EnterKey => {
-                    if !buf.is_empty() {
-                        self.editor_set_status_msg("");
-                        return Some(buf);
-                    }
-                }
-                EditorKey::Backspace | EditorKey::CtrlH | EditorKey::Delete => {
-                    if !buf.is_empty() {
-                        buf.pop();
-                    }
-                }
-                EditorKey::Escape => {
-                    self.editor_set_status_msg("");
-                    return None;
-                }
-                EditorKey::Other(ch) => {
-                    if ch.is_ascii_graphic() || ch == b' ' {
-                        buf.push(ch as char);
-                    }
-                }
-                _ => {}
-            }
-
-            // Call callback after each keypress
-            if let Some(ref mut cb) = callback {
-                cb(self, &buf, c);
+    // The rest of a multibyte character. Bytes that don't make one are
+    // passed on as they are.
+    let len = match c[0] {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1,
+    };
+    if len > 1 {
+        let mut bytes = [c[0], 0, 0, 0];
+        if handle.read_exact(&mut bytes[1..len]).is_ok() {
+            if let Some(ch) = std::str::from_utf8(&bytes[..len]).ok().and_then(|s| s.chars().next()) {
+                return Ok(EditorKey::Char(ch));
             }
         }
     }
 
+    // Just return the character as-is; Normal mode gives letters their meaning
+    Ok(EditorKey::Other(c[0]))
+}
+
     // move the cursor depending on the key pressed
     pub fn editor_move_cursor(&mut self, key: EditorKey) {
-    let current_row = if self.cy < self.number_of_rows {
+    let current_row = if self.cy < self.erow.len() {
         Some(&self.erow[self.cy])
     } else {
         None
@@ -1313,7 +1310,7 @@ impl EditorConfig {
     match key {
         EditorKey::ArrowLeft => {
             if self.cx > 0 {
-                self.cx -= 1;
+                self.cx = grapheme::prev_boundary(&self.erow[self.cy].chars, self.cx);
             } else if self.cy > 0 {
                 // Move to the end of the previous line
                 self.cy -= 1;
@@ -1324,32 +1321,35 @@ impl EditorConfig {
         EditorKey::ArrowRight => {
             if let Some(row) = current_row {
                 if self.cx < row.size {
-                    self.cx += 1;
+                    self.cx = grapheme::next_boundary(&row.chars, self.cx);
                 }
                 // let user explicitly press Enter
                 // or use End key to go to end of line
-            } else if self.cy < self.number_of_rows {
+            } else if self.cy < self.erow.len() {
                 // If we're past the last row, don't move
                 return;
             }
         }
 
-        EditorKey::ArrowUp => {
-            if self.cy > 0 {
-                self.cy -= 1;
-            }
+        // Up and down keep to the same screen column
+        EditorKey::ArrowUp if self.cy > 0 => {
+            let rx = current_row.map_or(0, |row| self.editor_row_cx_to_rx(row, self.cx));
+            self.cy -= 1;
+            self.cx = self.editor_row_rx_to_cx(&self.erow[self.cy], rx);
         }
 
-        EditorKey::ArrowDown => {
-            if self.cy < self.number_of_rows {
-                self.cy += 1;
+        EditorKey::ArrowDown if self.cy < self.erow.len() => {
+            let rx = current_row.map_or(0, |row| self.editor_row_cx_to_rx(row, self.cx));
+            self.cy += 1;
+            if self.cy < self.erow.len() {
+                self.cx = self.editor_row_rx_to_cx(&self.erow[self.cy], rx);
             }
         }
         _ => {}
     }
 
     // Snap cursor to end of line if it's beyond the line length
-    let current_row = if self.cy < self.number_of_rows {
+    let current_row = if self.cy < self.erow.len() {
         Some(&self.erow[self.cy])
     } else {
         None
@@ -1365,35 +1365,9 @@ impl EditorConfig {
 }
 
 
-    // Fixed process_keypress function with no unreachable patterns
-    fn process_keypress(&mut self) -> io::Result<bool> {
-        let c = self.read_key()?;
-
-        match c {
-            EditorKey::EnterKey => {
-                self.editor_insert_new_line();
-            }
-            EditorKey::CtrlQ => {
-                if self.dirty > 0 && self.quit_times > 0 {
-                    self.editor_set_status_msg(&format!(
-                        "WARNING!!! File has unsaved changes. Press Ctrl-Q {} more times to quit.",
-                        self.quit_times
-                    ));
-                    self.quit_times -= 1;
-                    return Ok(true);
-                }
-
-                self.refresh_screen()?; // or refresh_screen
-                return Ok(false); // exit
-            }
-
-            EditorKey::CtrlS => {
-                eprintln!("DEBUG: Ctrl+S pressed"); // Add this line
-                self.editor_save();
-            }
-
-            EditorKey::CtrlF => self.editor_find(),
-
+    // Arrow keys, Home/End and Page Up/Down behave the same in every mode
+    fn editor_move_key(&mut self, key: EditorKey) {
+        match key {
             EditorKey::PageUp => {
                 // move the cursor up by the number of screen rows
                 self.cy = self.row_off;
@@ -1401,8 +1375,8 @@ impl EditorConfig {
             EditorKey::PageDown => {
                 // Move the cursor down by the number of screen rows
                 self.cy = self.row_off + self.screen_rows - 1;
-                if self.cy > self.number_of_rows {
-                    self.cy = self.number_of_rows;
+                if self.cy > self.erow.len() {
+                    self.cy = self.erow.len();
                 }
             }
 
@@ -1410,38 +1384,9 @@ impl EditorConfig {
                 //move cursor to the beginning of the line
                 self.cx = 0
             }
-            EditorKey::EndKey => {
+            EditorKey::EndKey if self.cy < self.erow.len() => {
                 // move cursor to the end of the line
-                if self.cy < self.number_of_rows {
-                    self.cx = self.erow[self.cy].size;
-                }
-            }
-
-            EditorKey::Delete => {
-                if self.cy >= self.number_of_rows {
-                    return Ok(true); // Nothing to delete
-                }
-
-                // Check if we're deleting a character within the current line
-                if self.cx < self.erow[self.cy].chars.len() {
-                    // Delete character at current cursor position
-                    self.erow[self.cy].delete_char(self.cx);
-                    self.dirty += 1;
-                } else if self.cx == self.erow[self.cy].chars.len()
-                    && self.cy < self.number_of_rows - 1
-                {
-                    // At end of line, join with next line
-                    let next_row = self.erow.remove(self.cy + 1);
-                    let current_row = &mut self.erow[self.cy];
-                    current_row.append_string(&next_row.chars);
-                    self.number_of_rows -= 1;
-                    self.dirty += 1;
-                }
-            }
-
-            EditorKey::Backspace | EditorKey::CtrlH => {
-                // Backspace: delete character before cursor
-                self.editor_del_char();
+                self.cx = self.erow[self.cy].size;
             }
 
             EditorKey::ArrowUp
@@ -1449,26 +1394,72 @@ impl EditorConfig {
             | EditorKey::ArrowRight
             | EditorKey::ArrowLeft => {
                 //move the cursor based on the key pressed
-                self.editor_move_cursor(c);
+                self.editor_move_cursor(key);
             }
 
-            // display printable characters
-            EditorKey::Other(byte) => {
-                if byte.is_ascii_graphic() || byte == b' ' {
-                    self.editor_insert_char(byte as char);
+            _ => {}
+        }
+    }
+
+    fn process_keypress(&mut self) -> io::Result<bool> {
+        let c = self.read_key()?;
+        self.editor_handle_key(c)
+    }
+
+    // Act on one decoded key. Returns Ok(false) when the editor should exit.
+    fn editor_handle_key(&mut self, c: EditorKey) -> io::Result<bool> {
+        // read_key times out with Other(0) when no key was pressed, which
+        // is when the language server gets our edits and we get its news
+        if c == EditorKey::Other(0) {
+            self.lsp_poll();
+            return Ok(true);
+        }
+        if self.completion_key(c) {
+            return Ok(true);
+        }
+
+        match c {
+            EditorKey::CtrlQ => {
+                if self.dirty > 0 && self.quit_times > 0 {
+                    self.editor_set_status_msg(format!(
+                        "WARNING!!! File has unsaved changes. Press Ctrl-Q {} more times to quit.",
+                        self.quit_times
+                    ));
+                    self.quit_times -= 1;
+                    return Ok(true);
                 }
-            }
 
-            EditorKey::CtrlL | EditorKey::Escape => {
-                // Do nothing.
+                self.refresh_screen()?; // or refresh_screen
+                return Ok(false); // exit
             }
+
+            EditorKey::CtrlS => self.editor_save(),
+
+            EditorKey::CtrlF => self.editor_find(false),
+            EditorKey::CtrlBackslash => self.editor_replace(),
+
+            EditorKey::CtrlZ => self.editor_undo(),
+            EditorKey::CtrlY => self.editor_redo(),
+
+            // Draw the whole screen again, in case something else wrote on it
+            EditorKey::CtrlL => self.screen.invalidate(),
+
+            _ => match self.mode {
+                EditorMode::Insert => self.process_insert_key(c),
+                _ => self.process_normal_key(c),
+            },
         }
 
         if c != EditorKey::CtrlQ {
             self.quit_times = QUIT_TIMES;
         }
 
-        Ok(true) 
+        if self.quit_requested {
+            self.refresh_screen()?;
+            return Ok(false);
+        }
+
+        Ok(true)
     }
     // Mapping raw key (from input) to enum
     /*fn parse_key(byte: u8) -> EditorKey {
@@ -1479,6 +1470,26 @@ impl EditorConfig {
     } */
 }
 
+// Editors to test against and a way to look at what they hold.
+#[cfg(test)]
+impl EditorConfig {
+    // As if `lines` had just been read from a file: unmodified, with
+    // nothing to undo.
+    fn with_lines(lines: &[&str]) -> Self {
+        let mut editor = Self::with_size(80, 24);
+        for (y, line) in lines.iter().enumerate() {
+            editor.editor_insert_row(y, line);
+        }
+        editor.history = EditHistory::default();
+        editor.dirty = 0;
+        editor
+    }
+
+    fn lines(&self) -> Vec<String> {
+        (0..self.erow.len()).map(|y| self.erow[y].chars.clone()).collect()
+    }
+}
+
 fn main() -> io::Result<()> {
     // Create the editor instance
     let args: Vec<String> = std::env::args().collect();
@@ -1488,8 +1499,12 @@ fn main() -> io::Result<()> {
         Err(e) => die(&format!("Failed to initialize editor: {}", e)),
     };
 
-    // Set the status message
-    editor.editor_set_status_msg("HELP: Ctrl-S | Ctrl-Q = quit | Ctrl-F = find");
+    // Set the status message, unless there is already something to say
+    if editor.status_msg.is_empty() {
+        editor.editor_set_status_msg(
+            "HELP: i = insert | Esc = normal | Ctrl-S = save | Ctrl-Q = quit | Ctrl-F = find",
+        );
+    }
 
     // Open a file is provided as an argument
     if args.len() >= 2 {
@@ -1514,10 +1529,137 @@ fn main() -> io::Result<()> {
         }
     }
 
-    // Always restore terminal setting
+    // Always restore terminal setting and the default cursor shape
+    let _ = io::stdout().write_all(b"\x1b[0 q");
+    let _ = io::stdout().flush();
     if let Err(e) = editor.disable_raw_mode(stdin_fd) {
         eprintln!("Error disabling raw mode: {}", e);
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn in_comment(editor: &EditorConfig, y: usize) -> bool {
+        let hl = editor.erow[y].hl.as_ref().expect("row not highlighted");
+        !hl.is_empty() && hl.iter().all(|&class| class == EditorHighlight::HlMComment as u8)
+    }
+
+    #[test]
+    fn opening_a_block_comment_colours_the_rows_below_until_it_goes() {
+        let mut editor = EditorConfig::with_size(80, 24);
+        for (y, line) in ["let a = 1;", "let b = 2;", "let c = 3;"].iter().enumerate() {
+            editor.editor_insert_row(y, line);
+        }
+        editor.syntax = Some(Rc::new(syntax::rust_syntax()));
+        editor.editor_update_highlight(editor.erow.len());
+        assert!(!in_comment(&editor, 1) && !in_comment(&editor, 2));
+
+        (editor.cx, editor.cy) = (0, 0);
+        editor.editor_insert_char('/');
+        editor.editor_insert_char('*');
+        editor.editor_update_highlight(editor.erow.len());
+        assert!((0..3).all(|y| in_comment(&editor, y)));
+        assert_eq!(editor.erow[2].hl_state, HlState::Comment(1));
+
+        // Typing inside the comment leaves the rows below starting as they
+        // did, so they keep their highlighting
+        let untouched = vec![EditorHighlight::Match as u8];
+        editor.erow[2].hl = Some(untouched.clone());
+        editor.editor_insert_char(' ');
+        editor.editor_update_highlight(editor.erow.len());
+        assert!(in_comment(&editor, 0));
+        assert_eq!(editor.erow[2].hl, Some(untouched));
+        editor.editor_update_syntax(2);
+
+        for _ in 0..3 {
+            editor.editor_del_char();
+        }
+        assert_eq!(editor.erow[0].chars, "let a = 1;");
+        editor.editor_update_highlight(editor.erow.len());
+        assert!((0..3).all(|y| !in_comment(&editor, y)));
+        assert_eq!(editor.erow[2].hl_state, HlState::Normal);
+        let fresh = highlight::highlight_rust("let c = 3;", HlState::Normal, &syntax::rust_syntax());
+        assert_eq!(editor.erow[2].hl.as_ref(), Some(&fresh.0));
+    }
+
+    #[test]
+    fn a_terminal_of_one_row_and_five_columns_still_shows_the_cursor() {
+        let long = "x".repeat(100);
+        let mut lines = vec![long.as_str(); 50];
+        lines[30] = "字字字字字字字字字字字字字字字字";
+        let mut editor = EditorConfig::with_lines(&lines);
+        (editor.cx, editor.cy) = (90, 40);
+        editor.editor_draw_frame();
+        assert!(editor.row_off > 0 && editor.col_off > 0);
+
+        for number in [false, true] {
+            editor.options.number = number;
+            editor.editor_resize(5, 1);
+            editor.editor_draw_frame();
+            assert_eq!((editor.screen_cols, editor.screen_rows), (5, 1));
+            assert_eq!(editor.row_off, editor.cy);
+            assert!(editor.col_off <= editor.rx && editor.rx < editor.col_off + editor.text_cols());
+
+            // A wide character in a single column of text
+            (editor.cx, editor.cy) = (21, 30);
+            editor.editor_draw_frame();
+            assert_eq!((editor.row_off, editor.col_off), (30, 14));
+
+            (editor.cx, editor.cy) = (90, 40);
+            editor.editor_resize(80, 24);
+            editor.editor_draw_frame();
+        }
+    }
+
+    #[test]
+    fn the_cursor_moves_over_an_accent_as_one_character() {
+        // Precomposed, then as an e with a combining accent
+        for line in ["caf\u{e9}s", "cafe\u{301}s"] {
+            let mut editor = EditorConfig::with_lines(&[line]);
+            editor.type_keys("lll");
+            assert_eq!(editor.cx, 3);
+            editor.type_keys("l");
+            assert_eq!(editor.cx, line.len() - 1);
+            editor.type_keys("h");
+            assert_eq!(editor.cx, 3);
+            assert_eq!(editor.editor_row_cx_to_rx(&editor.erow[0], line.len() - 1), 4);
+
+            editor.type_keys("x");
+            assert_eq!(editor.lines(), ["cafs"]);
+        }
+    }
+
+    #[test]
+    fn wide_characters_take_two_columns_and_keep_the_column_across_lines() {
+        let mut editor = EditorConfig::with_lines(&["字字字", "abcdef"]);
+        let row = &editor.erow[0];
+        assert_eq!(editor.editor_row_cx_to_rx(row, 6), 4);
+        assert_eq!(editor.editor_row_rx_to_cx(row, 4), 6);
+        assert_eq!(editor.editor_row_rx_to_cx(row, 5), 6); // second half of 字
+        assert_eq!(editor.editor_row_rx_to_cx(row, 9), 9);
+
+        editor.type_keys("llj");
+        assert_eq!((editor.cx, editor.cy), (4, 1));
+        editor.type_keys("hk");
+        assert_eq!((editor.cx, editor.cy), (3, 0)); // column 3 is inside the second 字
+    }
+
+    #[test]
+    fn typing_and_deleting_in_the_middle_of_a_multibyte_line() {
+        let mut editor = EditorConfig::with_lines(&["字é字"]);
+        editor.type_keys("lia\u{301}b\x1b");
+        assert_eq!(editor.lines(), ["字a\u{301}bé字"]);
+        // Backspace takes the accent with the letter it sits on
+        editor.type_keys("a\x7f\x7f\x1b");
+        assert_eq!(editor.lines(), ["字é字"]);
+        assert_eq!(editor.erow[0].render, "字é字");
+
+        editor.type_keys("0lx");
+        assert_eq!(editor.lines(), ["字字"]);
+        assert_eq!((editor.cx, editor.cy), (3, 0));
+    }
+}
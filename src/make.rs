@@ -0,0 +1,161 @@
+// :make — run the build and collect its diagnostics.
+//
+// The command is 'makeprg' (by default `cargo check --message-format=short`)
+// with any :make arguments appended, run through `sh -c`. Every output line
+// of the form `file:line:col: level: message` becomes a quickfix entry, and
+// the cursor goes to the first one.
+
+use crate::quickfix::Location;
+use crate::EditorConfig;
+use std::process::{Command, Stdio};
+
+// The diagnostics in the build output, in the order they were printed.
+pub fn parse_diagnostics(output: &str) -> Vec<Location> {
+    let mut entries: Vec<Location> = Vec::new();
+    for line in output.lines() {
+        let Some(loc) = Location::parse(line) else {
+            continue;
+        };
+        // cargo prints the same warning once per target that has it
+        if !loc.text.is_empty() && !entries.contains(&loc) {
+            entries.push(loc);
+        }
+    }
+    entries
+}
+
+impl EditorConfig {
+    // :make [args]
+    pub(crate) fn ex_make(&mut self, args: &str) -> Result<(), String> {
+        let command = match args {
+            "" => self.options.make_program.clone(),
+            args => format!("{} {}", self.options.make_program, args),
+        };
+        self.editor_set_status_msg(format!("Running {}...", command));
+        self.refresh_screen().ok();
+
+        // Diagnostics go to stderr with cargo, stdout with most other tools
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("Can't run {}: {}", command, e))?;
+        let text = String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr);
+
+        let entries = parse_diagnostics(&text);
+        if entries.is_empty() {
+            if output.status.success() {
+                self.set_quickfix(&format!("[make {}]", command), entries);
+                self.editor_set_status_msg("No errors");
+                return Ok(());
+            }
+            // Show why it failed, if it said
+            let last = text.lines().rev().find(|line| !line.trim().is_empty()).unwrap_or("");
+            return Err(format!("{} failed ({}): {}", command, output.status, last.trim()));
+        }
+
+        self.set_quickfix(&format!("[make {}]", command), entries);
+        self.editor_quickfix_go(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // A directory for one test, with a file of three lines in it for each
+    // name in `files`.
+    fn scratch_dir(test: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kibi-make-{}-{}", std::process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        for name in files {
+            fs::write(dir.join(name), "first\nsecond line\nthird\n").unwrap();
+        }
+        dir
+    }
+
+    // An editor whose :make runs `program` instead of cargo.
+    fn editor_with_make(program: &str) -> EditorConfig {
+        let mut editor = EditorConfig::with_size(80, 24);
+        editor.options.make_program = program.to_string();
+        editor
+    }
+
+    #[test]
+    fn diagnostics_keep_their_order_without_repeats() {
+        let output = "\
+warning: unused manifest key
+src/a.rs:2:3: warning: unused variable `x`
+src/a.rs:2:3: warning: unused variable `x`
+src/b.rs:10: error: no column
+src/a.rs:1:1: error: mismatched types
+";
+        let entries = parse_diagnostics(output);
+        let found: Vec<(&str, usize, usize, &str)> =
+            entries.iter().map(|loc| (loc.file.as_str(), loc.line, loc.col, loc.text.as_str())).collect();
+        assert_eq!(
+            found,
+            [
+                ("src/a.rs", 2, 3, "warning: unused variable `x`"),
+                ("src/b.rs", 10, 1, "error: no column"),
+                ("src/a.rs", 1, 1, "error: mismatched types"),
+            ]
+        );
+    }
+
+    #[test]
+    fn make_goes_to_each_diagnostic_in_turn() {
+        let dir = scratch_dir("step", &["a.txt", "b.txt"]);
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        // The warning comes twice, as cargo prints it for each target
+        let warning = format!("{}:2:3: warning: unused", a.display());
+        let error = format!("{}:3:1: error: broken", b.display());
+        let mut editor = editor_with_make(&format!("printf '%s\\n' '{warning}' '{warning}' done '{error}'"));
+
+        editor.editor_ex_command(":make");
+        assert_eq!(editor.status_msg, "(1 of 2) warning: unused");
+        assert_eq!(editor.filename.as_deref(), a.to_str());
+        assert_eq!((editor.cx, editor.cy), (2, 1));
+
+        editor.editor_ex_command(":cnext");
+        assert_eq!(editor.status_msg, "(2 of 2) error: broken");
+        assert_eq!(editor.filename.as_deref(), b.to_str());
+        assert_eq!((editor.cx, editor.cy), (0, 2));
+
+        editor.editor_ex_command(":cnext");
+        assert_eq!(editor.status_msg, "No more items");
+        assert_eq!(editor.filename.as_deref(), b.to_str());
+
+        editor.editor_ex_command(":cprevious");
+        assert_eq!(editor.status_msg, "(1 of 2) warning: unused");
+        editor.editor_ex_command(":cprevious");
+        assert_eq!(editor.status_msg, "No more items");
+        assert_eq!(editor.filename.as_deref(), a.to_str());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_failed_build_without_diagnostics_says_why() {
+        let mut editor = editor_with_make("echo 'error: could not find `Cargo.toml`' >&2; exit 101");
+        editor.editor_ex_command(":make");
+        assert_eq!(
+            editor.status_msg,
+            "echo 'error: could not find `Cargo.toml`' >&2; exit 101 failed (exit status: 101): \
+             error: could not find `Cargo.toml`"
+        );
+        assert_eq!(editor.filename, None);
+    }
+
+    #[test]
+    fn a_clean_build_empties_the_list() {
+        let mut editor = editor_with_make("true");
+        editor.editor_ex_command(":make");
+        assert_eq!(editor.status_msg, "No errors");
+        editor.editor_ex_command(":cnext");
+        assert_eq!(editor.status_msg, "No errors");
+    }
+}
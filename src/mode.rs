@@ -0,0 +1,604 @@
+// Vim-style modes: Normal, Insert and Visual (charwise and linewise).
+//
+// Normal and Visual mode collect keys in `pending_keys` until they form a
+// complete command, so multi-key commands like `gg`, `3dw` and `ci(` work.
+// The command grammar itself lives in operator.rs.
+
+use crate::grapheme;
+use crate::motion::{self, Motion};
+use crate::operator::{CommandParse, NormalCommand, Operator, Region, Simple, TextObject};
+use crate::register::{Register, RegisterWrite, Registers};
+use crate::repeat::ChangeCommand;
+use crate::undo::UNDO_LEVELS;
+use crate::{EditorConfig, EditorKey};
+use unicode_segmentation::UnicodeSegmentation;
+
+// Most text a count may repeat in one go (p, 3ifoo<Esc>)
+pub const MAX_REPEAT_BYTES: usize = 64 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditorMode {
+    Normal,
+    Insert,
+    Visual,
+    VisualLine,
+}
+
+impl EditorMode {
+    pub fn label(self) -> &'static str {
+        match self {
+            EditorMode::Normal => "NORMAL",
+            EditorMode::Insert => "INSERT",
+            EditorMode::Visual => "VISUAL",
+            EditorMode::VisualLine => "V-LINE",
+        }
+    }
+
+    pub fn is_visual(self) -> bool {
+        matches!(self, EditorMode::Visual | EditorMode::VisualLine)
+    }
+}
+
+impl EditorConfig {
+    pub(crate) fn process_insert_key(&mut self, c: EditorKey) {
+        match c {
+            EditorKey::Escape => self.editor_leave_insert(),
+            EditorKey::EnterKey => self.editor_insert_new_line(),
+            EditorKey::Backspace | EditorKey::CtrlH => self.editor_del_char(),
+            EditorKey::Delete => self.editor_del_forward_char(),
+            EditorKey::Other(b'\t') if self.editor_complete(false) => {}
+            EditorKey::ShiftTab => {
+                self.editor_complete(true);
+            }
+            EditorKey::Other(byte) if byte.is_ascii_graphic() || byte == b' ' || byte == b'\t' => {
+                self.editor_insert_char(byte as char);
+            }
+            EditorKey::Char(ch) => self.editor_insert_char(ch),
+            EditorKey::Other(_) => {}
+            _ => {
+                // Moving around starts a new undo step, like leaving Insert mode
+                let cursor = (self.cx, self.cy);
+                self.history.end_group(cursor);
+                self.editor_move_key(c);
+                self.history.begin_group((self.cx, self.cy));
+                self.record_insert_moved();
+            }
+        }
+    }
+
+    pub(crate) fn process_normal_key(&mut self, c: EditorKey) {
+        let byte = match c {
+            EditorKey::Other(byte) => byte,
+            EditorKey::Escape => {
+                self.pending_keys.clear();
+                if self.mode.is_visual() {
+                    self.mode = EditorMode::Normal;
+                }
+                return;
+            }
+            EditorKey::CtrlR => {
+                self.pending_keys.clear();
+                self.editor_redo();
+                self.clamp_normal_cursor();
+                return;
+            }
+            EditorKey::Backspace | EditorKey::CtrlH => b'h',
+            EditorKey::EnterKey if self.pending_keys.is_empty() && self.quickfix.showing() => {
+                self.quickfix_select();
+                return;
+            }
+            EditorKey::EnterKey => b'j',
+            EditorKey::Delete => b'x',
+            _ => {
+                self.pending_keys.clear();
+                self.editor_move_key(c);
+                self.clamp_normal_cursor();
+                return;
+            }
+        };
+
+        self.pending_keys.push(byte);
+        let keys = self.pending_keys.clone();
+
+        // While recording, a lone q ends the recording
+        if keys == b"q" && self.macros.recording().is_some() {
+            self.pending_keys.clear();
+            self.editor_stop_recording();
+            return;
+        }
+
+        let done = if self.mode.is_visual() {
+            self.run_visual_command(&keys)
+        } else {
+            self.run_normal_command(&keys)
+        };
+
+        if done {
+            self.pending_keys.clear();
+            if self.mode != EditorMode::Insert {
+                self.history.seal();
+                self.clamp_normal_cursor();
+            }
+        }
+    }
+
+    // Returns false while `keys` is still an incomplete command.
+    fn run_normal_command(&mut self, keys: &[u8]) -> bool {
+        match NormalCommand::parse(keys) {
+            CommandParse::Complete(cmd) => self.execute_normal(cmd),
+            CommandParse::Pending => return false,
+            CommandParse::Invalid => {}
+        }
+        true
+    }
+
+    pub(crate) fn execute_normal(&mut self, cmd: NormalCommand) {
+        let cursor = (self.cx, self.cy);
+        if cmd.is_change() {
+            self.begin_change(ChangeCommand::Normal(cmd));
+        }
+
+        match cmd {
+            NormalCommand::Move { motion, count } => {
+                (self.cx, self.cy) = self.motion_target_count(motion, cursor, count);
+                if let Motion::SearchNext { .. } = motion {
+                    self.editor_report_search();
+                }
+            }
+            NormalCommand::Operate { op, target, count, register } => {
+                if let Some(region) = self.operator_region(op, target, count) {
+                    self.apply_operator(op, region, register);
+                }
+            }
+            NormalCommand::Simple { cmd: Simple::Repeat, count, .. } => self.editor_repeat_change(count),
+            NormalCommand::Simple { cmd, count, register } => {
+                self.execute_simple(cmd, count.unwrap_or(1), register)
+            }
+        }
+
+        // Commands that enter Insert mode finish when it is left
+        if self.mode != EditorMode::Insert {
+            self.finish_change();
+        }
+    }
+
+    fn execute_simple(&mut self, cmd: Simple, count: usize, register: Option<char>) {
+        let cursor = (self.cx, self.cy);
+
+        match cmd {
+            Simple::Insert => self.editor_enter_insert(),
+            Simple::Append => {
+                if let Some(row) = self.erow.get(self.cy) {
+                    self.cx = grapheme::next_boundary(&row.chars, self.cx);
+                }
+                self.editor_enter_insert();
+            }
+            Simple::InsertLineStart => {
+                self.cx = self.motion_target(Motion::FirstNonBlank, cursor).0;
+                self.editor_enter_insert();
+            }
+            Simple::AppendLineEnd => {
+                self.cx = self.motion_target(Motion::LineEnd, cursor).0;
+                self.editor_enter_insert();
+            }
+            Simple::OpenBelow | Simple::OpenAbove => {
+                self.history.begin_group(cursor);
+                let below = cmd == Simple::OpenBelow && !self.erow.is_empty();
+                let y = if below { self.cy + 1 } else { self.cy };
+                self.editor_insert_rows(y, &[String::new()]);
+                self.cy = y;
+                self.cx = 0;
+                self.mode = EditorMode::Insert;
+            }
+            Simple::DeleteChar | Simple::DeleteCharBefore => {
+                let line = match self.erow.get(self.cy) {
+                    Some(row) if !row.chars.is_empty() => row.chars.clone(),
+                    _ => return,
+                };
+                let (mut start, mut end) = (self.cx, self.cx);
+                for _ in 0..count.min(line.len()) {
+                    if cmd == Simple::DeleteChar {
+                        end = grapheme::next_boundary(&line, end);
+                    } else {
+                        start = grapheme::prev_boundary(&line, start);
+                    }
+                }
+                if start == end {
+                    return;
+                }
+                let text = self.editor_delete_range((start, self.cy), (end, self.cy));
+                self.cx = start;
+                let value = Register { text, linewise: false };
+                self.registers.store(register, RegisterWrite::Delete, value);
+            }
+            Simple::PasteAfter | Simple::PasteBefore => {
+                self.editor_paste(cmd == Simple::PasteAfter, count, register);
+            }
+            Simple::Undo => {
+                for _ in 0..count.min(UNDO_LEVELS) {
+                    self.editor_undo();
+                }
+            }
+            Simple::Visual => {
+                self.visual_anchor = cursor;
+                self.mode = EditorMode::Visual;
+            }
+            Simple::VisualLine => {
+                self.visual_anchor = cursor;
+                self.mode = EditorMode::VisualLine;
+            }
+            Simple::Repeat => {}
+            Simple::Record(register) => self.editor_start_recording(register),
+            Simple::PlayMacro(register) => self.editor_play_macro(register, count),
+            Simple::SetMark(name) => {
+                self.marks.insert(name, cursor);
+            }
+            Simple::CommandLine => {
+                // 3: starts the command line with the range .,.+2
+                let range = if count > 1 { format!(".,.+{}", count - 1) } else { String::new() };
+                self.editor_ex_prompt(&range);
+            }
+            Simple::Search { backward } => self.editor_find(backward),
+            Simple::Hover => self.editor_lsp_hover(),
+            Simple::Definition => self.editor_lsp_definition(),
+            Simple::References => self.editor_lsp_references(),
+        }
+    }
+
+    fn run_visual_command(&mut self, keys: &[u8]) -> bool {
+        let cursor = (self.cx, self.cy);
+
+        // An operator may be preceded by a register, as in "ay
+        let (register, keys) = match keys {
+            [b'"'] => return false,
+            [b'"', name, rest @ ..] if Registers::is_valid(*name as char) => {
+                if rest.is_empty() {
+                    return false;
+                }
+                (Some(*name as char), rest)
+            }
+            [b'"', ..] => return true,
+            _ => (None, keys),
+        };
+
+        let op = match keys {
+            b"d" | b"x" => Some(Operator::Delete),
+            b"c" | b"s" => Some(Operator::Change),
+            b"y" => Some(Operator::Yank),
+            b">" => Some(Operator::ShiftRight),
+            b"<" => Some(Operator::ShiftLeft),
+            b"u" | b"gu" => Some(Operator::Lowercase),
+            b"U" | b"gU" => Some(Operator::Uppercase),
+            _ => None,
+        };
+        if let Some(op) = op {
+            // Nothing to operate on, as in Normal mode
+            if self.erow.is_empty() {
+                self.mode = EditorMode::Normal;
+                return true;
+            }
+            let (start, end) = self.visual_range();
+            let region = if self.mode == EditorMode::VisualLine {
+                let (top, bottom) = self.visual_rows();
+                Region { start: (0, top), end: (self.erow[bottom].size, bottom), linewise: true }
+            } else {
+                Region { start, end, linewise: false }
+            };
+            self.mode = EditorMode::Normal;
+            if op != Operator::Yank {
+                let lines = region.end.1 - region.start.1 + 1;
+                // Counted in clusters, so a replay lands on a boundary
+                let line = &self.erow[region.end.1].chars;
+                let from = if lines == 1 { region.start.0 } else { 0 };
+                let width = line[from..region.end.0.min(line.len())].graphemes(true).count();
+                let linewise = region.linewise;
+                self.begin_change(ChangeCommand::Visual { op, linewise, lines, width, register });
+            }
+            self.apply_operator(op, region, register);
+            if self.mode != EditorMode::Insert {
+                self.finish_change();
+            }
+            return true;
+        }
+
+        match keys {
+            b"v" | b"V" => {
+                let wanted = if keys == b"v" { EditorMode::Visual } else { EditorMode::VisualLine };
+                self.mode = if self.mode == wanted { EditorMode::Normal } else { wanted };
+            }
+            b"o" => {
+                (self.cx, self.cy) = self.visual_anchor;
+                self.visual_anchor = cursor;
+            }
+            b":" => {
+                let (start, end) = self.visual_range();
+                self.marks.insert('<', start);
+                let bottom = self.visual_rows().1;
+                let line = self.erow.get(bottom).map_or("", |row| row.chars.as_str());
+                self.marks.insert('>', (grapheme::prev_boundary(line, end.0), bottom));
+                self.mode = EditorMode::Normal;
+                self.editor_ex_prompt("'<,'>");
+            }
+            [b'i' | b'a'] => return false,
+            [b'i' | b'a', _] => {
+                // Text objects replace the selection
+                let region = TextObject::parse(keys).and_then(|obj| self.text_object_region(obj, None));
+                if let Some(region) = region {
+                    if region.linewise {
+                        self.mode = EditorMode::VisualLine;
+                    }
+                    self.visual_anchor = region.start;
+                    let line = self.erow.get(region.end.1).map_or("", |row| row.chars.as_str());
+                    let x = if region.linewise { 0 } else { grapheme::prev_boundary(line, region.end.0) };
+                    (self.cx, self.cy) = (x, region.end.1);
+                }
+            }
+            _ => {
+                // Counts work for motions in Visual mode too
+                match NormalCommand::parse(keys) {
+                    CommandParse::Complete(NormalCommand::Move { motion, count }) => {
+                        (self.cx, self.cy) = self.motion_target_count(motion, cursor, count);
+                    }
+                    CommandParse::Pending => return false,
+                    _ => {}
+                }
+            }
+        }
+        true
+    }
+
+    // Selected region as (start, end) with end exclusive. For V-LINE the
+    // caller only looks at the rows.
+    pub(crate) fn visual_range(&self) -> ((usize, usize), (usize, usize)) {
+        let (a, b) = (self.visual_anchor, (self.cx, self.cy));
+        let (start, end) = if (a.1, a.0) <= (b.1, b.0) { (a, b) } else { (b, a) };
+
+        let line = self.erow.get(end.1).map_or("", |row| row.chars.as_str());
+        let end = if end.0 >= line.len() && end.1 + 1 < self.erow.len() {
+            (0, end.1 + 1) // selection covers the line break
+        } else {
+            (grapheme::next_boundary(line, end.0), end.1)
+        };
+        (start, end)
+    }
+
+    // Selected render columns of row `y` (end exclusive), for draw_rows.
+    // A column past the end of the text stands for the line break.
+    pub(crate) fn visual_cols(&self, y: usize) -> Option<(usize, usize)> {
+        let row = self.erow.get(y)?;
+        match self.mode {
+            EditorMode::VisualLine => {
+                let (top, bottom) = self.visual_rows();
+                (top..=bottom).contains(&y).then_some((0, grapheme::str_width(&row.render) + 1))
+            }
+            EditorMode::Visual => {
+                let (start, end) = self.visual_range();
+                if y < start.1 || y > end.1 || (y == end.1 && end.0 == 0 && end.1 > start.1) {
+                    return None;
+                }
+                let from = if y == start.1 { self.editor_row_cx_to_rx(row, start.0) } else { 0 };
+                let to = if y == end.1 {
+                    self.editor_row_cx_to_rx(row, end.0)
+                } else {
+                    grapheme::str_width(&row.render) + 1
+                };
+                Some((from, to))
+            }
+            _ => None,
+        }
+    }
+
+    // First and last row touched by the selection.
+    pub(crate) fn visual_rows(&self) -> (usize, usize) {
+        let (a, b) = (self.visual_anchor.1, self.cy);
+        (a.min(b), a.max(b))
+    }
+
+    fn editor_paste(&mut self, after: bool, count: usize, register: Option<char>) {
+        let mut yank = match self.registers.get(register) {
+            Some(value) => value.clone(),
+            None => {
+                match register {
+                    Some(name) if name != '"' => {
+                        self.editor_set_status_msg(format!("Register {} is empty", name))
+                    }
+                    _ => self.editor_set_status_msg("Nothing to paste"),
+                }
+                return;
+            }
+        };
+        if yank.text.len().saturating_add(1).saturating_mul(count) > MAX_REPEAT_BYTES {
+            self.editor_set_status_msg("Count too large");
+            return;
+        }
+        if count > 1 {
+            let sep = if yank.linewise { "\n" } else { "" };
+            yank.text = vec![yank.text.as_str(); count].join(sep);
+        }
+        let cursor = (self.cx, self.cy);
+        self.history.begin_group(cursor);
+
+        if yank.linewise {
+            let y = if after && !self.erow.is_empty() { self.cy + 1 } else { self.cy };
+            let lines: Vec<String> = yank.text.split('\n').map(str::to_string).collect();
+            self.editor_insert_rows(y, &lines);
+            self.cy = y;
+            self.cx = motion::first_non_blank(&self.erow[y].chars);
+        } else {
+            let line = self.erow.get(self.cy).map_or("", |row| row.chars.as_str());
+            let x = if after { grapheme::next_boundary(line, self.cx) } else { self.cx };
+            let end = self.editor_insert_text((x, self.cy), &yank.text);
+            (self.cx, self.cy) = if yank.text.contains('\n') { (x, self.cy) } else { end };
+            if !yank.text.contains('\n') {
+                let line = &self.erow[self.cy].chars;
+                self.cx = grapheme::prev_boundary(line, self.cx);
+            }
+        }
+
+        self.history.end_group((self.cx, self.cy));
+    }
+
+    pub(crate) fn editor_enter_insert(&mut self) {
+        self.history.begin_group((self.cx, self.cy));
+        self.mode = EditorMode::Insert;
+    }
+
+    pub(crate) fn editor_leave_insert(&mut self) {
+        self.repeat_insert_for_count();
+        self.finish_change();
+        self.history.end_group((self.cx, self.cy));
+        self.mode = EditorMode::Normal;
+        if let Some(row) = self.erow.get(self.cy) {
+            self.cx = grapheme::prev_boundary(&row.chars, self.cx);
+        }
+        self.clamp_normal_cursor();
+    }
+
+    // In Normal mode the cursor sits on a character, never past the end.
+    pub(crate) fn clamp_normal_cursor(&mut self) {
+        if self.erow.is_empty() {
+            self.cx = 0;
+            self.cy = 0;
+            return;
+        }
+        self.cy = self.cy.min(self.erow.len() - 1);
+        let line = &self.erow[self.cy].chars;
+        let last = grapheme::last_start(line);
+        self.cx = if self.cx > last {
+            last
+        } else {
+            grapheme::prev_boundary(line, grapheme::next_boundary(line, self.cx))
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EditorMode;
+    use crate::EditorConfig;
+
+    // Where the cursor is after typing `keys`
+    fn cursor(editor: &mut EditorConfig, keys: &str) -> (usize, usize) {
+        editor.type_keys(keys);
+        (editor.cx, editor.cy)
+    }
+
+    #[test]
+    fn hjkl_move_by_character_and_line_and_stay_on_the_text() {
+        let mut editor = EditorConfig::with_lines(&["abcd", "e", "fghi"]);
+        assert_eq!(cursor(&mut editor, "l"), (1, 0));
+        assert_eq!(cursor(&mut editor, "lll"), (3, 0)); // not past the last character
+        assert_eq!(cursor(&mut editor, "j"), (0, 1));
+        assert_eq!(cursor(&mut editor, "jlll"), (3, 2));
+        assert_eq!(cursor(&mut editor, "jj"), (3, 2)); // nor past the last line
+        assert_eq!(cursor(&mut editor, "hk"), (0, 1));
+        assert_eq!(cursor(&mut editor, "h"), (0, 1));
+        assert_eq!(cursor(&mut editor, "kl"), (1, 0));
+        assert_eq!(cursor(&mut editor, "k"), (1, 0));
+    }
+
+    #[test]
+    fn word_line_and_file_motions() {
+        let mut editor = EditorConfig::with_lines(&["one two.three", "  four"]);
+        assert_eq!(cursor(&mut editor, "w"), (4, 0));
+        assert_eq!(cursor(&mut editor, "w"), (7, 0));
+        assert_eq!(cursor(&mut editor, "w"), (8, 0));
+        assert_eq!(cursor(&mut editor, "w"), (2, 1));
+        assert_eq!(cursor(&mut editor, "b"), (8, 0));
+        assert_eq!(cursor(&mut editor, "e"), (12, 0));
+        assert_eq!(cursor(&mut editor, "0"), (0, 0));
+        assert_eq!(cursor(&mut editor, "$"), (12, 0));
+        assert_eq!(cursor(&mut editor, "G"), (2, 1));
+        assert_eq!(cursor(&mut editor, "$gg"), (0, 0));
+    }
+
+    #[test]
+    fn x_dd_yy_and_p() {
+        let mut editor = EditorConfig::with_lines(&["abc", "de", "fg"]);
+        editor.type_keys("x");
+        assert_eq!(editor.lines(), ["bc", "de", "fg"]);
+        editor.type_keys("p");
+        assert_eq!(editor.lines(), ["bac", "de", "fg"]);
+        assert_eq!((editor.cx, editor.cy), (1, 0));
+
+        editor.type_keys("jdd");
+        assert_eq!(editor.lines(), ["bac", "fg"]);
+        assert_eq!((editor.cx, editor.cy), (0, 1));
+        editor.type_keys("P");
+        assert_eq!(editor.lines(), ["bac", "de", "fg"]);
+        assert_eq!((editor.cx, editor.cy), (0, 1));
+
+        editor.type_keys("ggyyGp");
+        assert_eq!(editor.lines(), ["bac", "de", "fg", "bac"]);
+        assert_eq!((editor.cx, editor.cy), (0, 3));
+    }
+
+    #[test]
+    fn each_way_into_insert_mode_and_escape_moves_back_one() {
+        // What the line reads and where the cursor is after typing `keys`
+        // on "  ab" from its second character
+        let insert = |keys: &str| {
+            let mut editor = EditorConfig::with_lines(&["  ab"]);
+            editor.type_keys("3l");
+            editor.type_keys(keys);
+            assert_eq!(editor.mode, EditorMode::Insert);
+            editor.type_keys("\x1b");
+            assert_eq!(editor.mode, EditorMode::Normal);
+            (editor.lines(), (editor.cx, editor.cy))
+        };
+        assert_eq!(insert("ix"), (vec!["  axb".to_string()], (3, 0)));
+        assert_eq!(insert("ax"), (vec!["  abx".to_string()], (4, 0)));
+        assert_eq!(insert("Ix"), (vec!["  xab".to_string()], (2, 0)));
+        assert_eq!(insert("Ax"), (vec!["  abx".to_string()], (4, 0)));
+        assert_eq!(insert("ox").0, ["  ab", "x"]);
+        assert_eq!(insert("Ox").0, ["x", "  ab"]);
+        // Only Escape at the start of the line stays there
+        assert_eq!(insert("I").1, (1, 0));
+        assert_eq!(insert("0i").1, (0, 0));
+    }
+
+    #[test]
+    fn v_selects_characters_and_capital_v_whole_lines() {
+        let mut editor = EditorConfig::with_lines(&["abc", "def", "ghi"]);
+        editor.type_keys("lvj");
+        assert_eq!(editor.mode, EditorMode::Visual);
+        editor.type_keys("d");
+        assert_eq!(editor.mode, EditorMode::Normal);
+        assert_eq!(editor.lines(), ["af", "ghi"]);
+        assert_eq!((editor.cx, editor.cy), (1, 0));
+
+        editor.type_keys("V");
+        assert_eq!(editor.mode, EditorMode::VisualLine);
+        editor.type_keys("jy");
+        assert_eq!(editor.mode, EditorMode::Normal);
+        assert_eq!(editor.registers.get(None).map(|r| (r.text.as_str(), r.linewise)), Some(("af\nghi", true)));
+
+        editor.type_keys("v\x1b");
+        assert_eq!(editor.mode, EditorMode::Normal);
+        editor.type_keys("ggVd");
+        assert_eq!(editor.lines(), ["ghi"]);
+    }
+
+    #[test]
+    fn a_count_repeats_a_motion_or_an_edit() {
+        let mut editor = EditorConfig::with_lines(&["abcdef", "1", "2", "3", "4"]);
+        editor.type_keys("3x");
+        assert_eq!(editor.lines()[0], "def");
+        assert_eq!(cursor(&mut editor, "2l"), (2, 0));
+        assert_eq!(cursor(&mut editor, "3j"), (0, 3));
+        editor.type_keys("2dd");
+        assert_eq!(editor.lines(), ["def", "1", "2"]);
+        editor.type_keys("gg2p");
+        assert_eq!(editor.lines(), ["def", "3", "4", "3", "4", "1", "2"]);
+        editor.type_keys("G3ix\x1b");
+        assert_eq!(editor.lines()[6], "xxx2");
+    }
+
+    #[test]
+    fn the_end_mark_of_a_selection_is_on_its_last_character() {
+        let mut editor = EditorConfig::with_lines(&["ab字", "c"]);
+        editor.type_keys("lv$:\x1b");
+        assert_eq!(editor.marks.get(&'<'), Some(&(1, 0)));
+        assert_eq!(editor.marks.get(&'>'), Some(&(2, 0)));
+    }
+}
@@ -0,0 +1,254 @@
+// Cursor motions for Normal and Visual mode.
+//
+// Word motions follow Vim: a word is a run of keyword characters or a run of
+// punctuation, where punctuation is whatever EditorConfig::is_separator says
+// separates words (minus blanks). A WORD (W/B/E) is any run of non-blanks.
+// The end of each line counts as a blank, and an empty line is a word of its own.
+
+use crate::buffer::TextBuffer;
+use crate::grapheme;
+use crate::EditorConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordForward { big: bool },
+    WordBackward { big: bool },
+    WordEnd { big: bool },
+    LineStart,
+    LineEnd,
+    FirstNonBlank,
+    FileStart,
+    FileEnd,
+    Mark(char),     // `a, exact position
+    MarkLine(char), // 'a, first non-blank of the line
+    SearchNext { reverse: bool }, // n and N, the last search again
+}
+
+pub enum MotionParse {
+    Complete(Motion),
+    Pending,
+    Invalid,
+}
+
+impl Motion {
+    // Parse the keys typed so far as a motion.
+    pub fn parse(keys: &[u8]) -> MotionParse {
+        let motion = match keys {
+            b"h" => Motion::Left,
+            b"l" | b" " => Motion::Right,
+            b"j" => Motion::Down,
+            b"k" => Motion::Up,
+            b"w" => Motion::WordForward { big: false },
+            b"W" => Motion::WordForward { big: true },
+            b"b" => Motion::WordBackward { big: false },
+            b"B" => Motion::WordBackward { big: true },
+            b"e" => Motion::WordEnd { big: false },
+            b"E" => Motion::WordEnd { big: true },
+            b"0" => Motion::LineStart,
+            b"$" => Motion::LineEnd,
+            b"^" => Motion::FirstNonBlank,
+            b"gg" => Motion::FileStart,
+            b"G" => Motion::FileEnd,
+            b"g" | b"'" | b"`" => return MotionParse::Pending,
+            [b'`', name] => Motion::Mark(*name as char),
+            [b'\'', name] => Motion::MarkLine(*name as char),
+            b"n" => Motion::SearchNext { reverse: false },
+            b"N" => Motion::SearchNext { reverse: true },
+            _ => return MotionParse::Invalid,
+        };
+        MotionParse::Complete(motion)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CharClass {
+    Blank,
+    Punct,
+    Word,
+}
+
+pub(crate) fn char_class(c: char, big: bool) -> CharClass {
+    if c.is_whitespace() {
+        CharClass::Blank
+    } else if big {
+        CharClass::Word
+    } else if EditorConfig::is_separator(c) || (c.is_ascii_punctuation() && c != '_') {
+        CharClass::Punct
+    } else {
+        CharClass::Word
+    }
+}
+
+// Character at a position, '\n' for the end of a line.
+fn cell(buf: &TextBuffer, (x, y): (usize, usize)) -> char {
+    buf.get(y)
+        .and_then(|row| row.chars.get(x..))
+        .and_then(|rest| rest.chars().next())
+        .unwrap_or('\n')
+}
+
+fn is_empty_line(buf: &TextBuffer, y: usize) -> bool {
+    buf.get(y).is_some_and(|row| row.chars.is_empty())
+}
+
+// Next position, stepping over the end of each line onto the next one.
+fn advance(buf: &TextBuffer, (x, y): (usize, usize)) -> Option<(usize, usize)> {
+    let row = buf.get(y)?;
+    if x < row.size {
+        Some((grapheme::next_boundary(&row.chars, x), y))
+    } else if y + 1 < buf.len() {
+        Some((0, y + 1))
+    } else {
+        None
+    }
+}
+
+fn retreat(buf: &TextBuffer, (x, y): (usize, usize)) -> Option<(usize, usize)> {
+    if x > 0 {
+        let row = buf.get(y)?;
+        Some((grapheme::prev_boundary(&row.chars, x), y))
+    } else if y > 0 {
+        Some((buf.get(y - 1)?.size, y - 1))
+    } else {
+        None
+    }
+}
+
+fn word_forward(buf: &TextBuffer, from: (usize, usize), big: bool) -> (usize, usize) {
+    let mut pos = from;
+    let class = char_class(cell(buf, pos), big);
+
+    // Skip the rest of the current word
+    if class != CharClass::Blank {
+        while char_class(cell(buf, pos), big) == class {
+            match advance(buf, pos) {
+                Some(next) => pos = next,
+                None => return pos,
+            }
+        }
+    }
+
+    // Then any blanks, stopping at an empty line
+    while char_class(cell(buf, pos), big) == CharClass::Blank {
+        if pos != from && pos.0 == 0 && is_empty_line(buf, pos.1) {
+            break;
+        }
+        match advance(buf, pos) {
+            Some(next) => pos = next,
+            None => return pos,
+        }
+    }
+    pos
+}
+
+fn word_end(buf: &TextBuffer, from: (usize, usize), big: bool) -> (usize, usize) {
+    let mut pos = match advance(buf, from) {
+        Some(next) => next,
+        None => return from,
+    };
+
+    while char_class(cell(buf, pos), big) == CharClass::Blank {
+        match advance(buf, pos) {
+            Some(next) => pos = next,
+            None => return pos,
+        }
+    }
+
+    let class = char_class(cell(buf, pos), big);
+    while let Some(next) = advance(buf, pos) {
+        if next.1 != pos.1 || char_class(cell(buf, next), big) != class {
+            break;
+        }
+        pos = next;
+    }
+    pos
+}
+
+fn word_backward(buf: &TextBuffer, from: (usize, usize), big: bool) -> (usize, usize) {
+    let mut pos = match retreat(buf, from) {
+        Some(prev) => prev,
+        None => return from,
+    };
+
+    while char_class(cell(buf, pos), big) == CharClass::Blank {
+        if pos.0 == 0 && is_empty_line(buf, pos.1) {
+            return pos;
+        }
+        match retreat(buf, pos) {
+            Some(prev) => pos = prev,
+            None => return pos,
+        }
+    }
+
+    let class = char_class(cell(buf, pos), big);
+    while let Some(prev) = retreat(buf, pos) {
+        if prev.1 != pos.1 || char_class(cell(buf, prev), big) != class {
+            break;
+        }
+        pos = prev;
+    }
+    pos
+}
+
+impl EditorConfig {
+    // The position on row `y` in the same screen column as `from`.
+    fn same_column(&self, from: (usize, usize), y: usize) -> (usize, usize) {
+        let (Some(row), Some(to)) = (self.erow.get(from.1), self.erow.get(y)) else {
+            return (from.0, y);
+        };
+        let rx = self.editor_row_cx_to_rx(row, from.0);
+        (self.editor_row_rx_to_cx(to, rx), y)
+    }
+
+    // Where `motion` takes the cursor from `from`. Positions are (x, y).
+    pub(crate) fn motion_target(&self, motion: Motion, from: (usize, usize)) -> (usize, usize) {
+        let (x, y) = from;
+        let last_row = self.erow.len().saturating_sub(1);
+        let line = self.erow.get(y).map(|row| row.chars.as_str()).unwrap_or("");
+
+        match motion {
+            Motion::Left => (grapheme::prev_boundary(line, x), y),
+            Motion::Right => (grapheme::next_boundary(line, x), y),
+            Motion::Up => self.same_column(from, y.saturating_sub(1)),
+            Motion::Down => self.same_column(from, (y + 1).min(last_row)),
+            Motion::WordForward { big } => word_forward(&self.erow, from, big),
+            Motion::WordBackward { big } => word_backward(&self.erow, from, big),
+            Motion::WordEnd { big } => word_end(&self.erow, from, big),
+            Motion::LineStart => (0, y),
+            Motion::LineEnd => (line.len(), y),
+            Motion::FirstNonBlank => (first_non_blank(line), y),
+            Motion::FileStart => (first_non_blank(self.erow.get(0).map_or("", |r| &r.chars)), 0),
+            Motion::FileEnd => {
+                let row = self.erow.get(last_row).map_or("", |r| r.chars.as_str());
+                (first_non_blank(row), last_row)
+            }
+            Motion::Mark(name) | Motion::MarkLine(name) => {
+                // Lines may have gone since the mark was set
+                let Some(&(mx, my)) = self.marks.get(&name) else {
+                    return from;
+                };
+                let my = my.min(last_row);
+                let row = self.erow.get(my).map_or("", |r| r.chars.as_str());
+                if matches!(motion, Motion::MarkLine(_)) {
+                    (first_non_blank(row), my)
+                } else {
+                    // The line may have changed under the mark too
+                    (grapheme::cluster_start(row, mx), my)
+                }
+            }
+            Motion::SearchNext { reverse } => self
+                .last_search()
+                .ok()
+                .and_then(|pattern| self.search_from(&pattern, from, self.search_backward != reverse, false))
+                .map_or(from, |(x, y, _)| (x, y)),
+        }
+    }
+}
+
+pub fn first_non_blank(line: &str) -> usize {
+    line.find(|c: char| !c.is_whitespace()).unwrap_or(line.len())
+}
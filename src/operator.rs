@@ -0,0 +1,774 @@
+// Normal mode command grammar:
+//
+//   ["register] [count] operator [count] (motion | text object)
+//
+// Operators (d c y > < gu gU) act on a Region computed from either a motion
+// from the cursor, a text object around the cursor, or whole lines when the
+// operator is doubled (dd, cc, yy, >>, <<, guu, gUU).
+
+use crate::grapheme;
+use crate::macros::Macros;
+use crate::mode::EditorMode;
+use crate::motion::{self, Motion, MotionParse};
+use crate::register::{Register, RegisterWrite, Registers};
+use crate::undo::EditOp;
+use crate::EditorConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    ShiftRight,
+    ShiftLeft,
+    Lowercase,
+    Uppercase,
+}
+
+impl Operator {
+    // Parse an operator at the start of `keys`, returning it and its length.
+    fn parse(keys: &[u8]) -> Option<(Operator, usize)> {
+        let op = match keys {
+            [b'd', ..] => (Operator::Delete, 1),
+            [b'c', ..] => (Operator::Change, 1),
+            [b'y', ..] => (Operator::Yank, 1),
+            [b'>', ..] => (Operator::ShiftRight, 1),
+            [b'<', ..] => (Operator::ShiftLeft, 1),
+            [b'g', b'u', ..] => (Operator::Lowercase, 2),
+            [b'g', b'U', ..] => (Operator::Uppercase, 2),
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    // Keys that repeat the operator to make it act on whole lines.
+    fn is_doubled(self, keys: &[u8]) -> bool {
+        match self {
+            Operator::Delete => keys == b"d",
+            Operator::Change => keys == b"c",
+            Operator::Yank => keys == b"y",
+            Operator::ShiftRight => keys == b">",
+            Operator::ShiftLeft => keys == b"<",
+            Operator::Lowercase => keys == b"u" || keys == b"gu",
+            Operator::Uppercase => keys == b"U" || keys == b"gU",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextObject {
+    Word { big: bool, around: bool },
+    Pair { open: char, close: char, around: bool },
+    Quote { quote: char, around: bool },
+    Paragraph { around: bool },
+}
+
+impl TextObject {
+    // `keys` is the i/a prefix followed by the object key.
+    pub fn parse(keys: &[u8]) -> Option<TextObject> {
+        let around = match keys.first()? {
+            b'i' => false,
+            b'a' => true,
+            _ => return None,
+        };
+        let pair = |open, close| TextObject::Pair { open, close, around };
+        let object = match keys.get(1)? {
+            b'w' => TextObject::Word { big: false, around },
+            b'W' => TextObject::Word { big: true, around },
+            b'(' | b')' | b'b' => pair('(', ')'),
+            b'[' | b']' => pair('[', ']'),
+            b'{' | b'}' | b'B' => pair('{', '}'),
+            b'<' | b'>' => pair('<', '>'),
+            q @ (b'"' | b'\'' | b'`') => TextObject::Quote { quote: *q as char, around },
+            b'p' => TextObject::Paragraph { around },
+            _ => return None,
+        };
+        Some(object)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Motion(Motion),
+    Object(TextObject),
+    Lines, // doubled operator
+}
+
+// Commands that are neither a bare motion nor operator + target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Simple {
+    Insert,
+    Append,
+    InsertLineStart,
+    AppendLineEnd,
+    OpenBelow,
+    OpenAbove,
+    DeleteChar,
+    DeleteCharBefore,
+    PasteAfter,
+    PasteBefore,
+    Undo,
+    Visual,
+    VisualLine,
+    Repeat,
+    Record(char),
+    PlayMacro(char), // '@' for the last macro played
+    SetMark(char),
+    CommandLine,
+    Search { backward: bool },
+    Hover,
+    Definition,
+    References,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormalCommand {
+    Move { motion: Motion, count: Option<usize> },
+    Operate { op: Operator, target: Target, count: Option<usize>, register: Option<char> },
+    Simple { cmd: Simple, count: Option<usize>, register: Option<char> },
+}
+
+pub enum CommandParse {
+    Complete(NormalCommand),
+    Pending,
+    Invalid,
+}
+
+// A leading count; "0" on its own is the line-start motion, not a count.
+fn parse_count(keys: &[u8]) -> (Option<usize>, &[u8]) {
+    if keys.first().is_none_or(|k| !(b'1'..=b'9').contains(k)) {
+        return (None, keys);
+    }
+    let digits = keys.iter().take_while(|k| k.is_ascii_digit()).count();
+    let count = std::str::from_utf8(&keys[..digits])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(usize::MAX);
+    (Some(count), &keys[digits..])
+}
+
+fn multiply(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (None, None) => None,
+        (a, b) => Some(a.unwrap_or(1).saturating_mul(b.unwrap_or(1))),
+    }
+}
+
+impl NormalCommand {
+    pub fn parse(keys: &[u8]) -> CommandParse {
+        let (count, rest) = parse_count(keys);
+        let (register, rest) = match rest {
+            [b'"'] => return CommandParse::Pending,
+            [b'"', name, rest @ ..] if Registers::is_valid(*name as char) => (Some(*name as char), rest),
+            [b'"', ..] => return CommandParse::Invalid,
+            _ => (None, rest),
+        };
+        // The count may also come after the register, as in "a3yy
+        let (count2, rest) = if register.is_some() { parse_count(rest) } else { (None, rest) };
+        let count = multiply(count, count2);
+        if rest.is_empty() {
+            return CommandParse::Pending;
+        }
+
+        if let Some((op, len)) = Operator::parse(rest) {
+            return Self::parse_operator_target(op, count, register, &rest[len..]);
+        }
+
+        let simple = match rest {
+            b"i" => Simple::Insert,
+            b"a" => Simple::Append,
+            b"I" => Simple::InsertLineStart,
+            b"A" => Simple::AppendLineEnd,
+            b"o" => Simple::OpenBelow,
+            b"O" => Simple::OpenAbove,
+            b"x" => Simple::DeleteChar,
+            b"X" => Simple::DeleteCharBefore,
+            b"p" => Simple::PasteAfter,
+            b"P" => Simple::PasteBefore,
+            b"u" => Simple::Undo,
+            b"v" => Simple::Visual,
+            b"V" => Simple::VisualLine,
+            b"." => Simple::Repeat,
+            b":" => Simple::CommandLine,
+            b"/" => Simple::Search { backward: false },
+            b"?" => Simple::Search { backward: true },
+            b"K" => Simple::Hover,
+            b"gd" => Simple::Definition,
+            b"gr" => Simple::References,
+            b"q" | b"@" | b"m" => return CommandParse::Pending,
+            [b'm', name] if name.is_ascii_lowercase() => Simple::SetMark(*name as char),
+            [b'q', name] if Macros::is_valid(*name as char) => Simple::Record(*name as char),
+            [b'@', name] if *name == b'@' || Macros::is_valid(*name as char) => {
+                Simple::PlayMacro(*name as char)
+            }
+            // Shorthands for common operator + motion pairs
+            b"D" => {
+                return Self::operate(Operator::Delete, Target::Motion(Motion::LineEnd), count, register)
+            }
+            b"C" => {
+                return Self::operate(Operator::Change, Target::Motion(Motion::LineEnd), count, register)
+            }
+            b"Y" => return Self::operate(Operator::Yank, Target::Lines, count, register),
+            _ => {
+                return match Motion::parse(rest) {
+                    MotionParse::Complete(motion) => {
+                        CommandParse::Complete(NormalCommand::Move { motion, count })
+                    }
+                    MotionParse::Pending => CommandParse::Pending,
+                    MotionParse::Invalid => CommandParse::Invalid,
+                }
+            }
+        };
+        CommandParse::Complete(NormalCommand::Simple { cmd: simple, count, register })
+    }
+
+    fn parse_operator_target(
+        op: Operator,
+        count: Option<usize>,
+        register: Option<char>,
+        keys: &[u8],
+    ) -> CommandParse {
+        let (count2, rest) = parse_count(keys);
+        let count = multiply(count, count2);
+        if rest.is_empty() {
+            return CommandParse::Pending;
+        }
+
+        if op.is_doubled(rest) {
+            return Self::operate(op, Target::Lines, count, register);
+        }
+        if rest == b"g" {
+            return CommandParse::Pending; // gg, or the second half of gugu
+        }
+        if rest[0] == b'i' || rest[0] == b'a' {
+            if rest.len() == 1 {
+                return CommandParse::Pending;
+            }
+            return match TextObject::parse(rest) {
+                Some(object) => Self::operate(op, Target::Object(object), count, register),
+                None => CommandParse::Invalid,
+            };
+        }
+        match Motion::parse(rest) {
+            MotionParse::Complete(motion) => {
+                Self::operate(op, Target::Motion(motion), count, register)
+            }
+            MotionParse::Pending => CommandParse::Pending,
+            MotionParse::Invalid => CommandParse::Invalid,
+        }
+    }
+
+    fn operate(
+        op: Operator,
+        target: Target,
+        count: Option<usize>,
+        register: Option<char>,
+    ) -> CommandParse {
+        CommandParse::Complete(NormalCommand::Operate { op, target, count, register })
+    }
+}
+
+// Text an operator acts on. `end` is exclusive; for linewise regions only
+// the rows start.1..=end.1 matter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub linewise: bool,
+}
+
+impl EditorConfig {
+    // Apply a motion `count` times; `count` also picks the line for gg/G.
+    pub(crate) fn motion_target_count(
+        &self,
+        motion: Motion,
+        from: (usize, usize),
+        count: Option<usize>,
+    ) -> (usize, usize) {
+        let last_row = self.erow.len().saturating_sub(1);
+        match (motion, count) {
+            (Motion::FileStart | Motion::FileEnd, Some(n)) => {
+                let y = (n.max(1) - 1).min(last_row);
+                let line = self.erow.get(y).map_or("", |row| row.chars.as_str());
+                (motion::first_non_blank(line), y)
+            }
+            (Motion::LineEnd, Some(n)) => {
+                let y = from.1.saturating_add(n.max(1) - 1).min(last_row);
+                self.motion_target(motion, (from.0, y))
+            }
+            (Motion::SearchNext { reverse }, Some(n)) => {
+                // Search wraps around, so after one time round the buffer
+                // the matches come again in the same order
+                let Ok(pattern) = self.last_search() else {
+                    return from;
+                };
+                let backward = self.search_backward != reverse;
+                let mut found: Vec<(usize, usize)> = Vec::new();
+                let mut pos = from;
+                while found.len() < n {
+                    match self.search_from(&pattern, pos, backward, false) {
+                        Some((x, y, _)) if found.first() != Some(&(x, y)) => {
+                            pos = (x, y);
+                            found.push(pos);
+                        }
+                        Some(_) => return found[(n - 1) % found.len()],
+                        None => break,
+                    }
+                }
+                found.last().copied().unwrap_or(from)
+            }
+            _ => {
+                let mut pos = from;
+                for _ in 0..count.unwrap_or(1) {
+                    let next = self.motion_target(motion, pos);
+                    if next == pos {
+                        break;
+                    }
+                    pos = next;
+                }
+                pos
+            }
+        }
+    }
+
+    pub(crate) fn operator_region(
+        &self,
+        op: Operator,
+        target: Target,
+        count: Option<usize>,
+    ) -> Option<Region> {
+        let cursor = (self.cx, self.cy);
+        if self.erow.is_empty() {
+            return None;
+        }
+
+        match target {
+            Target::Lines => {
+                let last = self.cy.saturating_add(count.unwrap_or(1).max(1) - 1).min(self.erow.len() - 1);
+                Some(self.line_region(self.cy, last))
+            }
+            Target::Object(object) => self.text_object_region(object, count),
+            Target::Motion(motion) => {
+                // cw on a word behaves like ce
+                let on_word = self.erow[self.cy].chars[self.cx.min(self.erow[self.cy].size)..]
+                    .starts_with(|c: char| !c.is_whitespace());
+                let motion = match motion {
+                    Motion::WordForward { big } if op == Operator::Change && on_word => {
+                        Motion::WordEnd { big }
+                    }
+                    m => m,
+                };
+
+                let target = self.motion_target_count(motion, cursor, count);
+                match motion {
+                    Motion::Up
+                    | Motion::Down
+                    | Motion::FileStart
+                    | Motion::FileEnd
+                    | Motion::MarkLine(_) => {
+                        Some(self.line_region(cursor.1.min(target.1), cursor.1.max(target.1)))
+                    }
+                    Motion::WordEnd { .. } | Motion::LineEnd => {
+                        // Inclusive motions take the character under the target too
+                        let line = &self.erow[target.1].chars;
+                        let end = (grapheme::next_boundary(line, target.0), target.1);
+                        Some(self.char_region(cursor, end))
+                    }
+                    Motion::WordForward { .. }
+                        if target.1 > cursor.1
+                            && target.0 <= motion::first_non_blank(&self.erow[target.1].chars) =>
+                    {
+                        // dw on the last word of a line stops at the line end
+                        let y = target.1 - 1;
+                        Some(self.char_region(cursor, (self.erow[y].size, y)))
+                    }
+                    _ => Some(self.char_region(cursor, target)),
+                }
+            }
+        }
+    }
+
+    fn line_region(&self, top: usize, bottom: usize) -> Region {
+        Region {
+            start: (0, top),
+            end: (self.erow[bottom].size, bottom),
+            linewise: true,
+        }
+    }
+
+    fn char_region(&self, a: (usize, usize), b: (usize, usize)) -> Region {
+        let (start, end) = if (a.1, a.0) <= (b.1, b.0) { (a, b) } else { (b, a) };
+        Region { start, end, linewise: false }
+    }
+
+    pub(crate) fn text_object_region(&self, object: TextObject, count: Option<usize>) -> Option<Region> {
+        let (x, y) = (self.cx, self.cy);
+        let line = self.erow.get(y)?.chars.as_str();
+
+        match object {
+            TextObject::Word { big, around } => {
+                let (mut start, mut end) = word_span(line, x, big)?;
+                for _ in 1..count.unwrap_or(1) {
+                    match word_span(line, end, big) {
+                        Some((_, next_end)) => end = next_end,
+                        None => break,
+                    }
+                }
+                if around {
+                    // Take trailing blanks, or leading ones if there are none
+                    let trailing = end + line[end..].len() - line[end..].trim_start().len();
+                    if trailing > end {
+                        end = trailing;
+                    } else {
+                        start = line[..start].trim_end().len();
+                    }
+                }
+                Some(self.char_region((start, y), (end, y)))
+            }
+            TextObject::Quote { quote, around } => {
+                let (open, close) = quote_span(line, x, quote)?;
+                if around {
+                    // Blanks after the closing quote, or before the opening
+                    // one when there are none after
+                    let after = close + 1;
+                    let trailing = line[after..].len() - line[after..].trim_start().len();
+                    let start = if trailing == 0 { line[..open].trim_end().len() } else { open };
+                    Some(self.char_region((start, y), (after + trailing, y)))
+                } else {
+                    Some(self.char_region((open + 1, y), (close, y)))
+                }
+            }
+            TextObject::Pair { open, close, around } => {
+                let (start, end) = self.pair_span((x, y), open, close, count.unwrap_or(1))?;
+                let inner = (start.0 + open.len_utf8(), start.1);
+                if around {
+                    Some(self.char_region(start, (end.0 + close.len_utf8(), end.1)))
+                } else if self.erow[start.1].chars[inner.0..].trim().is_empty()
+                    && end.0 == motion::first_non_blank(&self.erow[end.1].chars)
+                    && end.1 > start.1 + 1
+                {
+                    // A block with its braces on their own lines: take whole lines
+                    Some(self.line_region(start.1 + 1, end.1 - 1))
+                } else {
+                    Some(self.char_region(inner, end))
+                }
+            }
+            TextObject::Paragraph { around } => {
+                let blank = |y: usize| self.erow[y].chars.trim().is_empty();
+                let kind = blank(y);
+                let mut top = y;
+                while top > 0 && blank(top - 1) == kind {
+                    top -= 1;
+                }
+                let mut bottom = y;
+                while bottom + 1 < self.erow.len() && blank(bottom + 1) == kind {
+                    bottom += 1;
+                }
+                if around {
+                    // Include the blank lines that follow (or precede, at the end)
+                    let before = bottom;
+                    while bottom + 1 < self.erow.len() && blank(bottom + 1) != kind {
+                        bottom += 1;
+                    }
+                    if bottom == before {
+                        while top > 0 && blank(top - 1) != kind {
+                            top -= 1;
+                        }
+                    }
+                }
+                Some(self.line_region(top, bottom))
+            }
+        }
+    }
+
+    // Find the `count`th enclosing open/close pair around `from`, searching
+    // across lines. Returns the positions of the open and close characters.
+    fn pair_span(
+        &self,
+        from: (usize, usize),
+        open: char,
+        close: char,
+        count: usize,
+    ) -> Option<((usize, usize), (usize, usize))> {
+        // Walk backwards for an unmatched opener; a closer under the cursor
+        // belongs to the pair we are looking for, so it is already matched
+        // and the walk starts before it at depth 0.
+        let mut depth = 0;
+        let mut found = 0;
+        let mut pos = from;
+        let under = self.erow[from.1].chars[from.0.min(self.erow[from.1].size)..].chars().next();
+        let mut start = None;
+        if under == Some(open) {
+            found += 1;
+            if found == count {
+                start = Some(from);
+            }
+        }
+        while start.is_none() {
+            pos = self.prev_pos(pos)?;
+            let c = self.erow[pos.1].chars[pos.0..].chars().next().unwrap_or('\n');
+            if c == close {
+                depth += 1;
+            } else if c == open {
+                if depth == 0 {
+                    found += 1;
+                    if found == count {
+                        start = Some(pos);
+                    }
+                } else {
+                    depth -= 1;
+                }
+            }
+        }
+        let start = start?;
+
+        // Then forwards from the opener to its matching closer
+        let mut depth = 0;
+        let mut pos = start;
+        loop {
+            pos = self.next_pos(pos)?;
+            let c = self.erow[pos.1].chars[pos.0..].chars().next().unwrap_or('\n');
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                if depth == 0 {
+                    return Some((start, pos));
+                }
+                depth -= 1;
+            }
+        }
+    }
+
+    fn next_pos(&self, (x, y): (usize, usize)) -> Option<(usize, usize)> {
+        let row = &self.erow.get(y)?.chars;
+        let next = grapheme::next_boundary(row, x);
+        if next < row.len() {
+            Some((next, y))
+        } else if y + 1 < self.erow.len() {
+            Some((0, y + 1))
+        } else {
+            None
+        }
+    }
+
+    fn prev_pos(&self, (x, y): (usize, usize)) -> Option<(usize, usize)> {
+        if x > 0 {
+            Some((grapheme::prev_boundary(&self.erow[y].chars, x), y))
+        } else if y > 0 {
+            let row = &self.erow[y - 1].chars;
+            // An empty line has no character to stand on; use x = 0 anyway
+            Some((grapheme::last_start(row), y - 1))
+        } else {
+            None
+        }
+    }
+
+    // Run `op` over `region`, storing removed or yanked text in `register`.
+    // Leaves the editor in Insert mode for Change.
+    pub(crate) fn apply_operator(&mut self, op: Operator, region: Region, register: Option<char>) {
+        let Region { start, end, linewise } = region;
+        let (top, bottom) = (start.1, end.1);
+
+        match op {
+            Operator::Yank => {
+                let text = self.region_text(region);
+                self.registers.store(register, RegisterWrite::Yank, Register { text, linewise });
+                if !linewise {
+                    (self.cx, self.cy) = start;
+                }
+            }
+            Operator::Delete => {
+                self.history.begin_group(start);
+                let text = if linewise {
+                    self.editor_delete_rows(top, bottom - top + 1).join("\n")
+                } else {
+                    self.editor_delete_range(start, end)
+                };
+                self.registers.store(register, RegisterWrite::Delete, Register { text, linewise });
+                if linewise {
+                    self.cy = top.min(self.erow.len().saturating_sub(1));
+                    self.cx = self.erow.get(self.cy).map_or(0, |row| motion::first_non_blank(&row.chars));
+                } else {
+                    (self.cx, self.cy) = start;
+                }
+                self.history.end_group((self.cx, self.cy));
+            }
+            Operator::Change => {
+                self.history.begin_group(start);
+                let text = if linewise {
+                    // Keep one empty line to type into
+                    let text = self.region_text(region);
+                    self.editor_delete_rows(top + 1, bottom - top);
+                    let len = self.erow[top].size;
+                    self.editor_delete_range((0, top), (len, top));
+                    text
+                } else {
+                    self.editor_delete_range(start, end)
+                };
+                self.registers.store(register, RegisterWrite::Delete, Register { text, linewise });
+                (self.cx, self.cy) = if linewise { (0, top) } else { start };
+                self.mode = EditorMode::Insert; // group ends when Insert mode does
+            }
+            Operator::ShiftRight | Operator::ShiftLeft => {
+                self.history.begin_group((self.cx, self.cy));
+                for y in top..=bottom {
+                    self.shift_line(y, op == Operator::ShiftRight);
+                }
+                self.cy = top;
+                self.cx = motion::first_non_blank(&self.erow[top].chars);
+                self.history.end_group((self.cx, self.cy));
+            }
+            Operator::Lowercase | Operator::Uppercase => {
+                self.history.begin_group(start);
+                for y in top..=bottom {
+                    let line = self.erow[y].chars.clone();
+                    let from = if linewise || y > top { 0 } else { start.0.min(line.len()) };
+                    let to = if linewise || y < bottom { line.len() } else { end.0.min(line.len()) };
+                    let old = &line[from..to];
+                    let new = if op == Operator::Lowercase {
+                        old.to_lowercase()
+                    } else {
+                        old.to_uppercase()
+                    };
+                    if new != old {
+                        self.editor_apply(EditOp::DeleteText { y, x: from, text: old.to_string() });
+                        self.editor_apply(EditOp::InsertText { y, x: from, text: new });
+                    }
+                }
+                (self.cx, self.cy) = start;
+                self.history.end_group((self.cx, self.cy));
+            }
+        }
+    }
+
+    fn region_text(&self, region: Region) -> String {
+        if region.linewise {
+            self.editor_text_range((0, region.start.1), (usize::MAX, region.end.1))
+        } else {
+            self.editor_text_range(region.start, region.end)
+        }
+    }
+
+    fn shift_line(&mut self, y: usize, right: bool) {
+        let line = &self.erow[y].chars;
+        if right {
+            if !line.is_empty() {
+                let text = if self.options.expand_tab {
+                    " ".repeat(self.options.shift_width)
+                } else {
+                    "\t".to_string()
+                };
+                self.editor_apply(EditOp::InsertText { y, x: 0, text });
+            }
+        } else {
+            let text = if line.starts_with('\t') {
+                "\t".to_string()
+            } else {
+                let spaces = line.len() - line.trim_start_matches(' ').len();
+                " ".repeat(spaces.min(self.options.shift_width))
+            };
+            if !text.is_empty() {
+                self.editor_apply(EditOp::DeleteText { y, x: 0, text });
+            }
+        }
+    }
+}
+
+// Span of the run of same-class characters (word, punctuation or blanks)
+// containing byte `x`.
+fn word_span(line: &str, x: usize, big: bool) -> Option<(usize, usize)> {
+    if x >= line.len() {
+        return None;
+    }
+    let class = |c: char| motion::char_class(c, big);
+    let here = class(line[x..].chars().next()?);
+
+    let start = line[..x]
+        .char_indices()
+        .rev()
+        .take_while(|&(_, c)| class(c) == here)
+        .last()
+        .map_or(x, |(i, _)| i);
+    let end = line[x..]
+        .char_indices()
+        .find(|&(_, c)| class(c) != here)
+        .map_or(line.len(), |(i, _)| x + i);
+    Some((start, end))
+}
+
+// Byte offsets of the quote pair containing `x`, or the first pair after it.
+fn quote_span(line: &str, x: usize, quote: char) -> Option<(usize, usize)> {
+    let mut quotes = Vec::new();
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == quote {
+            quotes.push(i);
+        }
+    }
+
+    quotes
+        .chunks_exact(2)
+        .map(|pair| (pair[0], pair[1]))
+        .find(|&(_, close)| close >= x)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::EditorConfig;
+
+    #[test]
+    fn a_count_before_the_motion_deletes_that_many_words() {
+        let mut editor = EditorConfig::with_lines(&["one two three four"]);
+        editor.type_keys("d3w");
+        assert_eq!(editor.lines(), ["four"]);
+        assert_eq!((editor.cx, editor.cy), (0, 0));
+    }
+
+    #[test]
+    fn ci_quote_changes_the_first_string_on_the_line() {
+        let mut editor = EditorConfig::with_lines(&[r#"say "hello \"you\"" now"#]);
+        editor.type_keys("ci\"bye\x1b");
+        assert_eq!(editor.lines(), [r#"say "bye" now"#]);
+        assert_eq!((editor.cx, editor.cy), (7, 0));
+    }
+
+    #[test]
+    fn a_quote_takes_blanks_after_or_else_before() {
+        let mut editor = EditorConfig::with_lines(&[r#"x = "a"  + 1"#, r#"x = "a""#]);
+        editor.type_keys("5lda\"");
+        assert_eq!(editor.lines()[0], "x = + 1");
+        editor.type_keys("j$da\"");
+        assert_eq!(editor.lines()[1], "x =");
+        assert_eq!((editor.cx, editor.cy), (2, 1));
+    }
+
+    #[test]
+    fn daw_at_the_end_of_a_line_takes_the_blank_before() {
+        let mut editor = EditorConfig::with_lines(&["one two three", "next"]);
+        editor.type_keys("$daw");
+        assert_eq!(editor.lines(), ["one two", "next"]);
+        assert_eq!((editor.cx, editor.cy), (6, 0));
+    }
+
+    #[test]
+    fn an_operator_to_a_mark_inside_a_changed_character_does_not_panic() {
+        // The mark stays at byte 2, which is now inside the é
+        let mut editor = EditorConfig::with_lines(&["abcd"]);
+        editor.type_keys("llma0ccxéy\x1b0y`a");
+        assert_eq!(editor.registers.get(None).map(|r| r.text.as_str()), Some("x"));
+        editor.type_keys("$d`a");
+        assert_eq!(editor.lines(), ["xy"]);
+        assert_eq!((editor.cx, editor.cy), (1, 0));
+    }
+
+    #[test]
+    fn an_operator_to_a_mark_goes_up_to_the_marked_character() {
+        let mut editor = EditorConfig::with_lines(&["abcd"]);
+        editor.type_keys("llma0d`a");
+        assert_eq!(editor.lines(), ["cd"]);
+    }
+}
@@ -0,0 +1,244 @@
+// Editor settings changed with :set.
+//
+// Every option has a long and a short name like in Vim. Arguments take the
+// usual forms: `name` and `noname` for booleans, `invname` or `name!` to
+// toggle, `name=value` for the rest and `name?` to show the current value.
+// Spaces in a value are written `\ `, as in `:set makeprg=cargo\ build`.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptionValue {
+    Bool(bool),
+    Number(usize),
+    Text(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct EditorOptions {
+    pub expand_tab: bool,   // > indents with spaces rather than a tab
+    pub shift_width: usize, // columns added or removed by > and <
+    pub hlsearch: bool,     // keep the matches of the last search highlighted
+    pub ignore_case: bool,  // searches ignore case...
+    pub smart_case: bool,   // ...unless the pattern has an uppercase letter
+    pub whole_word: bool,   // searches only match whole words
+    pub literal: bool,      // search patterns are plain text, not regexes
+    pub make_program: String, // shell command run by :make
+    pub lsp_server: String,   // language server for Rust files, empty for none
+    pub number: bool,         // show line numbers
+    pub theme: String,        // colours, from theme.rs
+}
+
+impl Default for EditorOptions {
+    fn default() -> Self {
+        EditorOptions {
+            expand_tab: true,
+            shift_width: 4,
+            hlsearch: true,
+            ignore_case: true,
+            smart_case: true,
+            whole_word: false,
+            literal: false,
+            make_program: "cargo check --message-format=short".to_string(),
+            lsp_server: "rust-analyzer".to_string(),
+            number: false,
+            theme: "default".to_string(),
+        }
+    }
+}
+
+// Long name, short name
+const OPTION_NAMES: &[(&str, &str)] = &[
+    ("expandtab", "et"),
+    ("hlsearch", "hls"),
+    ("ignorecase", "ic"),
+    ("literal", "lit"),
+    ("lspserver", "lsp"),
+    ("makeprg", "mp"),
+    ("number", "nu"),
+    ("shiftwidth", "sw"),
+    ("smartcase", "scs"),
+    ("theme", "th"),
+    ("wholeword", "ww"),
+];
+
+impl EditorOptions {
+    fn long_name(name: &str) -> Option<&'static str> {
+        OPTION_NAMES
+            .iter()
+            .find(|(long, short)| name == *long || name == *short)
+            .map(|(long, _)| *long)
+    }
+
+    pub fn get(&self, name: &str) -> Option<OptionValue> {
+        let value = match Self::long_name(name)? {
+            "expandtab" => OptionValue::Bool(self.expand_tab),
+            "hlsearch" => OptionValue::Bool(self.hlsearch),
+            "ignorecase" => OptionValue::Bool(self.ignore_case),
+            "literal" => OptionValue::Bool(self.literal),
+            "lspserver" => OptionValue::Text(self.lsp_server.clone()),
+            "makeprg" => OptionValue::Text(self.make_program.clone()),
+            "number" => OptionValue::Bool(self.number),
+            "shiftwidth" => OptionValue::Number(self.shift_width),
+            "smartcase" => OptionValue::Bool(self.smart_case),
+            "theme" => OptionValue::Text(self.theme.clone()),
+            "wholeword" => OptionValue::Bool(self.whole_word),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn put(&mut self, name: &str, value: OptionValue) -> Result<(), String> {
+        match (name, value) {
+            ("expandtab", OptionValue::Bool(on)) => self.expand_tab = on,
+            ("hlsearch", OptionValue::Bool(on)) => self.hlsearch = on,
+            ("ignorecase", OptionValue::Bool(on)) => self.ignore_case = on,
+            ("literal", OptionValue::Bool(on)) => self.literal = on,
+            ("number", OptionValue::Bool(on)) => self.number = on,
+            ("smartcase", OptionValue::Bool(on)) => self.smart_case = on,
+            ("wholeword", OptionValue::Bool(on)) => self.whole_word = on,
+            ("shiftwidth", OptionValue::Number(n)) if n > 0 => self.shift_width = n,
+            ("shiftwidth", _) => return Err("shiftwidth must be a positive number".to_string()),
+            ("makeprg", OptionValue::Text(text)) if !text.trim().is_empty() => self.make_program = text,
+            ("makeprg", _) => return Err("makeprg must not be empty".to_string()),
+            ("lspserver", OptionValue::Text(text)) => self.lsp_server = text,
+            ("theme", OptionValue::Text(text)) if !text.is_empty() => self.theme = text,
+            ("theme", _) => return Err("theme must not be empty".to_string()),
+            _ => return Err(format!("Unknown option: {}", name)),
+        }
+        Ok(())
+    }
+
+    // Apply one :set argument. Returns text to show for queries.
+    pub fn set(&mut self, arg: &str) -> Result<Option<String>, String> {
+        if let Some((name, value)) = arg.split_once('=') {
+            let long = Self::long_name(name).ok_or(format!("Unknown option: {}", name))?;
+            let value = match self.get(long) {
+                Some(OptionValue::Number(_)) => OptionValue::Number(
+                    value.parse().map_err(|_| format!("Number required: {}", arg))?,
+                ),
+                Some(OptionValue::Text(_)) => OptionValue::Text(value.to_string()),
+                _ => return Err(format!("Invalid argument: {}", arg)),
+            };
+            return self.put(long, value).map(|_| None);
+        }
+
+        let (name, query, toggle) = if let Some(name) = arg.strip_suffix('?') {
+            (name, true, false)
+        } else if let Some(name) = arg.strip_suffix('!') {
+            (name, false, true)
+        } else {
+            (arg, false, false)
+        };
+
+        if let Some(long) = Self::long_name(name) {
+            return match (self.get(long), query, toggle) {
+                (Some(value), true, _)
+                | (Some(value @ (OptionValue::Number(_) | OptionValue::Text(_))), false, false) => {
+                    Ok(Some(Self::describe(long, &value)))
+                }
+                (Some(OptionValue::Bool(on)), false, toggle) => {
+                    self.put(long, OptionValue::Bool(!toggle || !on)).map(|_| None)
+                }
+                _ => Err(format!("Invalid argument: {}", arg)),
+            };
+        }
+
+        // noname and invname for booleans
+        let (name, value) = if let Some(name) = name.strip_prefix("no") {
+            (name, Some(false))
+        } else if let Some(name) = name.strip_prefix("inv") {
+            (name, None)
+        } else {
+            return Err(format!("Unknown option: {}", name));
+        };
+        let long = Self::long_name(name).ok_or(format!("Unknown option: {}", arg))?;
+        match self.get(long) {
+            Some(OptionValue::Bool(on)) => {
+                self.put(long, OptionValue::Bool(value.unwrap_or(!on))).map(|_| None)
+            }
+            _ => Err(format!("Invalid argument: {}", arg)),
+        }
+    }
+
+    // All options, for a bare :set.
+    pub fn show_all(&self) -> String {
+        OPTION_NAMES
+            .iter()
+            .filter_map(|(long, _)| Some(Self::describe(long, &self.get(long)?)))
+            .collect::<Vec<_>>()
+            .join("  ")
+    }
+
+    fn describe(name: &str, value: &OptionValue) -> String {
+        match value {
+            OptionValue::Bool(true) => name.to_string(),
+            OptionValue::Bool(false) => format!("no{}", name),
+            OptionValue::Number(n) => format!("{}={}", name, n),
+            OptionValue::Text(text) => format!("{}={}", name, text),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn booleans_are_set_cleared_toggled_and_shown() {
+        let mut options = EditorOptions::default();
+        assert_eq!(options.set("number"), Ok(None));
+        assert!(options.number);
+        assert_eq!(options.set("nonu"), Ok(None));
+        assert!(!options.number);
+        assert_eq!(options.set("invnumber"), Ok(None));
+        assert!(options.number);
+        assert_eq!(options.set("nu!"), Ok(None));
+        assert!(!options.number);
+        assert_eq!(options.set("number?"), Ok(Some("nonumber".to_string())));
+        assert_eq!(options.set("hls?"), Ok(Some("hlsearch".to_string())));
+    }
+
+    #[test]
+    fn values_are_set_with_equals_and_shown_by_name() {
+        let mut options = EditorOptions::default();
+        assert_eq!(options.set("sw=2"), Ok(None));
+        assert_eq!(options.shift_width, 2);
+        assert_eq!(options.set("shiftwidth"), Ok(Some("shiftwidth=2".to_string())));
+        assert_eq!(options.set("sw?"), Ok(Some("shiftwidth=2".to_string())));
+
+        assert_eq!(options.set("mp=make -j4"), Ok(None));
+        assert_eq!(options.make_program, "make -j4");
+        assert_eq!(options.set("makeprg"), Ok(Some("makeprg=make -j4".to_string())));
+        // A text option may be emptied where that means something
+        assert_eq!(options.set("lsp="), Ok(None));
+        assert_eq!(options.lsp_server, "");
+
+        let all = EditorOptions::default().show_all();
+        assert!(all.starts_with("expandtab  hlsearch  ignorecase  noliteral  lspserver=rust-analyzer"));
+    }
+
+    #[test]
+    fn bad_arguments_are_refused_and_change_nothing() {
+        let mut options = EditorOptions::default();
+        let refused = |options: &mut EditorOptions, arg: &str, message: &str| {
+            assert_eq!(options.set(arg), Err(message.to_string()));
+        };
+        refused(&mut options, "foo", "Unknown option: foo");
+        refused(&mut options, "nofoo", "Unknown option: nofoo");
+        refused(&mut options, "foo=1", "Unknown option: foo");
+        refused(&mut options, "sw=0", "shiftwidth must be a positive number");
+        refused(&mut options, "sw=two", "Number required: sw=two");
+        refused(&mut options, "sw!", "Invalid argument: sw!");
+        refused(&mut options, "mp=", "makeprg must not be empty");
+        refused(&mut options, "mp=  ", "makeprg must not be empty");
+        refused(&mut options, "th=", "theme must not be empty");
+        refused(&mut options, "number=1", "Invalid argument: number=1");
+        refused(&mut options, "nomakeprg", "Invalid argument: nomakeprg");
+        refused(&mut options, "invsw", "Invalid argument: invsw");
+
+        let default = EditorOptions::default();
+        assert_eq!(options.shift_width, default.shift_width);
+        assert_eq!(options.make_program, default.make_program);
+        assert_eq!(options.theme, default.theme);
+        assert!(!options.number);
+    }
+}
@@ -0,0 +1,513 @@
+// The line editor behind editor_prompt, for search, : commands and file
+// names:
+//
+//   Left / Right, Home / End (Ctrl-A / Ctrl-E)   move the cursor
+//   Backspace / Delete                            delete a character
+//   Ctrl-W                                        delete the word before the cursor
+//   Ctrl-U                                        delete everything before the cursor
+//   Ctrl-R {reg}                                  insert the contents of a register
+//   Up / Down                                     older / newer history entries
+//                                                 that start with what was typed
+//
+// Each kind of prompt has a history of its own. The histories are kept in
+// $XDG_STATE_HOME/kibi/history (falling back to ~/.local/state/kibi/) so
+// they survive between sessions.
+
+use crate::grapheme;
+use crate::{EditorConfig, EditorKey};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use unicode_segmentation::UnicodeSegmentation;
+
+const HISTORY_MAGIC: &str = "kibi-history 1";
+const HISTORY_SIZE: usize = 100; // entries kept per kind
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryKind {
+    Search,
+    Command,
+    File,
+}
+
+impl HistoryKind {
+    const ALL: [HistoryKind; 3] = [HistoryKind::Search, HistoryKind::Command, HistoryKind::File];
+
+    fn name(self) -> &'static str {
+        match self {
+            HistoryKind::Search => "search",
+            HistoryKind::Command => "command",
+            HistoryKind::File => "file",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PromptHistory {
+    entries: [Vec<String>; 3], // oldest first, indexed by HistoryKind
+    path: Option<PathBuf>,     // where to save; None keeps it in memory only
+}
+
+// Where the histories are kept by default.
+pub fn history_file() -> Option<PathBuf> {
+    let state_home = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(state_home.join("kibi").join("history"))
+}
+
+impl PromptHistory {
+    // The histories saved in `path` by earlier sessions, saving back there.
+    // A missing or unreadable file just means starting with empty ones.
+    pub fn load(path: PathBuf) -> PromptHistory {
+        let text = fs::read_to_string(&path).ok();
+        let mut history = PromptHistory { path: Some(path), ..Default::default() };
+        let Some(text) = text else {
+            return history;
+        };
+        let mut lines = text.lines();
+        if lines.next() != Some(HISTORY_MAGIC) {
+            return history;
+        }
+        for line in lines {
+            let Some((name, entry)) = line.split_once('\t') else {
+                continue;
+            };
+            if let Some(kind) = HistoryKind::ALL.into_iter().find(|kind| kind.name() == name) {
+                history.entries[kind as usize].push(entry.to_string());
+            }
+        }
+        for entries in &mut history.entries {
+            let excess = entries.len().saturating_sub(HISTORY_SIZE);
+            entries.drain(..excess);
+        }
+        history
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut out = String::new();
+        out.push_str(HISTORY_MAGIC);
+        out.push('\n');
+        for kind in HistoryKind::ALL {
+            for entry in &self.entries[kind as usize] {
+                out.push_str(&format!("{}\t{}\n", kind.name(), entry));
+            }
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, out)
+    }
+
+    // Remember `entry` as the newest of its kind, dropping any older copy.
+    pub fn add(&mut self, kind: HistoryKind, entry: &str) {
+        if entry.is_empty() || entry.contains('\n') {
+            return;
+        }
+        let entries = &mut self.entries[kind as usize];
+        entries.retain(|old| old != entry);
+        entries.push(entry.to_string());
+        let excess = entries.len().saturating_sub(HISTORY_SIZE);
+        entries.drain(..excess);
+        // Losing the history is not worth interrupting anyone for
+        let _ = self.save();
+    }
+
+    fn entries(&self, kind: HistoryKind) -> &[String] {
+        &self.entries[kind as usize]
+    }
+}
+
+// A single line of text with a cursor, edited a key at a time.
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    text: String,
+    cursor: usize, // byte offset into text
+}
+
+impl LineEditor {
+    pub fn new(text: &str) -> LineEditor {
+        LineEditor { text: text.to_string(), cursor: text.len() }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // Screen columns before the cursor, for placing it on screen.
+    pub fn cursor_column(&self) -> usize {
+        grapheme::str_width(&self.text[..self.cursor])
+    }
+
+    // Replace the whole line, with the cursor at the end.
+    pub fn set(&mut self, text: &str) {
+        self.text = text.to_string();
+        self.cursor = self.text.len();
+    }
+
+    pub fn insert_str(&mut self, s: &str) {
+        self.text.insert_str(self.cursor, s);
+        self.cursor += s.len();
+    }
+
+    // Ctrl-W: blanks before the cursor, then a word or a run of punctuation.
+    fn delete_word(&mut self) {
+        let before = &self.text[..self.cursor];
+        let trimmed = before.trim_end();
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let start = match trimmed.chars().next_back() {
+            Some(last) if is_word(last) => trimmed.trim_end_matches(is_word).len(),
+            Some(_) => trimmed.trim_end_matches(|c: char| !is_word(c) && !c.is_whitespace()).len(),
+            None => 0,
+        };
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    // Apply an editing key. Returns false for keys the line editor does not
+    // use, which are left to the prompt.
+    pub fn edit(&mut self, key: EditorKey) -> bool {
+        match key {
+            EditorKey::ArrowLeft => self.cursor = grapheme::prev_boundary(&self.text, self.cursor),
+            EditorKey::ArrowRight => self.cursor = grapheme::next_boundary(&self.text, self.cursor),
+            EditorKey::HomeKey | EditorKey::Other(0x01) => self.cursor = 0,
+            EditorKey::EndKey | EditorKey::Other(0x05) => self.cursor = self.text.len(),
+            EditorKey::Backspace | EditorKey::CtrlH => {
+                let start = grapheme::prev_boundary(&self.text, self.cursor);
+                self.text.replace_range(start..self.cursor, "");
+                self.cursor = start;
+            }
+            EditorKey::Delete => {
+                let end = grapheme::next_boundary(&self.text, self.cursor);
+                self.text.replace_range(self.cursor..end, "");
+            }
+            EditorKey::Other(0x17) => self.delete_word(), // Ctrl-W
+            EditorKey::Other(0x15) => {
+                // Ctrl-U
+                self.text.replace_range(..self.cursor, "");
+                self.cursor = 0;
+            }
+            EditorKey::Char(ch) => self.insert_str(ch.encode_utf8(&mut [0; 4])),
+            EditorKey::Other(byte) if byte.is_ascii_graphic() || byte == b' ' => {
+                self.insert_str(&(byte as char).to_string());
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl EditorConfig {
+    pub(crate) fn editor_prompt<F>(
+        &mut self,
+        prompt: &str,
+        initial: &str,
+        history: Option<HistoryKind>,
+        callback: Option<F>,
+    ) -> Option<String>
+    where
+        F: FnMut(&mut Self, &mut String, EditorKey),
+    {
+        self.editor_prompt_with(prompt, initial, history, false, callback)
+    }
+
+    // editor_prompt, where Enter on an empty line gives "" when `allow_empty`.
+    // The callback sees the line after every key and may change it.
+    pub(crate) fn editor_prompt_with<F>(
+        &mut self,
+        prompt: &str,
+        initial: &str,
+        history: Option<HistoryKind>,
+        allow_empty: bool,
+        mut callback: Option<F>,
+    ) -> Option<String>
+    where
+        F: FnMut(&mut Self, &mut String, EditorKey),
+    {
+        let mut line = LineEditor::new(initial);
+        // While going through the history: the entry shown, and the text
+        // typed before the first Up, which entries have to start with
+        let mut browsing: Option<(usize, String)> = None;
+
+        let result = loop {
+            self.editor_show_prompt(prompt, &line);
+            if self.refresh_screen().is_err() {
+                break None;
+            }
+
+            let c = match self.read_key() {
+                Ok(key) => key,
+                Err(_) => break None,
+            };
+            // A read that timed out is no key, and callbacks mustn't take it
+            // for one: it comes every tenth of a second while idle
+            if c == EditorKey::Other(0) {
+                continue;
+            }
+
+            match c {
+                EditorKey::EnterKey if allow_empty || !line.text().is_empty() => {
+                    break Some(line.text().to_string());
+                }
+                EditorKey::Escape => break None,
+                EditorKey::ArrowUp | EditorKey::ArrowDown => {
+                    if let Some(kind) = history {
+                        self.prompt_history_step(kind, &mut line, &mut browsing, c == EditorKey::ArrowUp);
+                    }
+                }
+                EditorKey::CtrlR => {
+                    // The register name is the next key
+                    self.editor_show_prompt(prompt, &line);
+                    self.refresh_screen().ok();
+                    let name = loop {
+                        match self.read_key() {
+                            Ok(EditorKey::Other(0)) => continue, // nothing typed yet
+                            Ok(EditorKey::Other(byte)) => break Some(byte as char),
+                            Ok(EditorKey::Char(c)) => break Some(c),
+                            _ => break None,
+                        }
+                    };
+                    if let Some(register) = name.and_then(|name| self.registers.get(Some(name))) {
+                        let text = register.text.replace('\n', " ");
+                        line.insert_str(&text);
+                    }
+                }
+                key => {
+                    line.edit(key);
+                }
+            }
+            if !matches!(c, EditorKey::ArrowUp | EditorKey::ArrowDown) {
+                browsing = None;
+            }
+
+            // Call callback after each keypress
+            if let Some(ref mut cb) = callback {
+                let mut text = line.text().to_string();
+                cb(self, &mut text, c);
+                if text != line.text() {
+                    line.set(&text);
+                }
+            }
+        };
+
+        self.prompt_cursor = None;
+        self.editor_set_status_msg("");
+        if let (Some(kind), Some(text)) = (history, &result) {
+            self.prompt_history.add(kind, text);
+        }
+        result
+    }
+
+    // Put the prompt in the message bar with the cursor in it, scrolled so
+    // that the cursor stays on screen.
+    fn editor_show_prompt(&mut self, prompt: &str, line: &LineEditor) {
+        let column = grapheme::str_width(prompt) + line.cursor_column();
+        let full = format!("{prompt}{}", line.text());
+        // Leave out whole characters from the left until the cursor fits
+        let mut skip = (0, 0); // bytes, columns
+        for g in full.graphemes(true) {
+            if skip.1 + self.screen_cols > column {
+                break;
+            }
+            skip = (skip.0 + g.len(), skip.1 + grapheme::width(g));
+        }
+        self.editor_set_status_msg(&full[skip.0..]);
+        self.prompt_cursor = Some(column - skip.1);
+    }
+
+    fn prompt_history_step(
+        &mut self,
+        kind: HistoryKind,
+        line: &mut LineEditor,
+        browsing: &mut Option<(usize, String)>,
+        older: bool,
+    ) {
+        let entries = self.prompt_history.entries(kind);
+        let (at, typed) = browsing.take().unwrap_or((entries.len(), line.text().to_string()));
+        let found = if older {
+            entries[..at].iter().rposition(|entry| entry.starts_with(&typed))
+        } else {
+            entries.iter().skip(at + 1).position(|entry| entry.starts_with(&typed)).map(|i| at + 1 + i)
+        };
+        match found {
+            Some(i) => {
+                line.set(&entries[i]);
+                *browsing = Some((i, typed));
+            }
+            None if older => *browsing = Some((at, typed)), // stay on the oldest match
+            None => {
+                // Down past the newest entry goes back to what was typed
+                line.set(&typed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::register::{Register, RegisterWrite};
+
+    #[test]
+    fn ctrl_r_waits_for_the_register_name() {
+        let mut editor = EditorConfig::with_size(80, 24);
+        let text = "word".to_string();
+        editor.registers.store(Some('a'), RegisterWrite::Yank, Register { text, linewise: false });
+
+        editor.key_queue.extend([
+            EditorKey::Other(b'>'),
+            EditorKey::CtrlR,
+            EditorKey::Other(0),
+            EditorKey::Other(b'a'),
+            EditorKey::EnterKey,
+        ]);
+        let line = editor.editor_prompt("", "", None, None::<fn(&mut EditorConfig, &mut String, EditorKey)>);
+        assert_eq!(line.as_deref(), Some(">word"));
+    }
+
+    // The line after `keys`, starting from `text` with the cursor at its end.
+    fn edit(text: &str, keys: &[EditorKey]) -> LineEditor {
+        let mut line = LineEditor::new(text);
+        for &key in keys {
+            line.edit(key);
+        }
+        line
+    }
+
+    fn typed(text: &str) -> Vec<EditorKey> {
+        text.bytes().map(EditorKey::Other).collect()
+    }
+
+    #[test]
+    fn the_cursor_moves_by_character_and_to_either_end() {
+        use EditorKey::{ArrowLeft, ArrowRight, EndKey, HomeKey};
+        let line = edit("héllo", &[HomeKey, ArrowRight, ArrowRight, EditorKey::Char('X')]);
+        assert_eq!((line.text(), line.cursor, line.cursor_column()), ("héXllo", 4, 3));
+
+        let ctrl_a = EditorKey::Other(0x01);
+        let ctrl_e = EditorKey::Other(0x05);
+        assert_eq!(edit("héllo", &[ctrl_a]).cursor, 0);
+        assert_eq!(edit("héllo", &[ctrl_a, ArrowRight, ArrowRight, ctrl_e]).cursor, 6);
+        assert_eq!(edit("héllo", &[HomeKey, EndKey, ArrowLeft]).cursor, 5);
+        // Neither end is passed
+        assert_eq!(edit("ab", &[ArrowRight]).cursor, 2);
+        assert_eq!(edit("ab", &[HomeKey, ArrowLeft]).cursor, 0);
+    }
+
+    #[test]
+    fn backspace_and_delete_take_a_whole_character() {
+        use EditorKey::{ArrowLeft, Backspace, Delete};
+        let line = edit("aé字b", &[ArrowLeft, Backspace]);
+        assert_eq!((line.text(), line.cursor), ("aéb", 3));
+        let line = edit("aé字b", &[ArrowLeft, Backspace, ArrowLeft, Delete]);
+        assert_eq!((line.text(), line.cursor), ("ab", 1));
+        // An accent typed as its own code point goes with its letter
+        assert_eq!(edit("xe\u{301}", &[Backspace]).text(), "x");
+        assert_eq!(edit("e\u{301}x", &[EditorKey::HomeKey, Delete]).text(), "x");
+        // Nothing to take at the ends
+        assert_eq!(edit("ab", &[Delete]).text(), "ab");
+        assert_eq!(edit("ab", &[EditorKey::HomeKey, Backspace]).text(), "ab");
+    }
+
+    #[test]
+    fn ctrl_w_takes_a_word_or_a_run_of_punctuation_and_the_blanks_after_it() {
+        let ctrl_w = EditorKey::Other(0x17);
+        let mut line = LineEditor::new("let x = foo.bar  ");
+        for expected in ["let x = foo.", "let x = foo", "let x = ", "let x ", "let ", ""] {
+            line.edit(ctrl_w);
+            assert_eq!(line.text(), expected);
+            assert_eq!(line.cursor, expected.len());
+        }
+
+        // Only what is before the cursor goes
+        let mut keys = vec![EditorKey::ArrowLeft; 5];
+        keys.push(ctrl_w);
+        let line = edit("one two three", &keys);
+        assert_eq!((line.text(), line.cursor), ("one three", 4));
+    }
+
+    #[test]
+    fn ctrl_u_takes_everything_before_the_cursor() {
+        let mut keys = vec![EditorKey::ArrowLeft; 4];
+        keys.push(EditorKey::Other(0x15));
+        let line = edit("path/to/file", &keys);
+        assert_eq!((line.text(), line.cursor), ("file", 0));
+        assert!(!LineEditor::new("").edit(EditorKey::EnterKey));
+    }
+
+    fn prompt(editor: &mut EditorConfig, kind: HistoryKind, keys: &[EditorKey]) -> Option<String> {
+        editor.key_queue.extend(keys.iter().copied().chain([EditorKey::EnterKey]));
+        editor.editor_prompt("", "", Some(kind), None::<fn(&mut EditorConfig, &mut String, EditorKey)>)
+    }
+
+    #[test]
+    fn up_and_down_go_through_the_entries_that_start_with_what_was_typed() {
+        use EditorKey::{ArrowDown as Down, ArrowUp as Up};
+        // What the prompt gives after typing "fo" and then `keys`
+        let browse = |kind: HistoryKind, keys: &[EditorKey]| {
+            let mut editor = EditorConfig::with_size(80, 24);
+            for entry in ["foo", "bar", "food"] {
+                editor.prompt_history.add(HistoryKind::Search, entry);
+            }
+            editor.prompt_history.add(HistoryKind::Command, "fox");
+            prompt(&mut editor, kind, &[typed("fo").as_slice(), keys].concat())
+        };
+
+        assert_eq!(browse(HistoryKind::Search, &[Up]).as_deref(), Some("food"));
+        assert_eq!(browse(HistoryKind::Search, &[Up, Up]).as_deref(), Some("foo"));
+        // The oldest match stays, and Down past the newest gives back what was typed
+        assert_eq!(browse(HistoryKind::Search, &[Up, Up, Up]).as_deref(), Some("foo"));
+        assert_eq!(browse(HistoryKind::Search, &[Up, Up, Down]).as_deref(), Some("food"));
+        assert_eq!(browse(HistoryKind::Search, &[Up, Down]).as_deref(), Some("fo"));
+        // Each kind keeps its own entries
+        assert_eq!(browse(HistoryKind::Command, &[Up]).as_deref(), Some("fox"));
+        assert_eq!(browse(HistoryKind::File, &[Up]).as_deref(), Some("fo"));
+    }
+
+    #[test]
+    fn an_entered_line_becomes_the_newest_entry() {
+        let mut editor = EditorConfig::with_size(80, 24);
+        for entry in ["a", "b"] {
+            editor.prompt_history.add(HistoryKind::Search, entry);
+        }
+        assert_eq!(prompt(&mut editor, HistoryKind::Search, &[EditorKey::ArrowUp; 2]).as_deref(), Some("a"));
+        assert_eq!(prompt(&mut editor, HistoryKind::Search, &typed("c")).as_deref(), Some("c"));
+        assert_eq!(editor.prompt_history.entries(HistoryKind::Search), ["b", "a", "c"]);
+    }
+
+    #[test]
+    fn histories_are_saved_and_read_back_by_kind() {
+        let dir = std::env::temp_dir().join(format!("kibi-history-{}", std::process::id()));
+        let path = dir.join("history");
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut history = PromptHistory::load(path.clone());
+        history.add(HistoryKind::Search, "a b");
+        history.add(HistoryKind::Command, "w");
+        history.add(HistoryKind::Search, "x");
+        history.add(HistoryKind::Search, "a b");
+        history.add(HistoryKind::File, "two\nlines"); // not kept
+        let history = PromptHistory::load(path.clone());
+        assert_eq!(history.entries(HistoryKind::Search), ["x", "a b"]);
+        assert_eq!(history.entries(HistoryKind::Command), ["w"]);
+        assert!(history.entries(HistoryKind::File).is_empty());
+
+        // Only the newest are kept
+        let mut history = PromptHistory::load(path.clone());
+        for n in 0..HISTORY_SIZE + 5 {
+            history.add(HistoryKind::Command, &n.to_string());
+        }
+        let history = PromptHistory::load(path.clone());
+        let commands = history.entries(HistoryKind::Command);
+        assert_eq!((commands.len(), commands[0].as_str()), (HISTORY_SIZE, "5"));
+
+        // A file that isn't a history is left alone
+        fs::write(&path, "search\tx\n").unwrap();
+        assert!(PromptHistory::load(path).entries(HistoryKind::Search).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
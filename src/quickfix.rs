@@ -0,0 +1,158 @@
+// The quickfix list: file positions produced by :grep or :make, shown as
+// a buffer of its own with one `file:line:col: text` line per entry. Enter
+// on a line opens that file at that position; :copen shows the list again
+// and :cnext / :cprevious go to the next or previous entry from anywhere.
+// The list buffer can be edited like any other, to drop lines from it.
+
+use crate::buffer::TextBuffer;
+use crate::mode::EditorMode;
+use crate::undo::EditHistory;
+use crate::EditorConfig;
+use std::fs;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize, // 1-based
+    pub col: usize,  // 1-based, in characters
+    pub text: String,
+}
+
+impl Location {
+    // Parse `file:line:col: text`; the column may be left out.
+    pub fn parse(s: &str) -> Option<Location> {
+        let mut parts = s.splitn(4, ':');
+        let file = parts.next().filter(|file| !file.is_empty())?;
+        let line = parts.next()?.trim().parse().ok()?;
+        let rest = parts.next()?;
+        let (col, text) = match rest.trim().parse() {
+            Ok(col) => (col, parts.next().unwrap_or("")),
+            Err(_) => (1, s.splitn(3, ':').nth(2).unwrap_or(rest)),
+        };
+        Some(Location { file: file.to_string(), line, col, text: text.trim().to_string() })
+    }
+}
+
+// Whether two names are for the same file, as src/a.rs and ./src/a.rs are.
+fn same_file(a: &str, b: &str) -> bool {
+    a == b || matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+#[derive(Debug, Default)]
+pub struct Quickfix {
+    title: String,
+    entries: Vec<Location>,
+    current: Option<usize>, // the entry last gone to
+    showing: bool, // the buffer holds the list rather than a file
+}
+
+impl Quickfix {
+    // Name for the status bar while the list is shown.
+    pub fn title(&self) -> Option<&str> {
+        self.showing.then_some(self.title.as_str())
+    }
+
+    pub fn showing(&self) -> bool {
+        self.showing
+    }
+
+    pub fn hide(&mut self) {
+        self.showing = false;
+    }
+}
+
+impl EditorConfig {
+    pub(crate) fn set_quickfix(&mut self, title: &str, entries: Vec<Location>) {
+        let showing = self.quickfix.showing;
+        self.quickfix = Quickfix { title: title.to_string(), entries, current: None, showing };
+    }
+
+    // Replace the buffer with the list, cursor on the current entry.
+    pub(crate) fn editor_show_quickfix(&mut self) -> Result<(), String> {
+        if self.quickfix.entries.is_empty() {
+            return Err("The quickfix list is empty".to_string());
+        }
+        if self.dirty > 0 && !self.quickfix.showing {
+            return Err("No write since last change".to_string());
+        }
+
+        let lines: Vec<String> = self
+            .quickfix
+            .entries
+            .iter()
+            .map(|loc| format!("{}:{}:{}: {}", loc.file, loc.line, loc.col, loc.text))
+            .collect();
+        self.filename = None;
+        self.erow = TextBuffer::from_text(&lines.join("\n"));
+        self.history = EditHistory::default();
+        self.dirty = 0;
+        self.editor_select_syntax_highlight();
+        self.quickfix.showing = true;
+
+        self.mode = EditorMode::Normal;
+        self.marks.clear();
+        (self.cx, self.cy, self.row_off, self.col_off) = (0, self.quickfix.current.unwrap_or(0), 0, 0);
+        Ok(())
+    }
+
+    // Enter while the list is shown: go to the entry under the cursor.
+    pub(crate) fn quickfix_select(&mut self) {
+        let Some(loc) = self.erow.get(self.cy).and_then(|row| Location::parse(&row.chars)) else {
+            self.editor_set_status_msg("Not a file position");
+            return;
+        };
+        let result = match self.quickfix.entries.iter().position(|entry| *entry == loc) {
+            Some(index) => self.editor_quickfix_go(index),
+            None => self.editor_open_location(&loc),
+        };
+        if let Err(msg) = result {
+            self.editor_set_status_msg(msg);
+        }
+    }
+
+    // Go to entry `index` and show which one it is.
+    pub(crate) fn editor_quickfix_go(&mut self, index: usize) -> Result<(), String> {
+        let loc = self.quickfix.entries.get(index).cloned().ok_or("No such entry")?;
+        self.editor_open_location(&loc)?;
+        self.quickfix.current = Some(index);
+        let len = self.quickfix.entries.len();
+        self.editor_set_status_msg(format!("({} of {}) {}", index + 1, len, loc.text));
+        Ok(())
+    }
+
+    // :cnext and :cprevious. Before any entry has been visited both go to
+    // the first one.
+    pub(crate) fn ex_quickfix_step(&mut self, forward: bool) -> Result<(), String> {
+        let len = self.quickfix.entries.len();
+        if len == 0 {
+            return Err("No errors".to_string());
+        }
+        let index = match (self.quickfix.current, forward) {
+            (None, _) => 0,
+            (Some(i), true) if i + 1 < len => i + 1,
+            (Some(i), false) if i > 0 => i - 1,
+            _ => return Err("No more items".to_string()),
+        };
+        self.editor_quickfix_go(index)
+    }
+
+    // Open the file of `loc` and put the cursor on the position.
+    pub(crate) fn editor_open_location(&mut self, loc: &Location) -> Result<(), String> {
+        // Edits to the list itself don't need saving
+        let same = self.filename.as_deref().is_some_and(|name| same_file(name, &loc.file));
+        if self.dirty > 0 && !self.quickfix.showing && !same {
+            return Err("No write since last change".to_string());
+        }
+
+        if self.quickfix.showing || !same {
+            self.editor_open(&loc.file).map_err(|e| format!("Can't open {}: {}", loc.file, e))?;
+            self.mode = EditorMode::Normal;
+            self.marks.clear();
+            (self.row_off, self.col_off) = (0, 0);
+        }
+        self.cy = (loc.line.max(1) - 1).min(self.erow.len().saturating_sub(1));
+        let line = self.erow.get(self.cy).map_or("", |row| row.chars.as_str());
+        self.cx = line.char_indices().nth(loc.col.max(1) - 1).map_or(line.len(), |(i, _)| i);
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+// Registers for yank, delete and put, modelled on Vim's:
+//
+//   ""         unnamed, used by p and P when no register is given
+//   "a - "z    named; "A - "Z append to the same register
+//   "0         the last yank
+//   "1 - "9    the last deletes of a line or more, shifted down by each new one
+//   "-         the last delete within a line
+//   "_         black hole, anything written to it is dropped
+//
+// Recording a macro with q{reg} writes the keys to the register as text.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Register {
+    pub text: String, // lines separated by '\n', no trailing newline
+    pub linewise: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterWrite {
+    Yank,
+    Delete,
+}
+
+#[derive(Debug, Default)]
+pub struct Registers {
+    unnamed: Option<Register>,
+    named: [Option<Register>; 26],
+    numbered: [Option<Register>; 10],
+    small_delete: Option<Register>,
+}
+
+impl Registers {
+    pub fn is_valid(name: char) -> bool {
+        name.is_ascii_alphanumeric() || matches!(name, '"' | '_' | '-')
+    }
+
+    pub fn get(&self, name: Option<char>) -> Option<&Register> {
+        match name {
+            None | Some('"') => self.unnamed.as_ref(),
+            Some(c @ 'a'..='z') => self.named[c as usize - 'a' as usize].as_ref(),
+            Some(c @ 'A'..='Z') => self.named[c as usize - 'A' as usize].as_ref(),
+            Some(c @ '0'..='9') => self.numbered[c as usize - '0' as usize].as_ref(),
+            Some('-') => self.small_delete.as_ref(),
+            _ => None,
+        }
+    }
+
+    // Store text written to register `name` (None for no register given).
+    pub fn store(&mut self, name: Option<char>, kind: RegisterWrite, value: Register) {
+        let stored = match name {
+            Some('_') => return,
+            None | Some('"') => {
+                match kind {
+                    RegisterWrite::Yank => self.numbered[0] = Some(value.clone()),
+                    RegisterWrite::Delete if !value.linewise && !value.text.contains('\n') => {
+                        self.small_delete = Some(value.clone());
+                    }
+                    RegisterWrite::Delete => {
+                        self.numbered[1..].rotate_right(1);
+                        self.numbered[1] = Some(value.clone());
+                    }
+                }
+                value
+            }
+            Some(c @ 'a'..='z') => {
+                self.named[c as usize - 'a' as usize] = Some(value.clone());
+                value
+            }
+            Some(c @ 'A'..='Z') => {
+                let slot = &mut self.named[c as usize - 'A' as usize];
+                let merged = match slot.take() {
+                    Some(old) => append(old, value),
+                    None => value,
+                };
+                *slot = Some(merged.clone());
+                merged
+            }
+            Some(c @ '0'..='9') => {
+                self.numbered[c as usize - '0' as usize] = Some(value.clone());
+                value
+            }
+            Some('-') => {
+                self.small_delete = Some(value.clone());
+                value
+            }
+            Some(_) => return,
+        };
+        self.unnamed = Some(stored);
+    }
+
+    // Store a recorded macro in `name`; unlike a yank it leaves "" alone.
+    pub fn store_macro(&mut self, name: char, text: String) {
+        let unnamed = self.unnamed.take();
+        self.store(Some(name), RegisterWrite::Yank, Register { text, linewise: false });
+        self.unnamed = unnamed;
+    }
+}
+
+// Appending to a register: if either side is linewise the result is too,
+// with the new text on lines of its own.
+fn append(old: Register, new: Register) -> Register {
+    if old.linewise || new.linewise {
+        Register {
+            text: format!("{}\n{}", old.text, new.text),
+            linewise: true,
+        }
+    } else {
+        Register {
+            text: old.text + &new.text,
+            linewise: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EditorConfig;
+
+    fn text(registers: &Registers, name: char) -> Option<&str> {
+        registers.get(Some(name)).map(|r| r.text.as_str())
+    }
+
+    #[test]
+    fn deletes_within_a_line_go_to_minus_and_leave_the_ring_alone() {
+        let mut editor = EditorConfig::with_lines(&["one two", "three"]);
+        editor.type_keys("dd");
+        editor.type_keys("dw");
+        let registers = &editor.registers;
+        assert_eq!(text(registers, '1'), Some("one two"));
+        assert_eq!(text(registers, '2'), None);
+        assert_eq!(text(registers, '-'), Some("three"));
+        assert_eq!(text(registers, '"'), Some("three"));
+
+        // Unless a delete takes a line break with it
+        let mut editor = EditorConfig::with_lines(&["ab", "cd"]);
+        editor.type_keys("lvjd");
+        assert_eq!(text(&editor.registers, '1'), Some("b\ncd"));
+        assert_eq!(text(&editor.registers, '-'), None);
+    }
+
+    #[test]
+    fn an_uppercase_name_appends() {
+        let mut editor = EditorConfig::with_lines(&["one", "two", "three"]);
+        editor.type_keys("\"ayyj\"Ayy");
+        let a = editor.registers.get(Some('a')).unwrap();
+        assert_eq!((a.text.as_str(), a.linewise), ("one\ntwo", true));
+
+        // Charwise onto charwise stays on the line
+        editor.type_keys("\"byw\"Byw");
+        assert_eq!(text(&editor.registers, 'b'), Some("twotwo"));
+    }
+
+    #[test]
+    fn linewise_text_puts_on_its_own_line_and_charwise_after_the_cursor() {
+        let mut editor = EditorConfig::with_lines(&["one", "two"]);
+        editor.type_keys("yyjp");
+        assert_eq!(editor.lines(), ["one", "two", "one"]);
+        assert_eq!((editor.cx, editor.cy), (0, 2));
+
+        editor.type_keys("ggywjp");
+        assert_eq!(editor.lines(), ["one", "tonewo", "one"]);
+        assert_eq!((editor.cx, editor.cy), (3, 1));
+    }
+}
@@ -0,0 +1,239 @@
+// Dot-repeat: `.` replays the last buffer-modifying command.
+//
+// A change is the command that started it plus whatever was typed in Insert
+// mode before Esc, recorded as it reaches editor_insert_char and friends.
+// The change being built lives in `pending_change` until it is finished.
+
+use crate::mode::EditorMode;
+use crate::operator::{NormalCommand, Operator, Region, Simple};
+use crate::EditorConfig;
+use unicode_segmentation::UnicodeSegmentation;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertAction {
+    Char(char),
+    NewLine,
+    Backspace,
+    DeleteForward,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeCommand {
+    Normal(NormalCommand),
+    // A Visual mode operator, replayed on a region of the same size.
+    // `width` counts grapheme clusters: those selected on a single line, or
+    // those before the end on the last line of a multi-line selection.
+    Visual { op: Operator, linewise: bool, lines: usize, width: usize, register: Option<char> },
+}
+
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub command: ChangeCommand,
+    pub typed: Vec<InsertAction>,
+}
+
+impl NormalCommand {
+    // Commands that modify the buffer, and so can be repeated with `.`.
+    pub fn is_change(&self) -> bool {
+        match self {
+            NormalCommand::Move { .. } => false,
+            NormalCommand::Operate { op, .. } => *op != Operator::Yank,
+            NormalCommand::Simple { cmd, .. } => !matches!(
+                cmd,
+                Simple::Undo
+                    | Simple::Visual
+                    | Simple::VisualLine
+                    | Simple::Repeat
+                    | Simple::Record(_)
+                    | Simple::PlayMacro(_)
+                    | Simple::SetMark(_)
+                    | Simple::CommandLine
+                    | Simple::Search { .. }
+                    | Simple::Hover
+                    | Simple::Definition
+                    | Simple::References
+            ),
+        }
+    }
+
+    fn with_count(self, count: Option<usize>) -> NormalCommand {
+        if count.is_none() {
+            return self;
+        }
+        match self {
+            NormalCommand::Move { motion, .. } => NormalCommand::Move { motion, count },
+            NormalCommand::Operate { op, target, register, .. } => {
+                NormalCommand::Operate { op, target, count, register }
+            }
+            NormalCommand::Simple { cmd, register, .. } => NormalCommand::Simple { cmd, count, register },
+        }
+    }
+}
+
+impl EditorConfig {
+    pub(crate) fn begin_change(&mut self, command: ChangeCommand) {
+        self.pending_change = Some(Change { command, typed: Vec::new() });
+    }
+
+    // Called once the command is done, or when Insert mode ends for
+    // commands that enter it.
+    pub(crate) fn finish_change(&mut self) {
+        if let Some(change) = self.pending_change.take() {
+            self.last_change = Some(change);
+        }
+    }
+
+    pub(crate) fn record_insert(&mut self, action: InsertAction) {
+        if self.mode != EditorMode::Insert {
+            return;
+        }
+        if let Some(change) = &mut self.pending_change {
+            change.typed.push(action);
+        }
+    }
+
+    // Moving around in Insert mode starts over: only text typed after the
+    // last move is repeated, as a plain insert.
+    pub(crate) fn record_insert_moved(&mut self) {
+        if let Some(change) = &mut self.pending_change {
+            let insert = NormalCommand::Simple { cmd: Simple::Insert, count: None, register: None };
+            change.command = ChangeCommand::Normal(insert);
+            change.typed.clear();
+        }
+    }
+
+    pub(crate) fn replay_insert(&mut self, typed: &[InsertAction]) {
+        for action in typed {
+            match action {
+                InsertAction::Char(c) => self.editor_insert_char(*c),
+                InsertAction::NewLine => self.editor_insert_new_line(),
+                InsertAction::Backspace => self.editor_del_char(),
+                InsertAction::DeleteForward => self.editor_del_forward_char(),
+            }
+        }
+    }
+
+    // `3ifoo<Esc>` inserts the text three times, `3o` opens three lines.
+    pub(crate) fn repeat_insert_for_count(&mut self) {
+        let (cmd, count) = match &self.pending_change {
+            Some(Change { command: ChangeCommand::Normal(NormalCommand::Simple { cmd, count, .. }), .. }) => {
+                (*cmd, count.unwrap_or(1))
+            }
+            _ => return,
+        };
+        let typed = self.pending_change.as_ref().map_or(Vec::new(), |c| c.typed.clone());
+        let open_line = matches!(cmd, Simple::OpenBelow | Simple::OpenAbove);
+        if count < 2 || !(open_line || cmd.enters_insert()) {
+            return;
+        }
+
+        // Take the change out so the replayed keys are not recorded twice
+        let change = self.pending_change.take();
+        for _ in 1..count {
+            if open_line {
+                self.cx = self.erow.get(self.cy).map_or(0, |row| row.size);
+                self.editor_insert_new_line();
+            }
+            self.replay_insert(&typed);
+        }
+        self.pending_change = change;
+    }
+
+    pub(crate) fn editor_repeat_change(&mut self, count: Option<usize>) {
+        let change = match &self.last_change {
+            Some(change) => change.clone(),
+            None => {
+                self.editor_set_status_msg("No previous change to repeat");
+                return;
+            }
+        };
+
+        match change.command {
+            ChangeCommand::Normal(cmd) => self.execute_normal(cmd.with_count(count)),
+            ChangeCommand::Visual { op, linewise, lines, width, register } => {
+                if self.erow.is_empty() {
+                    return;
+                }
+                let top = self.cy;
+                let bottom = (top + lines - 1).min(self.erow.len() - 1);
+                let region = if linewise {
+                    Region { start: (0, top), end: (self.erow[bottom].size, bottom), linewise }
+                } else {
+                    // Same number of characters on a single line, the same
+                    // number of leading characters on the last line when the
+                    // selection spanned several lines
+                    let line = &self.erow[bottom].chars;
+                    let from = if lines == 1 { self.cx.min(line.len()) } else { 0 };
+                    let end = line[from..]
+                        .grapheme_indices(true)
+                        .nth(width)
+                        .map_or(line.len(), |(i, _)| from + i);
+                    Region { start: (self.cx, top), end: (end, bottom), linewise }
+                };
+                self.begin_change(change.command);
+                self.apply_operator(op, region, register);
+            }
+        }
+
+        if self.mode == EditorMode::Insert {
+            self.replay_insert(&change.typed);
+            self.editor_leave_insert();
+        } else {
+            self.finish_change();
+        }
+    }
+}
+
+impl Simple {
+    // Commands that only switch to Insert mode at some spot on the line.
+    fn enters_insert(self) -> bool {
+        matches!(
+            self,
+            Simple::Insert | Simple::Append | Simple::InsertLineStart | Simple::AppendLineEnd
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::EditorConfig;
+
+    #[test]
+    fn a_count_on_dot_replaces_the_count_of_the_change() {
+        let mut editor = EditorConfig::with_lines(&["a b c d e f g"]);
+        editor.type_keys("2dw");
+        assert_eq!(editor.lines(), ["c d e f g"]);
+        editor.type_keys("3.");
+        assert_eq!(editor.lines(), ["f g"]);
+        editor.type_keys(".");
+        assert_eq!(editor.lines(), [""]);
+    }
+
+    #[test]
+    fn dot_types_the_insert_again() {
+        let mut editor = EditorConfig::with_lines(&["one", "two"]);
+        editor.type_keys("A!?\x7f\x1b");
+        assert_eq!(editor.lines(), ["one!", "two"]);
+        editor.type_keys("j.");
+        assert_eq!(editor.lines(), ["one!", "two!"]);
+
+        editor.type_keys("0cwsix\x1bk0.");
+        assert_eq!(editor.lines(), ["six!", "six!"]);
+        assert_eq!((editor.cx, editor.cy), (2, 0));
+    }
+
+    #[test]
+    fn dot_repeats_a_visual_operator_on_a_region_of_the_same_size() {
+        let mut editor = EditorConfig::with_lines(&["abcdef", "ab", "é字é字"]);
+        editor.type_keys("lvlld");
+        assert_eq!(editor.lines(), ["aef", "ab", "é字é字"]);
+        editor.type_keys("jj0.");
+        assert_eq!(editor.lines(), ["aef", "ab", "字"]);
+
+        let mut editor = EditorConfig::with_lines(&["abcd", "abcd", "éé", "字字字"]);
+        editor.type_keys("lvjd");
+        assert_eq!(editor.lines(), ["acd", "éé", "字字字"]);
+        editor.type_keys("j0.");
+        assert_eq!(editor.lines(), ["acd", "字"]);
+    }
+}
@@ -0,0 +1,213 @@
+// Interactive find and replace (Ctrl-\).
+//
+// Asks for a pattern and a replacement, then walks the matches from the
+// cursor to the end of the buffer and on from the top back to the cursor,
+// asking about each one, with the match highlighted:
+//
+//   y  replace this match        n  skip it
+//   a  replace this and all the rest without asking
+//   q  stop (so does Escape)
+//
+// The replacement may use capture groups as in :s ($1, \1, ${name}).
+// Everything replaced is one undo step.
+
+use crate::prompt::HistoryKind;
+use crate::search::{self, SearchPattern};
+use crate::undo::EditOp;
+use crate::{EditorConfig, EditorKey};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Answer {
+    Yes,
+    No,
+    All,
+    Quit,
+}
+
+impl EditorConfig {
+    pub(crate) fn editor_replace(&mut self) {
+        let Some(pattern) = self.editor_prompt(
+            "Replace: (ESC to cancel) ",
+            "",
+            Some(HistoryKind::Search),
+            None::<fn(&mut Self, &mut String, EditorKey)>,
+        ) else {
+            return;
+        };
+        let regex = match SearchPattern::new(&pattern, &self.options, None) {
+            Ok(regex) => regex,
+            Err(msg) => {
+                self.editor_set_status_msg(msg);
+                return;
+            }
+        };
+        self.last_pattern = Some(pattern.clone());
+        self.search_backward = false;
+
+        let Some(replacement) = self.editor_prompt_with(
+            &format!("Replace {} with: ", pattern),
+            "",
+            None,
+            true,
+            None::<fn(&mut Self, &mut String, EditorKey)>,
+        ) else {
+            return;
+        };
+        let template = search::replacement_template(&replacement);
+
+        let start = (self.cx, self.cy);
+        self.history.begin_group(start);
+        let (replaced, seen) = self.replace_matches(&regex, &template, start);
+        self.highlight_search(Some(regex));
+        if replaced == 0 {
+            (self.cx, self.cy) = start;
+        }
+        self.history.end_group((self.cx, self.cy));
+
+        if seen == 0 {
+            self.editor_set_status_msg(format!("Pattern not found: {}", pattern));
+        } else {
+            self.editor_set_status_msg(format!(
+                "{} replacement{} made",
+                replaced,
+                if replaced == 1 { "" } else { "s" }
+            ));
+        }
+    }
+
+    // Go through the matches from `start`, wrapping around once. Returns
+    // how many were replaced and how many were found.
+    fn replace_matches(
+        &mut self,
+        regex: &SearchPattern,
+        template: &str,
+        start: (usize, usize),
+    ) -> (usize, usize) {
+        let (mut stop_x, stop_y) = start;
+        let (mut x, mut y) = start;
+        let mut wrapped = false;
+        let mut ask = true;
+        let (mut replaced, mut seen) = (0, 0);
+
+        loop {
+            if y >= self.erow.len() {
+                if wrapped || stop_y == 0 && stop_x == 0 {
+                    break;
+                }
+                wrapped = true;
+                (x, y) = (0, 0);
+                continue;
+            }
+
+            // On the second pass the line we started on ends at the start
+            let found = regex
+                .replace_at(&self.erow[y].chars, x, template)
+                .filter(|&(from, _, _)| !(wrapped && y == stop_y && from >= stop_x));
+            let Some((from, to, text)) = found else {
+                if wrapped && y == stop_y {
+                    break;
+                }
+                (x, y) = (0, y + 1);
+                continue;
+            };
+            seen += 1;
+
+            let answer = if ask { self.ask_replace(regex, (from, to, y), &text) } else { Answer::Yes };
+            let next = match answer {
+                Answer::Quit => break,
+                Answer::No => to,
+                Answer::Yes | Answer::All => {
+                    ask &= answer == Answer::Yes;
+                    if from < to {
+                        self.editor_delete_range((from, y), (to, y));
+                    }
+                    if !text.is_empty() {
+                        self.editor_apply(EditOp::InsertText { y, x: from, text: text.clone() });
+                    }
+                    if wrapped && y == stop_y {
+                        stop_x = stop_x.max(to) - (to - from) + text.len();
+                    }
+                    replaced += 1;
+                    (self.cx, self.cy) = (from, y);
+                    from + text.len()
+                }
+            };
+
+            // Step over an empty match so it is not found again
+            x = if from == to {
+                let line = &self.erow[y].chars;
+                if next >= line.len() {
+                    line.len() + 1
+                } else {
+                    next + line[next..].chars().next().map_or(1, char::len_utf8)
+                }
+            } else {
+                next
+            };
+        }
+        (replaced, seen)
+    }
+
+    // Show the match and wait for y, n, a or q.
+    fn ask_replace(
+        &mut self,
+        regex: &SearchPattern,
+        (from, to, y): (usize, usize, usize),
+        text: &str,
+    ) -> Answer {
+        (self.cx, self.cy) = (from, y);
+        // The overlay search draws its matches with, so nothing is written
+        // into the row highlighting and nothing has to be put back
+        self.highlight_current_match(regex, Some((from, y, to)));
+
+        loop {
+            self.editor_set_status_msg(format!("Replace with \"{}\"? (y/n/a/q)", text));
+            if self.refresh_screen().is_err() {
+                return Answer::Quit;
+            }
+            return match self.read_key() {
+                Ok(EditorKey::Other(b'y')) => Answer::Yes,
+                Ok(EditorKey::Other(b'n')) => Answer::No,
+                Ok(EditorKey::Other(b'a')) => Answer::All,
+                Ok(EditorKey::Other(b'q')) | Ok(EditorKey::Escape) | Err(_) => Answer::Quit,
+                Ok(_) => continue,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::EditorConfig;
+
+    fn editor() -> EditorConfig {
+        EditorConfig::with_lines(&["a a", "a", "a a"])
+    }
+
+    #[test]
+    fn answers_replace_skip_and_replace_the_rest_in_one_undo_step() {
+        let mut editor = editor();
+        editor.type_keys("\x1ca\nb\nyna");
+        assert_eq!(editor.lines(), ["b a", "b", "b b"]);
+        assert_eq!(editor.status_msg, "4 replacements made");
+
+        editor.type_keys("u");
+        assert_eq!(editor.lines(), ["a a", "a", "a a"]);
+        assert_eq!((editor.cx, editor.cy), (0, 0));
+    }
+
+    #[test]
+    fn q_stops_and_keeps_what_was_replaced() {
+        let mut editor = editor();
+        editor.type_keys("\x1ca\nb\nnyq");
+        assert_eq!(editor.lines(), ["a b", "a", "a a"]);
+        assert_eq!(editor.status_msg, "1 replacement made");
+        // On the match it was asked about
+        assert_eq!((editor.cx, editor.cy), (0, 1));
+
+        editor.type_keys("u");
+        assert_eq!(editor.lines(), ["a a", "a", "a a"]);
+        editor.type_keys("\x19");
+        assert_eq!(editor.lines(), ["a b", "a", "a a"]);
+    }
+}
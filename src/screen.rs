@@ -0,0 +1,375 @@
+// The screen as a grid of cells.
+//
+// Drawing fills a grid with the frame to show. It is compared with the
+// frame shown before, and only the cells that changed are sent to the
+// terminal, with the shortest cursor moves that get there: a keypress that
+// changes one character sends that character, and one that only moves the
+// cursor sends the move. Runs of blanks at the end of a row are cleared
+// with one erase instead of being written out.
+//
+// The frame shown is forgotten when the terminal may show something else,
+// like after Ctrl-L or a resize, and the next one is sent whole.
+//
+// A cell holds one grapheme cluster. One that is two columns wide takes its
+// cell and the next, which is left as a placeholder and never written.
+
+use crate::grapheme;
+use crate::theme::Style;
+use crate::AppendBuffer;
+use std::collections::HashMap;
+use unicode_segmentation::UnicodeSegmentation;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    ch: char,  // the cluster's first character
+    width: u8, // 0 for the second half of a wide cluster
+    style: Style,
+}
+
+impl Cell {
+    fn blank(style: Style) -> Cell {
+        Cell { ch: ' ', width: 1, style }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Grid {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,                 // row by row
+    clusters: HashMap<usize, String>, // cells whose cluster is more than one character
+}
+
+impl Grid {
+    pub fn new(width: usize, height: usize) -> Grid {
+        let cells = vec![Cell::blank(Style::default()); width * height];
+        Grid { width, height, cells, clusters: HashMap::new() }
+    }
+
+    // Set the cell at `(row, col)` to the cluster `g`, and the one after it
+    // too when `g` is wide. Returns the columns it takes. Cells off the
+    // screen are left out, and a wide cluster cut by the edge is a blank.
+    pub fn put(&mut self, row: usize, col: usize, g: &str, style: Style) -> usize {
+        let width = grapheme::width(g);
+        if row >= self.height || col >= self.width {
+            return width;
+        }
+        if col + width > self.width {
+            self.put(row, col, " ", style);
+            return width;
+        }
+
+        let at = row * self.width + col;
+        for i in at..at + width {
+            self.unlink(i, row * self.width..(row + 1) * self.width);
+        }
+        let ch = g.chars().next().unwrap_or(' ');
+        self.cells[at] = Cell { ch, width: width as u8, style };
+        if width == 2 {
+            self.cells[at + 1] = Cell { ch: ' ', width: 0, style };
+        }
+        if g.len() > ch.len_utf8() {
+            self.clusters.insert(at, g.to_string());
+        }
+        width
+    }
+
+    // Forget what cell `i` held, blanking the rest of a wide cluster it was
+    // part of. `line` is the range of its row.
+    fn unlink(&mut self, i: usize, line: std::ops::Range<usize>) {
+        self.clusters.remove(&i);
+        let cell = self.cells[i];
+        let other = match cell.width {
+            0 if i > line.start => Some(i - 1),
+            2 if i + 1 < line.end => Some(i + 1),
+            _ => None,
+        };
+        if let Some(other) = other {
+            self.clusters.remove(&other);
+            self.cells[other] = Cell::blank(self.cells[other].style);
+        }
+        self.cells[i] = Cell::blank(cell.style);
+    }
+
+    // Write `text` from `col` on, cut at the edge of the screen. Returns the
+    // column after it.
+    pub fn print(&mut self, row: usize, col: usize, text: &str, style: Style) -> usize {
+        let mut col = col;
+        for g in text.graphemes(true) {
+            col += self.put(row, col, g, style);
+        }
+        col
+    }
+
+    // Blank the row from `col` to its end.
+    pub fn clear(&mut self, row: usize, col: usize, style: Style) {
+        for col in col..self.width {
+            self.put(row, col, " ", style);
+        }
+    }
+
+    fn row(&self, row: usize) -> &[Cell] {
+        &self.cells[row * self.width..(row + 1) * self.width]
+    }
+
+    fn cluster(&self, row: usize, col: usize) -> Option<&String> {
+        self.clusters.get(&(row * self.width + col))
+    }
+
+    // Whether the cell at `(row, col)` shows the same as it does in `other`.
+    fn same_cell(&self, other: &Grid, row: usize, col: usize) -> bool {
+        let i = row * self.width + col;
+        let width = (self.cells[i].width as usize).max(1);
+        self.cells[i..i + width] == other.cells[i..i + width]
+            && self.cluster(row, col) == other.cluster(row, col)
+    }
+
+    fn write_cell(&self, row: usize, col: usize, ab: &mut AppendBuffer) {
+        match self.cluster(row, col) {
+            Some(cluster) => ab.append(cluster.as_bytes()),
+            None => ab.append_char(self.row(row)[col].ch),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Screen {
+    shown: Option<Grid>,             // what the terminal shows, None when it isn't known
+    cursor: Option<(usize, usize)>, // where the cursor was left
+    bar_cursor: Option<bool>,       // whether it was a bar or a block
+}
+
+// Where the terminal's cursor is and what style it writes in while a frame
+// is sent.
+struct Pen {
+    at: Option<(usize, usize)>, // None after writing the last column, which may wrap
+    style: Option<Style>,
+}
+
+impl Pen {
+    // Get to `(row, col)` the short way, by rewriting up to a few unchanged
+    // cells when that is shorter than a move.
+    fn move_to(&mut self, row: usize, col: usize, grid: &Grid, ab: &mut AppendBuffer) {
+        match self.at {
+            Some(at) if at == (row, col) => {}
+            Some((r, c)) if r == row && c < col => {
+                let gap = &grid.row(row)[c..col];
+                if gap.len() <= 3 && gap.iter().all(|cell| Some(cell.style) == self.style) {
+                    for (i, cell) in gap.iter().enumerate() {
+                        if cell.width > 0 {
+                            grid.write_cell(row, c + i, ab);
+                        }
+                    }
+                } else if gap.len() == 1 {
+                    ab.append(b"\x1b[C");
+                } else {
+                    ab.append(format!("\x1b[{}C", gap.len()).as_bytes());
+                }
+            }
+            Some((r, _)) if r + 1 == row && col == 0 => ab.append(b"\r\n"),
+            _ if (row, col) == (0, 0) => ab.append(b"\x1b[H"),
+            _ => ab.append(format!("\x1b[{};{}H", row + 1, col + 1).as_bytes()),
+        }
+        self.at = Some((row, col));
+    }
+
+    fn set_style(&mut self, style: Style, ab: &mut AppendBuffer) {
+        if self.style != Some(style) {
+            ab.append(style.sgr().as_bytes());
+            self.style = Some(style);
+        }
+    }
+}
+
+impl Screen {
+    // Send the whole of the next frame.
+    pub fn invalidate(&mut self) {
+        *self = Screen::default();
+    }
+
+    // Append what it takes to go from the frame shown to `grid`, with the
+    // cursor at `cursor`, to `ab`. A cursor off the grid is put on its edge.
+    pub fn update(&mut self, grid: Grid, cursor: (usize, usize), bar_cursor: bool, ab: &mut AppendBuffer) {
+        let (last_row, last_col) = (grid.height.saturating_sub(1), grid.width.saturating_sub(1));
+        let cursor = (cursor.0.min(last_row), cursor.1.min(last_col));
+        let same_size = |shown: &Grid| (shown.width, shown.height) == (grid.width, grid.height);
+        let shown = self.shown.take().filter(same_size);
+        let mut pen = Pen { at: None, style: None };
+        let mut drawn = AppendBuffer::new();
+
+        for row in 0..grid.height {
+            let line = grid.row(row);
+            let mut col = 0;
+            while col < grid.width {
+                let width = (line[col].width as usize).max(1);
+                if shown.as_ref().is_some_and(|shown| grid.same_cell(shown, row, col)) {
+                    col += width;
+                    continue;
+                }
+                pen.move_to(row, col, &grid, &mut drawn);
+
+                // Blanks to the end of the row are one erase, if the erase
+                // looks the same as the blanks
+                let cell = line[col];
+                let style = cell.style;
+                let tail = &line[col..];
+                if tail.len() > 3
+                    && !style.reverse
+                    && !style.underline
+                    && tail.iter().all(|&cell| cell == Cell::blank(style))
+                    && !(col..grid.width).any(|col| grid.cluster(row, col).is_some())
+                {
+                    pen.set_style(style, &mut drawn);
+                    drawn.append(b"\x1b[K");
+                    break;
+                }
+
+                pen.set_style(style, &mut drawn);
+                grid.write_cell(row, col, &mut drawn);
+                col += width;
+                pen.at = (col < grid.width).then_some((row, col));
+            }
+        }
+
+        if !drawn.buffer.is_empty() {
+            ab.append(b"\x1b[?25l");
+            ab.append(&drawn.buffer);
+            if pen.style != Some(Style::default()) {
+                ab.append(b"\x1b[m");
+            }
+        }
+        if !drawn.buffer.is_empty() || self.cursor != Some(cursor) {
+            ab.append(format!("\x1b[{};{}H", cursor.0 + 1, cursor.1 + 1).as_bytes());
+        }
+        if self.bar_cursor != Some(bar_cursor) {
+            ab.append(if bar_cursor { b"\x1b[6 q" } else { b"\x1b[2 q" });
+        }
+        if !drawn.buffer.is_empty() {
+            ab.append(b"\x1b[?25h");
+        }
+
+        self.shown = Some(grid);
+        self.cursor = Some(cursor);
+        self.bar_cursor = Some(bar_cursor);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What `update` sends to go from the screen's frame to `grid`.
+    fn send(screen: &mut Screen, grid: &Grid, cursor: (usize, usize)) -> String {
+        let mut ab = AppendBuffer::new();
+        screen.update(grid.clone(), cursor, false, &mut ab);
+        String::from_utf8(ab.buffer).unwrap()
+    }
+
+    fn frame(rows: &[&str]) -> Grid {
+        let mut grid = Grid::new(8, rows.len());
+        for (row, text) in rows.iter().enumerate() {
+            grid.print(row, 0, text, Style::default());
+        }
+        grid
+    }
+
+    #[test]
+    fn first_frame_is_sent_whole() {
+        let mut screen = Screen::default();
+        let sent = send(&mut screen, &frame(&["hello", "world!"]), (0, 5));
+        // The last column may wrap, so the next row is an absolute move
+        assert_eq!(
+            sent,
+            "\x1b[?25l\x1b[H\x1b[0mhello   \x1b[2;1Hworld!  \x1b[1;6H\x1b[2 q\x1b[?25h"
+        );
+    }
+
+    #[test]
+    fn one_changed_character_is_all_that_is_sent() {
+        let mut screen = Screen::default();
+        send(&mut screen, &frame(&["hello", "world"]), (0, 5));
+        let sent = send(&mut screen, &frame(&["hello", "wOrld"]), (1, 2));
+        assert_eq!(sent, "\x1b[?25l\x1b[2;2H\x1b[0mO\x1b[2;3H\x1b[?25h");
+    }
+
+    #[test]
+    fn moving_the_cursor_sends_only_the_move() {
+        let mut screen = Screen::default();
+        let grid = frame(&["hello", "world"]);
+        send(&mut screen, &grid, (0, 0));
+        assert_eq!(send(&mut screen, &grid, (1, 3)), "\x1b[2;4H");
+        assert_eq!(send(&mut screen, &grid, (1, 3)), "");
+    }
+
+    #[test]
+    fn a_wide_cluster_takes_two_cells() {
+        let mut screen = Screen::default();
+        send(&mut screen, &frame(&["ab"]), (0, 0));
+        // The second cell is never written, the terminal fills it
+        let sent = send(&mut screen, &frame(&["a漢x"]), (0, 0));
+        assert_eq!(sent, "\x1b[?25l\x1b[1;2H\x1b[0m漢x\x1b[1;1H\x1b[?25h");
+
+        // Writing over the placeholder blanks the cluster it belonged to
+        let mut grid = frame(&["a漢x"]);
+        grid.put(0, 2, "y", Style::default());
+        let sent = send(&mut screen, &grid, (0, 0));
+        assert_eq!(sent, "\x1b[?25l\x1b[1;2H\x1b[0m y\x1b[1;1H\x1b[?25h");
+    }
+
+    #[test]
+    fn trailing_blanks_are_one_erase() {
+        let mut screen = Screen::default();
+        send(&mut screen, &frame(&["abcdefgh"]), (0, 0));
+        let sent = send(&mut screen, &frame(&["ab"]), (0, 0));
+        assert_eq!(sent, "\x1b[?25l\x1b[1;3H\x1b[0m\x1b[K\x1b[1;1H\x1b[?25h");
+    }
+
+    #[test]
+    fn invalidate_sends_the_next_frame_whole() {
+        let mut screen = Screen::default();
+        let grid = frame(&["hi"]);
+        let first = send(&mut screen, &grid, (0, 0));
+        assert_eq!(send(&mut screen, &grid, (0, 0)), "");
+        screen.invalidate();
+        assert_eq!(send(&mut screen, &grid, (0, 0)), first);
+    }
+
+    // What the editor sends to the terminal for its next frame.
+    fn draw(editor: &mut crate::EditorConfig) -> String {
+        String::from_utf8(editor.editor_draw_frame().buffer).unwrap()
+    }
+
+    // A 12 by 4 terminal: two rows of text, the status bar and the message bar
+    fn editor() -> crate::EditorConfig {
+        let mut editor = crate::EditorConfig::with_size(12, 4);
+        editor.editor_insert_row(0, "abc");
+        editor.editor_insert_row(1, "de");
+        editor
+    }
+
+    #[test]
+    fn the_editor_sends_its_first_frame_whole_and_then_nothing_until_it_changes() {
+        let mut editor = editor();
+        assert_eq!(
+            draw(&mut editor),
+            "\x1b[?25l\x1b[H\x1b[0mabc\x1b[K\r\nde\x1b[K\r\n\x1b[0;7m NORMAL | No\
+             \x1b[4;1H\x1b[0m\x1b[K\x1b[1;1H\x1b[2 q\x1b[?25h"
+        );
+        assert_eq!(draw(&mut editor), "");
+    }
+
+    #[test]
+    fn the_editor_sends_a_cursor_move_or_a_typed_character_alone() {
+        let mut editor = editor();
+        draw(&mut editor);
+
+        editor.cx = 1;
+        assert_eq!(draw(&mut editor), "\x1b[1;2H");
+
+        // The rest of the line moves right. The status bar is too narrow to
+        // show the buffer was modified, so nothing else changes
+        editor.editor_insert_char('x');
+        assert_eq!(draw(&mut editor), "\x1b[?25l\x1b[1;2H\x1b[0mxbc\x1b[1;3H\x1b[?25h");
+        assert_eq!(draw(&mut editor), "");
+    }
+}
@@ -0,0 +1,461 @@
+// Search patterns, shared by the search prompt, :s and :g.
+//
+// Patterns are regular expressions in the syntax of the regex crate:
+// classes like [a-z] and \d, anchors ^ and $, alternation a|b and groups.
+// A few Vim items are understood on top of that:
+//
+//   \c  \C   anywhere in the pattern: ignore case / match case
+//   \V       at the start: the rest of the pattern is literal text
+//   \< \>    start and end of a word
+//
+// Without \c or \C, case is ignored when 'ignorecase' is set, unless
+// 'smartcase' is also set and the pattern has an uppercase letter in it.
+// 'wholeword' only matches whole words and 'literal' treats every pattern
+// as if it started with \V.
+//
+// Matches of the last search stay highlighted (unless 'hlsearch' is off)
+// until :nohlsearch; n and N bring them back.
+
+use crate::options::EditorOptions;
+use crate::prompt::HistoryKind;
+use crate::{EditorConfig, EditorKey};
+use regex::{Regex, RegexBuilder};
+
+// Bytes a compiled pattern may take, so a pathological one fails to
+// compile instead of eating all the memory
+const SIZE_LIMIT: usize = 1 << 20;
+
+#[derive(Debug, Clone)]
+pub struct SearchPattern {
+    regex: Regex,
+}
+
+// The matches draw_rows colours on top of the syntax highlighting.
+#[derive(Debug, Clone, Default)]
+pub struct MatchOverlay {
+    pattern: Option<SearchPattern>,          // every match of this
+    current: Option<(usize, usize, usize)>, // the one at the cursor: x, y, end x
+}
+
+impl SearchPattern {
+    // Compile `pattern`. `ignore_case` overrides the options and any \c or
+    // \C, as the i and I flags of :s do.
+    pub fn new(pattern: &str, options: &EditorOptions, ignore_case: Option<bool>) -> Result<Self, String> {
+        if pattern.is_empty() {
+            return Err("Empty pattern".to_string());
+        }
+
+        // Case switches are removed wherever they are
+        let mut case = None;
+        let mut text = String::with_capacity(pattern.len());
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                text.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('c') => case = Some(true),
+                Some('C') => case = Some(false),
+                Some(next) => {
+                    text.push('\\');
+                    text.push(next);
+                }
+                None => text.push('\\'),
+            }
+        }
+
+        let (literal, text) = match text.strip_prefix("\\V") {
+            Some(rest) => (true, rest.to_string()),
+            None => (options.literal, text),
+        };
+        let source = if literal {
+            regex::escape(&text)
+        } else {
+            text.replace("\\<", "\\b").replace("\\>", "\\b")
+        };
+        let source = if options.whole_word {
+            format!(r"\b(?:{})\b", source)
+        } else {
+            source
+        };
+
+        let ignore_case = ignore_case.or(case).unwrap_or_else(|| {
+            options.ignore_case && !(options.smart_case && has_uppercase(&text, literal))
+        });
+
+        RegexBuilder::new(&source)
+            .case_insensitive(ignore_case)
+            .size_limit(SIZE_LIMIT)
+            .build()
+            .map(|regex| SearchPattern { regex })
+            .map_err(|err| match err {
+                regex::Error::Syntax(msg) => {
+                    // The last line of the message says what is wrong
+                    let reason = msg.lines().rev().find(|l| !l.trim().is_empty()).unwrap_or("");
+                    let reason = reason.trim_start_matches("error: ").trim();
+                    format!("Invalid pattern: {}", reason)
+                }
+                regex::Error::CompiledTooBig(_) => "Pattern too complex".to_string(),
+                _ => format!("Invalid pattern: {}", pattern),
+            })
+    }
+
+    // Byte ranges of the matches in `line`, left to right, not overlapping.
+    pub fn find_all(&self, line: &str) -> Vec<(usize, usize)> {
+        self.regex.find_iter(line).map(|m| (m.start(), m.end())).collect()
+    }
+
+    // The first match in `line`.
+    pub fn find(&self, line: &str) -> Option<(usize, usize)> {
+        self.regex.find(line).map(|m| (m.start(), m.end()))
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        self.regex.is_match(line)
+    }
+
+    // The first match starting at or after byte `at`. Anchors and word
+    // boundaries still see the text before `at`.
+    pub fn find_at(&self, line: &str, mut at: usize) -> Option<(usize, usize)> {
+        if at > line.len() {
+            return None;
+        }
+        while !line.is_char_boundary(at) {
+            at += 1;
+        }
+        self.regex.find_at(line, at).map(|m| (m.start(), m.end()))
+    }
+
+    // Every match in `line` with the replacement for it, left to right.
+    // `template` comes from replacement_template.
+    pub fn replacements(&self, line: &str, template: &str) -> Vec<(usize, usize, String)> {
+        self.regex
+            .captures_iter(line)
+            .map(|caps| {
+                let whole = caps.get(0).expect("group 0 is the whole match");
+                let mut text = String::new();
+                caps.expand(template, &mut text);
+                (whole.start(), whole.end(), text)
+            })
+            .collect()
+    }
+
+    // The first match starting at or after byte `at`, with its replacement.
+    pub fn replace_at(&self, line: &str, at: usize, template: &str) -> Option<(usize, usize, String)> {
+        let (start, _) = self.find_at(line, at)?;
+        let caps = self.regex.captures_at(line, start)?;
+        let whole = caps.get(0)?;
+        let mut text = String::new();
+        caps.expand(template, &mut text);
+        Some((whole.start(), whole.end(), text))
+    }
+
+    // The last match starting before byte `limit`.
+    pub fn rfind_before(&self, line: &str, limit: usize) -> Option<(usize, usize)> {
+        let mut last = None;
+        let mut at = 0;
+        while let Some((start, end)) = self.find_at(line, at) {
+            if start >= limit {
+                break;
+            }
+            last = Some((start, end));
+            at = start + line[start..].chars().next().map_or(1, char::len_utf8);
+        }
+        last
+    }
+}
+
+// Replacement text as typed, turned into a template for the regex crate.
+// $1 and \1 are capture groups, ${name} a named group and $0 or \0 the
+// whole match; $$, \$ and \\ give a plain $ or \.
+pub fn replacement_template(replacement: &str) -> String {
+    let mut out = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(d) if d.is_ascii_digit() => out.push_str(&format!("${{{}}}", d)),
+            Some('$') => out.push_str("$$"),
+            Some(next) if next != '\\' => {
+                out.push('\\');
+                out.push(next);
+            }
+            _ => out.push('\\'),
+        }
+    }
+    out
+}
+
+// For smartcase: does the pattern ask for an uppercase letter? Letters
+// after a backslash (\S, \W, \D ...) are not counted.
+fn has_uppercase(pattern: &str, literal: bool) -> bool {
+    if literal {
+        return pattern.chars().any(char::is_uppercase);
+    }
+    let mut escaped = false;
+    for c in pattern.chars() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c.is_uppercase() {
+            return true;
+        }
+    }
+    false
+}
+
+impl EditorConfig {
+    // The next match of `pattern` after `from` (before it when `backward`),
+    // wrapping around the ends of the buffer. With `at_cursor` a match right
+    // at `from` counts too. Returns the start (x, y) and the end x.
+    pub(crate) fn search_from(
+        &self,
+        pattern: &SearchPattern,
+        from: (usize, usize),
+        backward: bool,
+        at_cursor: bool,
+    ) -> Option<(usize, usize, usize)> {
+        let rows = self.erow.len();
+        if rows == 0 {
+            return None;
+        }
+        let (x, y) = (from.0, from.1.min(rows - 1));
+
+        // The cursor line is looked at twice: from the cursor on, and after
+        // wrapping around, the part on the other side of the cursor
+        for i in 0..=rows {
+            let row_y = if backward { (y + rows - i % rows) % rows } else { (y + i) % rows };
+            let line = self.erow[row_y].chars.as_str();
+            let found = match (backward, i) {
+                (false, 0) => pattern.find_at(line, if at_cursor { x } else { x + 1 }),
+                (true, 0) => pattern.rfind_before(line, if at_cursor { x + 1 } else { x }),
+                (false, i) if i == rows => pattern.find(line).filter(|m| m.0 < x + !at_cursor as usize),
+                (true, i) if i == rows => {
+                    pattern.rfind_before(line, usize::MAX).filter(|m| m.0 + !at_cursor as usize > x)
+                }
+                (false, _) => pattern.find(line),
+                (true, _) => pattern.rfind_before(line, usize::MAX),
+            };
+            if let Some((start, end)) = found {
+                return Some((start, row_y, end));
+            }
+        }
+        None
+    }
+
+    // The pattern n and N look for.
+    pub(crate) fn last_search(&self) -> Result<SearchPattern, String> {
+        let pattern = self.last_pattern.as_deref().ok_or("No previous pattern")?;
+        SearchPattern::new(pattern, &self.options, None)
+    }
+
+    // After n or N: highlight the matches again, or say why there are none.
+    pub(crate) fn editor_report_search(&mut self) {
+        let msg = match self.last_search() {
+            Err(msg) => msg,
+            Ok(pattern) if self.search_from(&pattern, (0, 0), false, true).is_none() => {
+                format!("Pattern not found: {}", self.last_pattern.as_deref().unwrap_or(""))
+            }
+            Ok(pattern) => {
+                self.highlight_search(Some(pattern));
+                return;
+            }
+        };
+        self.editor_set_status_msg(msg);
+    }
+
+    // Keep the matches of `pattern` highlighted, if 'hlsearch' is on.
+    pub(crate) fn highlight_search(&mut self, pattern: Option<SearchPattern>) {
+        self.search_hl = MatchOverlay {
+            pattern: pattern.filter(|_| self.options.hlsearch),
+            current: None,
+        };
+    }
+
+    // Show all matches of `pattern`, with the one at x..end on line y as
+    // the current one, while a prompt is asking about it.
+    pub(crate) fn highlight_current_match(
+        &mut self,
+        pattern: &SearchPattern,
+        current: Option<(usize, usize, usize)>,
+    ) {
+        self.search_hl = MatchOverlay { pattern: Some(pattern.clone()), current };
+    }
+
+    // Render columns of the highlighted matches on line y, and whether each
+    // is the current one.
+    pub(crate) fn match_columns(&self, y: usize) -> Vec<(usize, usize, bool)> {
+        let Some(row) = self.erow.get(y) else {
+            return Vec::new();
+        };
+        let current = self.search_hl.current.filter(|&(_, cy, _)| cy == y);
+        let mut columns: Vec<(usize, usize, bool)> = self
+            .search_hl
+            .pattern
+            .as_ref()
+            .map(|pattern| pattern.find_all(&row.chars))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|&(start, end)| start < end && current.is_none_or(|(cx, _, _)| cx != start))
+            .map(|(start, end)| (start, end, false))
+            .collect();
+        if let Some((x, _, end)) = current {
+            columns.push((x, end, true));
+        }
+        columns
+            .into_iter()
+            .map(|(start, end, is_current)| {
+                (self.editor_row_cx_to_rx(row, start), self.editor_row_cx_to_rx(row, end), is_current)
+            })
+            .collect()
+    }
+
+    // Incremental search from the cursor. Ctrl-G and Ctrl-T move to the
+    // next and previous match while the prompt is open; Enter keeps the
+    // cursor on the match and Escape puts it back.
+    pub(crate) fn editor_find(&mut self, backward: bool) {
+        let saved = (self.cx, self.cy);
+        let saved_row_off = self.row_off;
+        let saved_col_off = self.col_off;
+        let saved_hl = self.search_hl.clone();
+
+        // Start of the match the cursor is on, if any, and what it matched
+        let mut current: Option<(usize, usize)> = None;
+        let mut searched = String::new();
+
+        let search_callback = move |editor: &mut Self, query: &mut String, key: EditorKey| {
+            let (from, reverse) = match key {
+                EditorKey::Other(0x07) => (current.unwrap_or(saved), false), // Ctrl-G
+                EditorKey::Other(0x14) => (current.unwrap_or(saved), true),  // Ctrl-T
+                // Only a changed query searches again, from where the search started
+                _ if *query == searched => return,
+                _ => (saved, false),
+            };
+            searched = query.clone();
+
+            let Ok(pattern) = SearchPattern::new(query, &editor.options, None) else {
+                // Nothing (yet): stay where the search started
+                current = None;
+                (editor.cx, editor.cy) = saved;
+                editor.search_hl = MatchOverlay::default();
+                return;
+            };
+            let found = editor.search_from(&pattern, from, backward != reverse, false);
+            editor.highlight_current_match(&pattern, found);
+            let Some((x, y, _)) = found else {
+                current = None;
+                (editor.cx, editor.cy) = saved;
+                return;
+            };
+
+            current = Some((x, y));
+            (editor.cx, editor.cy) = (x, y);
+            editor.row_off = editor.erow.len(); // scroll the match to the top
+        };
+
+        let mut flags = Vec::new();
+        if self.options.whole_word {
+            flags.push("word");
+        }
+        if self.options.literal {
+            flags.push("literal");
+        }
+        let prompt = format!(
+            "{}{}: (Ctrl-G/Ctrl-T next/previous, ESC to cancel) ",
+            if backward { "Search backward" } else { "Search" },
+            if flags.is_empty() { String::new() } else { format!(" [{}]", flags.join(", ")) }
+        );
+
+        let history = Some(HistoryKind::Search);
+        let Some(query) = self.editor_prompt(&prompt, "", history, Some(search_callback)) else {
+            // Restore original cursor position if search was cancelled
+            (self.cx, self.cy) = saved;
+            self.row_off = saved_row_off;
+            self.col_off = saved_col_off;
+            self.search_hl = saved_hl;
+            return;
+        };
+
+        self.last_pattern = Some(query.clone());
+        self.search_backward = backward;
+        let error = match SearchPattern::new(&query, &self.options, None) {
+            Err(msg) => msg,
+            Ok(pattern) if self.search_from(&pattern, saved, backward, true).is_none() => {
+                self.highlight_search(None);
+                format!("Pattern not found: {}", query)
+            }
+            Ok(pattern) => {
+                self.highlight_search(Some(pattern));
+                return;
+            }
+        };
+        self.search_hl = saved_hl;
+        self.editor_set_status_msg(error);
+        (self.cx, self.cy) = saved;
+        self.row_off = saved_row_off;
+        self.col_off = saved_col_off;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, options: &EditorOptions, line: &str) -> Option<(usize, usize)> {
+        SearchPattern::new(pattern, options, None).unwrap().find(line)
+    }
+
+    #[test]
+    fn an_uppercase_letter_makes_a_smartcase_search_match_case() {
+        let mut options = EditorOptions::default();
+        assert_eq!(find("foo", &options, "a FOO"), Some((2, 5)));
+        assert_eq!(find("Foo", &options, "a FOO"), None);
+        assert_eq!(find("Foo", &options, "a Foo"), Some((2, 5)));
+        // Escapes like \S are not uppercase letters
+        assert_eq!(find(r"\Sx", &options, "AX"), Some((0, 2)));
+
+        options.smart_case = false;
+        assert_eq!(find("Foo", &options, "a FOO"), Some((2, 5)));
+        options.ignore_case = false;
+        assert_eq!(find("foo", &options, "a FOO"), None);
+    }
+
+    #[test]
+    fn case_switches_win_over_the_options_and_lose_to_flags() {
+        let options = EditorOptions::default();
+        assert_eq!(find(r"Fo\co", &options, "foo"), Some((0, 3)));
+        assert_eq!(find(r"\Cfoo", &options, "FOO"), None);
+        let pattern = SearchPattern::new(r"foo\c", &options, Some(false)).unwrap();
+        assert_eq!(pattern.find("FOO"), None);
+    }
+
+    #[test]
+    fn very_nomagic_is_literal_and_word_edges_are_boundaries() {
+        let mut options = EditorOptions::default();
+        assert_eq!(find(r"\Va.b", &options, "axb a.b"), Some((4, 7)));
+        assert_eq!(find("a.b", &options, "axb a.b"), Some((0, 3)));
+        assert_eq!(find(r"\<is\>", &options, "this is"), Some((5, 7)));
+        options.literal = true;
+        assert_eq!(find("a.b", &options, "axb a.b"), Some((4, 7)));
+        assert!(SearchPattern::new("(", &EditorOptions::default(), None).is_err());
+    }
+
+    #[test]
+    fn searches_wrap_around_the_ends_of_the_buffer() {
+        let editor = EditorConfig::with_lines(&["one x", "two", "x three"]);
+        let pattern = SearchPattern::new("x", &editor.options, None).unwrap();
+        assert_eq!(editor.search_from(&pattern, (0, 2), false, false), Some((4, 0, 5)));
+        assert_eq!(editor.search_from(&pattern, (0, 2), false, true), Some((0, 2, 1)));
+        assert_eq!(editor.search_from(&pattern, (4, 0), true, false), Some((0, 2, 1)));
+
+        // The only match, found again after going all the way round
+        let pattern = SearchPattern::new("two", &editor.options, None).unwrap();
+        assert_eq!(editor.search_from(&pattern, (0, 1), false, false), Some((0, 1, 3)));
+        assert_eq!(editor.search_from(&pattern, (0, 1), true, false), Some((0, 1, 3)));
+    }
+}
@@ -0,0 +1,305 @@
+// Syntax definitions: what the highlighter knows about each language.
+//
+// Rust's is compiled in. The others are TOML files: those in the syntax/
+// directory of the source tree are built in, and more are read at start-up
+// from $XDG_CONFIG_HOME/kibi/syntax/*.toml (falling back to ~/.config/).
+// A file with the same filetype as a built-in one replaces it. Every key
+// but filetype and filematch may be left out:
+//
+//   filetype = "Python"
+//   filematch = [".py", "SConstruct"]   # extensions, or parts of file names
+//   keywords = ["if", "else", "def|"]   # a trailing | picks the second colour
+//   types = ["int", "str"]
+//   line_comment = "#"
+//   block_comment = ["/*", "*/"]
+//   strings = ["\"", "'"]               # characters that open and close one
+//   number_prefixes = ["0x", "0b"]      # letters and digits may follow these
+//   number_separator = "_"              # allowed between digits
+//   flags = ["numbers", "strings"]      # what to highlight besides keywords
+//
+// `highlighter = "toml"` or `"rust"` picks a highlighter from highlight.rs
+// instead of these rules; the Rust one still takes its keywords and types
+// from the definition.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use toml::{Table, Value};
+
+pub const HL_HIGHLIGHT_NUMBERS: usize = 1 << 0;
+pub const HL_HIGHLIGHT_STRINGS: usize = 1 << 1;
+
+// How a syntax is highlighted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Highlighter {
+    #[default]
+    Keywords, // the keyword, comment, string and number rules of its definition
+    Toml,
+    Rust,
+}
+
+#[derive(Debug, Default)]
+pub struct EditorSyntax {
+    pub filetype: String,
+    pub filematch: Vec<String>,
+    pub keywords: Vec<String>,
+    pub types: Vec<String>,
+    pub single_line_comment_start: String,
+    pub multiline_comment_start: String,
+    pub multiline_comment_end: String,
+    pub string_delimiters: Vec<char>,
+    pub number_prefixes: Vec<String>,
+    pub number_separator: Option<char>,
+    pub flags: usize,
+    pub highlighter: Highlighter,
+}
+
+const RUST_EXTENSION: &[&str] = &[".rs"];
+
+const RUST_HL_KEYWORDS: &[&str] = &[
+    // Control flow keywords (HL_KEYWORD1 - Yellow)
+    "if", "else", "while", "for", "loop", "break", "continue", "return",
+    "match",
+
+    // Declaration keywords (HL_KEYWORD2 - Green, marked with |)
+    "struct|", "enum|", "impl|", "trait|", "fn|", "let|", "mut|",
+    "const|", "static|", "pub|", "mod|", "use|", "crate|", "super|", "self|",
+];
+
+const RUST_TYPES: &[&str] = &[
+	 "i8", "i16", "i32", "i64", "i128", "isize",
+    "u8", "u16", "u32", "u64", "u128", "usize",
+    "f32", "f64", "bool", "char", "str", "String",
+    "Vec", "Option", "Result",
+];
+
+// The definitions shipped in syntax/, by file name.
+const BUILTIN: &[(&str, &str)] = &[
+    ("c.toml", include_str!("../syntax/c.toml")),
+    ("go.toml", include_str!("../syntax/go.toml")),
+    ("javascript.toml", include_str!("../syntax/javascript.toml")),
+    ("json.toml", include_str!("../syntax/json.toml")),
+    ("makefile.toml", include_str!("../syntax/makefile.toml")),
+    ("markdown.toml", include_str!("../syntax/markdown.toml")),
+    ("python.toml", include_str!("../syntax/python.toml")),
+    ("shell.toml", include_str!("../syntax/shell.toml")),
+    ("toml.toml", include_str!("../syntax/toml.toml")),
+    ("typescript.toml", include_str!("../syntax/typescript.toml")),
+    ("yaml.toml", include_str!("../syntax/yaml.toml")),
+];
+
+pub(crate) fn rust_syntax() -> EditorSyntax {
+    let strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect();
+    EditorSyntax {
+        filetype: "Rust".to_string(),
+        filematch: strings(RUST_EXTENSION),
+        keywords: strings(RUST_HL_KEYWORDS),
+        types: strings(RUST_TYPES),
+        single_line_comment_start: "//".to_string(),
+        multiline_comment_start: "/*".to_string(),
+        multiline_comment_end: "*/".to_string(),
+        string_delimiters: vec!['"', '\''],
+        number_prefixes: strings(&["0x", "0o", "0b"]),
+        number_separator: Some('_'),
+        flags: HL_HIGHLIGHT_NUMBERS | HL_HIGHLIGHT_STRINGS,
+        highlighter: Highlighter::Rust,
+    }
+}
+
+fn syntax_dir() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("kibi").join("syntax"))
+}
+
+fn get_string(table: &Table, key: &str) -> Result<Option<String>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(format!("{} must be a string", key)),
+    }
+}
+
+fn get_strings(table: &Table, key: &str) -> Result<Vec<String>, String> {
+    let Some(value) = table.get(key) else {
+        return Ok(Vec::new());
+    };
+    let list = value.as_array().ok_or(format!("{} must be a list of strings", key))?;
+    list.iter()
+        .map(|item| item.as_str().map(String::from).ok_or(format!("{} must be a list of strings", key)))
+        .collect()
+}
+
+fn get_char(s: &str, key: &str) -> Result<char, String> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(format!("{} must be single characters", key)),
+    }
+}
+
+// Read one definition from the text of its file.
+pub fn parse_syntax(text: &str) -> Result<EditorSyntax, String> {
+    let table: Table = text.parse().map_err(|e: toml::de::Error| {
+        let line = e.span().map_or(1, |span| text[..span.start].matches('\n').count() + 1);
+        format!("line {}: {}", line, e.message().lines().next().unwrap_or(""))
+    })?;
+    const KEYS: &[&str] = &[
+        "filetype", "filematch", "keywords", "types", "line_comment", "block_comment", "strings",
+        "number_prefixes", "number_separator", "flags", "highlighter",
+    ];
+    if let Some(key) = table.keys().find(|key| !KEYS.contains(&key.as_str())) {
+        return Err(format!("unknown key {}", key));
+    }
+
+    let filetype = get_string(&table, "filetype")?.ok_or("filetype is missing")?;
+    let filematch = get_strings(&table, "filematch")?;
+    if filematch.is_empty() {
+        return Err("filematch is missing".to_string());
+    }
+    let (multiline_comment_start, multiline_comment_end) =
+        match get_strings(&table, "block_comment")?.as_slice() {
+            [] => (String::new(), String::new()),
+            [start, end] if !start.is_empty() && !end.is_empty() => (start.clone(), end.clone()),
+            _ => return Err("block_comment must be a start and an end".to_string()),
+        };
+    let string_delimiters =
+        get_strings(&table, "strings")?.iter().map(|s| get_char(s, "strings")).collect::<Result<_, _>>()?;
+    let number_separator = match get_string(&table, "number_separator")? {
+        Some(s) => Some(get_char(&s, "number_separator")?),
+        None => None,
+    };
+    let mut flags = 0;
+    for flag in get_strings(&table, "flags")? {
+        flags |= match flag.as_str() {
+            "numbers" => HL_HIGHLIGHT_NUMBERS,
+            "strings" => HL_HIGHLIGHT_STRINGS,
+            _ => return Err(format!("unknown flag {}", flag)),
+        };
+    }
+
+    let highlighter = match get_string(&table, "highlighter")?.as_deref() {
+        None | Some("keywords") => Highlighter::Keywords,
+        Some("toml") => Highlighter::Toml,
+        Some("rust") => Highlighter::Rust,
+        Some(name) => return Err(format!("unknown highlighter {}", name)),
+    };
+
+    Ok(EditorSyntax {
+        filetype,
+        filematch,
+        keywords: get_strings(&table, "keywords")?,
+        types: get_strings(&table, "types")?,
+        single_line_comment_start: get_string(&table, "line_comment")?.unwrap_or_default(),
+        multiline_comment_start,
+        multiline_comment_end,
+        string_delimiters,
+        number_prefixes: get_strings(&table, "number_prefixes")?,
+        number_separator,
+        flags,
+        highlighter,
+    })
+}
+
+// The definitions that come with the editor.
+pub fn builtin_syntaxes() -> Vec<EditorSyntax> {
+    let builtin = BUILTIN.iter().map(|(name, text)| {
+        parse_syntax(text).unwrap_or_else(|e| panic!("syntax/{}: {}", name, e))
+    });
+    std::iter::once(rust_syntax()).chain(builtin).collect()
+}
+
+// The user's definitions followed by the built-in ones they don't replace.
+// The second value lists the files that could not be read.
+pub fn load_syntaxes() -> (Vec<Rc<EditorSyntax>>, Vec<String>) {
+    let mut syntaxes: Vec<Rc<EditorSyntax>> = Vec::new();
+    let mut errors = Vec::new();
+
+    let mut paths: Vec<PathBuf> = syntax_dir()
+        .and_then(|dir| fs::read_dir(dir).ok())
+        .map(|entries| entries.flatten().map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "toml"));
+    paths.sort();
+    for path in paths {
+        let text = fs::read_to_string(&path).map_err(|e| e.to_string());
+        let result = text.and_then(|text| parse_syntax(&text));
+        match result {
+            Ok(syntax) => syntaxes.push(Rc::new(syntax)),
+            Err(e) => errors.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    for syntax in builtin_syntaxes() {
+        if !syntaxes.iter().any(|user| user.filetype == syntax.filetype) {
+            syntaxes.push(Rc::new(syntax));
+        }
+    }
+    (syntaxes, errors)
+}
+
+// The definition for a file: one listing its extension, or else one with
+// a pattern that is part of its name.
+pub fn find_syntax(syntaxes: &[Rc<EditorSyntax>], filename: &str) -> Option<Rc<EditorSyntax>> {
+    let name = Path::new(filename).file_name().map_or(filename.into(), |name| name.to_string_lossy());
+    let ext = name.rfind('.').map(|pos| &name[pos..]);
+    let matches = |pattern: &String| pattern.starts_with('.') && Some(pattern.as_str()) == ext;
+    let contains = |pattern: &String| !pattern.starts_with('.') && name.contains(pattern.as_str());
+    let by_ext = syntaxes.iter().find(|syntax| syntax.filematch.iter().any(matches));
+    let by_name = || syntaxes.iter().find(|syntax| syntax.filematch.iter().any(contains));
+    by_ext.or_else(by_name).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        parse_syntax(text).unwrap_err()
+    }
+
+    #[test]
+    fn a_minimal_definition_takes_the_defaults() {
+        let syntax = parse_syntax("filetype = \"Ini\"\nfilematch = [\".ini\"]\n").unwrap();
+        assert_eq!((syntax.filetype.as_str(), syntax.filematch[0].as_str()), ("Ini", ".ini"));
+        assert_eq!((syntax.flags, syntax.highlighter), (0, Highlighter::Keywords));
+        assert!(syntax.keywords.is_empty() && syntax.multiline_comment_start.is_empty());
+        assert_eq!(syntax.number_separator, None);
+    }
+
+    #[test]
+    fn bad_definitions_say_what_is_wrong() {
+        let head = "filetype = \"X\"\nfilematch = [\".x\"]\n";
+        assert_eq!(error("filematch = [\".x\"]"), "filetype is missing");
+        assert_eq!(error("filetype = \"X\""), "filematch is missing");
+        assert_eq!(error("filetype = 3\nfilematch = [\".x\"]"), "filetype must be a string");
+        assert_eq!(error(&format!("{head}keywords = [\"a\", 1]")), "keywords must be a list of strings");
+        assert_eq!(error(&format!("{head}colour = \"red\"")), "unknown key colour");
+        assert_eq!(error(&format!("{head}block_comment = [\"/*\"]")), "block_comment must be a start and an end");
+        assert_eq!(error(&format!("{head}strings = [\"''\"]")), "strings must be single characters");
+        assert_eq!(error(&format!("{head}number_separator = \"\"")), "number_separator must be single characters");
+        assert_eq!(error(&format!("{head}flags = [\"bold\"]")), "unknown flag bold");
+        assert_eq!(error(&format!("{head}highlighter = \"c\"")), "unknown highlighter c");
+        assert!(error("filetype = \"X\"\n\nfilematch = [").starts_with("line 3: "));
+    }
+
+    #[test]
+    fn an_extension_wins_over_a_name_pattern() {
+        let syntax = |filetype: &str, filematch: &[&str]| {
+            let filematch = filematch.iter().map(|s| s.to_string()).collect();
+            Rc::new(EditorSyntax { filetype: filetype.to_string(), filematch, ..Default::default() })
+        };
+        let syntaxes = [syntax("Make", &["Makefile", "makefile"]), syntax("Shell", &[".sh"]), syntax("C", &[".c"])];
+        let found = |name| find_syntax(&syntaxes, name).map(|syntax| syntax.filetype.clone());
+
+        assert_eq!(found("src/Makefile.sh").as_deref(), Some("Shell"));
+        assert_eq!(found("GNUmakefile").as_deref(), Some("Make"));
+        assert_eq!(found("build/Makefile.old").as_deref(), Some("Make"));
+        assert_eq!(found("a.c").as_deref(), Some("C"));
+        // Only the file name is looked at, and only its last extension
+        assert_eq!(found("Makefile.d/x.h").as_deref(), None);
+        assert_eq!(found("x.c.txt").as_deref(), None);
+    }
+}
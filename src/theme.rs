@@ -0,0 +1,441 @@
+// Colours and text attributes.
+//
+// A theme gives a style to every kind of token the highlighters mark and to
+// the parts of the screen around the text. The built-in themes are the TOML
+// files in the themes/ directory of the source tree; more are read at
+// start-up from $XDG_CONFIG_HOME/kibi/themes/*.toml (falling back to
+// ~/.config/). A theme is named after its file, so dusk.toml is
+// `:set theme=dusk`, and a file named like a built-in one replaces it.
+//
+//   normal = { fg = "#c0c0c0", bg = "#1c1c1c" }
+//   keyword = { fg = "yellow", bold = true }
+//   comment = "bright_black"          # a bare colour is a foreground
+//   selection = { reverse = true }
+//
+// A style has fg, bg, bold, italic, underline and reverse. Colours are
+// "default", one of the 16 names (black, red, green, yellow, blue, magenta,
+// cyan, white and their bright_ forms), a number from the 256-colour
+// palette or "#rrggbb". Elements a theme leaves out look as they do in the
+// default theme, except block_comment which follows comment.
+//
+// Colours the terminal can't show are replaced by the nearest one it can:
+// 24-bit colour when COLORTERM is truecolor or 24bit, the 256-colour
+// palette when TERM ends in 256color, otherwise the 16 ANSI colours.
+
+use crate::{EditorConfig, EditorHighlight};
+use std::fs;
+use std::path::PathBuf;
+use toml::{Table, Value};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    #[default]
+    Default,
+    Ansi(u8),    // 0-7 and their bright forms 8-15
+    Indexed(u8), // the 256-colour palette
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+    pub fg: Color,
+    pub bg: Color,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+// How many colours the terminal shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColorDepth {
+    Ansi16,
+    Indexed256,
+    TrueColor,
+}
+
+// Parts of the screen besides the highlighted text. They come after the
+// EditorHighlight values in a theme's list of styles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ui {
+    StatusBar = HIGHLIGHTS as isize,
+    MessageBar,
+    LineNumber,
+    Selection,
+    Tilde,
+    Diagnostic,
+    Menu,
+    MenuSelected,
+}
+
+// What a theme file calls each style: the highlights in the order of
+// EditorHighlight, then the Ui elements.
+const ELEMENTS: &[&str] = &[
+    "normal", "number", "match", "string", "comment", "block_comment", "keyword", "keyword2",
+    "current_match", "section", "key", "value", "lifetime", "macro", "attribute", "escape",
+    "status_bar", "message_bar", "line_number", "selection", "tilde", "diagnostic", "menu",
+    "menu_selected",
+];
+const HIGHLIGHTS: usize = 16;
+
+const COLOR_NAMES: &[&str] = &[
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    "bright_black", "bright_red", "bright_green", "bright_yellow", "bright_blue",
+    "bright_magenta", "bright_cyan", "bright_white",
+];
+
+// The 16 colours as xterm shows them, to find the nearest one.
+const ANSI_RGB: [(u8, u8, u8); 16] = [
+    (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0), (0, 0, 238), (205, 0, 205), (0, 205, 205),
+    (229, 229, 229), (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0), (92, 92, 255),
+    (255, 0, 255), (0, 255, 255), (255, 255, 255),
+];
+
+// The levels of each component in the 6x6x6 cube of the 256-colour palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+// The themes shipped in themes/, by name. The first is the default.
+const BUILTIN: &[(&str, &str)] = &[
+    ("default", include_str!("../themes/default.toml")),
+    ("gruvbox", include_str!("../themes/gruvbox.toml")),
+    ("mono", include_str!("../themes/mono.toml")),
+    ("solarized-dark", include_str!("../themes/solarized-dark.toml")),
+    ("solarized-light", include_str!("../themes/solarized-light.toml")),
+];
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> i32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+impl Color {
+    fn rgb(self) -> Option<(u8, u8, u8)> {
+        match self {
+            Color::Default => None,
+            Color::Ansi(n) => Some(ANSI_RGB[n as usize % 16]),
+            Color::Indexed(n @ 0..=15) => Some(ANSI_RGB[n as usize]),
+            Color::Indexed(n @ 16..=231) => {
+                let n = (n - 16) as usize;
+                Some((CUBE_LEVELS[n / 36], CUBE_LEVELS[n / 6 % 6], CUBE_LEVELS[n % 6]))
+            }
+            Color::Indexed(n) => {
+                let level = 8 + (n - 232) * 10;
+                Some((level, level, level))
+            }
+            Color::Rgb(r, g, b) => Some((r, g, b)),
+        }
+    }
+
+    // The nearest colour of the 256-colour palette, from its cube or its
+    // grey ramp.
+    fn to_indexed(rgb: (u8, u8, u8)) -> Color {
+        let level = |v: u8| (0..6).min_by_key(|&i| (CUBE_LEVELS[i] as i32 - v as i32).abs()).unwrap();
+        let cube = Color::Indexed((16 + level(rgb.0) * 36 + level(rgb.1) * 6 + level(rgb.2)) as u8);
+        let average = (rgb.0 as u32 + rgb.1 as u32 + rgb.2 as u32) / 3;
+        let grey = Color::Indexed(232 + (average.saturating_sub(3) / 10).min(23) as u8);
+        let dist = |color: Color| distance(rgb, color.rgb().unwrap());
+        if dist(grey) < dist(cube) { grey } else { cube }
+    }
+
+    fn to_ansi(rgb: (u8, u8, u8)) -> Color {
+        let nearest = (0..16).min_by_key(|&n| distance(rgb, ANSI_RGB[n])).unwrap();
+        Color::Ansi(nearest as u8)
+    }
+
+    // This colour, or the nearest one a terminal with `depth` shows.
+    pub fn downgrade(self, depth: ColorDepth) -> Color {
+        match (self, depth) {
+            (Color::Indexed(n @ 0..=15), _) => Color::Ansi(n),
+            (Color::Rgb(..), ColorDepth::Indexed256) => Color::to_indexed(self.rgb().unwrap()),
+            (Color::Rgb(..) | Color::Indexed(_), ColorDepth::Ansi16) => Color::to_ansi(self.rgb().unwrap()),
+            _ => self,
+        }
+    }
+
+    // The SGR parameters that set this colour as the foreground or the
+    // background.
+    fn sgr(self, background: bool) -> Option<String> {
+        let base = if background { 40 } else { 30 };
+        Some(match self {
+            Color::Default => return None,
+            Color::Ansi(n) if n < 8 => (base + n as u32).to_string(),
+            Color::Ansi(n) => (base + 60 + (n as u32 % 16 - 8)).to_string(),
+            Color::Indexed(n) => format!("{};5;{}", base + 8, n),
+            Color::Rgb(r, g, b) => format!("{};2;{};{};{}", base + 8, r, g, b),
+        })
+    }
+}
+
+impl Style {
+    // This style drawn over `base`: its colours where it has them, and the
+    // attributes of both.
+    pub fn over(self, base: Style) -> Style {
+        let pick = |top: Color, bottom: Color| if top == Color::Default { bottom } else { top };
+        Style {
+            fg: pick(self.fg, base.fg),
+            bg: pick(self.bg, base.bg),
+            bold: self.bold || base.bold,
+            italic: self.italic || base.italic,
+            underline: self.underline || base.underline,
+            reverse: self.reverse || base.reverse,
+        }
+    }
+
+    // The escape sequence that switches to this style from any other.
+    pub fn sgr(&self) -> String {
+        let mut codes = vec!["0".to_string()];
+        for (on, code) in [(self.bold, "1"), (self.italic, "3"), (self.underline, "4"), (self.reverse, "7")] {
+            if on {
+                codes.push(code.to_string());
+            }
+        }
+        codes.extend(self.fg.sgr(false));
+        codes.extend(self.bg.sgr(true));
+        format!("\x1b[{}m", codes.join(";"))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub name: String,
+    styles: Vec<Style>, // by ELEMENTS
+}
+
+impl Theme {
+    // The style of an EditorHighlight value.
+    pub fn highlight(&self, hl: u8) -> Style {
+        self.styles.get(hl as usize).copied().unwrap_or(self.styles[0])
+    }
+
+    pub fn ui(&self, element: Ui) -> Style {
+        self.styles[element as usize]
+    }
+
+    // The theme with only the colours a terminal with `depth` shows.
+    pub fn for_depth(&self, depth: ColorDepth) -> Theme {
+        let styles = self
+            .styles
+            .iter()
+            .map(|style| Style { fg: style.fg.downgrade(depth), bg: style.bg.downgrade(depth), ..*style })
+            .collect();
+        Theme { name: self.name.clone(), styles }
+    }
+}
+
+// What the terminal shows, from the values of COLORTERM and TERM.
+pub fn detect_color_depth(colorterm: Option<&str>, term: Option<&str>) -> ColorDepth {
+    let term = term.unwrap_or("");
+    if matches!(colorterm, Some("truecolor" | "24bit")) || term.ends_with("-direct") {
+        ColorDepth::TrueColor
+    } else if term.ends_with("256color") {
+        ColorDepth::Indexed256
+    } else {
+        ColorDepth::Ansi16
+    }
+}
+
+pub fn color_depth() -> ColorDepth {
+    let var = |name| std::env::var(name).ok();
+    detect_color_depth(var("COLORTERM").as_deref(), var("TERM").as_deref())
+}
+
+fn theme_dir() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("kibi").join("themes"))
+}
+
+fn parse_color(value: &Value, key: &str) -> Result<Color, String> {
+    let invalid = || format!("{} has an invalid colour", key);
+    match value {
+        Value::Integer(n) => u8::try_from(*n).map(Color::Indexed).map_err(|_| invalid()),
+        Value::String(s) if s == "default" => Ok(Color::Default),
+        Value::String(s) => {
+            if let Some(n) = COLOR_NAMES.iter().position(|name| name == s) {
+                return Ok(Color::Ansi(n as u8));
+            }
+            let hex = s.strip_prefix('#').filter(|hex| hex.len() == 6).ok_or_else(invalid)?;
+            let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+            Ok(Color::Rgb(component(0)?, component(2)?, component(4)?))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_style(value: &Value, key: &str) -> Result<Style, String> {
+    let Value::Table(table) = value else {
+        return Ok(Style { fg: parse_color(value, key)?, ..Style::default() });
+    };
+    let mut style = Style::default();
+    for (name, value) in table {
+        let flag = || value.as_bool().ok_or(format!("{}.{} must be true or false", key, name));
+        match name.as_str() {
+            "fg" => style.fg = parse_color(value, key)?,
+            "bg" => style.bg = parse_color(value, key)?,
+            "bold" => style.bold = flag()?,
+            "italic" => style.italic = flag()?,
+            "underline" => style.underline = flag()?,
+            "reverse" => style.reverse = flag()?,
+            _ => return Err(format!("unknown key {}.{}", key, name)),
+        }
+    }
+    Ok(style)
+}
+
+// Read one theme from the text of its file. Elements it leaves out are
+// taken from `base`.
+pub fn parse_theme(name: &str, text: &str, base: Option<&Theme>) -> Result<Theme, String> {
+    let table: Table = text.parse().map_err(|e: toml::de::Error| {
+        let line = e.span().map_or(1, |span| text[..span.start].matches('\n').count() + 1);
+        format!("line {}: {}", line, e.message().lines().next().unwrap_or(""))
+    })?;
+    if let Some(key) = table.keys().find(|key| !ELEMENTS.contains(&key.as_str())) {
+        return Err(format!("unknown element {}", key));
+    }
+
+    let mut styles = Vec::with_capacity(ELEMENTS.len());
+    for &element in ELEMENTS {
+        let style = match (table.get(element), base) {
+            (Some(value), _) => parse_style(value, element)?,
+            (None, _) if element == "block_comment" && table.contains_key("comment") => {
+                styles[EditorHighlight::HlComment as usize]
+            }
+            (None, Some(base)) => base.styles[styles.len()],
+            (None, None) => return Err(format!("{} is missing", element)),
+        };
+        styles.push(style);
+    }
+    Ok(Theme { name: name.to_string(), styles })
+}
+
+// The themes that come with the editor, "default" first. The others take
+// what they leave out from it.
+pub fn builtin_themes() -> Vec<Theme> {
+    let parse_builtin = |(name, text): &(&str, &str), base: Option<&Theme>| {
+        parse_theme(name, text, base).unwrap_or_else(|e| panic!("themes/{}.toml: {}", name, e))
+    };
+    let default = parse_builtin(&BUILTIN[0], None);
+    let others: Vec<Theme> = BUILTIN[1..].iter().map(|builtin| parse_builtin(builtin, Some(&default))).collect();
+    std::iter::once(default).chain(others).collect()
+}
+
+// The user's themes followed by the built-in ones they don't replace. The
+// second value lists the files that could not be read.
+pub fn load_themes() -> (Vec<Theme>, Vec<String>) {
+    let builtin = builtin_themes();
+    let default = &builtin[0];
+
+    let mut themes: Vec<Theme> = Vec::new();
+    let mut errors = Vec::new();
+    let mut paths: Vec<PathBuf> = theme_dir()
+        .and_then(|dir| fs::read_dir(dir).ok())
+        .map(|entries| entries.flatten().map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "toml"));
+    paths.sort();
+    for path in paths {
+        let name = path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+        let text = fs::read_to_string(&path).map_err(|e| e.to_string());
+        match text.and_then(|text| parse_theme(&name, &text, Some(default))) {
+            Ok(theme) => themes.push(theme),
+            Err(e) => errors.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    for theme in builtin {
+        if !themes.iter().any(|user| user.name == theme.name) {
+            themes.push(theme);
+        }
+    }
+    (themes, errors)
+}
+
+pub fn find_theme<'a>(themes: &'a [Theme], name: &str) -> Option<&'a Theme> {
+    themes.iter().find(|theme| theme.name == name)
+}
+
+pub fn theme_names(themes: &[Theme]) -> String {
+    let mut names: Vec<&str> = themes.iter().map(|theme| theme.name.as_str()).collect();
+    names.sort();
+    names.join(", ")
+}
+
+impl EditorConfig {
+    // Switch to the theme named by the 'theme' option.
+    pub(crate) fn editor_select_theme(&mut self) -> Result<(), String> {
+        let name = self.options.theme.as_str();
+        let theme = find_theme(&self.themes, name)
+            .ok_or_else(|| format!("Unknown theme: {} (try {})", name, theme_names(&self.themes)))?;
+        self.theme = theme.for_depth(self.color_depth);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colours_go_to_the_nearest_in_the_palette() {
+        assert_eq!(Color::to_indexed((255, 0, 0)), Color::Indexed(196));
+        assert_eq!(Color::to_indexed((0, 0, 0)), Color::Indexed(16));
+        assert_eq!(Color::to_indexed((95, 135, 215)), Color::Indexed(68));
+        // Greys come from the ramp, which is finer than the cube
+        assert_eq!(Color::to_indexed((128, 128, 128)), Color::Indexed(244));
+        assert_eq!(Color::to_indexed((30, 30, 32)), Color::Indexed(234));
+
+        assert_eq!(Color::to_ansi((250, 10, 10)), Color::Ansi(9));
+        assert_eq!(Color::to_ansi((190, 0, 0)), Color::Ansi(1));
+        assert_eq!(Color::to_ansi((20, 20, 20)), Color::Ansi(0));
+    }
+
+    #[test]
+    fn downgrading_only_changes_what_the_terminal_cannot_show() {
+        let rgb = Color::Rgb(255, 0, 0);
+        assert_eq!(rgb.downgrade(ColorDepth::TrueColor), rgb);
+        assert_eq!(rgb.downgrade(ColorDepth::Indexed256), Color::Indexed(196));
+        assert_eq!(rgb.downgrade(ColorDepth::Ansi16), Color::Ansi(9));
+        assert_eq!(Color::Indexed(196).downgrade(ColorDepth::Indexed256), Color::Indexed(196));
+        assert_eq!(Color::Indexed(196).downgrade(ColorDepth::Ansi16), Color::Ansi(9));
+        // The first 16 of the palette are the ANSI colours
+        assert_eq!(Color::Indexed(3).downgrade(ColorDepth::TrueColor), Color::Ansi(3));
+        assert_eq!(Color::Ansi(12).downgrade(ColorDepth::Ansi16), Color::Ansi(12));
+        assert_eq!(Color::Default.downgrade(ColorDepth::Ansi16), Color::Default);
+    }
+
+    #[test]
+    fn styles_are_sent_as_sgr_parameters() {
+        let style = Style { fg: Color::Ansi(9), bg: Color::Indexed(236), bold: true, ..Style::default() };
+        assert_eq!(style.sgr(), "\x1b[0;1;91;48;5;236m");
+        let style = Style { fg: Color::Rgb(1, 2, 3), bg: Color::Ansi(4), underline: true, ..Style::default() };
+        assert_eq!(style.sgr(), "\x1b[0;4;38;2;1;2;3;44m");
+        assert_eq!(Style::default().sgr(), "\x1b[0m");
+    }
+
+    #[test]
+    fn the_depth_comes_from_colorterm_and_term() {
+        assert_eq!(detect_color_depth(Some("truecolor"), Some("xterm")), ColorDepth::TrueColor);
+        assert_eq!(detect_color_depth(Some("24bit"), None), ColorDepth::TrueColor);
+        assert_eq!(detect_color_depth(None, Some("xterm-direct")), ColorDepth::TrueColor);
+        assert_eq!(detect_color_depth(Some("yes"), Some("xterm-256color")), ColorDepth::Indexed256);
+        assert_eq!(detect_color_depth(None, Some("screen-256color")), ColorDepth::Indexed256);
+        assert_eq!(detect_color_depth(None, Some("xterm")), ColorDepth::Ansi16);
+        assert_eq!(detect_color_depth(None, None), ColorDepth::Ansi16);
+    }
+
+    #[test]
+    fn a_theme_file_fills_in_what_it_leaves_out() {
+        let default = &parse_theme("default", BUILTIN[0].1, None).unwrap();
+        let theme = parse_theme("mine", "comment = \"#00ff00\"", Some(default)).unwrap();
+        let comment = Style { fg: Color::Rgb(0, 255, 0), ..Style::default() };
+        assert_eq!(theme.highlight(EditorHighlight::HlComment as u8), comment);
+        assert_eq!(theme.highlight(EditorHighlight::HlMComment as u8), comment);
+        assert_eq!(theme.ui(Ui::StatusBar), default.ui(Ui::StatusBar));
+
+        assert_eq!(parse_theme("x", "comment = \"pink\"", Some(default)).unwrap_err(), "comment has an invalid colour");
+        assert_eq!(parse_theme("x", "colour = 1", Some(default)).unwrap_err(), "unknown element colour");
+        assert_eq!(parse_theme("x", "comment = 1", None).unwrap_err(), "normal is missing");
+    }
+}
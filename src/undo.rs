@@ -0,0 +1,498 @@
+// Edit history: every buffer mutation is recorded as a reversible EditOp.
+//
+// Ops are collected into undo steps. Consecutive typing (or a run of
+// Backspace/Delete presses) is merged into a single step, and callers can
+// wrap compound commands in begin_group/end_group so they undo as one unit.
+
+use crate::{EditorConfig, EditorRow};
+
+pub const UNDO_LEVELS: usize = 1000; // Oldest steps are dropped past this
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EditOp {
+    InsertChar { y: usize, x: usize, c: char },
+    DeleteChar { y: usize, x: usize, c: char },
+    SplitLine { y: usize, x: usize },  // line y was split at byte x
+    JoinLines { y: usize, x: usize },  // line y + 1 was appended at byte x
+    InsertText { y: usize, x: usize, text: String }, // text never contains '\n'
+    DeleteText { y: usize, x: usize, text: String },
+    InsertRow { y: usize, text: String },
+    RemoveRow { y: usize, text: String },
+}
+
+impl EditOp {
+    // The op that undoes this one.
+    pub fn inverse(&self) -> EditOp {
+        match self {
+            EditOp::InsertChar { y, x, c } => EditOp::DeleteChar { y: *y, x: *x, c: *c },
+            EditOp::DeleteChar { y, x, c } => EditOp::InsertChar { y: *y, x: *x, c: *c },
+            EditOp::SplitLine { y, x } => EditOp::JoinLines { y: *y, x: *x },
+            EditOp::JoinLines { y, x } => EditOp::SplitLine { y: *y, x: *x },
+            EditOp::InsertText { y, x, text } => EditOp::DeleteText { y: *y, x: *x, text: text.clone() },
+            EditOp::DeleteText { y, x, text } => EditOp::InsertText { y: *y, x: *x, text: text.clone() },
+            EditOp::InsertRow { y, text } => EditOp::RemoveRow { y: *y, text: text.clone() },
+            EditOp::RemoveRow { y, text } => EditOp::InsertRow { y: *y, text: text.clone() },
+        }
+    }
+
+    // Whether `next` continues the same run of typing or deleting as `self`.
+    fn continues_with(&self, next: &EditOp) -> bool {
+        match (self, next) {
+            (EditOp::InsertChar { y, x, c }, EditOp::InsertChar { y: ny, x: nx, .. }) => {
+                y == ny && x + c.len_utf8() == *nx
+            }
+            (EditOp::InsertRow { y, text }, EditOp::InsertChar { y: ny, x: 0, .. }) => {
+                // Typing past the last line first appends an empty row
+                y == ny && text.is_empty()
+            }
+            _ => match (self.deleted(), next.deleted()) {
+                // Backspace walks left, Delete stays put. A character made
+                // of several is deleted as text.
+                (Some((y, x, _)), Some((ny, nx, len))) => y == ny && (nx + len == x || nx == x),
+                _ => false,
+            },
+        }
+    }
+
+    // Line, byte and length of what a DeleteChar or DeleteText took out.
+    fn deleted(&self) -> Option<(usize, usize, usize)> {
+        match self {
+            EditOp::DeleteChar { y, x, c } => Some((*y, *x, c.len_utf8())),
+            EditOp::DeleteText { y, x, text } => Some((*y, *x, text.len())),
+            _ => None,
+        }
+    }
+}
+
+// One undoable unit, with the cursor (cx, cy) before and after the change.
+#[derive(Debug, Clone)]
+pub struct UndoStep {
+    pub ops: Vec<EditOp>,
+    pub before: (usize, usize),
+    pub after: (usize, usize),
+}
+
+#[derive(Debug)]
+pub struct EditHistory {
+    undo: Vec<UndoStep>,
+    redo: Vec<UndoStep>,
+    group_depth: usize,
+    sealed: bool, // true when the last step must not absorb further ops
+    saved: Option<usize>, // undo steps in the saved text, None once it can't be got back to
+}
+
+impl Default for EditHistory {
+    // The text starts out as saved: empty, or as read from the file
+    fn default() -> Self {
+        EditHistory {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            group_depth: 0,
+            sealed: false,
+            saved: Some(0),
+        }
+    }
+}
+
+impl EditHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, op: EditOp, before: (usize, usize), after: (usize, usize)) {
+        // The saved text is gone with the steps that would redo to it
+        if !self.redo.is_empty() && self.saved > Some(self.undo.len()) {
+            self.saved = None;
+        }
+        self.redo.clear();
+
+        let depth = self.undo.len();
+        if let Some(step) = self.undo.last_mut() {
+            let merge = self.group_depth > 0
+                || (!self.sealed
+                    && step.after == before
+                    && step.ops.last().is_some_and(|last| last.continues_with(&op)));
+
+            if merge {
+                if self.saved == Some(depth) {
+                    self.saved = None; // the step ending in the saved text now goes past it
+                }
+                step.ops.push(op);
+                step.after = after;
+                return;
+            }
+        }
+
+        self.push_step(UndoStep {
+            ops: vec![op],
+            before,
+            after,
+        });
+        self.sealed = false;
+    }
+
+    // Rebuild a history from stored steps (see undo_file), for the text
+    // they were saved with.
+    pub fn from_steps(undo: Vec<UndoStep>, redo: Vec<UndoStep>) -> Self {
+        EditHistory {
+            saved: Some(undo.len()),
+            undo,
+            redo,
+            group_depth: 0,
+            sealed: true,
+        }
+    }
+
+    // The text was just saved; edits after this start a new step.
+    pub fn mark_saved(&mut self) {
+        self.saved = Some(self.undo.len());
+        self.sealed = true;
+    }
+
+    // Whether undo or redo has brought the text back to what was saved.
+    pub fn at_saved(&self) -> bool {
+        self.saved == Some(self.undo.len())
+    }
+
+    pub fn steps(&self) -> (&[UndoStep], &[UndoStep]) {
+        (&self.undo, &self.redo)
+    }
+
+    // Start a compound change; everything recorded until the matching
+    // end_group becomes a single undo step.
+    pub fn begin_group(&mut self, cursor: (usize, usize)) {
+        if self.group_depth == 0 {
+            self.push_step(UndoStep {
+                ops: Vec::new(),
+                before: cursor,
+                after: cursor,
+            });
+        }
+        self.group_depth += 1;
+    }
+
+    pub fn end_group(&mut self, cursor: (usize, usize)) {
+        if self.group_depth == 0 {
+            return;
+        }
+        self.group_depth -= 1;
+        if self.group_depth == 0 {
+            // Drop groups that ended up recording nothing
+            match self.undo.last_mut() {
+                Some(step) if step.ops.is_empty() => {
+                    self.undo.pop();
+                }
+                Some(step) => step.after = cursor,
+                None => {}
+            }
+            self.sealed = true;
+        }
+    }
+
+    // Stop the current typing run so the next edit starts a new step.
+    pub fn seal(&mut self) {
+        self.sealed = true;
+    }
+
+    pub fn pop_undo(&mut self) -> Option<UndoStep> {
+        self.close_groups();
+        let step = self.undo.pop()?;
+        self.redo.push(step.clone());
+        Some(step)
+    }
+
+    pub fn pop_redo(&mut self) -> Option<UndoStep> {
+        self.close_groups();
+        let step = self.redo.pop()?;
+        self.undo.push(step.clone());
+        Some(step)
+    }
+
+    // Undo/redo in the middle of a group ends it early.
+    fn close_groups(&mut self) {
+        if self.group_depth > 0 {
+            self.group_depth = 1;
+            let cursor = self.undo.last().map(|step| step.after).unwrap_or_default();
+            self.end_group(cursor);
+        }
+        self.sealed = true;
+    }
+
+    fn push_step(&mut self, step: UndoStep) {
+        self.undo.push(step);
+        if self.undo.len() > UNDO_LEVELS {
+            self.undo.remove(0);
+            self.saved = self.saved.and_then(|saved| saved.checked_sub(1));
+        }
+    }
+}
+
+impl EditorConfig {
+    // Record a mutation that has already been applied to the buffer.
+    pub(crate) fn record_edit(&mut self, op: EditOp, before: (usize, usize)) {
+        let after = (self.cx, self.cy);
+        self.history.record(op, before, after);
+        self.lsp.buffer_changed();
+    }
+
+    // Apply an op and record it; use inside begin_group/end_group.
+    pub(crate) fn editor_apply(&mut self, op: EditOp) {
+        self.apply_edit_op(&op);
+        let cursor = (self.cx, self.cy);
+        self.history.record(op, cursor, cursor);
+    }
+
+    pub(crate) fn editor_undo(&mut self) {
+        let step = match self.history.pop_undo() {
+            Some(step) => step,
+            None => {
+                self.editor_set_status_msg("Already at oldest change");
+                return;
+            }
+        };
+
+        for op in step.ops.iter().rev() {
+            self.apply_edit_op(&op.inverse());
+        }
+        (self.cx, self.cy) = step.before;
+        self.clamp_cursor();
+        if self.history.at_saved() {
+            self.dirty = 0;
+        }
+    }
+
+    pub(crate) fn editor_redo(&mut self) {
+        let step = match self.history.pop_redo() {
+            Some(step) => step,
+            None => {
+                self.editor_set_status_msg("Already at newest change");
+                return;
+            }
+        };
+
+        for op in step.ops.iter() {
+            self.apply_edit_op(op);
+        }
+        (self.cx, self.cy) = step.after;
+        self.clamp_cursor();
+        if self.history.at_saved() {
+            self.dirty = 0;
+        }
+    }
+
+    // Apply an op straight to the buffer without recording it.
+    pub(crate) fn apply_edit_op(&mut self, op: &EditOp) {
+        match op {
+            EditOp::InsertChar { y, x, c } => {
+                self.erow.insert_char(*y, *x, *c);
+                self.editor_update_syntax(*y);
+            }
+            EditOp::DeleteChar { y, x, .. } => {
+                self.erow.delete_char(*y, *x);
+                self.editor_update_syntax(*y);
+            }
+            EditOp::SplitLine { y, x } => {
+                self.erow.split_line(*y, *x);
+                self.editor_update_syntax(*y);
+                self.editor_update_syntax(*y + 1);
+            }
+            EditOp::JoinLines { y, .. } => {
+                self.erow.join_lines(*y);
+                self.editor_update_syntax(*y);
+            }
+            EditOp::InsertText { y, x, text } => {
+                self.erow.insert_str(*y, *x, text);
+                self.editor_update_syntax(*y);
+            }
+            EditOp::DeleteText { y, x, text } => {
+                self.erow.delete_str(*y, *x, *x + text.len());
+                self.editor_update_syntax(*y);
+            }
+            EditOp::InsertRow { y, text } => {
+                self.erow.insert_row(*y, EditorRow::new(text));
+                self.editor_update_syntax(*y);
+            }
+            EditOp::RemoveRow { y, .. } => {
+                self.erow.remove_row(*y);
+                // Also when it was the last row, so nothing is taken as
+                // highlighted below the end of the buffer
+                self.editor_update_syntax(*y);
+            }
+        }
+        self.dirty += 1;
+        self.lsp.buffer_changed();
+    }
+
+    // Keep the cursor inside the buffer after the text under it changed.
+    fn clamp_cursor(&mut self) {
+        if self.cy > self.erow.len() {
+            self.cy = self.erow.len();
+        }
+        match self.erow.get(self.cy) {
+            Some(row) => self.cx = self.cx.min(row.size),
+            None => self.cx = 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_text(editor: &mut EditorConfig, text: &str) {
+        for c in text.chars() {
+            editor.editor_insert_char(c);
+        }
+    }
+
+    // What editor_save does once the file is written
+    fn save(editor: &mut EditorConfig) {
+        editor.dirty = 0;
+        editor.history.mark_saved();
+    }
+
+    #[test]
+    fn undo_and_redo_back_to_the_saved_text_leave_it_unmodified() {
+        let mut editor = EditorConfig::with_size(80, 24);
+        type_text(&mut editor, "ab");
+        save(&mut editor);
+        type_text(&mut editor, "cd"); // after a save, a step of its own
+        assert!(editor.dirty > 0);
+
+        editor.editor_undo();
+        assert_eq!(editor.erow[0].chars, "ab");
+        assert_eq!(editor.dirty, 0);
+        editor.editor_undo();
+        assert!(editor.dirty > 0);
+        editor.editor_redo();
+        assert_eq!(editor.dirty, 0);
+        editor.editor_redo();
+        assert!(editor.dirty > 0);
+    }
+
+    #[test]
+    fn the_saved_text_is_lost_with_the_redo_steps() {
+        let mut editor = EditorConfig::with_size(80, 24);
+        type_text(&mut editor, "ab");
+        editor.history.seal();
+        type_text(&mut editor, "cd");
+        save(&mut editor);
+
+        editor.editor_undo();
+        type_text(&mut editor, "x");
+        editor.editor_undo();
+        editor.editor_redo();
+        assert_eq!(editor.erow[0].chars, "abx");
+        assert!(editor.dirty > 0);
+    }
+
+    #[test]
+    fn a_run_of_typing_is_one_step_until_the_cursor_jumps() {
+        let mut editor = EditorConfig::with_size(80, 24);
+        type_text(&mut editor, "ab");
+        editor.cx = 0;
+        type_text(&mut editor, "xy");
+        assert_eq!(editor.erow[0].chars, "xyab");
+
+        editor.editor_undo();
+        assert_eq!(editor.erow[0].chars, "ab");
+        assert_eq!((editor.cx, editor.cy), (0, 0));
+        editor.editor_undo();
+        assert_eq!(editor.erow.len(), 0);
+
+        // Backspaces walking left are one step too
+        type_text(&mut editor, "abcd");
+        editor.history.seal();
+        editor.editor_del_char();
+        editor.editor_del_char();
+        editor.editor_undo();
+        assert_eq!((editor.erow[0].chars.as_str(), editor.cx), ("abcd", 4));
+    }
+
+    #[test]
+    fn what_is_typed_in_insert_mode_undoes_in_one_step() {
+        let mut editor = EditorConfig::with_lines(&["ab"]);
+        editor.type_keys("A cd ef\x1b");
+        assert_eq!(editor.lines(), ["ab cd ef"]);
+        editor.type_keys("u");
+        assert_eq!(editor.lines(), ["ab"]);
+        editor.type_keys("\x12");
+        assert_eq!(editor.lines(), ["ab cd ef"]);
+
+        // Moving with an arrow key starts a new step
+        editor.type_keys("Axy\u{e004}z\x1b");
+        assert_eq!(editor.lines(), ["ab cd efxzy"]);
+        editor.type_keys("u");
+        assert_eq!(editor.lines(), ["ab cd efxy"]);
+        editor.type_keys("u");
+        assert_eq!(editor.lines(), ["ab cd ef"]);
+    }
+
+    #[test]
+    fn a_line_join_and_a_line_split_undo_and_redo() {
+        let mut editor = EditorConfig::with_lines(&["ab", "cd"]);
+        editor.type_keys("jI\x7f\x1b");
+        assert_eq!(editor.lines(), ["abcd"]);
+        editor.type_keys("u");
+        assert_eq!(editor.lines(), ["ab", "cd"]);
+        assert_eq!((editor.cx, editor.cy), (0, 1));
+        editor.type_keys("\x12");
+        assert_eq!(editor.lines(), ["abcd"]);
+        assert_eq!((editor.cx, editor.cy), (2, 0));
+
+        editor.type_keys("i\n\x1b");
+        assert_eq!(editor.lines(), ["ab", "cd"]);
+        editor.type_keys("u");
+        assert_eq!(editor.lines(), ["abcd"]);
+        assert_eq!((editor.cx, editor.cy), (2, 0));
+        editor.type_keys("\x12");
+        assert_eq!(editor.lines(), ["ab", "cd"]);
+        assert_eq!((editor.cx, editor.cy), (0, 1));
+    }
+
+    #[test]
+    fn undo_puts_the_cursor_back_where_the_change_was() {
+        let mut editor = EditorConfig::with_lines(&["one", "two", "three"]);
+        editor.type_keys("jjllxgg");
+        assert_eq!(editor.lines(), ["one", "two", "thee"]);
+        assert_eq!((editor.cx, editor.cy), (0, 0));
+        editor.type_keys("u");
+        assert_eq!(editor.lines(), ["one", "two", "three"]);
+        assert_eq!((editor.cx, editor.cy), (2, 2));
+
+        editor.type_keys("kddG\x12");
+        assert_eq!(editor.lines(), ["one", "three"]);
+        assert_eq!((editor.cx, editor.cy), (0, 1));
+    }
+
+    #[test]
+    fn ctrl_z_and_ctrl_y_work_like_u_and_ctrl_r() {
+        let mut editor = EditorConfig::with_lines(&["abc"]);
+        editor.type_keys("xx");
+        editor.type_keys("\x1a");
+        assert_eq!(editor.lines(), ["bc"]);
+        editor.type_keys("u");
+        assert_eq!(editor.lines(), ["abc"]);
+        editor.type_keys("\x19");
+        assert_eq!(editor.lines(), ["bc"]);
+        editor.type_keys("\x12");
+        assert_eq!(editor.lines(), ["c"]);
+
+        editor.type_keys("\x19");
+        assert_eq!(editor.status_msg, "Already at newest change");
+        editor.type_keys("uuu");
+        assert_eq!(editor.lines(), ["abc"]);
+        assert_eq!(editor.status_msg, "Already at oldest change");
+    }
+
+    #[test]
+    fn only_the_newest_steps_are_kept() {
+        let mut editor = EditorConfig::with_lines(&[&"a".repeat(UNDO_LEVELS + 2)]);
+        editor.type_keys(&"x".repeat(UNDO_LEVELS + 1));
+        assert_eq!(editor.lines(), ["a"]);
+
+        editor.type_keys(&"u".repeat(UNDO_LEVELS + 1));
+        assert_eq!(editor.lines()[0].len(), UNDO_LEVELS + 1);
+        assert_eq!(editor.status_msg, "Already at oldest change");
+    }
+}
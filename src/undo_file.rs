@@ -0,0 +1,376 @@
+// Persistent undo: the edit history of a file is kept in a sidecar file under
+// $XDG_STATE_HOME/kibi/undo/ (falling back to ~/.local/state/kibi/undo/).
+//
+// The sidecar is named after a hash of the file's canonical path and records
+// a hash of the contents it belongs to. If the file was changed outside the
+// editor the hashes no longer match and the history is thrown away. Anything
+// that fails to parse, or whose edits don't fit the text, is ignored the
+// same way.
+
+use crate::undo::{EditHistory, EditOp, UndoStep};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const UNDO_FILE_MAGIC: &str = "kibi-undo 1";
+
+// FNV-1a: tiny, and stable across Rust versions unlike DefaultHasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Where sidecars go by default.
+pub fn undo_dir() -> Option<PathBuf> {
+    let state_home = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(state_home.join("kibi").join("undo"))
+}
+
+fn undo_file_for(dir: &Path, canonical: &Path) -> PathBuf {
+    let key = fnv1a(canonical.to_string_lossy().as_bytes());
+    dir.join(format!("{:016x}", key))
+}
+
+// Store `history` for `filename`, whose saved contents are `text`, in the
+// sidecar directory `dir`.
+pub fn save_history(dir: &Path, filename: &str, text: &str, history: &EditHistory) -> io::Result<()> {
+    let canonical = fs::canonicalize(filename)?;
+    let path = undo_file_for(dir, &canonical);
+
+    let mut out = String::new();
+    out.push_str(UNDO_FILE_MAGIC);
+    out.push('\n');
+    out.push_str(&format!("path {}\n", hex_encode(canonical.to_string_lossy().as_bytes())));
+    out.push_str(&format!("hash {:016x}\n", fnv1a(text.as_bytes())));
+
+    let (undo, redo) = history.steps();
+    write_steps(&mut out, "undo", undo);
+    write_steps(&mut out, "redo", redo);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, out)
+}
+
+// Load the history for `filename` from `dir` if one exists and matches `text`.
+pub fn load_history(dir: &Path, filename: &str, text: &str) -> Option<EditHistory> {
+    let canonical = fs::canonicalize(filename).ok()?;
+    let contents = fs::read_to_string(undo_file_for(dir, &canonical)).ok()?;
+    let mut lines = contents.lines();
+
+    if lines.next()? != UNDO_FILE_MAGIC {
+        return None;
+    }
+    let path = lines.next()?.strip_prefix("path ")?;
+    if path != hex_encode(canonical.to_string_lossy().as_bytes()) {
+        return None;
+    }
+    let hash = lines.next()?.strip_prefix("hash ")?;
+    if hash != format!("{:016x}", fnv1a(text.as_bytes())) {
+        return None; // File changed behind our back, history is stale
+    }
+
+    let mut undo = Vec::new();
+    let mut redo = Vec::new();
+
+    while let Some(line) = lines.next() {
+        let mut fields = line.split(' ');
+        let stack = match fields.next()? {
+            "undo" => &mut undo,
+            "redo" => &mut redo,
+            _ => return None,
+        };
+        let mut nums = fields.map(|f| f.parse::<usize>());
+        let mut next_num = || nums.next()?.ok();
+        let before = (next_num()?, next_num()?);
+        let after = (next_num()?, next_num()?);
+        let op_count = next_num()?;
+
+        // The count is only trusted as far as there are lines to back it
+        let mut ops = Vec::new();
+        for _ in 0..op_count {
+            ops.push(parse_op(lines.next()?)?);
+        }
+        stack.push(UndoStep { ops, before, after });
+    }
+
+    if !steps_fit(text, &undo, &redo) {
+        return None;
+    }
+    Some(EditHistory::from_steps(undo, redo))
+}
+
+// Walk the steps back to the oldest text and forward again to the newest, so
+// an op that doesn't fit the rows it would be applied to is caught here and
+// not when undo or redo gets to it.
+fn steps_fit(text: &str, undo: &[UndoStep], redo: &[UndoStep]) -> bool {
+    let mut rows: Vec<String> = text.lines().map(|line| line.trim_end_matches('\r').to_string()).collect();
+    let back = undo.iter().rev().flat_map(|step| step.ops.iter().rev().map(EditOp::inverse));
+    let forward = undo.iter().chain(redo.iter().rev()).flat_map(|step| step.ops.iter().cloned());
+    back.chain(forward).all(|op| apply_op(&mut rows, &op).is_some())
+}
+
+// Apply `op` to plain rows as apply_edit_op does to the buffer, or None if
+// its line, byte or text don't match what is there.
+fn apply_op(rows: &mut Vec<String>, op: &EditOp) -> Option<()> {
+    match op {
+        EditOp::InsertChar { y, x, c } => {
+            let row = rows.get_mut(*y)?;
+            row.is_char_boundary(*x).then(|| row.insert(*x, *c))
+        }
+        EditOp::DeleteChar { y, x, c } => {
+            let row = rows.get_mut(*y)?;
+            row.get(*x..)?.starts_with(*c).then(|| {
+                row.remove(*x);
+            })
+        }
+        EditOp::SplitLine { y, x } => {
+            let row = rows.get_mut(*y)?;
+            let right = row.is_char_boundary(*x).then(|| row.split_off(*x))?;
+            rows.insert(y + 1, right);
+            Some(())
+        }
+        EditOp::JoinLines { y, x } => {
+            if y + 1 >= rows.len() || rows[*y].len() != *x {
+                return None;
+            }
+            let next = rows.remove(y + 1);
+            rows[*y].push_str(&next);
+            Some(())
+        }
+        EditOp::InsertText { y, x, text } => {
+            let row = rows.get_mut(*y)?;
+            row.is_char_boundary(*x).then(|| row.insert_str(*x, text))
+        }
+        EditOp::DeleteText { y, x, text } => {
+            let row = rows.get_mut(*y)?;
+            row.get(*x..)?.starts_with(text.as_str()).then(|| row.replace_range(*x..x + text.len(), ""))
+        }
+        EditOp::InsertRow { y, text } => (*y <= rows.len()).then(|| rows.insert(*y, text.clone())),
+        EditOp::RemoveRow { y, text } => (rows.get(*y)? == text).then(|| {
+            rows.remove(*y);
+        }),
+    }
+}
+
+fn write_steps(out: &mut String, tag: &str, steps: &[UndoStep]) {
+    for step in steps {
+        out.push_str(&format!(
+            "{} {} {} {} {} {}\n",
+            tag,
+            step.before.0,
+            step.before.1,
+            step.after.0,
+            step.after.1,
+            step.ops.len()
+        ));
+        for op in &step.ops {
+            let line = match op {
+                EditOp::InsertChar { y, x, c } => format!("ic {} {} {}", y, x, *c as u32),
+                EditOp::DeleteChar { y, x, c } => format!("dc {} {} {}", y, x, *c as u32),
+                EditOp::SplitLine { y, x } => format!("sl {} {}", y, x),
+                EditOp::JoinLines { y, x } => format!("jl {} {}", y, x),
+                EditOp::InsertText { y, x, text } => {
+                    format!("it {} {} {}", y, x, hex_encode(text.as_bytes()))
+                }
+                EditOp::DeleteText { y, x, text } => {
+                    format!("dt {} {} {}", y, x, hex_encode(text.as_bytes()))
+                }
+                EditOp::InsertRow { y, text } => format!("ir {} {}", y, hex_encode(text.as_bytes())),
+                EditOp::RemoveRow { y, text } => format!("rr {} {}", y, hex_encode(text.as_bytes())),
+            };
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+}
+
+fn parse_op(line: &str) -> Option<EditOp> {
+    let mut fields = line.split(' ');
+    let kind = fields.next()?;
+    let y = fields.next()?.parse().ok()?;
+
+    let op = match kind {
+        "ic" | "dc" => {
+            let x = fields.next()?.parse().ok()?;
+            let c = char::from_u32(fields.next()?.parse().ok()?)?;
+            if kind == "ic" {
+                EditOp::InsertChar { y, x, c }
+            } else {
+                EditOp::DeleteChar { y, x, c }
+            }
+        }
+        "sl" => EditOp::SplitLine { y, x: fields.next()?.parse().ok()? },
+        "jl" => EditOp::JoinLines { y, x: fields.next()?.parse().ok()? },
+        "it" | "dt" => {
+            let x = fields.next()?.parse().ok()?;
+            let text = String::from_utf8(hex_decode(fields.next().unwrap_or(""))?).ok()?;
+            if kind == "it" {
+                EditOp::InsertText { y, x, text }
+            } else {
+                EditOp::DeleteText { y, x, text }
+            }
+        }
+        "ir" | "rr" => {
+            let text = String::from_utf8(hex_decode(fields.next().unwrap_or(""))?).ok()?;
+            if kind == "ir" {
+                EditOp::InsertRow { y, text }
+            } else {
+                EditOp::RemoveRow { y, text }
+            }
+        }
+        _ => return None,
+    };
+    Some(op)
+}
+
+// Row text is hex encoded so newlines and spaces can't break the format.
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Where the tests keep their files and sidecars.
+    fn scratch_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("kibi-undo-{}", std::process::id()))
+    }
+
+    // A file holding `text`, with the sidecars of every test kept next to it.
+    fn scratch_file(test: &str, text: &str) -> String {
+        let dir = scratch_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join(format!("{}.txt", test));
+        fs::write(&file, text).unwrap();
+        file.to_string_lossy().into_owned()
+    }
+
+    fn save(filename: &str, text: &str, history: &EditHistory) {
+        save_history(&scratch_dir(), filename, text, history).unwrap();
+    }
+
+    fn load(filename: &str, text: &str) -> Option<EditHistory> {
+        load_history(&scratch_dir(), filename, text)
+    }
+
+    fn sidecar(filename: &str) -> PathBuf {
+        undo_file_for(&scratch_dir(), &fs::canonicalize(filename).unwrap())
+    }
+
+    type Steps = Vec<(Vec<EditOp>, (usize, usize), (usize, usize))>;
+
+    fn steps(history: &EditHistory) -> (Steps, Steps) {
+        let plain = |steps: &[UndoStep]| steps.iter().map(|s| (s.ops.clone(), s.before, s.after)).collect();
+        let (undo, redo) = history.steps();
+        (plain(undo), plain(redo))
+    }
+
+    // From "one\ntwo\n": "one!", drop "two", add "thrée", then undo the
+    // last step, which leaves "one!\n".
+    fn edited_history() -> EditHistory {
+        let mut history = EditHistory::new();
+        history.record(EditOp::InsertChar { y: 0, x: 3, c: '!' }, (3, 0), (4, 0));
+        history.seal();
+        history.record(EditOp::RemoveRow { y: 1, text: "two".to_string() }, (0, 1), (0, 0));
+        history.seal();
+        history.record(EditOp::InsertRow { y: 1, text: "thrée".to_string() }, (0, 0), (0, 1));
+        history.pop_undo();
+        history
+    }
+
+    #[test]
+    fn saved_history_loads_back() {
+        let file = scratch_file("round-trip", "one!\n");
+        let history = edited_history();
+        save(&file, "one!\n", &history);
+
+        let loaded = load(&file, "one!\n").unwrap();
+        assert_eq!(steps(&loaded), steps(&history));
+        assert_eq!(steps(&loaded).1.len(), 1);
+        assert!(loaded.at_saved());
+    }
+
+    #[test]
+    fn truncated_or_garbage_sidecars_are_ignored() {
+        let file = scratch_file("garbage", "one!\n");
+        save(&file, "one!\n", &edited_history());
+        let contents = fs::read_to_string(sidecar(&file)).unwrap();
+
+        // Cut off the last op of the last step
+        let cut = contents.trim_end().rfind('\n').unwrap();
+        fs::write(sidecar(&file), &contents[..cut + 1]).unwrap();
+        assert!(load(&file, "one!\n").is_none());
+
+        // A bad op after the header
+        let header: String = contents.lines().take(3).map(|line| format!("{}\n", line)).collect();
+        fs::write(sidecar(&file), format!("{}undo 0 0 0 0 1\nzz 0 0\n", header)).unwrap();
+        assert!(load(&file, "one!\n").is_none());
+
+        fs::write(sidecar(&file), "not an undo file\n").unwrap();
+        assert!(load(&file, "one!\n").is_none());
+        fs::write(sidecar(&file), [0xff, 0xfe, b'\n']).unwrap();
+        assert!(load(&file, "one!\n").is_none());
+    }
+
+    #[test]
+    fn history_for_other_text_is_ignored() {
+        let file = scratch_file("stale", "one!\n");
+        save(&file, "one!\n", &edited_history());
+        assert!(load(&file, "one?\n").is_none());
+        assert!(load(&file, "one!\n").is_some());
+    }
+
+    #[test]
+    fn ops_that_do_not_fit_the_text_are_ignored() {
+        let file = scratch_file("range", "one!\n");
+        let bad = [
+            EditOp::InsertChar { y: 5, x: 0, c: 'x' },
+            EditOp::InsertText { y: 0, x: 9, text: "x".to_string() },
+            EditOp::DeleteChar { y: 0, x: 9, c: 'x' },
+            EditOp::SplitLine { y: 0, x: 7 },
+            EditOp::JoinLines { y: 1, x: 0 },
+            EditOp::RemoveRow { y: 3, text: String::new() },
+        ];
+        for op in bad {
+            let mut history = EditHistory::new();
+            history.record(op.clone(), (0, 0), (0, 0));
+            save(&file, "one!\n", &history);
+            assert!(load(&file, "one!\n").is_none(), "{:?}", op);
+        }
+
+        // Undoing the split leaves "ab", which the join doesn't fit
+        let mut history = EditHistory::new();
+        history.record(EditOp::SplitLine { y: 0, x: 1 }, (0, 0), (0, 0));
+        history.record(EditOp::JoinLines { y: 0, x: 2 }, (0, 0), (0, 0));
+        let file = scratch_file("join", "ab\n");
+        save(&file, "ab\n", &history);
+        assert!(load(&file, "ab\n").is_none());
+
+        // Undoing this would delete in the middle of the 'é'
+        let file = scratch_file("char-boundary", "é\n");
+        let mut history = EditHistory::new();
+        history.record(EditOp::InsertChar { y: 0, x: 1, c: 'x' }, (0, 0), (0, 0));
+        save(&file, "é\n", &history);
+        assert!(load(&file, "é\n").is_none());
+    }
+}